@@ -0,0 +1,67 @@
+// Mounts the blog under /journal, next to the routes of a larger application.
+// Run from the repository root, so that the default theme is found:
+//
+//     cargo run --example embed
+//
+// then open http://127.0.0.1:8090/journal/blog/simple
+use warp::Filter;
+
+use swes::{routes::rejection_response, BlogEngine};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let engine = BlogEngine::builder()
+        .base_path("tests")
+        .referrer_tracking(false)
+        .mount_path("/journal")
+        .build()
+        .await?;
+    engine.start_watchers(tokio::runtime::Handle::current())?;
+
+    let hello = warp::path!("hello").map(|| "Hello from the host application");
+    let routes = warp::path("journal")
+        .and(engine.routes())
+        .or(hello)
+        .recover(rejection_response);
+
+    let response = warp::test::request()
+        .path("/journal/blog/simple")
+        .reply(&routes)
+        .await;
+    println!(
+        "GET /journal/blog/simple: {} ({} bytes)",
+        response.status(),
+        response.body().len()
+    );
+    anyhow::ensure!(
+        response.status() == warp::http::StatusCode::OK,
+        "The embedded blog didn't serve its entry"
+    );
+    let body = String::from_utf8_lossy(response.body());
+    anyhow::ensure!(
+        body.contains("href=\"/journal/files/style.css\""),
+        "The links of the entry page aren't under /journal"
+    );
+
+    // The file names redirect to the slugs, relatively to stay under /journal
+    let response = warp::test::request()
+        .path("/journal/blog/simple.md")
+        .reply(&routes)
+        .await;
+    anyhow::ensure!(
+        response.headers().get("location").map(|l| l.as_bytes()) == Some(b"simple".as_slice()),
+        "Unexpected redirect {:?}",
+        response.headers().get("location")
+    );
+
+    let (addr, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(([127, 0, 0, 1], 8090), async {
+            let _ = tokio::signal::ctrl_c().await;
+        });
+    println!("Listening on http://{addr}/journal/blog");
+    server.await;
+    engine.shutdown().await;
+    Ok(())
+}
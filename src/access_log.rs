@@ -0,0 +1,297 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+};
+use warp::{
+    http::{HeaderMap, Method},
+    hyper::body::HttpBody,
+    path::FullPath,
+    reply::Response,
+    Filter, Reply,
+};
+
+const ACCESS_LOG_QUEUE_SIZE: usize = 4096;
+
+pub struct AccessLog {
+    sender: Sender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+struct Request {
+    remote: Option<SocketAddr>,
+    time: DateTime<Utc>,
+    method: Method,
+    path: FullPath,
+    query: String,
+    headers: HeaderMap,
+}
+
+impl AccessLog {
+    // The file is opened right away so that a bad path fails the startup
+    pub async fn spawn(path: PathBuf) -> anyhow::Result<Arc<Self>> {
+        let file = open_log(&path).await?;
+        let (sender, receiver) = mpsc::channel(ACCESS_LOG_QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_lines(path, file, receiver, dropped.clone()));
+        Ok(Arc::new(Self { sender, dropped }))
+    }
+
+    // Never blocks request serving: when the writer can't keep up the line is
+    // dropped and counted instead
+    pub fn record(&self, line: String) {
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("Access log queue unavailable, {dropped} lines dropped so far");
+            }
+        }
+    }
+}
+
+async fn open_log(path: &PathBuf) -> anyhow::Result<File> {
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    Ok(file)
+}
+
+// SIGUSR2 reopens the file, so that logrotate can move it away and signal us
+async fn write_lines(
+    path: PathBuf,
+    mut file: File,
+    mut receiver: Receiver<String>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut reopen = match signal(SignalKind::user_defined2()) {
+        Ok(reopen) => Some(reopen),
+        Err(e) => {
+            error!("Cannot listen for SIGUSR2, the access log won't be reopened: {e}");
+            None
+        }
+    };
+    loop {
+        tokio::select! {
+            line = receiver.recv() => {
+                let Some(line) = line else {
+                    break;
+                };
+                // Flushed, or the error of a failed write would only come
+                // out with the next line
+                let written = async {
+                    file.write_all(line.as_bytes()).await?;
+                    file.flush().await
+                };
+                if let Err(e) = written.await {
+                    let dropped = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    error!("Failed to write access log line ({dropped} dropped so far): {e}");
+                }
+            }
+            Some(()) = async { reopen.as_mut()?.recv().await } => {
+                info!("Reopening access log {path:?}");
+                match open_log(&path).await {
+                    Ok(new_file) => file = new_file,
+                    Err(e) => error!("Failed to reopen access log {path:?}: {e}"),
+                }
+            }
+        }
+    }
+}
+
+pub fn with_access_log<F, T>(
+    filter: F,
+    access_log: Option<Arc<AccessLog>>,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (T,), Error = Infallible> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    let request = warp::addr::remote()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .map(|remote, method, path, query, headers| Request {
+            remote,
+            time: Utc::now(),
+            method,
+            path,
+            query,
+            headers,
+        });
+
+    request.and(filter).map(move |request: Request, reply: T| {
+        let response = reply.into_response();
+        if let Some(access_log) = &access_log {
+            access_log.record(combined_log_line(&request, &response));
+        }
+        response
+    })
+}
+
+fn combined_log_line(request: &Request, response: &Response) -> String {
+    let remote = request
+        .remote
+        .map(|r| r.ip().to_string())
+        .unwrap_or("-".to_owned());
+    let target = if request.query.is_empty() {
+        request.path.as_str().to_owned()
+    } else {
+        format!("{}?{}", request.path.as_str(), request.query)
+    };
+    let bytes = response
+        .body()
+        .size_hint()
+        .exact()
+        .map(|b| b.to_string())
+        .unwrap_or("-".to_owned());
+    let header = |name: &str| {
+        request
+            .headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("-")
+            .replace('"', "\\\"")
+    };
+    // warp doesn't expose the request's protocol version, HTTP/1.1 is assumed
+    format!(
+        "{remote} - - [{}] \"{} {target} HTTP/1.1\" {} {bytes} \"{}\" \"{}\"\n",
+        request.time.format("%d/%b/%Y:%H:%M:%S %z"),
+        request.method,
+        response.status().as_u16(),
+        header("referer"),
+        header("user-agent"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    // The lines are written in the background, this waits for them
+    async fn written_lines(path: &PathBuf, count: usize) -> Vec<String> {
+        for _ in 0..200 {
+            let content = tokio::fs::read_to_string(path).await.unwrap_or_default();
+            let lines: Vec<_> = content.lines().map(str::to_owned).collect();
+            if lines.len() >= count {
+                return lines;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Expected {count} lines in {path:?}");
+    }
+
+    #[tokio::test]
+    async fn logs_in_the_combined_format() {
+        let dir = TempDir::new("access-log-format");
+        let path = dir.join("access.log");
+        let access_log = AccessLog::spawn(path.clone()).await.unwrap();
+        let routes = with_access_log(warp::any().map(|| "hello"), Some(access_log));
+
+        warp::test::request()
+            .remote_addr("192.0.2.7:4000".parse().unwrap())
+            .path("/blog/first?page=2")
+            .header("referer", "https://example.com/")
+            .header("user-agent", "Reader \"1.0\"")
+            .reply(&routes)
+            .await;
+        warp::test::request()
+            .method("HEAD")
+            .path("/feed")
+            .reply(&routes)
+            .await;
+
+        let lines = written_lines(&path, 2).await;
+        let (remote, rest) = lines[0].split_once(" [").unwrap();
+        assert_eq!(remote, "192.0.2.7 - -");
+        let (time, rest) = rest.split_once("] ").unwrap();
+        assert!(
+            DateTime::parse_from_str(time, "%d/%b/%Y:%H:%M:%S %z").is_ok(),
+            "{time}"
+        );
+        assert_eq!(
+            rest,
+            "\"GET /blog/first?page=2 HTTP/1.1\" 200 5 \
+             \"https://example.com/\" \"Reader \\\"1.0\\\"\""
+        );
+        // Without a remote address or headers
+        assert!(lines[1].starts_with("- - - ["), "{}", lines[1]);
+        assert!(
+            lines[1].ends_with("\"HEAD /feed HTTP/1.1\" 200 5 \"-\" \"-\""),
+            "{}",
+            lines[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn reopens_the_file_on_sigusr2() {
+        let dir = TempDir::new("access-log-reopen");
+        let path = dir.join("access.log");
+        let access_log = AccessLog::spawn(path.clone()).await.unwrap();
+        access_log.record("before\n".to_owned());
+        // Also makes sure the writer is listening for the signal
+        written_lines(&path, 1).await;
+
+        // What logrotate does
+        let rotated = dir.join("access.log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        let status = std::process::Command::new("kill")
+            .args(["-USR2", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // Until the signal is handled, lines still go to the rotated file
+        let mut reopened = false;
+        for _ in 0..200 {
+            access_log.record("after\n".to_owned());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if path.exists() {
+                reopened = true;
+                break;
+            }
+        }
+        assert!(reopened, "The log wasn't reopened");
+        access_log.record("after\n".to_owned());
+        assert_eq!(written_lines(&path, 1).await[0], "after");
+        let rotated = std::fs::read_to_string(&rotated).unwrap();
+        assert!(rotated.starts_with("before\n"), "{rotated}");
+        assert_eq!(access_log.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn counts_the_lines_that_fail_to_be_written() {
+        // Every write fails with "no space left on device"
+        let access_log = AccessLog::spawn(PathBuf::from("/dev/full")).await.unwrap();
+        access_log.record("lost\n".to_owned());
+        access_log.record("lost too\n".to_owned());
+        for _ in 0..200 {
+            if access_log.dropped.load(Ordering::Relaxed) == 2 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "{} lines counted as dropped",
+            access_log.dropped.load(Ordering::Relaxed)
+        );
+    }
+}
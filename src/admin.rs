@@ -0,0 +1,595 @@
+use std::{
+    cmp::Ordering,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use log::info;
+use serde::{Deserialize, Serialize};
+use warp::{
+    filters::multipart::FormData,
+    http::StatusCode,
+    hyper::body::Bytes,
+    reply::{Reply, Response},
+    Buf,
+};
+
+use crate::{
+    artifact_store::ArtifactStore,
+    blog_storage::{parse_front_matter, AdminEntry, BlogStorage, EntryStatus, PostMetadata},
+    clock::SharedClock,
+    handlebars_support::{AdminEntryLinks, AdminRow, HandlebarsSupport},
+    images::RESIZABLE_EXTENSIONS,
+    incidents::{Failure, Incidents, INCIDENTS_KEPT},
+    outbox::Outbox,
+    purge::{PurgeRegistry, PurgeRequest},
+    referrers::Referrers,
+    retries::ReadRetries,
+    routes::html_response,
+    signing::{constant_time_eq, Signer},
+    uploads::{self, UploadError, UploadKind, Uploaded, Uploads},
+};
+
+pub(crate) const SHARE_DEFAULT_HOURS: i64 = 72;
+// A shared link can't outlive a month
+const SHARE_MAX_HOURS: i64 = 24 * 30;
+
+pub(crate) fn admin_referrers(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    referrers: Option<Arc<Referrers>>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED)
+            .into_response();
+    }
+    match referrers {
+        Some(referrers) => warp::reply::json(&referrers.counts()).into_response(),
+        None => warp::reply::with_status(
+            "Referrer tracking is disabled",
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    }
+}
+
+pub(crate) fn admin_incident(
+    id: String,
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    incidents: Arc<Incidents>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED)
+            .into_response();
+    }
+    match incidents.get(&id) {
+        Some(incident) => warp::reply::json(&incident).into_response(),
+        None => warp::reply::with_status(
+            format!("No incident {id}, only the last {INCIDENTS_KEPT} are kept"),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AdminEntriesQuery {
+    sort: Option<AdminSort>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AdminSort {
+    Slug,
+    Status,
+    #[default]
+    Date,
+    Parsed,
+    Words,
+    Views,
+}
+
+impl AdminSort {
+    fn name(self) -> &'static str {
+        match self {
+            AdminSort::Slug => "slug",
+            AdminSort::Status => "status",
+            AdminSort::Date => "date",
+            AdminSort::Parsed => "parsed",
+            AdminSort::Words => "words",
+            AdminSort::Views => "views",
+        }
+    }
+
+    // Text ascending, everything else biggest or newest first. Ties are
+    // always broken by slug, so that refreshing keeps the rows in place
+    fn sort(self, entries: &mut [AdminEntry]) {
+        entries.sort_by(|a, b| {
+            let ordering = match self {
+                AdminSort::Slug => Ordering::Equal,
+                AdminSort::Status => a.status.cmp(&b.status),
+                AdminSort::Date => b.publish_date.cmp(&a.publish_date),
+                AdminSort::Parsed => b.parsed_at.cmp(&a.parsed_at),
+                AdminSort::Words => b.word_count.cmp(&a.word_count),
+                AdminSort::Views => b.views.cmp(&a.views),
+            };
+            ordering.then_with(|| a.slug.cmp(&b.slug))
+        });
+    }
+}
+
+// What's needed to tell which routes can serve an entry
+#[derive(Clone)]
+pub(crate) struct AdminLinks {
+    pub(crate) signer: Option<Arc<Signer>>,
+    pub(crate) clock: SharedClock,
+    pub(crate) dev: bool,
+}
+
+impl AdminLinks {
+    fn links_for(&self, entry: &AdminEntry) -> AdminEntryLinks {
+        // Previews and diffs take a single path segment
+        let top_level = !entry.slug.contains('/');
+        let known = entry.status != EntryStatus::Draft && entry.title.is_some();
+        AdminEntryLinks {
+            view: entry.public.then(|| format!("/blog/{}", entry.slug)),
+            preview: match &self.signer {
+                Some(signer) if top_level && entry.error.is_none() => {
+                    let expires_at =
+                        self.clock.now() + chrono::Duration::hours(SHARE_DEFAULT_HOURS);
+                    let sig = signer.sign(&entry.slug, expires_at);
+                    Some(format!(
+                        "/preview/{}?sig={sig}&exp={}",
+                        entry.slug,
+                        expires_at.timestamp()
+                    ))
+                }
+                _ => None,
+            },
+            diff: (self.dev && top_level && known).then(|| format!("/preview/{}/diff", entry.slug)),
+            purge: known,
+        }
+    }
+}
+
+pub(crate) async fn admin_entries(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    query: AdminEntriesQuery,
+    links: AdminLinks,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    }
+    let sort = query.sort.unwrap_or_default();
+    let mut entries = storage.admin_listing().await;
+    sort.sort(&mut entries);
+    let rows = entries
+        .into_iter()
+        .map(|entry| AdminRow {
+            links: links.links_for(&entry),
+            entry,
+        })
+        .collect();
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    html_response(
+        handlebars_support.format_admin_entries(storage.blog_info(), sort.name(), rows),
+        StatusCode::OK,
+    )
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PurgeForm {
+    slug: String,
+}
+
+// The form flavour of /admin/purge, for the buttons of the admin listing
+pub(crate) async fn admin_entries_purge(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    form: PurgeForm,
+    storage: Arc<BlogStorage>,
+    purge_registry: Arc<PurgeRegistry>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    }
+    let request = PurgeRequest {
+        slugs: vec![form.slug],
+        ..Default::default()
+    };
+    let entries = storage.matching_entries(|e| request.matches(e)).await;
+    let purged = purge_registry.purge(&request, &entries).await;
+    info!("Purged {purged:?}");
+    // Relative, from /admin/entries/purge
+    warp::reply::with_header(StatusCode::SEE_OTHER, "location", "../entries").into_response()
+}
+
+pub(crate) fn admin_artifacts(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    artifacts: Option<Arc<ArtifactStore>>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED)
+            .into_response();
+    }
+    match artifacts {
+        Some(artifacts) => warp::reply::json(&artifacts.usage()).into_response(),
+        None => warp::reply::with_status(
+            "The artifact store is disabled, set --artifacts-path to enable it",
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    }
+}
+
+// How often the reads of entries and files had to be retried
+pub(crate) fn admin_reads(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    read_retries: Arc<ReadRetries>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED)
+            .into_response();
+    }
+    warp::reply::json(&read_retries.stats()).into_response()
+}
+
+// How many notifications to other services are waiting, and how many were
+// given up
+pub(crate) fn admin_outbox(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    outbox: Option<Arc<Outbox>>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED)
+            .into_response();
+    }
+    match outbox {
+        Some(outbox) => warp::reply::json(&outbox.stats()).into_response(),
+        None => warp::reply::with_status(
+            "The outbox is disabled, set --outbox-path to enable it",
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct ParsedFrontMatter {
+    metadata: PostMetadata,
+    // In bytes, where the markdown starts
+    body_offset: usize,
+}
+
+// The front matter of a post as publishing it would read it, for the editor
+// plugins. A list of errors, though the parsers stop at the first one
+pub(crate) fn admin_parse_front_matter(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    body: Bytes,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    }
+    let Ok(src) = std::str::from_utf8(&body) else {
+        return warp::reply::with_status("The post isn't valid UTF-8", StatusCode::BAD_REQUEST)
+            .into_response();
+    };
+    match parse_front_matter(src) {
+        Ok((metadata, body_offset)) => warp::reply::json(&ParsedFrontMatter {
+            metadata,
+            body_offset,
+        })
+        .into_response(),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "errors": [e] })),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .into_response(),
+    }
+}
+
+pub(crate) async fn admin_purge(
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    request: PurgeRequest,
+    storage: Arc<BlogStorage>,
+    purge_registry: Arc<PurgeRegistry>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    }
+    if !request.has_selectors() {
+        return warp::reply::with_status(
+            "Nothing to purge, pass slugs, tags or paths_prefix",
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    }
+    if let Some(kind) = purge_registry.unknown_kind(&request) {
+        return warp::reply::with_status(
+            format!(
+                "Unknown cache kind {kind}, known ones are {:?}",
+                purge_registry.kinds()
+            ),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    }
+    let entries = storage.matching_entries(|e| request.matches(e)).await;
+    let purged = purge_registry.purge(&request, &entries).await;
+    info!("Purged {purged:?}");
+    warp::reply::json(&purged).into_response()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ShareQuery {
+    hours: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ShareResponse {
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+pub(crate) fn is_admin(authorization: Option<String>, admin_token: Option<Arc<String>>) -> bool {
+    let (Some(authorization), Some(admin_token)) = (authorization, admin_token) else {
+        return false;
+    };
+    let Some(token) = authorization.strip_prefix("Bearer ") else {
+        return false;
+    };
+    constant_time_eq(token.as_bytes(), admin_token.as_bytes())
+}
+
+pub(crate) fn share(
+    entry: String,
+    authorization: Option<String>,
+    query: ShareQuery,
+    admin_token: Option<Arc<String>>,
+    signer: Option<Arc<Signer>>,
+    clock: SharedClock,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED)
+            .into_response();
+    }
+    let Some(signer) = signer else {
+        return warp::reply::with_status(
+            "Sharing is disabled, set --share-secret to enable it",
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response();
+    };
+    let hours = query.hours.unwrap_or(SHARE_DEFAULT_HOURS);
+    let Some(expires_at) = share_expiry(clock.now(), hours) else {
+        return warp::reply::with_status(
+            format!("hours must be between 1 and {SHARE_MAX_HOURS}"),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    };
+    let sig = signer.sign(&entry, expires_at);
+    info!("Shared entry {entry} until {expires_at}");
+    warp::reply::json(&ShareResponse {
+        url: format!("/preview/{entry}?sig={sig}&exp={}", expires_at.timestamp()),
+        expires_at,
+    })
+    .into_response()
+}
+
+fn share_expiry(now: DateTime<Utc>, hours: i64) -> Option<DateTime<Utc>> {
+    if !(1..=SHARE_MAX_HOURS).contains(&hours) {
+        return None;
+    }
+    now.checked_add_signed(chrono::Duration::hours(hours))
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    #[serde(flatten)]
+    uploaded: Uploaded,
+    image: Option<Uploaded>,
+}
+
+pub(crate) async fn admin_upload(
+    filename: String,
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    if_match: Option<String>,
+    body: Bytes,
+    uploads: Arc<Uploads>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    }
+    let uploaded = match uploads.store(&filename, &body, if_match.as_deref()).await {
+        Ok(uploaded) => uploaded,
+        Err(e) => return upload_error_response(&filename, e),
+    };
+    upload_response(UploadResponse {
+        uploaded,
+        image: None,
+    })
+}
+
+// An entry in the 'content' part, and optionally an image in the 'image' one,
+// stored under the name it was sent with. The image is written first so that
+// the entry never shows up pointing to a missing file
+pub(crate) async fn admin_upload_multipart(
+    filename: String,
+    authorization: Option<String>,
+    admin_token: Option<Arc<String>>,
+    if_match: Option<String>,
+    form: FormData,
+    uploads: Arc<Uploads>,
+) -> Response {
+    if !is_admin(authorization, admin_token) {
+        return warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    }
+    let parts = match read_parts(form).await {
+        Ok(parts) => parts,
+        Err(e) => {
+            return warp::reply::with_status(
+                format!("Malformed multipart body: {e}"),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response()
+        }
+    };
+    let mut content = None;
+    let mut image = None;
+    for (name, part_filename, data) in parts {
+        match name.as_str() {
+            "content" => content = Some(data),
+            "image" => image = Some((part_filename.unwrap_or_default(), data)),
+            _ => {}
+        }
+    }
+    let Some(content) = content else {
+        return warp::reply::with_status("Missing the 'content' part", StatusCode::BAD_REQUEST)
+            .into_response();
+    };
+    match uploads.validate(&filename, &content) {
+        Ok(UploadKind::Entry) => {}
+        Ok(UploadKind::Image) => return upload_error_response(&filename, UploadError::InvalidName),
+        Err(e) => return upload_error_response(&filename, e),
+    }
+    let image = match image {
+        Some((image_name, data)) => {
+            if Uploads::kind(&image_name) != Some(UploadKind::Image) {
+                return upload_error_response(&image_name, UploadError::InvalidName);
+            }
+            match uploads.store(&image_name, &data, None).await {
+                Ok(uploaded) => Some(uploaded),
+                // Sending the same image again, e.g. when retrying, is fine
+                Err(UploadError::MissingPrecondition { current })
+                    if current == uploads::etag(&data) =>
+                {
+                    Some(Uploaded {
+                        url: uploads.url(&image_name),
+                        etag: current,
+                        created: false,
+                    })
+                }
+                Err(UploadError::MissingPrecondition { .. }) => {
+                    return warp::reply::with_status(
+                        format!("A different {image_name} already exists"),
+                        StatusCode::CONFLICT,
+                    )
+                    .into_response()
+                }
+                Err(e) => return upload_error_response(&image_name, e),
+            }
+        }
+        None => None,
+    };
+    let uploaded = match uploads
+        .store(&filename, &content, if_match.as_deref())
+        .await
+    {
+        Ok(uploaded) => uploaded,
+        Err(e) => return upload_error_response(&filename, e),
+    };
+    upload_response(UploadResponse { uploaded, image })
+}
+
+async fn read_parts(form: FormData) -> anyhow::Result<Vec<(String, Option<String>, Vec<u8>)>> {
+    let mut parts = vec![];
+    let mut form = Box::pin(form);
+    while let Some(mut part) = form.try_next().await? {
+        let mut data = vec![];
+        while let Some(chunk) = part.data().await {
+            data.extend_from_slice(chunk?.chunk());
+        }
+        parts.push((
+            part.name().to_owned(),
+            part.filename().map(str::to_owned),
+            data,
+        ));
+    }
+    Ok(parts)
+}
+
+fn upload_response(response: UploadResponse) -> Response {
+    let status = if response.uploaded.created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    let etag = response.uploaded.etag.clone();
+    let reply = warp::reply::with_status(warp::reply::json(&response), status);
+    warp::reply::with_header(reply, "etag", etag).into_response()
+}
+
+fn upload_error_response(filename: &str, error: UploadError) -> Response {
+    match error {
+        UploadError::InvalidName => warp::reply::with_status(
+            format!(
+                "Invalid file name {filename}: entries end in .md, images in one of {}",
+                RESIZABLE_EXTENSIONS.join(", ")
+            ),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response(),
+        UploadError::InvalidEntry(e) => warp::reply::with_status(
+            format!("{filename} is not a valid entry: {e}"),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .into_response(),
+        UploadError::MissingPrecondition { current } => warp::reply::with_header(
+            warp::reply::with_status(
+                format!("{filename} already exists, replacing it requires If-Match"),
+                StatusCode::PRECONDITION_REQUIRED,
+            ),
+            "etag",
+            current,
+        )
+        .into_response(),
+        UploadError::PreconditionFailed { current } => {
+            let reply = warp::reply::with_status(
+                format!("{filename} changed since it was last read"),
+                StatusCode::PRECONDITION_FAILED,
+            );
+            match current {
+                Some(current) => warp::reply::with_header(reply, "etag", current).into_response(),
+                None => reply.into_response(),
+            }
+        }
+        UploadError::Io(e) => {
+            Failure::new(format!("Failed to store the upload {filename}"), &e).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_links_expire_within_bounds() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert_eq!(
+            share_expiry(now, 72),
+            Some(now + chrono::Duration::hours(72))
+        );
+        assert_eq!(
+            share_expiry(now, SHARE_MAX_HOURS),
+            Some(now + chrono::Duration::hours(SHARE_MAX_HOURS))
+        );
+        for hours in [0, -1, SHARE_MAX_HOURS + 1, i64::MAX, i64::MIN] {
+            assert_eq!(share_expiry(now, hours), None, "{hours}");
+        }
+    }
+}
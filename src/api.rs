@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Serialize;
+use warp::{
+    http::{HeaderValue, StatusCode},
+    reply::{Reply, Response},
+};
+
+use crate::{
+    blog_storage::{BlogEntry, BlogStorage, BlogStorageError, ContributorRole},
+    cdn::{listing_keys, with_keys},
+    incidents::Failure,
+    purge::entry_keys,
+};
+
+// The JSON shapes of /api/v1, kept apart from BlogEntry so that changing the
+// internals doesn't break the clients
+
+#[derive(Serialize)]
+pub struct ApiContributor {
+    pub name: String,
+    pub role: ContributorRole,
+}
+
+#[derive(Serialize)]
+pub struct ApiEntrySummary {
+    pub slug: String,
+    pub title: String,
+    pub authors: Vec<ApiContributor>,
+    pub publish_date: DateTime<Utc>,
+    pub updated_date: Option<DateTime<Utc>>,
+    pub evergreen: bool,
+    pub content_warning: Option<String>,
+    pub tags: Vec<String>,
+    pub accent_color: Option<String>,
+    pub word_count: usize,
+    pub reading_time_minutes: u32,
+}
+
+impl From<&BlogEntry> for ApiEntrySummary {
+    fn from(entry: &BlogEntry) -> Self {
+        let description = &entry.description;
+        Self {
+            slug: entry.slug.clone(),
+            title: description.title.clone(),
+            authors: description
+                .authors
+                .iter()
+                .map(|a| ApiContributor {
+                    name: a.name.clone(),
+                    role: a.role,
+                })
+                .collect(),
+            publish_date: description.publish_date,
+            updated_date: description.updated_date,
+            evergreen: description.evergreen,
+            content_warning: description.content_warning.clone(),
+            tags: description.tags.clone(),
+            accent_color: description.accent_color.clone(),
+            word_count: entry.word_count,
+            reading_time_minutes: entry.reading_time_minutes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiEntry {
+    #[serde(flatten)]
+    pub summary: ApiEntrySummary,
+    pub html: String,
+}
+
+impl From<&BlogEntry> for ApiEntry {
+    fn from(entry: &BlogEntry) -> Self {
+        Self {
+            summary: entry.into(),
+            html: entry.html.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+// Read only and public, any origin may fetch it
+fn allow_any_origin(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert("access-control-allow-origin", HeaderValue::from_static("*"));
+    response
+}
+
+// Every published entry, newest first
+pub(crate) async fn entries(storage: Arc<BlogStorage>) -> Response {
+    let entries: Vec<ApiEntrySummary> = storage
+        .entries_page(0, usize::MAX, None)
+        .await
+        .iter()
+        .map(|e| e.as_ref().into())
+        .collect();
+    let response = warp::reply::json(&entries).into_response();
+    allow_any_origin(with_keys(response, listing_keys()))
+}
+
+pub(crate) async fn entry(slug: String, storage: Arc<BlogStorage>) -> Response {
+    let entry_name = storage.resolve_slug(&slug).await;
+    let response = match storage.get_entry(&entry_name).await {
+        Ok(entry) => {
+            info!("Serving entry {entry_name} through the API");
+            let response = warp::reply::json(&ApiEntry::from(entry.as_ref())).into_response();
+            with_keys(response, entry_keys(&entry))
+        }
+        Err(BlogStorageError::ParseError(e)) => {
+            return Failure::new("Failed to load an entry", &e)
+                .entry(entry_name)
+                .into_response();
+        }
+        Err(BlogStorageError::NotFound(_)) => warp::reply::with_status(
+            warp::reply::json(&ApiError {
+                error: format!("No entry named {slug}"),
+            }),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    };
+    allow_any_origin(response)
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use futures_util::future::BoxFuture;
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{blog_storage::BlogEntry, purge::PurgeableCache};
+
+pub const PLAINTEXT_CATEGORY: &str = "plaintext";
+pub const THUMBNAIL_CATEGORY: &str = "thumbnail";
+pub const CATEGORIES: &[&str] = &[PLAINTEXT_CATEGORY, THUMBNAIL_CATEGORY];
+
+struct Artifact {
+    size: u64,
+    last_used: SystemTime,
+}
+
+#[derive(Default)]
+struct Category {
+    budget: Option<u64>,
+    used: u64,
+    artifacts: HashMap<String, Artifact>,
+}
+
+#[derive(Serialize)]
+pub struct CategoryUsage {
+    pub used: u64,
+    pub budget: Option<u64>,
+    pub artifacts: usize,
+}
+
+#[derive(Serialize)]
+pub struct ArtifactUsage {
+    pub used: u64,
+    pub budget: u64,
+    pub categories: BTreeMap<String, CategoryUsage>,
+}
+
+// Derived files (caches, renders...) are written here, one directory per
+// category. The store keeps them within a total budget and optional per
+// category budgets, evicting the least recently used ones first. It never
+// touches anything outside of its own root
+pub struct ArtifactStore {
+    root: PathBuf,
+    budget: u64,
+    categories: Mutex<HashMap<String, Category>>,
+}
+
+impl ArtifactStore {
+    pub async fn open(
+        root: PathBuf,
+        budget: u64,
+        category_budgets: HashMap<String, u64>,
+        protected: &[&Path],
+    ) -> anyhow::Result<Self> {
+        // Checked before creating anything, the root may not exist yet
+        let absolute_root = std::path::absolute(&root)?;
+        for protected in protected {
+            let Ok(protected) = tokio::fs::canonicalize(protected).await else {
+                continue;
+            };
+            if absolute_root.starts_with(&protected) || protected.starts_with(&absolute_root) {
+                anyhow::bail!(
+                    "The artifacts directory {root:?} overlaps with {protected:?}, pick another one"
+                );
+            }
+        }
+        if let Some(unknown) = category_budgets
+            .keys()
+            .find(|c| !CATEGORIES.contains(&c.as_str()))
+        {
+            anyhow::bail!("Unknown artifact category {unknown}, known ones are {CATEGORIES:?}");
+        }
+        tokio::fs::create_dir_all(&root).await?;
+
+        let mut categories = HashMap::new();
+        for name in CATEGORIES {
+            let mut category = Category {
+                budget: category_budgets.get(*name).copied(),
+                ..Default::default()
+            };
+            let dir = root.join(name);
+            tokio::fs::create_dir_all(&dir).await?;
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                let Some(artifact_name) = entry.file_name().to_str().map(str::to_owned) else {
+                    continue;
+                };
+                category.used += metadata.len();
+                category.artifacts.insert(
+                    artifact_name,
+                    Artifact {
+                        size: metadata.len(),
+                        last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    },
+                );
+            }
+            categories.insert(name.to_string(), category);
+        }
+
+        let store = Self {
+            root,
+            budget,
+            categories: Mutex::new(categories),
+        };
+        let evicted = store.evict_over_budget();
+        store.remove_files(evicted).await;
+        info!(
+            "Artifacts use {} bytes out of {}",
+            store.usage().used,
+            store.budget
+        );
+        Ok(store)
+    }
+
+    pub async fn read(&self, category: &str, name: &str) -> Option<Vec<u8>> {
+        let path = self.artifact_path(category, name)?;
+        {
+            let mut categories = self.categories.lock().expect("Poisoned artifact store");
+            let artifact = categories.get_mut(category)?.artifacts.get_mut(name)?;
+            artifact.last_used = SystemTime::now();
+        }
+        match tokio::fs::read(&path).await {
+            Ok(content) => Some(content),
+            Err(e) => {
+                warn!("Failed to read artifact {path:?}: {e}");
+                self.forget(category, name);
+                None
+            }
+        }
+    }
+
+    pub async fn write(&self, category: &str, name: &str, content: &[u8]) {
+        let Some(path) = self.artifact_path(category, name) else {
+            error!("Refusing to write artifact {category}/{name}");
+            return;
+        };
+        if let Err(e) = tokio::fs::write(&path, content).await {
+            error!("Failed to write artifact {path:?}: {e}");
+            return;
+        }
+        let evicted = {
+            let mut categories = self.categories.lock().expect("Poisoned artifact store");
+            let Some(entry) = categories.get_mut(category) else {
+                return;
+            };
+            let previous = entry.artifacts.insert(
+                name.to_owned(),
+                Artifact {
+                    size: content.len() as u64,
+                    last_used: SystemTime::now(),
+                },
+            );
+            entry.used -= previous.map_or(0, |p| p.size);
+            entry.used += content.len() as u64;
+            drop(categories);
+            self.evict_over_budget()
+        };
+        self.remove_files(evicted).await;
+    }
+
+    pub async fn purge_matching<F: Fn(&str) -> bool>(&self, category: &str, predicate: F) -> usize {
+        let purged: Vec<_> = {
+            let mut categories = self.categories.lock().expect("Poisoned artifact store");
+            let Some(entry) = categories.get_mut(category) else {
+                return 0;
+            };
+            let names: Vec<_> = entry
+                .artifacts
+                .keys()
+                .filter(|name| predicate(name))
+                .cloned()
+                .collect();
+            names
+                .into_iter()
+                .map(|name| {
+                    entry.forget(&name);
+                    self.root.join(category).join(name)
+                })
+                .collect()
+        };
+        let count = purged.len();
+        self.remove_files(purged).await;
+        count
+    }
+
+    pub fn usage(&self) -> ArtifactUsage {
+        let categories = self.categories.lock().expect("Poisoned artifact store");
+        ArtifactUsage {
+            used: categories.values().map(|c| c.used).sum(),
+            budget: self.budget,
+            categories: categories
+                .iter()
+                .map(|(name, c)| {
+                    let usage = CategoryUsage {
+                        used: c.used,
+                        budget: c.budget,
+                        artifacts: c.artifacts.len(),
+                    };
+                    (name.clone(), usage)
+                })
+                .collect(),
+        }
+    }
+
+    // Artifacts are dropped from the accounting right away, the files are
+    // removed afterwards without holding the lock
+    fn evict_over_budget(&self) -> Vec<PathBuf> {
+        let mut categories = self.categories.lock().expect("Poisoned artifact store");
+        let mut evicted = vec![];
+        for (name, category) in categories.iter_mut() {
+            while category.budget.is_some_and(|budget| category.used > budget) {
+                let Some(victim) = least_recently_used(category) else {
+                    break;
+                };
+                evicted.push(self.root.join(name).join(&victim));
+                category.forget(&victim);
+            }
+        }
+        while categories.values().map(|c| c.used).sum::<u64>() > self.budget {
+            let victim = categories
+                .iter()
+                .filter_map(|(name, c)| {
+                    let victim = least_recently_used(c)?;
+                    Some((c.artifacts[&victim].last_used, name.clone(), victim))
+                })
+                .min();
+            let Some((_, name, victim)) = victim else {
+                break;
+            };
+            evicted.push(self.root.join(&name).join(&victim));
+            if let Some(category) = categories.get_mut(&name) {
+                category.forget(&victim);
+            }
+        }
+        evicted
+    }
+
+    async fn remove_files(&self, paths: Vec<PathBuf>) {
+        for path in paths {
+            info!("Removing artifact {path:?}");
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("Failed to remove artifact {path:?}: {e}");
+            }
+        }
+    }
+
+    fn forget(&self, category: &str, name: &str) {
+        let mut categories = self.categories.lock().expect("Poisoned artifact store");
+        if let Some(category) = categories.get_mut(category) {
+            category.forget(name);
+        }
+    }
+
+    fn artifact_path(&self, category: &str, name: &str) -> Option<PathBuf> {
+        if !CATEGORIES.contains(&category)
+            || name.is_empty()
+            || name.starts_with('.')
+            || name.contains(['/', '\\'])
+        {
+            return None;
+        }
+        Some(self.root.join(category).join(name))
+    }
+}
+
+impl Category {
+    fn forget(&mut self, name: &str) {
+        if let Some(artifact) = self.artifacts.remove(name) {
+            self.used -= artifact.size;
+        }
+    }
+}
+
+fn least_recently_used(category: &Category) -> Option<String> {
+    category
+        .artifacts
+        .iter()
+        .min_by_key(|(_, a)| a.last_used)
+        .map(|(name, _)| name.clone())
+}
+
+// Plain text renderings are named after the content hash of their entry
+impl PurgeableCache for ArtifactStore {
+    fn purge<'a>(&'a self, entries: &'a [Arc<BlogEntry>]) -> BoxFuture<'a, usize> {
+        Box::pin(async move {
+            self.purge_matching(PLAINTEXT_CATEGORY, |name| {
+                entries
+                    .iter()
+                    .any(|e| name.starts_with(&format!("{}-", e.content_hash)))
+            })
+            .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    async fn open(dir: &TempDir, budget: u64, category_budgets: &[(&str, u64)]) -> ArtifactStore {
+        let category_budgets = category_budgets
+            .iter()
+            .map(|(name, budget)| (name.to_string(), *budget))
+            .collect();
+        ArtifactStore::open(dir.join("artifacts"), budget, category_budgets, &[])
+            .await
+            .unwrap()
+    }
+
+    // Recency is tracked with the system clock, keep the writes apart
+    async fn write(store: &ArtifactStore, category: &str, name: &str, content: &[u8]) {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        store.write(category, name, content).await;
+    }
+
+    // The accounting must always match what's actually on disk
+    fn assert_consistent(store: &ArtifactStore) {
+        let usage = store.usage();
+        let mut total = 0;
+        for (name, category) in &usage.categories {
+            let files: Vec<_> = std::fs::read_dir(store.root.join(name))
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .collect();
+            assert_eq!(category.artifacts, files.len(), "{name}");
+            assert_eq!(category.used, files.iter().sum::<u64>(), "{name}");
+            total += category.used;
+        }
+        assert_eq!(usage.used, total);
+        assert!(usage.used <= usage.budget);
+    }
+
+    #[tokio::test]
+    async fn stores_and_loads_artifacts() {
+        let dir = TempDir::new("artifacts-roundtrip");
+        let store = open(&dir, 1000, &[]).await;
+        write(&store, PLAINTEXT_CATEGORY, "a", b"first").await;
+        assert_eq!(store.read(PLAINTEXT_CATEGORY, "a").await.unwrap(), b"first");
+        assert_eq!(store.read(THUMBNAIL_CATEGORY, "a").await, None);
+        assert_eq!(store.read(PLAINTEXT_CATEGORY, "b").await, None);
+
+        // Overwriting replaces the size too
+        write(&store, PLAINTEXT_CATEGORY, "a", b"second!").await;
+        assert_eq!(
+            store.read(PLAINTEXT_CATEGORY, "a").await.unwrap(),
+            b"second!"
+        );
+        assert_eq!(store.usage().used, 7);
+        assert_consistent(&store);
+    }
+
+    #[tokio::test]
+    async fn refuses_names_outside_of_its_root() {
+        let dir = TempDir::new("artifacts-names");
+        let store = open(&dir, 1000, &[]).await;
+        for name in ["", ".hidden", "../escape", "a/b", "a\\b"] {
+            write(&store, PLAINTEXT_CATEGORY, name, b"x").await;
+            assert_eq!(store.read(PLAINTEXT_CATEGORY, name).await, None, "{name}");
+        }
+        write(&store, "unknown", "a", b"x").await;
+        assert_eq!(store.usage().used, 0);
+        assert!(!dir.join("escape").exists());
+        assert_consistent(&store);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_past_the_budget() {
+        let dir = TempDir::new("artifacts-eviction");
+        let store = open(&dir, 10, &[]).await;
+        write(&store, PLAINTEXT_CATEGORY, "old", b"1234").await;
+        write(&store, THUMBNAIL_CATEGORY, "used", b"1234").await;
+        write(&store, PLAINTEXT_CATEGORY, "newer", b"12").await;
+        // Reading refreshes an artifact
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        store.read(PLAINTEXT_CATEGORY, "old").await.unwrap();
+
+        write(&store, PLAINTEXT_CATEGORY, "new", b"1234").await;
+        assert_eq!(store.read(THUMBNAIL_CATEGORY, "used").await, None);
+        assert!(store.read(PLAINTEXT_CATEGORY, "old").await.is_some());
+        assert!(store.read(PLAINTEXT_CATEGORY, "newer").await.is_some());
+        assert!(store.read(PLAINTEXT_CATEGORY, "new").await.is_some());
+        assert_eq!(store.usage().used, 10);
+        assert_consistent(&store);
+    }
+
+    #[tokio::test]
+    async fn keeps_each_category_within_its_own_budget() {
+        let dir = TempDir::new("artifacts-category");
+        let store = open(&dir, 100, &[(THUMBNAIL_CATEGORY, 5)]).await;
+        write(&store, PLAINTEXT_CATEGORY, "text", b"12345678").await;
+        write(&store, THUMBNAIL_CATEGORY, "a", b"123").await;
+        write(&store, THUMBNAIL_CATEGORY, "b", b"123").await;
+
+        assert_eq!(store.read(THUMBNAIL_CATEGORY, "a").await, None);
+        assert!(store.read(THUMBNAIL_CATEGORY, "b").await.is_some());
+        assert!(store.read(PLAINTEXT_CATEGORY, "text").await.is_some());
+        let usage = store.usage();
+        assert_eq!(usage.categories[THUMBNAIL_CATEGORY].used, 3);
+        assert_eq!(usage.categories[THUMBNAIL_CATEGORY].budget, Some(5));
+        assert_consistent(&store);
+    }
+
+    #[tokio::test]
+    async fn reopening_accounts_for_the_files_and_trims_them() {
+        let dir = TempDir::new("artifacts-reopen");
+        let store = open(&dir, 100, &[]).await;
+        write(&store, PLAINTEXT_CATEGORY, "a", b"1234").await;
+        write(&store, THUMBNAIL_CATEGORY, "b", b"123456").await;
+        drop(store);
+
+        let store = open(&dir, 100, &[]).await;
+        assert_eq!(store.usage().used, 10);
+        assert_consistent(&store);
+        drop(store);
+
+        let store = open(&dir, 8, &[]).await;
+        assert_eq!(store.usage().used, 6);
+        assert!(store.read(THUMBNAIL_CATEGORY, "b").await.is_some());
+        assert_consistent(&store);
+    }
+
+    #[tokio::test]
+    async fn purges_matching_artifacts() {
+        let dir = TempDir::new("artifacts-purge");
+        let store = open(&dir, 100, &[]).await;
+        write(&store, PLAINTEXT_CATEGORY, "hash1-78", b"1").await;
+        write(&store, PLAINTEXT_CATEGORY, "hash2-78", b"2").await;
+        write(&store, THUMBNAIL_CATEGORY, "hash1-78", b"3").await;
+
+        let purged = store
+            .purge_matching(PLAINTEXT_CATEGORY, |name| name.starts_with("hash1-"))
+            .await;
+        assert_eq!(purged, 1);
+        assert_eq!(store.read(PLAINTEXT_CATEGORY, "hash1-78").await, None);
+        assert!(store.read(PLAINTEXT_CATEGORY, "hash2-78").await.is_some());
+        assert!(store.read(THUMBNAIL_CATEGORY, "hash1-78").await.is_some());
+        assert_consistent(&store);
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_configurations() {
+        let dir = TempDir::new("artifacts-config");
+        let protected = dir.join("blog");
+        std::fs::create_dir_all(&protected).unwrap();
+        let overlapping =
+            ArtifactStore::open(protected.join("cache"), 10, HashMap::new(), &[&protected]).await;
+        assert!(overlapping.is_err());
+
+        let budgets = HashMap::from([("unknown".to_owned(), 1)]);
+        let unknown = ArtifactStore::open(dir.join("artifacts"), 10, budgets, &[]).await;
+        assert!(unknown.is_err());
+    }
+}
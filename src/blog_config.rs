@@ -0,0 +1,82 @@
+use std::{num::NonZeroU32, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    blog_storage::{BlogInfo, DEFAULT_WORDS_PER_MINUTE},
+    file_server::FilePolicyConfig,
+    identity::IdentityConfig,
+    images::DEFAULT_WIDTHS,
+    markdown::MarkdownConfig,
+    theme::accent_color,
+};
+
+pub const CONFIG_FILE: &str = "blog.toml";
+
+// Site wide metadata, read from blog.toml so that it can be changed without
+// recompiling. Every key is optional
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlogConfig {
+    pub name: String,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub base_url: Option<String>,
+    // The default theme-color of the pages, as a hex color
+    pub accent_color: Option<String>,
+    // Widths of the scaled down copies offered for the images in posts, read
+    // at startup only. An empty list turns the rewriting off
+    pub image_widths: Vec<u32>,
+    // Whether /blog/stats is served, read at startup only
+    pub stats_page: bool,
+    // The reading speed behind the reading times, read at startup only
+    pub words_per_minute: NonZeroU32,
+    // Read at startup only
+    pub markdown: MarkdownConfig,
+    // rel="me" links and webfinger, read at startup only
+    pub identity: Option<IdentityConfig>,
+    // Headers and hotlink protection of the files under some prefixes, as
+    // [[files]] tables. Read at startup only
+    pub files: Vec<FilePolicyConfig>,
+}
+
+impl Default for BlogConfig {
+    fn default() -> Self {
+        Self {
+            name: "Crax's blog".to_owned(),
+            description: None,
+            author: None,
+            base_url: None,
+            accent_color: None,
+            image_widths: DEFAULT_WIDTHS.to_vec(),
+            stats_page: true,
+            words_per_minute: NonZeroU32::new(DEFAULT_WORDS_PER_MINUTE).unwrap(),
+            markdown: MarkdownConfig::default(),
+            identity: None,
+            files: vec![],
+        }
+    }
+}
+
+impl BlogConfig {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        toml::from_str(&content).with_context(|| format!("Invalid blog config {path:?}"))
+    }
+
+    pub fn info(&self) -> BlogInfo {
+        BlogInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            author: self.author.clone(),
+            accent_color: accent_color(self.accent_color.clone(), CONFIG_FILE),
+            favicons: vec![],
+            profile_links: vec![],
+        }
+    }
+}
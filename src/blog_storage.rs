@@ -1,142 +1,2583 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::SystemTime,
 };
 
 use chrono::{DateTime, Utc};
-use log::info;
-use serde::{Deserialize, Serialize};
+use futures_util::future::BoxFuture;
+use log::{info, warn};
+use lru::LruCache;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::sync::RwLock;
-use yaml_front_matter::YamlFrontMatter;
+
+use crate::purge::PurgeableCache;
+use crate::{
+    blog_config::BlogConfig,
+    cdn::Cdn,
+    clock::{SharedClock, SystemClock},
+    counters::{write_atomically, PersistentCounters},
+    images::ResponsiveImages,
+    journal::Journal,
+    markdown::{render_entry, Markdown, MarkdownConfig, MarkdownOptions, TocItem, WordCount},
+    paging::{PageRequest, Paged},
+    retries::ReadRetries,
+    search::{SearchBackend, SearchIndex, SearchResult},
+    snippets::{Snippets, SNIPPETS_DIR},
+    stats::PublicStats,
+    theme::{accent_color, Favicon},
+};
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "RawPostMetadata")]
 pub struct PostMetadata {
     pub title: String,
-    pub author: String,
+    pub authors: Vec<Contributor>,
     pub publish_date: DateTime<Utc>,
+    pub updated_date: Option<DateTime<Utc>>,
+    pub evergreen: bool,
+    pub content_warning: Option<String>,
+    pub tags: Vec<String>,
+    // Tints the browser UI while reading the entry
+    pub accent_color: Option<String>,
+    // The entry is served at /blog/{slug}, by default its file name without
+    // the .md extension
+    pub slug: Option<String>,
+    // Only served with --serve-drafts, and never listed in the feeds or the
+    // sitemap
+    pub draft: bool,
+    // Shown by the link previews of social networks, by default the start
+    // of the first paragraph
+    pub description: Option<String>,
+    // Shown by the link previews too. An absolute url, a path on the site
+    // when it starts with /, otherwise a path under /files
+    pub image: Option<String>,
+    // `toc: false` leaves the table of contents out
+    pub toc: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ContributorRole {
+    #[default]
+    Author,
+    Editor,
+    Translator,
+    Illustrator,
+}
+
+impl ContributorRole {
+    fn verb(&self) -> &'static str {
+        match self {
+            ContributorRole::Author => "written",
+            ContributorRole::Editor => "edited",
+            ContributorRole::Translator => "translated",
+            ContributorRole::Illustrator => "illustrated",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Contributor {
+    pub name: String,
+    #[serde(default)]
+    pub role: ContributorRole,
+}
+
+// The front matter may credit people as `author: X`, as a list of names, or
+// as a list of `{name, role}`, under either `author` or `authors`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawContributor {
+    Name(String),
+    Structured(Contributor),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawContributors {
+    One(RawContributor),
+    Many(Vec<RawContributor>),
+}
+
+#[derive(Deserialize)]
+struct RawPostMetadata {
+    title: String,
+    #[serde(default)]
+    author: Option<RawContributors>,
+    #[serde(default)]
+    authors: Option<RawContributors>,
+    publish_date: DateTime<Utc>,
+    #[serde(default)]
+    updated_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    evergreen: bool,
+    #[serde(default)]
+    content_warning: Option<String>,
+    #[serde(default, alias = "categories")]
+    tags: Vec<String>,
+    #[serde(default)]
+    accent_color: Option<String>,
+    #[serde(default)]
+    slug: Option<String>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default, alias = "cover_image")]
+    image: Option<String>,
+    #[serde(default = "default_toc")]
+    toc: bool,
+}
+
+fn default_toc() -> bool {
+    true
+}
+
+impl TryFrom<RawPostMetadata> for PostMetadata {
+    type Error = String;
+
+    fn try_from(raw: RawPostMetadata) -> Result<Self, Self::Error> {
+        let authors: Vec<_> = [raw.author, raw.authors]
+            .into_iter()
+            .flatten()
+            .flat_map(|contributors| match contributors {
+                RawContributors::One(contributor) => vec![contributor],
+                RawContributors::Many(contributors) => contributors,
+            })
+            .map(|contributor| match contributor {
+                RawContributor::Name(name) => Contributor {
+                    name,
+                    role: ContributorRole::Author,
+                },
+                RawContributor::Structured(contributor) => contributor,
+            })
+            .collect();
+        if authors.is_empty() {
+            return Err("missing field `author`, a post needs at least one".to_owned());
+        }
+        let accent_color = accent_color(raw.accent_color, &format!("\"{}\"", raw.title));
+        Ok(Self {
+            title: raw.title,
+            authors,
+            publish_date: raw.publish_date,
+            updated_date: raw.updated_date,
+            evergreen: raw.evergreen,
+            content_warning: raw.content_warning,
+            tags: raw.tags,
+            accent_color,
+            slug: raw
+                .slug
+                .map(|slug| slug.trim().trim_matches('/').to_owned())
+                .filter(|slug| !slug.is_empty()),
+            draft: raw.draft,
+            description: raw
+                .description
+                .map(|description| description.trim().to_owned())
+                .filter(|description| !description.is_empty()),
+            image: raw.image.filter(|image| !image.trim().is_empty()),
+            toc: raw.toc,
+        })
+    }
+}
+
+impl PostMetadata {
+    pub fn names_with_role(&self, role: ContributorRole) -> Vec<String> {
+        self.authors
+            .iter()
+            .filter(|c| c.role == role)
+            .map(|c| c.name.clone())
+            .collect()
+    }
+
+    // e.g. "Written by X and Y, edited by Z"
+    pub fn byline(&self) -> String {
+        let mut roles: Vec<ContributorRole> = vec![];
+        for contributor in &self.authors {
+            if !roles.contains(&contributor.role) {
+                roles.push(contributor.role);
+            }
+        }
+        let byline = roles
+            .into_iter()
+            .map(|role| {
+                let names = self.names_with_role(role);
+                let names = match names.split_last() {
+                    Some((last, [])) => last.clone(),
+                    Some((last, rest)) => format!("{} and {last}", rest.join(", ")),
+                    None => String::new(),
+                };
+                format!("{} by {names}", role.verb())
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut chars = byline.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => byline,
+        }
+    }
+
+    // Once each, whatever their roles
+    pub fn author_slugs(&self) -> BTreeSet<String> {
+        self.authors
+            .iter()
+            .map(|contributor| author_slug(&contributor.name))
+            .filter(|slug| !slug.is_empty())
+            .collect()
+    }
+
+    fn author_named(&self, slug: &str) -> Option<&str> {
+        self.authors
+            .iter()
+            .find(|contributor| author_slug(&contributor.name) == slug)
+            .map(|contributor| contributor.name.as_str())
+    }
+}
+
+// What /blog/author/{slug} is served at: "Jane O'Brien" is jane-o-brien
+pub fn author_slug(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[derive(Serialize)]
+pub struct AuthorSummary {
+    pub name: String,
+    pub slug: String,
+    pub entry_count: usize,
+}
+
+// The entry exists, it just isn't out yet: to visitors it's like a missing one
+#[derive(Debug)]
+pub struct Scheduled {
+    entry: String,
+    publish_date: DateTime<Utc>,
+}
+
+impl std::fmt::Display for Scheduled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Entry {} is scheduled for {}",
+            self.entry, self.publish_date
+        )
+    }
+}
+
+impl std::error::Error for Scheduled {}
+
+// Not something that could be an entry: drafts, hidden files, or a path
+// leading outside of the blog directory
+#[derive(Debug)]
+pub struct InvalidEntryName(String);
+
+impl std::fmt::Display for InvalidEntryName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid entry name {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidEntryName {}
+
+// Another entry was served at that address first, and keeps it
+#[derive(Debug)]
+pub struct SlugTaken {
+    entry: String,
+    slug: String,
+    taken_by: String,
+}
+
+impl std::fmt::Display for SlugTaken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Entry {} uses the slug {}, which already belongs to {}",
+            self.entry, self.slug, self.taken_by
+        )
+    }
+}
+
+impl std::error::Error for SlugTaken {}
+
+// Marked as a draft in its front matter, while drafts aren't served
+#[derive(Debug)]
+pub struct Draft(String);
+
+impl std::fmt::Display for Draft {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Entry {} is a draft", self.0)
+    }
+}
+
+impl std::error::Error for Draft {}
+
+// Names are relative to the blog directory, nested entries included, and
+// always spelled the same way so that they're cached only once
+fn is_entry_name(entry_name: &str) -> bool {
+    entry_name.ends_with(".md")
+        && !entry_name.contains('\\')
+        && entry_name
+            .split('/')
+            .all(|c| !c.is_empty() && !c.starts_with(['.', '_']))
+}
+
+// What get_entry fails with: either there's nothing to show, which is a 404,
+// or the entry couldn't be read or parsed
+#[derive(Debug)]
+pub enum BlogStorageError {
+    NotFound(String),
+    ParseError(anyhow::Error),
+}
+
+impl std::fmt::Display for BlogStorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlogStorageError::NotFound(entry) => write!(f, "No entry named {entry}"),
+            BlogStorageError::ParseError(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for BlogStorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlogStorageError::NotFound(_) => None,
+            BlogStorageError::ParseError(e) => e.source(),
+        }
+    }
+}
+
+// Scheduled entries, drafts and the like are as missing as absent files
+fn is_missing_entry(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.is::<Scheduled>()
+            || e.is::<InvalidEntryName>()
+            || e.is::<SlugTaken>()
+            || e.is::<Draft>()
+            || e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+    })
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct BlogInfo {
     pub name: String,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    // The theme's and then the entry's accent color win over this one
+    #[serde(default)]
+    pub accent_color: Option<String>,
+    // Filled from the theme when rendering
+    #[serde(default)]
+    pub favicons: Vec<Favicon>,
+    // Filled from the [identity] table when rendering
+    #[serde(default)]
+    pub profile_links: Vec<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct BlogEntry {
     pub description: PostMetadata,
     pub html: String,
+    pub markdown: String,
     pub creation_date: SystemTime,
     pub filename: String,
+    #[serde(default)]
+    pub slug: String,
+    pub content_hash: String,
+    // Counted on the markdown, which is close enough for reading times and
+    // the stats
+    #[serde(default)]
+    pub word_count: usize,
+    // The words of the text at the words per minute of the storage, those
+    // of code blocks at half the pace. Rounded up, and at least 1 unless the
+    // entry has no words at all
+    #[serde(default)]
+    pub reading_time_minutes: u32,
+    // Images without alt text and skipped heading levels, see render_entry
+    #[serde(default)]
+    pub accessibility_warnings: Vec<String>,
+    // The first paragraph as HTML, for the listings
+    #[serde(default)]
+    pub excerpt: String,
+    // The same as plain text, for the description <meta> tags
+    #[serde(default)]
+    pub excerpt_text: String,
+    // The h2 and h3, empty when the front matter has `toc: false`
+    #[serde(default)]
+    pub toc: Vec<TocItem>,
+    // The snippets included in the content, even indirectly
+    #[serde(skip)]
+    pub snippets: BTreeSet<String>,
+}
+
+impl BlogEntry {
+    fn set_filename(&mut self, filename: String) {
+        self.slug = match &self.description.slug {
+            Some(slug) => slug.clone(),
+            None => filename.strip_suffix(".md").unwrap_or(&filename).to_owned(),
+        };
+        self.filename = filename;
+    }
+
+    // Everything the listings need, without the content
+    fn summary(&self) -> BlogEntry {
+        BlogEntry {
+            description: self.description.clone(),
+            html: String::new(),
+            markdown: String::new(),
+            creation_date: self.creation_date,
+            filename: self.filename.clone(),
+            slug: self.slug.clone(),
+            content_hash: self.content_hash.clone(),
+            word_count: self.word_count,
+            reading_time_minutes: self.reading_time_minutes,
+            accessibility_warnings: self.accessibility_warnings.clone(),
+            excerpt: self.excerpt.clone(),
+            excerpt_text: self.excerpt_text.clone(),
+            toc: vec![],
+            snippets: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SectionMetadata {
+    pub title: String,
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Section {
+    pub path: String,
+    pub metadata: SectionMetadata,
+    pub intro_html: String,
+}
+
+// Enough to link to an entry from another one
+#[derive(Clone, Serialize)]
+pub struct BlogEntryRef {
+    pub slug: String,
+    pub title: String,
+}
+
+impl BlogEntryRef {
+    fn new(entry: &BlogEntry) -> Self {
+        Self {
+            slug: entry.slug.clone(),
+            title: entry.description.title.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Breadcrumb {
+    pub title: String,
+    pub url: String,
+}
+
+pub struct Document<M> {
+    pub metadata: M,
+    pub markdown: String,
+    pub html: String,
+}
+
+// The front matter + markdown pipeline shared by everything stored as a
+// markdown file: entries, sections and pages
+pub fn parse_document<M: DeserializeOwned>(
+    content: &str,
+    options: &Markdown,
+) -> anyhow::Result<Document<M>> {
+    let (metadata, markdown) = split_front_matter(content)?;
+    let html = comrak::markdown_to_html_with_plugins(&markdown, options, &options.plugins());
+    Ok(Document {
+        metadata,
+        markdown,
+        html,
+    })
+}
+
+// The front matter of an entry as the server reads it, and the byte offset
+// where its markdown starts. Lets the editor plugins check a post without
+// publishing it
+pub fn parse_front_matter(src: &str) -> Result<(PostMetadata, usize), FrontMatterError> {
+    front_matter(src)
+}
+
+fn split_front_matter<M: DeserializeOwned>(content: &str) -> anyhow::Result<(M, String)> {
+    let (metadata, body_offset) = front_matter(content)?;
+    Ok((metadata, content[body_offset..].to_owned()))
+}
+
+fn front_matter<M: DeserializeOwned>(content: &str) -> Result<(M, usize), FrontMatterError> {
+    let split = FrontMatter::split(content)?;
+    let metadata = match split.style {
+        FrontMatter::Yaml => serde_yaml::from_str(split.text).map_err(|e| split.yaml_error(&e))?,
+        FrontMatter::Toml => {
+            let value: toml::Value =
+                toml::from_str(split.text).map_err(|e| split.toml_error(&e))?;
+            dates_as_strings(value)
+                .try_into()
+                .map_err(|e| split.toml_error(&e))?
+        }
+    };
+    Ok((metadata, split.body_offset))
+}
+
+// Why a front matter didn't parse, and where when the parser tells
+#[derive(Serialize, Debug, Clone)]
+pub struct FrontMatterError {
+    // The key at fault, e.g. publish_date or authors[0].name
+    pub field: Option<String>,
+    pub message: String,
+    // Both start at 1, the lines from the top of the file
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl FrontMatterError {
+    fn at(line: usize, message: String) -> Self {
+        Self {
+            field: None,
+            message,
+            line: Some(line),
+            column: None,
+        }
+    }
+
+    // The serde messages start with the path of the field, or quote it
+    fn from_message(message: &str) -> Self {
+        let quoted = ["missing field `", "unknown field `", "duplicate field `"]
+            .iter()
+            .find_map(|prefix| message.strip_prefix(prefix))
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(field, _)| field.to_owned());
+        let (field, message) = match (quoted, message.split_once(": ")) {
+            (Some(field), _) => (Some(field), message),
+            (None, Some((path, rest)))
+                if path
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || "_-.[]".contains(c)) =>
+            {
+                (Some(path.to_owned()), rest)
+            }
+            _ => (None, message),
+        };
+        Self {
+            field,
+            message: message.to_owned(),
+            line: None,
+            column: None,
+        }
+    }
+}
+
+impl std::fmt::Display for FrontMatterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Unless the message quotes it already
+        if let Some(field) = &self.field {
+            if !self.message.contains(&format!("`{field}`")) {
+                write!(f, "{field}: ")?;
+            }
+        }
+        write!(f, "{}", self.message)?;
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, " at line {line} column {column}"),
+            (Some(line), None) => write!(f, " at line {line}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for FrontMatterError {}
+
+// Front matter between '---' lines is YAML, between '+++' lines TOML
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FrontMatter {
+    Yaml,
+    Toml,
+}
+
+struct SplitFrontMatter<'a> {
+    style: FrontMatter,
+    // Between the delimiters
+    text: &'a str,
+    // The line of the file the text starts at
+    first_line: usize,
+    // Right after the closing delimiter
+    body_offset: usize,
+}
+
+impl FrontMatter {
+    fn from_delimiter(line: &str) -> Option<Self> {
+        match line.trim() {
+            "---" => Some(Self::Yaml),
+            "+++" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    fn delimiter(self) -> &'static str {
+        match self {
+            Self::Yaml => "---",
+            Self::Toml => "+++",
+        }
+    }
+
+    // Decided by the first line, the closing one has to agree
+    fn split(content: &str) -> Result<SplitFrontMatter<'_>, FrontMatterError> {
+        let mut lines = content
+            .split_inclusive('\n')
+            .scan(0, |offset, line| {
+                let start = *offset;
+                *offset += line.len();
+                Some((start, line))
+            })
+            .enumerate()
+            .map(|(i, (start, line))| (i + 1, start, line));
+        let Some((opening_line, opening_start, opening)) =
+            lines.find(|(_, _, line)| !line.trim().is_empty())
+        else {
+            return Err(FrontMatterError::at(
+                1,
+                "The file is empty, it needs a front matter".to_owned(),
+            ));
+        };
+        let Some(style) = Self::from_delimiter(opening) else {
+            return Err(FrontMatterError::at(
+                opening_line,
+                "The front matter must start with a '---' (YAML) or '+++' (TOML) line".to_owned(),
+            ));
+        };
+        let text_start = opening_start + opening.len();
+        let closing = lines.find_map(|(number, start, line)| {
+            Self::from_delimiter(line).map(|closing| (number, start, line, closing))
+        });
+        match closing {
+            Some((_, start, line, closing)) if closing == style => Ok(SplitFrontMatter {
+                style,
+                text: &content[text_start..start],
+                first_line: opening_line + 1,
+                body_offset: start + line.len(),
+            }),
+            Some((number, _, _, closing)) => Err(FrontMatterError::at(
+                number,
+                format!(
+                    "The front matter opens with '{}' but closes with '{}'",
+                    style.delimiter(),
+                    closing.delimiter()
+                ),
+            )),
+            None => Err(FrontMatterError::at(
+                opening_line,
+                format!(
+                    "The front matter opened with '{}' is never closed",
+                    style.delimiter()
+                ),
+            )),
+        }
+    }
+}
+
+impl SplitFrontMatter<'_> {
+    fn yaml_error(&self, e: &serde_yaml::Error) -> FrontMatterError {
+        let message = e.to_string();
+        let Some(location) = e.location() else {
+            return FrontMatterError::from_message(&message);
+        };
+        let suffix = format!(" at line {} column {}", location.line(), location.column());
+        let mut error = FrontMatterError::from_message(message.trim_end_matches(&suffix));
+        // A missing field is reported at the end of the front matter
+        if !error.message.starts_with("missing field") {
+            error.line = Some(self.first_line + location.line() - 1);
+            error.column = Some(location.column());
+        }
+        error
+    }
+
+    // Only syntax errors have a span, the others name the field in a line of
+    // their own, which is then looked for
+    fn toml_error(&self, e: &toml::de::Error) -> FrontMatterError {
+        let mut error = FrontMatterError::from_message(e.message());
+        if let Some(span) = e.span() {
+            let before = &self.text[..span.start.min(self.text.len())];
+            let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            error.line = Some(self.first_line + before.matches('\n').count());
+            error.column = Some(before[line_start..].chars().count() + 1);
+            return error;
+        }
+        let field = e.to_string().lines().find_map(|line| {
+            line.strip_prefix("in `")?
+                .strip_suffix('`')
+                .map(str::to_owned)
+        });
+        if let Some(field) = field {
+            let key = field.split(['.', '[']).next().unwrap_or(&field).to_owned();
+            error.line = self
+                .text
+                .lines()
+                .position(|line| {
+                    line.split_once('=')
+                        .is_some_and(|(name, _)| name.trim().trim_matches('"') == key)
+                })
+                .map(|i| self.first_line + i);
+            error.field = Some(field);
+        }
+        error
+    }
+}
+
+// TOML has its own date type, the metadata expects RFC 3339 strings like the
+// YAML front matter gives
+fn dates_as_strings(value: toml::Value) -> toml::Value {
+    match value {
+        toml::Value::Datetime(date) => toml::Value::String(date.to_string()),
+        toml::Value::Array(values) => {
+            toml::Value::Array(values.into_iter().map(dates_as_strings).collect())
+        }
+        toml::Value::Table(table) => toml::Value::Table(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, dates_as_strings(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+pub const SECTION_INDEX_FILE: &str = "_index.md";
+
+// What the views of an entry are counted under
+const VIEWS_KEY: &str = "views";
+
+// An average adult reading speed
+pub const DEFAULT_WORDS_PER_MINUTE: u32 = 200;
+// A word of code takes as long as this many words of prose
+const CODE_WORD_WEIGHT: usize = 2;
+
+fn reading_time_minutes(words: WordCount, words_per_minute: u32) -> u32 {
+    let weighted = words.prose + words.code * CODE_WORD_WEIGHT;
+    weighted.div_ceil(words_per_minute.max(1) as usize) as u32
+}
+
+// Entries whose name has a component starting with '_' are never published,
+// section indices and snippets aside
+pub fn is_draft_name(entry_name: &str) -> bool {
+    entry_name.ends_with(".md")
+        && entry_name.split('/').any(|c| c.starts_with('_'))
+        && entry_name.rsplit('/').next() != Some(SECTION_INDEX_FILE)
+        && !entry_name.starts_with(&format!("{SNIPPETS_DIR}/"))
 }
 
 pub struct BlogStorage {
     base_path: PathBuf,
 
-    entries: RwLock<HashMap<String, Arc<BlogEntry>>>,
+    // Rendered entries, only the most recently used ones are kept around
+    entries: RwLock<LruCache<String, Arc<BlogEntry>>>,
+    // Every known entry without its content, which is what the listings use.
+    // Entries evicted from the cache are loaded again from disk when needed
+    summaries: RwLock<HashMap<String, Arc<BlogEntry>>>,
+    // The summaries by publish date, so that the entries yet to be published
+    // can be counted without going through all of them
+    publish_dates: RwLock<BTreeSet<(DateTime<Utc>, String)>>,
+    sections: RwLock<HashMap<String, Arc<Section>>>,
+    // Lowercased tag -> entries carrying it, newest first
+    tags: RwLock<HashMap<String, Vec<Arc<BlogEntry>>>>,
+    // Slug of a contributor's name -> entries crediting them, newest first
+    authors: RwLock<HashMap<String, Vec<Arc<BlogEntry>>>>,
+    slugs: RwLock<HashMap<String, Arc<BlogEntry>>>,
+    // Always locked after the summaries when both are needed
     most_recent_entries: RwLock<Vec<Arc<BlogEntry>>>,
     max_most_recent_entries: usize,
+    journal: Option<Arc<Journal>>,
+    cdn: Option<Arc<Cdn>>,
+    info: std::sync::RwLock<BlogInfo>,
+    images: Option<Arc<ResponsiveImages>>,
+    markdown: MarkdownOptions,
+    demote_headings: bool,
+    words_per_minute: u32,
+    // Entries published in the future stay hidden until then, unless asked
+    // otherwise for local previews
+    clock: SharedClock,
+    show_future: bool,
+    serve_drafts: bool,
+    // Computed on demand, and again only once the content version changes
+    public_stats: std::sync::Mutex<Option<(String, Arc<PublicStats>)>>,
+    search_index: Box<dyn SearchBackend>,
+    snippets: Snippets,
+    // Snippet name -> entries including it, parsed again when it changes
+    snippet_users: std::sync::Mutex<HashMap<String, HashSet<String>>>,
+    // Outcome of the last parse of every file, for the admin listing
+    parse_records: std::sync::Mutex<HashMap<String, ParseRecord>>,
+    // Entries read from the cache file, until the startup scan picks them up
+    cached: std::sync::Mutex<HashMap<String, CachedEntry>>,
+    // Only kept across restarts when a views path is configured
+    views: Arc<PersistentCounters>,
+    // Bumped on every change, so that clients can cheaply tell whether the
+    // listings changed. The generation keeps revisions of different runs apart
+    generation: i64,
+    revision: AtomicU64,
+    // Rides out the transient errors of network filesystems
+    retries: Arc<ReadRetries>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryStatus {
+    Published,
+    Scheduled,
+    Draft,
+    Error,
+}
+
+// One row of the admin listing. An entry failing to parse again after an edit
+// is still served in its last good version, so it can have both a title and
+// an error
+#[derive(Serialize)]
+pub struct AdminEntry {
+    pub slug: String,
+    pub title: Option<String>,
+    pub status: EntryStatus,
+    // Whether /blog/{slug} serves it right now
+    pub public: bool,
+    pub publish_date: Option<DateTime<Utc>>,
+    pub parsed_at: Option<DateTime<Utc>>,
+    pub word_count: Option<usize>,
+    pub views: u64,
+    pub error: Option<String>,
+    pub accessibility_warnings: Vec<String>,
+}
+
+struct ParseRecord {
+    at: DateTime<Utc>,
+    error: Option<String>,
+}
+
+// The parsed entries as saved on shutdown, so that the next start doesn't
+// parse the unchanged ones again. JSON like everything else persisted, the
+// metadata doesn't deserialize from formats that aren't self-describing
+#[derive(Serialize, Deserialize)]
+struct EntryCache {
+    // Entries parsed by another version or with other settings render
+    // differently, the whole file is ignored then
+    fingerprint: String,
+    entries: HashMap<String, CachedEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedEntry {
+    entry: BlogEntry,
+    snippets: BTreeSet<String>,
+    // The entry's file and its snippets as they were when it was parsed,
+    // relative to the base path. None for a snippet that didn't exist
+    files: BTreeMap<String, Option<FileStamp>>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+async fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let meta = tokio::fs::metadata(path).await.ok()?;
+    Some(FileStamp {
+        modified: meta.modified().ok(),
+        len: meta.len(),
+    })
+}
+
+pub struct ContentVersion {
+    pub tag: String,
+    pub last_modified: Option<DateTime<Utc>>,
 }
 
 impl BlogStorage {
-    pub fn new<P: AsRef<Path>>(base: P) -> Self {
+    pub fn new<P: AsRef<Path>>(base: P, cache_size: NonZeroUsize) -> Self {
         Self {
             base_path: PathBuf::from(base.as_ref()),
-            entries: Default::default(),
+            entries: RwLock::new(LruCache::new(cache_size)),
+            summaries: Default::default(),
+            publish_dates: Default::default(),
+            sections: Default::default(),
+            tags: Default::default(),
+            authors: Default::default(),
+            slugs: Default::default(),
             most_recent_entries: Default::default(),
             max_most_recent_entries: 10,
+            journal: None,
+            cdn: None,
+            info: std::sync::RwLock::new(BlogConfig::default().info()),
+            images: None,
+            markdown: MarkdownConfig::default().options(),
+            demote_headings: false,
+            words_per_minute: DEFAULT_WORDS_PER_MINUTE,
+            clock: Arc::new(SystemClock),
+            show_future: false,
+            serve_drafts: false,
+            public_stats: Default::default(),
+            search_index: Box::new(SearchIndex::new(MarkdownConfig::default().options())),
+            snippets: Snippets::new(base.as_ref()),
+            snippet_users: Default::default(),
+            parse_records: Default::default(),
+            cached: Default::default(),
+            views: Arc::new(PersistentCounters::in_memory()),
+            generation: Utc::now().timestamp_millis(),
+            revision: AtomicU64::new(0),
+            retries: Default::default(),
         }
     }
 
-    pub async fn get_entry(&self, entry_name: &str) -> anyhow::Result<Arc<BlogEntry>> {
-        if let Some(cached_entry) = self.try_find_cached_entry(entry_name).await {
-            info!("Hit a cache entry for {entry_name}");
-            Ok(cached_entry)
-        } else {
-            info!("Entry {entry_name} not found in cache, attempting to load it");
-            let entry = Self::parse_file_to_html(&self.base_path.join(entry_name)).await?;
-            let entry = Arc::new(entry);
-            self.try_store_entry(entry_name, entry.clone()).await;
-            Ok(entry)
-        }
+    // Kept across restarts when they're persisted
+    pub fn with_views(mut self, views: Arc<PersistentCounters>) -> Self {
+        self.views = views;
+        self
     }
 
-    pub async fn remove_entry(&self, entry_name: String) {
-        self.entries.write().await.remove_entry(&entry_name);
+    // How many of the newest entries are kept at hand, for the feeds
+    pub fn with_max_most_recent_entries(mut self, count: usize) -> Self {
+        self.max_most_recent_entries = count;
+        self
     }
 
-    pub async fn try_store_entry(&self, entry_name: &str, entry: Arc<BlogEntry>) {
-        let old = self
-            .entries
-            .write()
-            .await
-            .insert(entry_name.to_owned(), entry.clone());
-        info!("Entry {entry_name} successfully stored in cache");
-        if old.is_some() {
-            // Avoid inserting again entry
-            return;
-        }
+    pub fn max_most_recent_entries(&self) -> usize {
+        self.max_most_recent_entries
+    }
+
+    pub fn with_retries(mut self, retries: Arc<ReadRetries>) -> Self {
+        self.retries = retries;
+        self
+    }
 
-        let mut entries = self.most_recent_entries.write().await;
+    pub fn with_journal(mut self, journal: Arc<Journal>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
 
-        if entries.iter().any(|e| e.filename == entry.filename) {
-            // Avoid inserting again entry
-            // Another check just to be extra sure
-            return;
-        }
-        match entries.binary_search_by(|e| {
-            entry
-                .description
-                .publish_date
-                .cmp(&e.description.publish_date)
-        }) {
-            Ok(pos) => entries.insert(pos, entry),
-            Err(pos) => entries.insert(pos, entry),
-        }
+    pub fn with_cdn(mut self, cdn: Arc<Cdn>) -> Self {
+        self.cdn = Some(cdn);
+        self
+    }
 
-        entries.truncate(self.max_most_recent_entries);
+    pub fn with_images(mut self, images: Arc<ResponsiveImages>) -> Self {
+        self.images = Some(images);
+        self
     }
 
-    pub async fn contains_entry(&self, entry_name: &str) -> bool {
-        self.entries
-            .read()
-            .await
-            .keys()
-            .any(|e| e.as_str() == entry_name)
+    // The search index strips the markdown with the same options
+    pub fn with_markdown(mut self, markdown: MarkdownOptions) -> Self {
+        self.search_index = Box::new(SearchIndex::new(markdown.clone()));
+        self.markdown = markdown;
+        self
     }
 
-    pub async fn iterate_most_recent_entries<F: FnMut(&BlogEntry)>(&self, mut f: F) {
-        self.most_recent_entries
+    // The content of the entries never has an h1, see MarkdownConfig
+    pub fn demote_headings(mut self) -> Self {
+        self.demote_headings = true;
+        self
+    }
+
+    pub fn with_words_per_minute(mut self, words_per_minute: u32) -> Self {
+        self.words_per_minute = words_per_minute;
+        self
+    }
+
+    pub fn markdown_options(&self) -> MarkdownOptions {
+        self.markdown.clone()
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn show_future_entries(mut self) -> Self {
+        self.show_future = true;
+        self
+    }
+
+    // Entries with `draft: true` are otherwise kept out of the cache
+    pub fn serve_drafts(mut self) -> Self {
+        self.serve_drafts = true;
+        self
+    }
+
+    fn is_hidden_draft(&self, entry: &BlogEntry) -> bool {
+        entry.description.draft && !self.serve_drafts
+    }
+
+    // Snippets failing to be included are pointed out in the page itself
+    pub fn show_snippet_errors(mut self) -> Self {
+        self.snippets = self.snippets.show_errors();
+        self
+    }
+
+    pub fn is_published(&self, entry: &BlogEntry) -> bool {
+        self.show_future || entry.description.publish_date <= self.clock.now()
+    }
+
+    // The listings as they were at as_of, which can hide entries but never
+    // reveal unpublished ones
+    fn is_listed(&self, entry: &BlogEntry, as_of: Option<DateTime<Utc>>) -> bool {
+        self.is_published(entry)
+            && as_of.is_none_or(|as_of| entry.description.publish_date <= as_of)
+    }
+
+    // Entries whose publish date fell in (since, until], nothing touches their
+    // files at that moment so somebody has to look for them
+    pub async fn published_between(
+        &self,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Vec<String> {
+        self.summaries
             .read()
             .await
-            .iter()
-            .for_each(|entry| f(entry));
+            .values()
+            .filter(|e| e.description.publish_date > since && e.description.publish_date <= until)
+            .map(|e| e.filename.clone())
+            .collect()
     }
 
-    pub async fn parse_file_to_html<P: AsRef<Path>>(path: &P) -> anyhow::Result<BlogEntry> {
-        let content = tokio::fs::read_to_string(&path).await?;
-        let meta = tokio::fs::metadata(path).await?;
-        let document = YamlFrontMatter::parse::<PostMetadata>(&content);
-        let document = match document {
-            Ok(doc) => doc,
-            Err(e) => {
-                anyhow::bail!(e.to_string())
+    pub fn blog_info(&self) -> BlogInfo {
+        self.info.read().expect("Poisoned blog info").clone()
+    }
+
+    pub fn set_blog_info(&self, info: BlogInfo) {
+        *self.info.write().expect("Poisoned blog info") = info;
+        self.revision.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn get_entry(&self, entry_name: &str) -> Result<Arc<BlogEntry>, BlogStorageError> {
+        let entry_name = &self.resolve_slug(entry_name).await;
+        self.load_entry(entry_name).await.map_err(|e| {
+            if is_missing_entry(&e) {
+                BlogStorageError::NotFound(entry_name.to_owned())
+            } else {
+                BlogStorageError::ParseError(e)
             }
-        };
-        let html = comrak::markdown_to_html(&document.content, &comrak::Options::default());
-        let filename = path.as_ref().to_path_buf();
-        let filename = filename.file_name().unwrap().to_string_lossy();
-        let filename = filename.to_string();
-        Ok(BlogEntry {
-            description: document.metadata,
-            html,
-            creation_date: meta.created()?,
-            filename,
         })
     }
 
-    async fn try_find_cached_entry(&self, entry_name: &str) -> Option<Arc<BlogEntry>> {
-        self.entries.read().await.get(entry_name).cloned()
+    async fn load_entry(&self, entry_name: &str) -> anyhow::Result<Arc<BlogEntry>> {
+        if !is_entry_name(entry_name) {
+            return Err(InvalidEntryName(entry_name.to_owned()).into());
+        }
+        let entry = if let Some(cached_entry) = self.try_find_cached_entry(entry_name).await {
+            info!("Hit a cache entry for {entry_name}");
+            cached_entry
+        } else {
+            info!("Entry {entry_name} not found in cache, attempting to load it");
+            let entry = self.parse_entry(entry_name).await?;
+            if self.is_hidden_draft(&entry) {
+                return Err(Draft(entry_name.to_owned()).into());
+            }
+            let entry = Arc::new(entry);
+            self.try_store_entry(entry_name, entry.clone()).await;
+            entry
+        };
+        // Still cached, so that it shows up as soon as its date passes
+        if !self.is_published(&entry) {
+            return Err(Scheduled {
+                entry: entry_name.to_owned(),
+                publish_date: entry.description.publish_date,
+            }
+            .into());
+        }
+        Ok(entry)
+    }
+
+    pub async fn parse_entry(&self, entry_name: &str) -> anyhow::Result<BlogEntry> {
+        let entry = match self.parse_file(&self.base_path.join(entry_name)).await {
+            Ok(mut entry) => {
+                entry.set_filename(entry_name.to_owned());
+                self.check_slug(&entry).await.map(|()| entry)
+            }
+            Err(e) => Err(e),
+        };
+        self.record_parse(entry_name, entry)
+    }
+
+    // Used by the startup scan: the entry from the cache file when neither it
+    // nor its snippets changed since, parsed otherwise
+    pub async fn cached_or_parse_entry(&self, entry_name: &str) -> anyhow::Result<BlogEntry> {
+        let cached = self
+            .cached
+            .lock()
+            .expect("Poisoned entry cache")
+            .remove(entry_name);
+        if let Some(cached) = cached {
+            if self.is_current(&cached).await {
+                let mut entry = cached.entry;
+                entry.snippets = cached.snippets;
+                let entry = self.check_slug(&entry).await.map(|()| entry);
+                return self.record_parse(entry_name, entry);
+            }
+            info!("Entry {entry_name} changed since it was cached");
+        }
+        self.parse_entry(entry_name).await
+    }
+
+    async fn is_current(&self, cached: &CachedEntry) -> bool {
+        for (file, stamp) in &cached.files {
+            if file_stamp(&self.base_path.join(file)).await != *stamp {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn record_parse(
+        &self,
+        entry_name: &str,
+        entry: anyhow::Result<BlogEntry>,
+    ) -> anyhow::Result<BlogEntry> {
+        self.parse_records
+            .lock()
+            .expect("Poisoned parse records")
+            .insert(
+                entry_name.to_owned(),
+                ParseRecord {
+                    at: self.clock.now(),
+                    error: entry.as_ref().err().map(|e| format!("{e:#}")),
+                },
+            );
+        let entry = entry?;
+        self.track_snippets(entry_name, &entry.snippets);
+        for warning in &entry.accessibility_warnings {
+            warn!("Entry {entry_name}: {warning}");
+        }
+        Ok(entry)
+    }
+
+    // The number of entries read, 0 when there's no cache file yet
+    pub async fn load_cache(&self, path: &Path) -> anyhow::Result<usize> {
+        let content = match tokio::fs::read(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let cache: EntryCache = serde_json::from_slice(&content)?;
+        if cache.fingerprint != self.cache_fingerprint() {
+            anyhow::bail!("it was written by another version or with other settings");
+        }
+        let count = cache.entries.len();
+        *self.cached.lock().expect("Poisoned entry cache") = cache.entries;
+        Ok(count)
+    }
+
+    // What's left once the startup scan is done belongs to removed files
+    pub fn forget_cache(&self) {
+        self.cached.lock().expect("Poisoned entry cache").clear();
+    }
+
+    // Saves the entries in the cache, which with a cache size above the
+    // number of entries is all of them. The others are parsed on startup
+    pub async fn persist(&self, path: &Path) -> anyhow::Result<()> {
+        let entries: Vec<_> = self
+            .entries
+            .read()
+            .await
+            .iter()
+            .map(|(entry_name, entry)| (entry_name.clone(), entry.clone()))
+            .collect();
+        let parsed_at: HashMap<_, _> = self
+            .parse_records
+            .lock()
+            .expect("Poisoned parse records")
+            .iter()
+            .map(|(entry_name, record)| (entry_name.clone(), record.at))
+            .collect();
+        let mut cached = HashMap::new();
+        for (entry_name, entry) in entries {
+            let Some(parsed_at) = parsed_at.get(&entry_name) else {
+                continue;
+            };
+            let mut files = BTreeMap::new();
+            let snippet_files = entry
+                .snippets
+                .iter()
+                .map(|snippet| format!("{SNIPPETS_DIR}/{snippet}.md"));
+            for file in std::iter::once(entry_name.clone()).chain(snippet_files) {
+                let stamp = file_stamp(&self.base_path.join(&file)).await;
+                files.insert(file, stamp);
+            }
+            // Edited after the watchers stopped, the stamp would hide it
+            let edited = files.values().flatten().any(|stamp| {
+                stamp
+                    .modified
+                    .is_some_and(|modified| DateTime::<Utc>::from(modified) > *parsed_at)
+            });
+            if edited {
+                continue;
+            }
+            cached.insert(
+                entry_name,
+                CachedEntry {
+                    snippets: entry.snippets.clone(),
+                    entry: (*entry).clone(),
+                    files,
+                },
+            );
+        }
+        let count = cached.len();
+        let cache = EntryCache {
+            fingerprint: self.cache_fingerprint(),
+            entries: cached,
+        };
+        write_atomically(path, serde_json::to_vec(&cache)?).await?;
+        info!("Saved {count} entries to {path:?}");
+        Ok(())
+    }
+
+    fn cache_fingerprint(&self) -> String {
+        let settings = format!(
+            "{} {:?} {} {} {} {:?}",
+            env!("CARGO_PKG_VERSION"),
+            self.markdown,
+            self.demote_headings,
+            self.words_per_minute,
+            self.snippets.shows_errors(),
+            self.images.as_ref().map(|images| images.widths()),
+        );
+        format!("{:x}", Sha256::digest(settings.as_bytes()))
+    }
+
+    fn track_snippets(&self, entry_name: &str, snippets: &BTreeSet<String>) {
+        let mut users = self.snippet_users.lock().expect("Poisoned snippet users");
+        users.retain(|snippet, entries| {
+            if !snippets.contains(snippet) {
+                entries.remove(entry_name);
+            }
+            !entries.is_empty()
+        });
+        for snippet in snippets {
+            users
+                .entry(snippet.clone())
+                .or_default()
+                .insert(entry_name.to_owned());
+        }
+    }
+
+    // Called when a snippet is created, edited or removed
+    pub async fn reload_snippet_users(&self, snippet: &str) {
+        let entries: Vec<_> = self
+            .snippet_users
+            .lock()
+            .expect("Poisoned snippet users")
+            .get(snippet)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default();
+        for entry_name in entries {
+            info!("Reloading entry {entry_name}, which includes snippet {snippet}");
+            match self.parse_entry(&entry_name).await {
+                Ok(entry) => self.try_store_entry(&entry_name, Arc::new(entry)).await,
+                Err(e) => warn!("Failed to read entry {entry_name}: {e}"),
+            }
+        }
+    }
+
+    // Reads the entry straight from disk, bypassing the cache: used for drafts,
+    // which must never end up in the public listings. Entries of subdirectories
+    // are fine, as long as every component stays within the blog directory
+    pub async fn load_uncached(&self, entry_name: &str) -> anyhow::Result<BlogEntry> {
+        let within_blog = !entry_name.contains('\\')
+            && entry_name
+                .split('/')
+                .all(|c| !c.is_empty() && c != "." && c != "..");
+        if !within_blog {
+            anyhow::bail!("Invalid entry name {entry_name}");
+        }
+        self.parse_file(&self.base_path.join(entry_name)).await
+    }
+
+    async fn parse_file(&self, path: &Path) -> anyhow::Result<BlogEntry> {
+        let mut entry = self.parse_file_to_html(&path).await?;
+        if let Some(images) = &self.images {
+            entry.html = images.rewrite_html(&entry.html);
+        }
+        Ok(entry)
+    }
+
+    pub async fn remove_entry(&self, entry_name: String) {
+        self.parse_records
+            .lock()
+            .expect("Poisoned parse records")
+            .remove(&entry_name);
+        self.track_snippets(&entry_name, &BTreeSet::new());
+        self.entries.write().await.pop(&entry_name);
+        let removed = {
+            let mut summaries = self.summaries.write().await;
+            self.most_recent_entries
+                .write()
+                .await
+                .retain(|e| e.filename != entry_name);
+            let removed = summaries.remove(&entry_name);
+            if let Some(removed) = &removed {
+                self.publish_dates
+                    .write()
+                    .await
+                    .remove(&(removed.description.publish_date, entry_name.clone()));
+            }
+            removed
+        };
+        if let Some(removed) = removed {
+            self.unindex_tags(&removed).await;
+            self.unindex_authors(&removed).await;
+            self.unindex_slug(&removed).await;
+            if let Some(cdn) = &self.cdn {
+                cdn.entry_changed(&removed);
+            }
+            self.backfill_most_recent_entries().await;
+        }
+        self.search_index.remove(&entry_name).await;
+        self.revision.fetch_add(1, Ordering::Relaxed);
+        if let Some(journal) = &self.journal {
+            journal.record_removed(&entry_name).await;
+        }
+    }
+
+    // Tops the most recent entries back up after some were removed, with the
+    // newest of the other known entries. The feeds need their whole content,
+    // so they're loaded rather than taken from the summaries
+    async fn backfill_most_recent_entries(&self) {
+        let missing = {
+            let summaries = self.summaries.read().await;
+            let most_recent = self.most_recent_entries.read().await;
+            let mut candidates: Vec<_> = summaries
+                .values()
+                .filter(|s| !most_recent.iter().any(|e| e.filename == s.filename))
+                .collect();
+            candidates.sort_by_key(|s| Reverse(s.description.publish_date));
+            candidates
+                .into_iter()
+                .take(
+                    self.max_most_recent_entries
+                        .saturating_sub(most_recent.len()),
+                )
+                .map(|s| s.filename.clone())
+                .collect::<Vec<_>>()
+        };
+        for entry_name in missing {
+            let entry = match self.try_find_cached_entry(&entry_name).await {
+                Some(entry) => entry,
+                None => match self.parse_entry(&entry_name).await {
+                    Ok(entry) => Arc::new(entry),
+                    Err(e) => {
+                        warn!("Failed to load {entry_name} back into the most recent entries: {e}");
+                        continue;
+                    }
+                },
+            };
+            let summaries = self.summaries.read().await;
+            let mut most_recent = self.most_recent_entries.write().await;
+            // Removed or stored again while it was loading
+            if !summaries.contains_key(&entry_name)
+                || most_recent.iter().any(|e| e.filename == entry_name)
+                || most_recent.len() >= self.max_most_recent_entries
+            {
+                continue;
+            }
+            let pos = most_recent
+                .partition_point(|e| e.description.publish_date > entry.description.publish_date);
+            most_recent.insert(pos, entry);
+        }
+    }
+
+    pub async fn try_store_entry(&self, entry_name: &str, entry: Arc<BlogEntry>) {
+        self.store_entry(entry_name, entry, false).await;
+    }
+
+    // Forced, an entry is stored again even if its content didn't change
+    async fn store_entry(&self, entry_name: &str, entry: Arc<BlogEntry>, force: bool) {
+        if self.is_hidden_draft(&entry) {
+            // Turned back into a draft after being published
+            if self.contains_entry(entry_name).await {
+                info!("Entry {entry_name} is now a draft, removing it");
+                self.remove_entry(entry_name.to_owned()).await;
+            } else {
+                info!("Skipping draft {entry_name}");
+            }
+            return;
+        }
+        self.entries
+            .write()
+            .await
+            .put(entry_name.to_owned(), entry.clone());
+        let summary = Arc::new(entry.summary());
+        let (old, moved_last) = {
+            let mut summaries = self.summaries.write().await;
+            let unchanged = !force
+                && summaries
+                    .get(entry_name)
+                    .is_some_and(|old| old.content_hash == entry.content_hash);
+            if unchanged {
+                // Loaded again after being evicted, or saved without changes
+                return;
+            }
+            // While still holding the summaries, so that concurrent stores of
+            // the same entry can't leave the two out of step
+            let mut most_recent = self.most_recent_entries.write().await;
+            let listed = most_recent.len();
+            most_recent.retain(|e| e.filename != entry_name);
+            let pos = most_recent
+                .partition_point(|e| e.description.publish_date > entry.description.publish_date);
+            // Listed before and now last: an entry that didn't make it may be
+            // newer, the backfill picks between them
+            let moved_last = most_recent.len() < listed && pos == most_recent.len();
+            if !moved_last {
+                most_recent.insert(pos, entry.clone());
+                most_recent.truncate(self.max_most_recent_entries);
+            }
+            let old = summaries.insert(entry_name.to_owned(), summary.clone());
+            let mut publish_dates = self.publish_dates.write().await;
+            if let Some(old) = &old {
+                publish_dates.remove(&(old.description.publish_date, entry_name.to_owned()));
+            }
+            publish_dates.insert((summary.description.publish_date, entry_name.to_owned()));
+            (old, moved_last)
+        };
+        if moved_last {
+            self.backfill_most_recent_entries().await;
+        }
+        if let Some(old) = &old {
+            self.unindex_tags(old).await;
+            self.unindex_authors(old).await;
+            self.unindex_slug(old).await;
+        }
+        self.index_tags(&summary).await;
+        self.index_authors(&summary).await;
+        self.index_slug(&summary).await;
+        self.search_index.insert(entry_name, &entry.markdown).await;
+        info!("Entry {entry_name} successfully stored in cache");
+        self.revision.fetch_add(1, Ordering::Relaxed);
+        if let Some(journal) = &self.journal {
+            journal
+                .record_stored(entry_name, &entry.slug, &entry.content_hash)
+                .await;
+        }
+        if let Some(cdn) = &self.cdn {
+            // The old tags too, in case some were dropped
+            cdn.entry_changed(&summary);
+            if let Some(old) = &old {
+                cdn.entry_changed(old);
+            }
+        }
+    }
+
+    // Entries are named by their path relative to the blog directory, always
+    // using '/' as a separator so that names can be used in urls as they are
+    pub fn entry_name_for_path(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.base_path).ok().or_else(|| {
+            let base_path = self.base_path.canonicalize().ok()?;
+            path.strip_prefix(base_path).ok()
+        })?;
+        let components: Vec<_> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        if components.is_empty() {
+            return None;
+        }
+        Some(components.join("/"))
+    }
+
+    pub async fn matching_entries<F: Fn(&BlogEntry) -> bool>(
+        &self,
+        predicate: F,
+    ) -> Vec<Arc<BlogEntry>> {
+        self.summaries
+            .read()
+            .await
+            .values()
+            .filter(|e| predicate(e))
+            .cloned()
+            .collect()
+    }
+
+    // Drops the cached copies of an entry and reads it again from disk. One
+    // that can't be read anymore is removed, as if it had been deleted
+    async fn refresh_entry(&self, entry_name: &str) -> bool {
+        if !self.contains_entry(entry_name).await {
+            return false;
+        }
+        self.entries.write().await.pop(entry_name);
+        match self.parse_entry(entry_name).await {
+            Ok(entry) => {
+                self.store_entry(entry_name, Arc::new(entry), true).await;
+                true
+            }
+            Err(e) => {
+                warn!("Failed to reload purged entry {entry_name}, removing it: {e}");
+                self.remove_entry(entry_name.to_owned()).await;
+                false
+            }
+        }
+    }
+
+    // Every known entry, not only the most recent ones, newest first. Like the
+    // other listings these are summaries, without the content
+    pub async fn entries_page(
+        &self,
+        offset: usize,
+        limit: usize,
+        as_of: Option<DateTime<Utc>>,
+    ) -> Vec<Arc<BlogEntry>> {
+        let mut entries: Vec<_> = self
+            .summaries
+            .read()
+            .await
+            .values()
+            .filter(|e| self.is_listed(e, as_of))
+            .cloned()
+            .collect();
+        entries.sort_by_key(|e| Reverse(e.description.publish_date));
+        entries.into_iter().skip(offset).take(limit).collect()
+    }
+
+    pub async fn entry_count(&self, as_of: Option<DateTime<Utc>>) -> usize {
+        self.summaries
+            .read()
+            .await
+            .values()
+            .filter(|e| self.is_listed(e, as_of))
+            .count()
+    }
+
+    // The listed entries published right before and right after the entry.
+    // The most recent entries only go so far back, so every listed one is
+    // searched. An entry that isn't listed itself, e.g. a scheduled one being
+    // previewed, still gets the neighbours its date would give it
+    pub async fn get_adjacent_entries(
+        &self,
+        entry_name: &str,
+    ) -> (Option<BlogEntryRef>, Option<BlogEntryRef>) {
+        let Some(entry) = self.summaries.read().await.get(entry_name).cloned() else {
+            return (None, None);
+        };
+        // Newest first, entries of the same date by name so that they don't
+        // point at each other
+        let order = |e: &BlogEntry| (Reverse(e.description.publish_date), e.filename.clone());
+        let mut entries: Vec<_> = self
+            .summaries
+            .read()
+            .await
+            .values()
+            .filter(|e| self.is_listed(e, None) && !e.description.draft)
+            .filter(|e| e.filename != entry_name)
+            .cloned()
+            .collect();
+        entries.sort_by_cached_key(|e| order(e));
+        let key = order(&entry);
+        let pos = entries.partition_point(|e| order(e) < key);
+        let prev = entries.get(pos).map(|e| BlogEntryRef::new(e));
+        let next = pos
+            .checked_sub(1)
+            .and_then(|pos| entries.get(pos))
+            .map(|e| BlogEntryRef::new(e));
+        (prev, next)
+    }
+
+    // The listed entries for the home page, newest first
+    pub async fn listed_entries(
+        &self,
+        as_of: Option<DateTime<Utc>>,
+        request: PageRequest,
+    ) -> Paged<Arc<BlogEntry>> {
+        Paged::new(self.entries_page(0, usize::MAX, as_of).await, request)
+    }
+
+    pub async fn public_stats(&self) -> Arc<PublicStats> {
+        let version = self.content_version().await.tag;
+        {
+            let cached = self.public_stats.lock().expect("Poisoned public stats");
+            if let Some((cached_version, stats)) = cached.as_ref() {
+                if *cached_version == version {
+                    return stats.clone();
+                }
+            }
+        }
+        let entries = self.entries_page(0, usize::MAX, None).await;
+        let stats = Arc::new(PublicStats::compute(&entries));
+        *self.public_stats.lock().expect("Poisoned public stats") = Some((version, stats.clone()));
+        stats
+    }
+
+    // Only published entries are candidates, newest first, so that equally
+    // relevant results keep that order
+    pub async fn search(&self, query: &str) -> Vec<SearchResult> {
+        let candidates = self.entries_page(0, usize::MAX, None).await;
+        self.search_index.search(&candidates, query).await
+    }
+
+    pub fn record_view(&self, entry_name: &str) {
+        self.views.increment(entry_name, VIEWS_KEY);
+    }
+
+    // Every entry the author may care about, whether it's public or not:
+    // drafts are looked up on disk, since they never make it to the cache
+    pub async fn admin_listing(&self) -> Vec<AdminEntry> {
+        let mut listing: Vec<_> = {
+            let summaries = self.summaries.read().await;
+            let records = self.parse_records.lock().expect("Poisoned parse records");
+            let views = self.views.counts();
+            let views = |name: &str| {
+                views
+                    .get(name)
+                    .and_then(|counts| counts.get(VIEWS_KEY))
+                    .copied()
+                    .unwrap_or(0)
+            };
+            let failed = records
+                .iter()
+                .filter(|(name, record)| {
+                    record.error.is_some()
+                        && name.ends_with(".md")
+                        && !is_draft_name(name)
+                        && !summaries.contains_key(*name)
+                })
+                .map(|(name, record)| AdminEntry {
+                    slug: name.clone(),
+                    title: None,
+                    status: EntryStatus::Error,
+                    public: false,
+                    publish_date: None,
+                    parsed_at: Some(record.at),
+                    word_count: None,
+                    views: views(name),
+                    error: record.error.clone(),
+                    accessibility_warnings: vec![],
+                });
+            summaries
+                .iter()
+                .map(|(name, entry)| {
+                    let record = records.get(name);
+                    let error = record.and_then(|r| r.error.clone());
+                    let public = self.is_published(entry);
+                    let status = if error.is_some() {
+                        EntryStatus::Error
+                    } else if public {
+                        EntryStatus::Published
+                    } else {
+                        EntryStatus::Scheduled
+                    };
+                    AdminEntry {
+                        slug: name.clone(),
+                        title: Some(entry.description.title.clone()),
+                        status,
+                        public,
+                        publish_date: Some(entry.description.publish_date),
+                        parsed_at: record.map(|r| r.at),
+                        word_count: Some(entry.word_count),
+                        views: views(name),
+                        error,
+                        accessibility_warnings: entry.accessibility_warnings.clone(),
+                    }
+                })
+                .chain(failed)
+                .collect()
+        };
+        for name in self.draft_names().await {
+            let draft = self.parse_file(&self.base_path.join(&name)).await;
+            let parsed_at = Some(self.clock.now());
+            listing.push(match draft {
+                Ok(draft) => AdminEntry {
+                    slug: name,
+                    title: Some(draft.description.title),
+                    status: EntryStatus::Draft,
+                    public: false,
+                    publish_date: Some(draft.description.publish_date),
+                    parsed_at,
+                    word_count: Some(draft.word_count),
+                    views: 0,
+                    error: None,
+                    accessibility_warnings: draft.accessibility_warnings,
+                },
+                Err(e) => AdminEntry {
+                    slug: name,
+                    title: None,
+                    status: EntryStatus::Error,
+                    public: false,
+                    publish_date: None,
+                    parsed_at,
+                    word_count: None,
+                    views: 0,
+                    error: Some(format!("{e:#}")),
+                    accessibility_warnings: vec![],
+                },
+            });
+        }
+        listing
+    }
+
+    async fn draft_names(&self) -> Vec<String> {
+        let mut drafts = vec![];
+        let mut dirs = vec![self.base_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(mut read_dir) = tokio::fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = read_dir.next_entry().await {
+                match entry.file_type().await {
+                    Ok(t) if t.is_dir() => dirs.push(entry.path()),
+                    Ok(t) if t.is_file() => {
+                        if let Some(name) = self.entry_name_for_path(&entry.path()) {
+                            if is_draft_name(&name) {
+                                drafts.push(name);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        drafts
+    }
+
+    pub async fn tagged_entries(
+        &self,
+        tag: &str,
+        as_of: Option<DateTime<Utc>>,
+        request: PageRequest,
+    ) -> Paged<Arc<BlogEntry>> {
+        let entries = self
+            .tags
+            .read()
+            .await
+            .get(&tag.to_lowercase())
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|e| self.is_listed(e, as_of))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Paged::new(entries, request)
+    }
+
+    async fn index_tags(&self, entry: &Arc<BlogEntry>) {
+        let mut tags = self.tags.write().await;
+        for tag in &entry.description.tags {
+            let entries = tags.entry(tag.to_lowercase()).or_default();
+            if entries.iter().any(|e| e.filename == entry.filename) {
+                continue;
+            }
+            let pos = entries
+                .partition_point(|e| e.description.publish_date > entry.description.publish_date);
+            entries.insert(pos, entry.clone());
+        }
+    }
+
+    // The name as it was written in the newest listed entry, and the entries
+    // crediting it. The slug of the name is matched rather than the name
+    pub async fn author_entries(
+        &self,
+        name: &str,
+        as_of: Option<DateTime<Utc>>,
+        request: PageRequest,
+    ) -> Option<(String, Paged<Arc<BlogEntry>>)> {
+        let slug = author_slug(name);
+        let entries: Vec<_> = self
+            .authors
+            .read()
+            .await
+            .get(&slug)?
+            .iter()
+            .filter(|e| self.is_listed(e, as_of))
+            .cloned()
+            .collect();
+        let name = entries.first()?.description.author_named(&slug)?.to_owned();
+        Some((name, Paged::new(entries, request)))
+    }
+
+    // Everybody credited by a listed entry, by name
+    pub async fn authors(&self, as_of: Option<DateTime<Utc>>) -> Vec<AuthorSummary> {
+        let mut authors: Vec<_> = self
+            .authors
+            .read()
+            .await
+            .iter()
+            .filter_map(|(slug, entries)| {
+                let listed: Vec<_> = entries
+                    .iter()
+                    .filter(|e| self.is_listed(e, as_of))
+                    .collect();
+                let name = listed.first()?.description.author_named(slug)?;
+                Some(AuthorSummary {
+                    name: name.to_owned(),
+                    slug: slug.clone(),
+                    entry_count: listed.len(),
+                })
+            })
+            .collect();
+        authors.sort_by_cached_key(|author| author.name.to_lowercase());
+        authors
+    }
+
+    async fn index_authors(&self, entry: &Arc<BlogEntry>) {
+        let mut authors = self.authors.write().await;
+        for slug in entry.description.author_slugs() {
+            let entries = authors.entry(slug).or_default();
+            let pos = entries
+                .partition_point(|e| e.description.publish_date > entry.description.publish_date);
+            entries.insert(pos, entry.clone());
+        }
+    }
+
+    async fn unindex_authors(&self, entry: &BlogEntry) {
+        let mut authors = self.authors.write().await;
+        for slug in entry.description.author_slugs() {
+            if let Some(entries) = authors.get_mut(&slug) {
+                entries.retain(|e| e.filename != entry.filename);
+                if entries.is_empty() {
+                    authors.remove(&slug);
+                }
+            }
+        }
+    }
+
+    // Rejects the entries wanting the slug of another one, instead of letting
+    // them shadow each other
+    async fn check_slug(&self, entry: &BlogEntry) -> anyhow::Result<()> {
+        match self.slugs.read().await.get(&entry.slug) {
+            Some(other) if other.filename != entry.filename => Err(SlugTaken {
+                entry: entry.filename.clone(),
+                slug: entry.slug.clone(),
+                taken_by: other.filename.clone(),
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    // The first entry claiming a slug keeps it
+    async fn index_slug(&self, entry: &Arc<BlogEntry>) {
+        let mut slugs = self.slugs.write().await;
+        match slugs.get(&entry.slug) {
+            Some(other) if other.filename != entry.filename => warn!(
+                "{} and {} both use the slug {}, only the first is served by it",
+                other.filename, entry.filename, entry.slug
+            ),
+            _ => {
+                slugs.insert(entry.slug.clone(), entry.clone());
+            }
+        }
+    }
+
+    async fn unindex_slug(&self, entry: &BlogEntry) {
+        let mut slugs = self.slugs.write().await;
+        if slugs
+            .get(&entry.slug)
+            .is_some_and(|e| e.filename == entry.filename)
+        {
+            slugs.remove(&entry.slug);
+        }
+    }
+
+    // The file of the entry served at /blog/{name}: names are looked up as
+    // slugs first, then as file names, with or without the extension
+    pub async fn resolve_slug(&self, name: &str) -> String {
+        match self.slugs.read().await.get(name) {
+            Some(entry) => entry.filename.clone(),
+            None if name.ends_with(".md") => name.to_owned(),
+            None => format!("{name}.md"),
+        }
+    }
+
+    async fn unindex_tags(&self, entry: &BlogEntry) {
+        let mut tags = self.tags.write().await;
+        for tag in &entry.description.tags {
+            let tag = tag.to_lowercase();
+            if let Some(entries) = tags.get_mut(&tag) {
+                entries.retain(|e| e.filename != entry.filename);
+                if entries.is_empty() {
+                    tags.remove(&tag);
+                }
+            }
+        }
+    }
+
+    pub async fn store_section(&self, section: Arc<Section>) {
+        info!("Section {} successfully stored", section.path);
+        self.sections
+            .write()
+            .await
+            .insert(section.path.clone(), section);
+    }
+
+    pub async fn remove_section(&self, section_path: &str) {
+        self.sections.write().await.remove(section_path);
+    }
+
+    pub async fn get_section(&self, section_path: &str) -> Option<Arc<Section>> {
+        self.sections.read().await.get(section_path).cloned()
+    }
+
+    pub async fn section_entries(
+        &self,
+        section: &Section,
+        request: PageRequest,
+    ) -> Paged<Arc<BlogEntry>> {
+        let prefix = format!("{}/", section.path);
+        let mut entries: Vec<_> = self
+            .summaries
+            .read()
+            .await
+            .iter()
+            .filter(|(name, _)| match name.strip_prefix(&prefix) {
+                Some(rest) => section.metadata.recursive || !rest.contains('/'),
+                None => false,
+            })
+            .filter(|(_, entry)| self.is_published(entry))
+            .map(|(_, entry)| entry.clone())
+            .collect();
+        entries.sort_by_key(|e| Reverse(e.description.publish_date));
+        Paged::new(entries, request)
+    }
+
+    // The trail of sections leading to an entry or a section, excluding the
+    // entry/section itself
+    pub async fn breadcrumbs(&self, name: &str) -> Vec<Breadcrumb> {
+        let mut breadcrumbs = vec![Breadcrumb {
+            title: "Home".to_owned(),
+            url: "/blog".to_owned(),
+        }];
+        let sections = self.sections.read().await;
+        let components: Vec<_> = name.trim_end_matches('/').split('/').collect();
+        for depth in 1..components.len() {
+            let path = components[..depth].join("/");
+            let title = match sections.get(&path) {
+                Some(section) => section.metadata.title.clone(),
+                None => components[depth - 1].to_owned(),
+            };
+            breadcrumbs.push(Breadcrumb {
+                title,
+                url: format!("/blog/{path}/"),
+            });
+        }
+        breadcrumbs
+    }
+
+    // Unlike get_entry, never loads the entry when it isn't cached
+    pub async fn cached_entry(&self, entry_name: &str) -> Option<Arc<BlogEntry>> {
+        self.try_find_cached_entry(entry_name).await
+    }
+
+    pub async fn contains_entry(&self, entry_name: &str) -> bool {
+        self.summaries.read().await.contains_key(entry_name)
+    }
+
+    pub async fn iterate_most_recent_entries<F: FnMut(&BlogEntry)>(&self, mut f: F) {
+        self.most_recent_entries
+            .read()
+            .await
+            .iter()
+            .filter(|entry| self.is_published(entry))
+            .for_each(|entry| f(entry));
+    }
+
+    // Like entry_count(None), counting back from the newest entries instead
+    async fn published_count(&self) -> usize {
+        let summaries = self.summaries.read().await;
+        if self.show_future {
+            return summaries.len();
+        }
+        let now = self.clock.now();
+        let scheduled = self
+            .publish_dates
+            .read()
+            .await
+            .iter()
+            .rev()
+            .take_while(|(publish_date, _)| *publish_date > now)
+            .count();
+        summaries.len() - scheduled
+    }
+
+    // The journal cursor survives restarts, so it's preferred when available:
+    // a restarted server then keeps answering with the same tags. It doesn't
+    // see the blog info, which is hashed in instead. Scheduled entries going
+    // live change nothing else, hence the published count
+    pub async fn content_version(&self) -> ContentVersion {
+        let published = self.published_count().await;
+        let tag = match &self.journal {
+            Some(journal) => {
+                let info = serde_json::to_string(&self.blog_info())
+                    .expect("The blog info is always serializable");
+                let info = format!("{:x}", Sha256::digest(info.as_bytes()));
+                format!(
+                    "j{}-{published}-{}",
+                    journal.last_cursor().await,
+                    &info[..12]
+                )
+            }
+            None => format!(
+                "{}-{}-{published}",
+                self.generation,
+                self.revision.load(Ordering::Relaxed)
+            ),
+        };
+        let last_modified = self
+            .most_recent_entries
+            .read()
+            .await
+            .iter()
+            .filter(|e| self.is_published(e))
+            .map(|e| {
+                e.description
+                    .updated_date
+                    .unwrap_or(e.description.publish_date)
+            })
+            .max();
+        ContentVersion { tag, last_modified }
+    }
+
+    pub async fn parse_file_to_html<P: AsRef<Path>>(&self, path: &P) -> anyhow::Result<BlogEntry> {
+        let path = path.as_ref();
+        let content = self
+            .retries
+            .read(path, || tokio::fs::read_to_string(path))
+            .await?;
+        let meta = self
+            .retries
+            .read(path, || tokio::fs::metadata(path))
+            .await?;
+        // Hashed after the includes, so that a snippet edit is a change too
+        let expanded = self.snippets.expand(&content).await;
+        let content = expanded.content;
+        let (metadata, markdown) = split_front_matter::<PostMetadata>(&content)?;
+        let rendered = render_entry(
+            &markdown,
+            &self.markdown,
+            self.demote_headings,
+            metadata.toc,
+        )?;
+        let filename = path.to_path_buf();
+        let filename = filename.file_name().unwrap().to_string_lossy();
+        let filename = filename.to_string();
+        let mut entry = BlogEntry {
+            word_count: markdown.split_whitespace().count(),
+            reading_time_minutes: reading_time_minutes(rendered.words, self.words_per_minute),
+            description: metadata,
+            html: rendered.html,
+            excerpt: rendered.excerpt,
+            excerpt_text: rendered.excerpt_text,
+            markdown,
+            accessibility_warnings: rendered.accessibility_warnings,
+            toc: rendered.toc,
+            creation_date: meta.created()?,
+            filename: String::new(),
+            slug: String::new(),
+            content_hash: format!("{:x}", Sha256::digest(content.as_bytes())),
+            snippets: expanded.snippets,
+        };
+        entry.set_filename(filename);
+        Ok(entry)
+    }
+
+    pub async fn parse_section<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        section_path: String,
+    ) -> anyhow::Result<Section> {
+        let content = tokio::fs::read_to_string(&path).await?;
+        let document = parse_document::<SectionMetadata>(&content, &self.markdown)?;
+        let intro_html = match &self.images {
+            Some(images) => images.rewrite_html(&document.html),
+            None => document.html,
+        };
+        Ok(Section {
+            path: section_path,
+            metadata: document.metadata,
+            intro_html,
+        })
+    }
+
+    async fn try_find_cached_entry(&self, entry_name: &str) -> Option<Arc<BlogEntry>> {
+        // Looking an entry up marks it as recently used, hence the write lock
+        self.entries.write().await.get(entry_name).cloned()
+    }
+}
+
+impl PurgeableCache for BlogStorage {
+    fn purge<'a>(&'a self, entries: &'a [Arc<BlogEntry>]) -> BoxFuture<'a, usize> {
+        Box::pin(async move {
+            let mut purged = 0;
+            for entry in entries {
+                if self.refresh_entry(&entry.filename).await {
+                    purged += 1;
+                }
+            }
+            purged
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::FixedClock,
+        test_support::{self, TempDir},
+    };
+
+    fn storage(base: impl AsRef<Path>) -> BlogStorage {
+        BlogStorage::new(base, NonZeroUsize::new(10).unwrap())
+    }
+
+    async fn store(storage: &BlogStorage, name: &str, front_matter: &str) {
+        let entry = test_support::entry(name, front_matter);
+        storage.try_store_entry(name, Arc::new(entry)).await;
+    }
+
+    fn front_matter(title: &str, extra: &str) -> String {
+        format!("title: {title}\nauthor: Crax\npublish_date: 2024-01-01T08:00:00Z\n{extra}")
+    }
+
+    fn front_matter_error(src: &str) -> FrontMatterError {
+        match parse_front_matter(src) {
+            Ok(_) => panic!("Parsed {src:?}"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn reads_toml_front_matter() {
+        let src = concat!(
+            "+++\ntitle = \"In TOML\"\nauthor = \"Crax\"\n",
+            "publish_date = 2024-02-03T08:00:00Z\ntags = [\"meta\"]\n",
+            "updated_date = \"2024-02-04T08:00:00Z\"\n+++\n\nThe body\n"
+        );
+        let (metadata, body_offset) = parse_front_matter(src).unwrap();
+        assert_eq!(metadata.title, "In TOML");
+        assert_eq!(metadata.byline(), "Written by Crax");
+        assert_eq!(
+            metadata.publish_date.to_rfc3339(),
+            "2024-02-03T08:00:00+00:00"
+        );
+        assert_eq!(
+            metadata.updated_date.unwrap().to_rfc3339(),
+            "2024-02-04T08:00:00+00:00"
+        );
+        assert_eq!(metadata.tags, ["meta"]);
+        assert_eq!(&src[body_offset..], "\nThe body\n");
+    }
+
+    #[test]
+    fn reads_the_same_metadata_from_yaml_and_toml() {
+        let yaml = concat!(
+            "---\ntitle: Same\nauthor: Crax\n",
+            "publish_date: 2024-02-03T08:00:00Z\ntags: [a, b]\ndraft: true\n---\n"
+        );
+        let toml = concat!(
+            "+++\ntitle = \"Same\"\nauthor = \"Crax\"\n",
+            "publish_date = 2024-02-03T08:00:00Z\ntags = [\"a\", \"b\"]\ndraft = true\n+++\n"
+        );
+        let (yaml, _) = parse_front_matter(yaml).unwrap();
+        let (toml, _) = parse_front_matter(toml).unwrap();
+        assert_eq!(yaml.title, toml.title);
+        assert_eq!(yaml.publish_date, toml.publish_date);
+        assert_eq!(yaml.tags, toml.tags);
+        assert_eq!(yaml.draft, toml.draft);
+        assert_eq!(yaml.byline(), toml.byline());
+    }
+
+    #[test]
+    fn refuses_mixed_delimiters() {
+        let error = front_matter_error("---\ntitle = \"Mixed\"\n+++\nbody\n");
+        assert_eq!(error.line, Some(3));
+        assert_eq!(
+            error.message,
+            "The front matter opens with '---' but closes with '+++'"
+        );
+        let error = front_matter_error("\n+++\ntitle: Mixed\n---\n");
+        assert_eq!(error.line, Some(4));
+        assert!(error
+            .message
+            .contains("opens with '+++' but closes with '---'"));
+    }
+
+    #[test]
+    fn refuses_missing_or_unclosed_front_matter() {
+        let error = front_matter_error("");
+        assert_eq!(error.line, Some(1));
+        let error = front_matter_error("# Just a title\n");
+        assert_eq!(error.line, Some(1));
+        let error = front_matter_error("+++\ntitle = \"Open\"\n");
+        assert_eq!(error.line, Some(1));
+        assert!(error.message.contains("never closed"));
+    }
+
+    #[test]
+    fn locates_toml_errors() {
+        // A syntax error, with a span
+        let error = front_matter_error("+++\ntitle = \"Broken\nauthor = \"Crax\"\n+++\n");
+        assert_eq!(error.line, Some(2));
+        // A type error, found by its key
+        let src = concat!(
+            "+++\ntitle = \"Typed\"\nauthor = \"Crax\"\n",
+            "publish_date = 2024-02-03T08:00:00Z\ntags = 3\n+++\n"
+        );
+        let error = front_matter_error(src);
+        assert_eq!(error.field.as_deref(), Some("tags"));
+        assert_eq!(error.line, Some(5));
+        let error = front_matter_error(
+            "+++\ntitle = \"Anonymous\"\npublish_date = 2024-02-03T08:00:00Z\n+++\n",
+        );
+        assert!(error.to_string().contains("author"), "{error}");
+    }
+
+    fn credits(contributors: &str) -> Vec<(String, ContributorRole)> {
+        let src = format!(
+            "---\ntitle: Credited\n{contributors}\npublish_date: 2024-01-01T08:00:00Z\n---\n"
+        );
+        match parse_front_matter(&src) {
+            Ok((metadata, _)) => metadata
+                .authors
+                .into_iter()
+                .map(|c| (c.name, c.role))
+                .collect(),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn named(names: &[(&str, ContributorRole)]) -> Vec<(String, ContributorRole)> {
+        names
+            .iter()
+            .map(|(name, role)| (name.to_string(), *role))
+            .collect()
+    }
+
+    #[test]
+    fn reads_each_way_of_crediting_people() {
+        use ContributorRole::*;
+        assert_eq!(credits("author: Crax"), named(&[("Crax", Author)]));
+        assert_eq!(
+            credits("author: [Crax, Ada]"),
+            named(&[("Crax", Author), ("Ada", Author)])
+        );
+        assert_eq!(
+            credits("authors:\n  - name: Crax\n  - name: Ada\n    role: translator"),
+            named(&[("Crax", Author), ("Ada", Translator)])
+        );
+        // Names and roles mixed in one list
+        assert_eq!(
+            credits("authors: [Crax, {name: Ada, role: editor}]"),
+            named(&[("Crax", Author), ("Ada", Editor)])
+        );
+        // Both keys together, `author` first
+        assert_eq!(
+            credits("authors: [{name: Ada, role: illustrator}]\nauthor: Crax"),
+            named(&[("Crax", Author), ("Ada", Illustrator)])
+        );
+    }
+
+    #[test]
+    fn rejects_missing_or_unknown_credits() {
+        let error =
+            front_matter_error("---\ntitle: Anonymous\npublish_date: 2024-01-01T08:00:00Z\n---\n");
+        assert!(error.to_string().contains("author"), "{error}");
+        let error = front_matter_error(
+            "---\ntitle: Empty\nauthors: []\npublish_date: 2024-01-01T08:00:00Z\n---\n",
+        );
+        assert!(error.to_string().contains("author"), "{error}");
+        // Neither a name nor a known role
+        front_matter_error(
+            "---\ntitle: Cooked\nauthors: [{name: Crax, role: cook}]\n\
+             publish_date: 2024-01-01T08:00:00Z\n---\n",
+        );
+    }
+
+    #[test]
+    fn bylines_group_the_names_by_role() {
+        let byline = |contributors: &str| {
+            test_support::entry(
+                "credited.md",
+                &format!("title: Credited\n{contributors}\npublish_date: 2024-01-01T08:00:00Z"),
+            )
+            .description
+            .byline()
+        };
+        assert_eq!(byline("author: Crax"), "Written by Crax");
+        assert_eq!(
+            byline("author: [Crax, Ada, Grace]"),
+            "Written by Crax, Ada and Grace"
+        );
+        assert_eq!(
+            byline(
+                "authors: [{name: Ada, role: translator}, Crax, \
+                 {name: Grace, role: translator}, {name: Alan, role: editor}]"
+            ),
+            "Translated by Ada and Grace, written by Crax, edited by Alan"
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_uncached_entries_only_within_the_blog() {
+        let dir = TempDir::new("storage-uncached");
+        let draft = format!("---\n{}\ndraft: true\n---\n", front_matter("Draft", ""));
+        dir.write("blog/projects/_wip.md", &draft);
+        dir.write("outside.md", &draft);
+        let storage = storage(dir.join("blog"));
+
+        let entry = storage.load_uncached("projects/_wip.md").await.unwrap();
+        assert_eq!(entry.description.title, "Draft");
+        for name in [
+            "../outside.md",
+            "projects/../../outside.md",
+            "/etc/passwd",
+            "projects\\_wip.md",
+            "projects//_wip.md",
+            "./projects/_wip.md",
+            "",
+        ] {
+            assert!(storage.load_uncached(name).await.is_err(), "{name:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_slugs_then_file_names() {
+        let storage = storage("unused");
+        store(
+            &storage,
+            "2024/long_name.md",
+            &front_matter("Custom", "slug: short"),
+        )
+        .await;
+        store(&storage, "plain.md", &front_matter("Plain", "")).await;
+
+        assert_eq!(storage.resolve_slug("short").await, "2024/long_name.md");
+        assert_eq!(storage.resolve_slug("plain").await, "plain.md");
+        // The file names keep resolving, to be redirected
+        assert_eq!(
+            storage.resolve_slug("2024/long_name").await,
+            "2024/long_name.md"
+        );
+        assert_eq!(
+            storage.resolve_slug("2024/long_name.md").await,
+            "2024/long_name.md"
+        );
+        assert_eq!(storage.resolve_slug("missing").await, "missing.md");
+
+        storage.remove_entry("2024/long_name.md".to_owned()).await;
+        assert_eq!(storage.resolve_slug("short").await, "short.md");
+    }
+
+    #[tokio::test]
+    async fn the_first_entry_keeps_its_slug() {
+        let dir = TempDir::new("storage-slugs");
+        let storage = storage(dir.join(""));
+        store(&storage, "first.md", &front_matter("First", "slug: taken")).await;
+        dir.write(
+            "second.md",
+            format!("---\n{}\n---\n", front_matter("Second", "slug: taken")),
+        );
+
+        match storage.parse_entry("second.md").await {
+            Err(error) => assert!(error.downcast_ref::<SlugTaken>().is_some(), "{error}"),
+            Ok(_) => panic!("The slug of first.md was claimed again"),
+        }
+        // Even when stored anyway
+        store(
+            &storage,
+            "second.md",
+            &front_matter("Second", "slug: taken"),
+        )
+        .await;
+        assert_eq!(storage.resolve_slug("taken").await, "first.md");
+        // Changing its slug frees the old one
+        store(&storage, "first.md", &front_matter("First", "slug: moved")).await;
+        assert_eq!(storage.resolve_slug("moved").await, "first.md");
+        assert_eq!(storage.resolve_slug("taken").await, "taken.md");
+    }
+
+    async fn most_recent(storage: &BlogStorage) -> Vec<String> {
+        let mut names = vec![];
+        storage
+            .iterate_most_recent_entries(|entry| names.push(entry.filename.clone()))
+            .await;
+        names
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_stores_never_list_an_entry_twice() {
+        let storage = Arc::new(storage("unused"));
+        let mut tasks = vec![];
+        for round in 0..50 {
+            for name in ["a.md", "b.md", "c.md"] {
+                let storage = storage.clone();
+                tasks.push(tokio::spawn(async move {
+                    // Each one a different version, so that none is skipped
+                    // as unchanged, and moving around in the list
+                    let day = 1 + (round * 7) % 28;
+                    let front_matter = format!(
+                        "title: {name} {round}\nauthor: Crax\n\
+                         publish_date: 2024-01-{day:02}T08:00:00Z"
+                    );
+                    store(&storage, name, &front_matter).await;
+                }));
+            }
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut names = most_recent(&storage).await;
+        assert_eq!(names.len(), 3, "{names:?}");
+        names.sort();
+        names.dedup();
+        assert_eq!(names, ["a.md", "b.md", "c.md"]);
+        // Still sorted by date, newest first
+        let list = storage.most_recent_entries.read().await;
+        assert!(list
+            .windows(2)
+            .all(|w| w[0].description.publish_date >= w[1].description.publish_date));
+    }
+
+    fn dated(title: &str, day: u32) -> String {
+        format!("title: {title}\nauthor: Crax\npublish_date: 2024-01-{day:02}T08:00:00Z")
+    }
+
+    async fn recent_titles(storage: &BlogStorage) -> Vec<String> {
+        let mut titles = vec![];
+        storage
+            .iterate_most_recent_entries(|entry| titles.push(entry.description.title.clone()))
+            .await;
+        titles
+    }
+
+    #[tokio::test]
+    async fn edited_entries_replace_their_recent_copy() {
+        let storage = storage("unused");
+        store(&storage, "a.md", &dated("Old title", 1)).await;
+        store(&storage, "b.md", &dated("Other", 2)).await;
+        assert_eq!(recent_titles(&storage).await, ["Other", "Old title"]);
+
+        store(&storage, "a.md", &dated("New title", 1)).await;
+        assert_eq!(recent_titles(&storage).await, ["Other", "New title"]);
+        // A new date moves it
+        store(&storage, "a.md", &dated("New title", 3)).await;
+        assert_eq!(recent_titles(&storage).await, ["New title", "Other"]);
+    }
+
+    #[tokio::test]
+    async fn edited_dates_move_entries_in_and_out_of_the_recent_ones() {
+        let storage = BlogStorage::new("unused", NonZeroUsize::new(10).unwrap())
+            .with_max_most_recent_entries(2);
+        store(&storage, "a.md", &dated("A", 2)).await;
+        store(&storage, "b.md", &dated("B", 4)).await;
+        store(&storage, "c.md", &dated("C", 6)).await;
+        assert_eq!(recent_titles(&storage).await, ["C", "B"]);
+
+        // Backdated out of the list, the next newest takes its place
+        store(&storage, "c.md", &dated("C", 1)).await;
+        assert_eq!(recent_titles(&storage).await, ["B", "A"]);
+        // Dated forward back into it
+        store(&storage, "a.md", &dated("A", 9)).await;
+        assert_eq!(recent_titles(&storage).await, ["A", "B"]);
+        store(&storage, "c.md", &dated("C", 5)).await;
+        assert_eq!(recent_titles(&storage).await, ["A", "C"]);
+    }
+
+    #[tokio::test]
+    async fn the_most_recent_entries_are_capped() {
+        let storage = BlogStorage::new("unused", NonZeroUsize::new(10).unwrap())
+            .with_max_most_recent_entries(2);
+        for (name, day) in [("a.md", 1), ("b.md", 3), ("c.md", 2)] {
+            let front_matter =
+                format!("title: {name}\nauthor: Crax\npublish_date: 2024-01-0{day}T08:00:00Z");
+            store(&storage, name, &front_matter).await;
+        }
+        assert_eq!(most_recent(&storage).await, ["b.md", "c.md"]);
+        storage.remove_entry("b.md".to_owned()).await;
+        assert_eq!(most_recent(&storage).await, ["c.md", "a.md"]);
+    }
+
+    #[tokio::test]
+    async fn purged_entries_are_read_again_or_removed() {
+        let dir = TempDir::new("storage-purge");
+        let storage = BlogStorage::new(dir.join(""), NonZeroUsize::new(10).unwrap())
+            .with_max_most_recent_entries(2);
+        for (name, day, extra) in [("a.md", 1, "slug: old"), ("b.md", 2, ""), ("c.md", 3, "")] {
+            let front_matter = format!("{}\n{extra}", dated(name, day));
+            dir.write(name, format!("---\n{front_matter}\n---\n"));
+            store(&storage, name, &front_matter).await;
+        }
+        assert_eq!(most_recent(&storage).await, ["c.md", "b.md"]);
+
+        // Read again, with what changed on disk since
+        dir.write(
+            "a.md",
+            format!("---\n{}\nslug: new\n---\n", dated("a.md", 7)),
+        );
+        let a = storage.summaries.read().await["a.md"].clone();
+        assert_eq!(storage.purge(&[a]).await, 1);
+        assert_eq!(storage.resolve_slug("new").await, "a.md");
+        assert_eq!(storage.resolve_slug("old").await, "old.md");
+        assert_eq!(most_recent(&storage).await, ["a.md", "c.md"]);
+
+        // Gone from disk, it goes from everywhere else too
+        let before = storage.content_version().await.tag;
+        std::fs::remove_file(dir.join("c.md")).unwrap();
+        let c = storage.summaries.read().await["c.md"].clone();
+        assert_eq!(storage.purge(std::slice::from_ref(&c)).await, 0);
+        assert!(!storage.contains_entry("c.md").await);
+        assert_eq!(storage.resolve_slug("c").await, "c.md");
+        assert_eq!(most_recent(&storage).await, ["a.md", "b.md"]);
+        assert_ne!(storage.content_version().await.tag, before);
+        // Unknown by now
+        assert_eq!(storage.purge(&[c]).await, 0);
+    }
+
+    #[tokio::test]
+    async fn counts_the_published_entries_as_they_change() {
+        let now = DateTime::parse_from_rfc3339("2024-01-15T08:00:00Z").unwrap();
+        let storage = storage("unused").with_clock(Arc::new(FixedClock(now.into())));
+        store(&storage, "a.md", &dated("A", 1)).await;
+        store(&storage, "b.md", &dated("B", 15)).await;
+        store(&storage, "c.md", &dated("C", 20)).await;
+        assert_eq!(storage.published_count().await, 2);
+
+        // Moved back, to before now
+        store(&storage, "c.md", &dated("C", 10)).await;
+        assert_eq!(storage.published_count().await, 3);
+        store(&storage, "a.md", &dated("A", 30)).await;
+        assert_eq!(storage.published_count().await, 2);
+        storage.remove_entry("a.md".to_owned()).await;
+        assert_eq!(storage.published_count().await, 2);
+        storage.remove_entry("b.md".to_owned()).await;
+        assert_eq!(storage.published_count().await, 1);
+        assert_eq!(storage.entry_count(None).await, 1);
+    }
+
+    #[tokio::test]
+    async fn journaled_versions_change_with_the_blog_info() {
+        let dir = TempDir::new("storage-version");
+        let clock = Arc::new(FixedClock(Utc::now()));
+        let journal = Journal::open(dir.join("journal.jsonl"), clock)
+            .await
+            .unwrap();
+        let storage = storage("unused").with_journal(Arc::new(journal));
+        store(&storage, "a.md", &dated("A", 1)).await;
+        let before = storage.content_version().await.tag;
+        assert_eq!(storage.content_version().await.tag, before);
+
+        let mut info = storage.blog_info();
+        info.name = "Renamed".to_owned();
+        storage.set_blog_info(info);
+        assert_ne!(storage.content_version().await.tag, before);
     }
 }
@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use futures_util::future::BoxFuture;
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use warp::{http::HeaderValue, reply::Response};
+
+use crate::{
+    blog_storage::BlogEntry,
+    outbox::{JobHandler, OutboundJob, Outbox},
+    purge::{entry_keys, PurgeableCache, LISTINGS_KEY},
+};
+
+// Changes arriving together (an editor saving several files, a git pull) are
+// sent in one purge
+const PURGE_DEBOUNCE: Duration = Duration::from_secs(2);
+const PURGE_ATTEMPTS: u32 = 4;
+const PURGE_FIRST_RETRY: Duration = Duration::from_secs(1);
+
+/// How the HTML pages are cached when the blog sits behind a CDN. The CDN
+/// keeps them for `s_maxage` seconds and is told to drop them when they
+/// change, browsers only for `browser_max_age`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CdnConfig {
+    pub s_maxage: u64,
+    pub stale_while_revalidate: u64,
+    pub browser_max_age: u64,
+    /// Receives a POST of `{"keys": [...]}` with the surrogate keys to purge.
+    /// Only plain http is supported, e.g. a relay next to the server
+    pub purge_webhook: Option<String>,
+    /// Sent as a bearer token to the webhook
+    pub purge_token: Option<String>,
+}
+
+impl Default for CdnConfig {
+    fn default() -> Self {
+        Self {
+            s_maxage: 24 * 3600,
+            stale_while_revalidate: 60,
+            browser_max_age: 60,
+            purge_webhook: None,
+            purge_token: None,
+        }
+    }
+}
+
+// The keys of what a response was built from, attached by the handlers. The
+// routes turn them into the CDN headers, or drop them without a CDN
+struct CacheKeys(Vec<String>);
+
+pub(crate) fn with_keys(mut response: Response, keys: Vec<String>) -> Response {
+    response.extensions_mut().insert(CacheKeys(keys));
+    response
+}
+
+pub(crate) fn listing_keys() -> Vec<String> {
+    vec![LISTINGS_KEY.to_owned()]
+}
+
+#[derive(Serialize)]
+struct PurgeBody<'a> {
+    keys: &'a [String],
+}
+
+pub struct Cdn {
+    cache_control: HeaderValue,
+    webhook: Option<(Uri, Option<String>)>,
+    client: Client<HttpConnector>,
+    pending: Mutex<BTreeSet<String>>,
+    notify: Notify,
+    // The entries loaded at startup aren't changes: nothing is queued before
+    // the purge task runs
+    armed: AtomicBool,
+    // Where the purges wait to be sent when set, so that a restart doesn't
+    // lose them
+    outbox: Option<Arc<Outbox>>,
+}
+
+impl Cdn {
+    pub fn new(config: CdnConfig) -> anyhow::Result<Self> {
+        let cache_control = format!(
+            "public, max-age={}, s-maxage={}, stale-while-revalidate={}",
+            config.browser_max_age, config.s_maxage, config.stale_while_revalidate
+        );
+        let webhook = match config.purge_webhook {
+            Some(url) => {
+                let uri: Uri = url.parse()?;
+                if uri.scheme_str() != Some("http") {
+                    anyhow::bail!("The CDN purge webhook {url} must be a plain http URL");
+                }
+                Some((uri, config.purge_token))
+            }
+            None => None,
+        };
+        Ok(Self {
+            cache_control: HeaderValue::from_str(&cache_control)?,
+            webhook,
+            client: Client::new(),
+            pending: Mutex::new(BTreeSet::new()),
+            notify: Notify::new(),
+            armed: AtomicBool::new(false),
+            outbox: None,
+        })
+    }
+
+    pub(crate) fn with_outbox(mut self, outbox: Arc<Outbox>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    // Only successful pages carrying keys are made cacheable, and never the
+    // ones that already say how to be cached (e.g. the time travel previews)
+    pub(crate) fn apply(&self, response: &mut Response) {
+        let Some(CacheKeys(keys)) = response.extensions_mut().remove::<CacheKeys>() else {
+            return;
+        };
+        if !response.status().is_success() || response.headers().contains_key("cache-control") {
+            return;
+        }
+        let headers = response.headers_mut();
+        headers.insert("cache-control", self.cache_control.clone());
+        if let Ok(keys) = HeaderValue::from_str(&keys.join(" ")) {
+            headers.insert("surrogate-key", keys);
+        }
+    }
+
+    pub(crate) fn purges(&self) -> bool {
+        self.webhook.is_some()
+    }
+
+    pub(crate) fn entry_changed(&self, entry: &BlogEntry) {
+        let mut keys = entry_keys(entry);
+        keys.push(LISTINGS_KEY.to_owned());
+        self.queue(keys);
+    }
+
+    pub(crate) fn queue(&self, keys: Vec<String>) {
+        if self.webhook.is_none() || !self.armed.load(Ordering::Relaxed) {
+            return;
+        }
+        self.pending
+            .lock()
+            .expect("Poisoned CDN purges")
+            .extend(keys);
+        self.notify.notify_one();
+    }
+
+    pub(crate) async fn run_purges(&self) {
+        self.armed.store(true, Ordering::Relaxed);
+        loop {
+            self.notify.notified().await;
+            tokio::time::sleep(PURGE_DEBOUNCE).await;
+            let keys: Vec<_> =
+                std::mem::take(&mut *self.pending.lock().expect("Poisoned CDN purges"))
+                    .into_iter()
+                    .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            match &self.outbox {
+                Some(outbox) => {
+                    // Every batch is a purge of its own, even one repeating
+                    // the keys of an earlier one
+                    let nanos = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_nanos();
+                    let job = OutboundJob::CdnPurge { keys };
+                    if let Err(e) = outbox.enqueue(format!("cdn-purge:{nanos}"), job).await {
+                        error!("Failed to queue the CDN purge: {e}");
+                    }
+                }
+                None => self.send(&keys).await,
+            }
+        }
+    }
+
+    async fn send(&self, keys: &[String]) {
+        let mut retry_in = PURGE_FIRST_RETRY;
+        for attempt in 1..=PURGE_ATTEMPTS {
+            match self.send_once(keys).await {
+                Ok(()) => return,
+                Err(e) => warn!("CDN purge attempt {attempt}/{PURGE_ATTEMPTS} failed: {e}"),
+            }
+            if attempt < PURGE_ATTEMPTS {
+                tokio::time::sleep(retry_in).await;
+                retry_in *= 2;
+            }
+        }
+        error!("Gave up purging {} from the CDN", keys.join(" "));
+    }
+
+    async fn send_once(&self, keys: &[String]) -> anyhow::Result<()> {
+        let Some((uri, token)) = &self.webhook else {
+            anyhow::bail!("No CDN purge webhook is configured");
+        };
+        let body = serde_json::to_vec(&PurgeBody { keys })?;
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(uri.clone())
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {token}"));
+        }
+        let response = self.client.request(request.body(Body::from(body))?).await?;
+        if !response.status().is_success() {
+            anyhow::bail!("The webhook answered {}", response.status());
+        }
+        info!(
+            "Purged {} keys from the CDN: {}",
+            keys.len(),
+            keys.join(" ")
+        );
+        Ok(())
+    }
+}
+
+// Purging the same keys twice is harmless, so a purge the webhook may have
+// got before a restart is just sent again
+impl JobHandler for Cdn {
+    fn run<'a>(&'a self, job: &'a OutboundJob) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            match job {
+                OutboundJob::CdnPurge { keys } => self.send_once(keys).await,
+            }
+        })
+    }
+}
+
+// Purging through the admin API reaches the CDN too
+impl PurgeableCache for Cdn {
+    fn purge<'a>(&'a self, entries: &'a [Arc<BlogEntry>]) -> BoxFuture<'a, usize> {
+        Box::pin(async move {
+            if self.webhook.is_none() {
+                return 0;
+            }
+            let mut keys: Vec<_> = entries.iter().flat_map(|e| entry_keys(e)).collect();
+            if keys.is_empty() {
+                return 0;
+            }
+            keys.push(LISTINGS_KEY.to_owned());
+            let queued = keys.len();
+            self.queue(keys);
+            queued
+        })
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use warp::{http::header::CONTENT_TYPE, reply::Response};
+
+use crate::retries::EIO;
+
+// Failures injected on purpose, to watch the retries, the caches and the
+// error pages at work. The engine only takes them in dev mode, and the binary
+// only on loopback addresses
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChaosConfig {
+    // Share of the file reads failing with EIO, from 0 to 1
+    pub fs_error_rate: f64,
+    // Added to every page rendered from the theme
+    pub render_delay: Duration,
+    // Share of the file watcher events ignored, from 0 to 1
+    pub drop_events_rate: f64,
+}
+
+impl ChaosConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.fs_error_rate > 0.0 || !self.render_delay.is_zero() || self.drop_events_rate > 0.0
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, rate) in [
+            ("fs error rate", self.fs_error_rate),
+            ("drop events rate", self.drop_events_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                anyhow::bail!("The chaos {name} must be between 0 and 1, not {rate}");
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Chaos {
+    config: ChaosConfig,
+    // xorshift, reproducibility isn't the point
+    state: AtomicU64,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_nanos() as u64)
+            .unwrap_or_default();
+        warn!("Chaos enabled: {config:?}");
+        Self {
+            config,
+            state: AtomicU64::new(seed | 1),
+        }
+    }
+
+    fn roll(&self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        let next = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let previous = self
+            .state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(next(x)))
+            .unwrap_or(1);
+        let sample = (next(previous) >> 11) as f64 / (1u64 << 53) as f64;
+        sample < rate
+    }
+
+    // Fails the read before it starts, the way a network filesystem would
+    pub async fn read<T>(&self, read: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        if self.roll(self.config.fs_error_rate) {
+            return Err(io::Error::from_raw_os_error(EIO));
+        }
+        read.await
+    }
+
+    pub async fn delay_render(&self, response: &Response) {
+        if self.config.render_delay.is_zero() {
+            return;
+        }
+        let is_html = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"));
+        if is_html {
+            tokio::time::sleep(self.config.render_delay).await;
+        }
+    }
+
+    fn drops_event(&self) -> bool {
+        self.roll(self.config.drop_events_rate)
+    }
+}
+
+// Wraps the handler of a file watcher, which then misses some events
+pub fn watcher_events<F>(
+    chaos: Option<Arc<Chaos>>,
+    mut handler: F,
+) -> impl FnMut(notify::Result<notify::Event>) + Send + 'static
+where
+    F: FnMut(notify::Result<notify::Event>) + Send + 'static,
+{
+    move |event| {
+        if let (Some(chaos), Ok(event)) = (&chaos, &event) {
+            if chaos.drops_event() {
+                warn!("Chaos: dropped {:?} of {:?}", event.kind, event.paths);
+                return;
+            }
+        }
+        handler(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::atomic::AtomicUsize,
+        time::Instant,
+    };
+
+    use notify::{event::CreateKind, Event, EventKind};
+    use warp::Reply;
+
+    use super::*;
+    use crate::retries::ReadRetries;
+
+    fn chaos(config: ChaosConfig) -> Chaos {
+        Chaos::new(config)
+    }
+
+    fn failing_reads(rate: f64) -> Chaos {
+        chaos(ChaosConfig {
+            fs_error_rate: rate,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn validates_the_rates() {
+        assert!(!ChaosConfig::default().is_enabled());
+        for config in [
+            ChaosConfig {
+                fs_error_rate: 0.1,
+                ..Default::default()
+            },
+            ChaosConfig {
+                render_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ChaosConfig {
+                drop_events_rate: 1.0,
+                ..Default::default()
+            },
+        ] {
+            assert!(config.is_enabled(), "{config:?}");
+            assert!(config.validate().is_ok(), "{config:?}");
+        }
+        for (fs_error_rate, drop_events_rate) in [(1.5, 0.0), (0.0, -0.1), (f64::NAN, 0.0)] {
+            let config = ChaosConfig {
+                fs_error_rate,
+                drop_events_rate,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn rolls_follow_the_rate() {
+        let chaos = failing_reads(0.3);
+        let hits = (0..10_000).filter(|_| chaos.roll(0.3)).count();
+        assert!((2_500..3_500).contains(&hits), "{hits}");
+        assert!((0..1_000).all(|_| !chaos.roll(0.0)));
+        assert!((0..1_000).all(|_| chaos.roll(1.0)));
+    }
+
+    #[tokio::test]
+    async fn reads_fail_with_eio() {
+        let always = failing_reads(1.0);
+        let error = always.read(async { Ok(()) }).await.unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(EIO));
+        let never = failing_reads(0.0);
+        assert_eq!(never.read(async { Ok(1) }).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn injected_read_errors_go_through_the_retries() {
+        let reads = AtomicUsize::new(0);
+        let read = || async {
+            reads.fetch_add(1, Ordering::Relaxed);
+            Ok("content")
+        };
+        let retries = ReadRetries::new(3, Duration::ZERO).with_chaos(Arc::new(failing_reads(1.0)));
+        assert!(retries.read(Path::new("a.md"), read).await.is_err());
+        // Failed before reading anything, then retried as transient
+        assert_eq!(reads.load(Ordering::Relaxed), 0);
+        let stats = retries.stats();
+        assert_eq!((stats.retries, stats.exhausted), (2, 1));
+
+        // Half of the reads failing barely show with enough attempts
+        let retries = ReadRetries::new(20, Duration::ZERO).with_chaos(Arc::new(failing_reads(0.5)));
+        for _ in 0..20 {
+            assert!(retries.read(Path::new("a.md"), read).await.is_ok());
+        }
+        assert_eq!(reads.load(Ordering::Relaxed), 20);
+        assert!(retries.stats().retries > 0);
+    }
+
+    #[tokio::test]
+    async fn only_pages_are_delayed() {
+        let chaos = chaos(ChaosConfig {
+            render_delay: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let page = warp::reply::html("<p>page</p>").into_response();
+        let start = Instant::now();
+        chaos.delay_render(&page).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let feed = warp::reply::json(&"feed").into_response();
+        let start = Instant::now();
+        chaos.delay_render(&feed).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    fn created() -> notify::Result<Event> {
+        Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("a.md")))
+    }
+
+    fn handled(chaos: Option<Chaos>, events: Vec<notify::Result<Event>>) -> usize {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let mut handler = watcher_events(chaos.map(Arc::new), {
+            let handled = handled.clone();
+            move |_| {
+                handled.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        for event in events {
+            handler(event);
+        }
+        handled.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn watcher_events_are_dropped() {
+        let dropping = |rate| {
+            Some(chaos(ChaosConfig {
+                drop_events_rate: rate,
+                ..Default::default()
+            }))
+        };
+        let events = || (0..100).map(|_| created()).collect::<Vec<_>>();
+        assert_eq!(handled(None, events()), 100);
+        assert_eq!(handled(dropping(0.0), events()), 100);
+        assert_eq!(handled(dropping(1.0), events()), 0);
+        let some = handled(dropping(0.5), events());
+        assert!((10..90).contains(&some), "{some}");
+        // The watcher's own errors are always passed on
+        let error = notify::Error::generic("watch failed");
+        assert_eq!(handled(dropping(1.0), vec![Err(error)]), 1);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{io::Write, num::NonZeroUsize, sync::Mutex};
+
+use flate2::{write::GzEncoder, Compression};
+use hyper::body::{Bytes, HttpBody};
+use log::warn;
+use lru::LruCache;
+use warp::{
+    http::{header, HeaderMap, HeaderValue, Method},
+    hyper::Body,
+    reply::Response,
+};
+
+// Below this, the gzip header and the CPU time aren't worth it
+pub const DEFAULT_MIN_COMPRESSED_SIZE: usize = 1024;
+// Compressed versions of the files under /files
+const CACHED_FILES: usize = 64;
+
+// Whether the client takes gzip, `*` included, unless given a zero weight
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for coding in accept_encoding.split(',') {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default().to_ascii_lowercase();
+        let weight = params
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(weight > 0.0),
+            "*" => any = Some(weight > 0.0),
+            _ => {}
+        }
+    }
+    gzip.or(any).unwrap_or(false)
+}
+
+// Text in all its forms. Images, archives and videos are compressed already,
+// and event streams must reach the client as they're written
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if essence == "text/event-stream" {
+        return false;
+    }
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence.as_str(),
+            "application/javascript" | "application/json" | "application/xml" | "application/wasm"
+        )
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// Gzips the responses to the clients asking for it. Brotli would save a bit
+// more, but gzip is understood everywhere
+pub struct Compressor {
+    // (path, etag) -> compressed body: a file changing on disk changes its
+    // etag, so stale versions just fall out of the cache
+    files: Mutex<LruCache<(String, String), Bytes>>,
+    min_size: usize,
+}
+
+impl Compressor {
+    pub fn new(min_size: usize) -> Self {
+        Self {
+            min_size,
+            files: Mutex::new(LruCache::new(
+                NonZeroUsize::new(CACHED_FILES).expect("Zero cached files"),
+            )),
+        }
+    }
+
+    pub(crate) async fn apply(
+        &self,
+        method: &Method,
+        path: &str,
+        request: &HeaderMap,
+        mut response: Response,
+    ) -> Response {
+        let headers = response.headers();
+        let compressible = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .is_some_and(is_compressible);
+        // Streamed bodies must go out as they're produced, not be gathered
+        // here first
+        let streamed = response.body().size_hint().exact().is_none();
+        if !compressible
+            || streamed
+            || !response.status().is_success()
+            || headers.contains_key(header::CONTENT_ENCODING)
+        {
+            return response;
+        }
+        // The same URL now has several representations
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        let accepted = request
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|a| a.to_str().ok())
+            .is_some_and(accepts_gzip);
+        if !accepted || method == Method::HEAD {
+            return response;
+        }
+
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|e| e.to_str().ok())
+            .map(str::to_owned);
+        let cache_key = etag
+            .as_ref()
+            .filter(|_| path.starts_with("/files/"))
+            .map(|etag| (path.to_owned(), etag.clone()));
+        let cached = cache_key.as_ref().and_then(|key| {
+            self.files
+                .lock()
+                .expect("Poisoned compressed files")
+                .get(key)
+                .cloned()
+        });
+        let compressed = match cached {
+            Some(compressed) => compressed,
+            None => {
+                let (parts, body) = response.into_parts();
+                let data = match hyper::body::to_bytes(body).await {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("Failed to read the response to compress: {e}");
+                        return Response::from_parts(parts, Body::empty());
+                    }
+                };
+                if data.len() < self.min_size {
+                    return Response::from_parts(parts, Body::from(data));
+                }
+                let compressed = match tokio::task::spawn_blocking({
+                    let data = data.clone();
+                    move || gzip(&data)
+                })
+                .await
+                {
+                    Ok(Ok(compressed)) => Bytes::from(compressed),
+                    _ => {
+                        warn!("Failed to compress the response to {path}");
+                        return Response::from_parts(parts, Body::from(data));
+                    }
+                };
+                if let Some(key) = cache_key {
+                    self.files
+                        .lock()
+                        .expect("Poisoned compressed files")
+                        .put(key, compressed.clone());
+                }
+                response = Response::from_parts(parts, Body::empty());
+                compressed
+            }
+        };
+
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        headers.remove(header::CONTENT_LENGTH);
+        // The bytes differ from the uncompressed ones, so a strong tag no
+        // longer holds. The conditional requests compare them weakly anyway
+        if let Some(etag) = etag.filter(|e| !e.starts_with("W/")) {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{etag}")) {
+                headers.insert(header::ETAG, weak);
+            }
+        }
+        *response.body_mut() = Body::from(compressed);
+        response
+    }
+}
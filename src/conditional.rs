@@ -0,0 +1,97 @@
+use chrono::{DateTime, Utc};
+use warp::{http::Method, Filter, Rejection};
+
+// The validators a client sent along, to tell whether its copy is still fresh
+pub struct ConditionalRequest {
+    pub method: Method,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+}
+
+pub fn conditional_request(
+) -> impl Filter<Extract = (ConditionalRequest,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .map(
+            |method, if_none_match, if_modified_since| ConditionalRequest {
+                method,
+                if_none_match,
+                if_modified_since,
+            },
+        )
+}
+
+impl ConditionalRequest {
+    // If-None-Match wins over If-Modified-Since when both are sent (RFC 9110).
+    // Tags are compared weakly, so W/ is ignored on both sides
+    pub fn is_fresh(&self, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+        let etag = etag.strip_prefix("W/").unwrap_or(etag);
+        if let Some(if_none_match) = &self.if_none_match {
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+        }
+        let (Some(if_modified_since), Some(last_modified)) =
+            (&self.if_modified_since, last_modified)
+        else {
+            return false;
+        };
+        DateTime::parse_from_rfc2822(if_modified_since)
+            .is_ok_and(|since| last_modified.timestamp() <= since.timestamp())
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.method == Method::HEAD
+    }
+}
+
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(if_none_match: Option<&str>, if_modified_since: Option<&str>) -> ConditionalRequest {
+        ConditionalRequest {
+            method: Method::GET,
+            if_none_match: if_none_match.map(str::to_owned),
+            if_modified_since: if_modified_since.map(str::to_owned),
+        }
+    }
+
+    fn date(date: &str) -> Option<DateTime<Utc>> {
+        Some(date.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_entity_tags_weakly() {
+        let modified = date("2024-01-01T08:00:00Z");
+        for if_none_match in ["\"a\"", "W/\"a\"", "\"b\", \"a\"", "*"] {
+            assert!(request(Some(if_none_match), None).is_fresh("\"a\"", modified));
+        }
+        assert!(request(Some("\"a\""), None).is_fresh("W/\"a\"", modified));
+        assert!(!request(Some("\"b\""), None).is_fresh("\"a\"", modified));
+    }
+
+    #[test]
+    fn entity_tags_win_over_dates() {
+        let since = http_date(date("2024-02-01T08:00:00Z").unwrap());
+        let request = request(Some("\"b\""), Some(&since));
+        assert!(!request.is_fresh("\"a\"", date("2024-01-01T08:00:00Z")));
+    }
+
+    #[test]
+    fn compares_dates_to_the_second() {
+        let since = http_date(date("2024-01-01T08:00:00Z").unwrap());
+        let fresh = request(None, Some(&since));
+        assert!(fresh.is_fresh("\"a\"", date("2024-01-01T08:00:00.5Z")));
+        assert!(!fresh.is_fresh("\"a\"", date("2024-01-01T08:00:01Z")));
+        assert!(!fresh.is_fresh("\"a\"", None));
+        let garbage = request(None, Some("yesterday"));
+        assert!(!garbage.is_fresh("\"a\"", date("2020-01-01T00:00:00Z")));
+    }
+}
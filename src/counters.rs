@@ -0,0 +1,455 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+// entry -> what is counted (a referring origin, "views"...) -> count
+pub type Counts = BTreeMap<String, BTreeMap<String, u64>>;
+
+// The whole counts, and the first journal generation they don't include
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    generation: u64,
+    counts: Counts,
+}
+
+// The first line of a journal
+#[derive(Serialize, Deserialize)]
+struct JournalHeader {
+    generation: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Increment {
+    entry: String,
+    key: String,
+    n: u64,
+}
+
+#[derive(Default)]
+struct State {
+    counts: Counts,
+    // Not in the journal yet
+    pending: BTreeMap<(String, String), u64>,
+    generation: u64,
+}
+
+// Counters kept in memory and made durable in two steps: the increments are
+// appended to a journal every few seconds, and the journal is periodically
+// folded into a JSON snapshot. A crash loses at most the increments not
+// flushed yet.
+//
+// The snapshot says which journal generation comes after it. Compacting
+// writes the snapshot first and then starts a journal of the next
+// generation, so a crash in between leaves an older journal behind that
+// replaying skips, instead of counting it twice
+pub struct PersistentCounters {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+    // Appending and replacing the journal never overlap
+    io: tokio::sync::Mutex<()>,
+}
+
+impl PersistentCounters {
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            state: Mutex::new(State::default()),
+            io: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // Without a path, nothing is persisted
+    pub async fn open(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::in_memory());
+        };
+        let mut state = State::default();
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => {
+                let snapshot = serde_json::from_str::<Snapshot>(&content).or_else(|_| {
+                    // Written before the journal existed: the counts alone
+                    serde_json::from_str(&content).map(|counts| Snapshot {
+                        generation: 0,
+                        counts,
+                    })
+                })?;
+                state.counts = snapshot.counts;
+                state.generation = snapshot.generation;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        replay_journal(&journal_path(&path), &mut state).await?;
+        Ok(Self {
+            path: Some(path),
+            state: Mutex::new(state),
+            io: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn increment(&self, entry: &str, key: &str) {
+        let mut state = self.state.lock().expect("Poisoned counters");
+        *state
+            .counts
+            .entry(entry.to_owned())
+            .or_default()
+            .entry(key.to_owned())
+            .or_default() += 1;
+        if self.path.is_some() {
+            *state
+                .pending
+                .entry((entry.to_owned(), key.to_owned()))
+                .or_default() += 1;
+        }
+    }
+
+    pub fn get(&self, entry: &str, key: &str) -> u64 {
+        self.state
+            .lock()
+            .expect("Poisoned counters")
+            .counts
+            .get(entry)
+            .and_then(|counts| counts.get(key))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Every count of every entry
+    pub fn counts(&self) -> Counts {
+        self.state.lock().expect("Poisoned counters").counts.clone()
+    }
+
+    // Appends what changed since the last flush to the journal
+    pub async fn flush(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _io = self.io.lock().await;
+        let (pending, generation) = {
+            let mut state = self.state.lock().expect("Poisoned counters");
+            (std::mem::take(&mut state.pending), state.generation)
+        };
+        if pending.is_empty() {
+            return;
+        }
+        let journal = journal_path(path);
+        if let Err(e) = append(&journal, generation, &pending).await {
+            error!("Failed to append to the counters journal {journal:?}: {e}");
+            // Kept for the next attempt
+            let mut state = self.state.lock().expect("Poisoned counters");
+            for (key, n) in pending {
+                *state.pending.entry(key).or_default() += n;
+            }
+        }
+    }
+
+    // Writes all the counts to the snapshot, and starts a new journal
+    pub async fn compact(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _io = self.io.lock().await;
+        let (snapshot, pending) = {
+            let mut state = self.state.lock().expect("Poisoned counters");
+            state.generation += 1;
+            let snapshot = Snapshot {
+                generation: state.generation,
+                counts: state.counts.clone(),
+            };
+            (snapshot, std::mem::take(&mut state.pending))
+        };
+        let journal = journal_path(path);
+        let written = async { write_atomically(path, serde_json::to_vec(&snapshot)?).await }.await;
+        if let Err(e) = written {
+            error!("Failed to compact the counters into {path:?}: {e}");
+            // Nothing changed on disk: the journal takes them on the next flush
+            let mut state = self.state.lock().expect("Poisoned counters");
+            state.generation -= 1;
+            for (key, n) in pending {
+                *state.pending.entry(key).or_default() += n;
+            }
+            return;
+        }
+        let header = JournalHeader {
+            generation: snapshot.generation,
+        };
+        let mut content = serde_json::to_vec(&header).expect("Headers are serializable");
+        content.push(b'\n');
+        match write_atomically(&journal, content).await {
+            Ok(()) => info!("Compacted the counters into {path:?}"),
+            Err(e) => {
+                // The snapshot has everything, the old journal must not be
+                // replayed on top of it. The next flush starts a new one
+                error!("Failed to start a new counters journal {journal:?}: {e}");
+                if let Err(e) = tokio::fs::remove_file(&journal).await {
+                    error!("Failed to remove the old counters journal {journal:?}: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    path.with_extension("journal")
+}
+
+pub(crate) async fn write_atomically(path: &Path, content: Vec<u8>) -> anyhow::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&temp_path).await?;
+    file.write_all(&content).await?;
+    file.sync_data().await?;
+    tokio::fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+async fn append(
+    journal: &Path,
+    generation: u64,
+    pending: &BTreeMap<(String, String), u64>,
+) -> anyhow::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal)
+        .await?;
+    let mut content = vec![];
+    if file.metadata().await?.len() == 0 {
+        serde_json::to_writer(&mut content, &JournalHeader { generation })?;
+        content.push(b'\n');
+    }
+    for ((entry, key), n) in pending {
+        let increment = Increment {
+            entry: entry.clone(),
+            key: key.clone(),
+            n: *n,
+        };
+        serde_json::to_writer(&mut content, &increment)?;
+        content.push(b'\n');
+    }
+    // In one write, so that a crash leaves at most one partial record
+    file.write_all(&content).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+// Applies the journal written after the snapshot. A crash in the middle of
+// an append leaves a partial last record: the journal is cut right before it
+async fn replay_journal(journal: &Path, state: &mut State) -> anyhow::Result<()> {
+    let content = match tokio::fs::read(journal).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = content.split_inclusive(|b| *b == b'\n');
+    let first = lines.next().unwrap_or_default();
+    let header = Some(first)
+        .filter(|line| line.ends_with(b"\n"))
+        .and_then(|line| serde_json::from_slice::<JournalHeader>(line).ok());
+    let Some(header) = header else {
+        warn!("Discarding the counters journal {journal:?}, its header is unreadable");
+        tokio::fs::remove_file(journal).await?;
+        return Ok(());
+    };
+    if header.generation < state.generation {
+        info!("Skipping the counters journal {journal:?}, the snapshot already includes it");
+        tokio::fs::remove_file(journal).await?;
+        return Ok(());
+    }
+    let mut valid = first.len();
+    let mut replayed = 0;
+    for line in lines {
+        let increment = line
+            .ends_with(b"\n")
+            .then(|| serde_json::from_slice::<Increment>(line).ok())
+            .flatten();
+        let Some(increment) = increment else {
+            break;
+        };
+        *state
+            .counts
+            .entry(increment.entry)
+            .or_default()
+            .entry(increment.key)
+            .or_default() += increment.n;
+        valid += line.len();
+        replayed += 1;
+    }
+    state.generation = header.generation;
+    if valid < content.len() {
+        warn!(
+            "Truncating {} bytes of partial records from the counters journal {journal:?}",
+            content.len() - valid
+        );
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(journal)
+            .await?;
+        file.set_len(valid as u64).await?;
+    }
+    info!("Replayed {replayed} records of the counters journal {journal:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    async fn open(dir: &TempDir) -> PersistentCounters {
+        PersistentCounters::open(Some(dir.join("counters.json")))
+            .await
+            .unwrap()
+    }
+
+    fn journal(dir: &TempDir) -> String {
+        std::fs::read_to_string(dir.join("counters.journal")).unwrap()
+    }
+
+    fn count(counters: &PersistentCounters, entry: &str, key: &str, times: usize) {
+        for _ in 0..times {
+            counters.increment(entry, key);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_journal_is_replayed_after_a_crash() {
+        let dir = TempDir::new("counters-crash");
+        let counters = open(&dir).await;
+        count(&counters, "a.md", "views", 3);
+        count(&counters, "b.md", "https://example.com", 1);
+        counters.flush().await;
+        count(&counters, "a.md", "views", 2);
+        counters.flush().await;
+        // Never flushed, lost with the crash
+        count(&counters, "a.md", "views", 5);
+        // Dropped without compacting: only the journal is on disk
+        drop(counters);
+        assert!(!dir.join("counters.json").exists());
+
+        let counters = open(&dir).await;
+        assert_eq!(counters.get("a.md", "views"), 5);
+        assert_eq!(counters.get("b.md", "https://example.com"), 1);
+        assert_eq!(counters.get("b.md", "views"), 0);
+    }
+
+    #[tokio::test]
+    async fn compacting_folds_the_journal_into_the_snapshot() {
+        let dir = TempDir::new("counters-compact");
+        let counters = open(&dir).await;
+        count(&counters, "a.md", "views", 3);
+        counters.flush().await;
+        count(&counters, "a.md", "views", 1);
+        counters.compact().await;
+        assert_eq!(journal(&dir), "{\"generation\":1}\n");
+        count(&counters, "a.md", "views", 1);
+        counters.flush().await;
+        drop(counters);
+
+        let counters = open(&dir).await;
+        assert_eq!(counters.get("a.md", "views"), 5);
+        // And once more, nothing counted twice
+        counters.compact().await;
+        drop(counters);
+        assert_eq!(open(&dir).await.get("a.md", "views"), 5);
+    }
+
+    #[tokio::test]
+    async fn journals_older_than_the_snapshot_are_skipped() {
+        let dir = TempDir::new("counters-generations");
+        let counters = open(&dir).await;
+        count(&counters, "a.md", "views", 3);
+        counters.flush().await;
+        let old_journal = journal(&dir);
+        counters.compact().await;
+        drop(counters);
+        // As if the crash came between the snapshot and the new journal
+        dir.write("counters.journal", &old_journal);
+
+        let counters = open(&dir).await;
+        assert_eq!(counters.get("a.md", "views"), 3);
+        assert!(!dir.join("counters.journal").exists());
+        count(&counters, "a.md", "views", 1);
+        counters.flush().await;
+        drop(counters);
+        assert_eq!(open(&dir).await.get("a.md", "views"), 4);
+    }
+
+    #[tokio::test]
+    async fn partial_records_are_truncated() {
+        let dir = TempDir::new("counters-tail");
+        let counters = open(&dir).await;
+        count(&counters, "a.md", "views", 2);
+        counters.flush().await;
+        drop(counters);
+        let valid = journal(&dir);
+        let mut torn = valid.clone();
+        torn.push_str("{\"entry\":\"a.md\",\"key\":\"vi");
+        dir.write("counters.journal", &torn);
+
+        let counters = open(&dir).await;
+        assert_eq!(counters.get("a.md", "views"), 2);
+        assert_eq!(journal(&dir), valid);
+        // The next records follow the valid ones
+        count(&counters, "a.md", "views", 1);
+        counters.flush().await;
+        drop(counters);
+        assert_eq!(open(&dir).await.get("a.md", "views"), 3);
+    }
+
+    #[tokio::test]
+    async fn complete_but_unreadable_records_end_the_replay() {
+        let dir = TempDir::new("counters-garbage");
+        dir.write(
+            "counters.journal",
+            concat!(
+                "{\"generation\":0}\n",
+                "{\"entry\":\"a.md\",\"key\":\"views\",\"n\":2}\n",
+                "not json\n",
+                "{\"entry\":\"a.md\",\"key\":\"views\",\"n\":40}\n",
+            ),
+        );
+        let counters = open(&dir).await;
+        assert_eq!(counters.get("a.md", "views"), 2);
+        assert_eq!(
+            journal(&dir),
+            "{\"generation\":0}\n{\"entry\":\"a.md\",\"key\":\"views\",\"n\":2}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn journals_without_a_header_are_discarded() {
+        let dir = TempDir::new("counters-header");
+        dir.write("counters.journal", "{\"generation\":");
+        let counters = open(&dir).await;
+        assert!(counters.counts().is_empty());
+        assert!(!dir.join("counters.journal").exists());
+    }
+
+    #[tokio::test]
+    async fn reads_snapshots_written_before_the_journal() {
+        let dir = TempDir::new("counters-legacy");
+        dir.write("counters.json", "{\"a.md\":{\"views\":7}}");
+        let counters = open(&dir).await;
+        assert_eq!(counters.get("a.md", "views"), 7);
+        count(&counters, "a.md", "views", 1);
+        counters.flush().await;
+        drop(counters);
+        assert_eq!(open(&dir).await.get("a.md", "views"), 8);
+    }
+
+    #[tokio::test]
+    async fn in_memory_counters_write_nothing() {
+        let counters = PersistentCounters::open(None).await.unwrap();
+        count(&counters, "a.md", "views", 2);
+        counters.flush().await;
+        counters.compact().await;
+        assert_eq!(counters.get("a.md", "views"), 2);
+        assert!(counters.state.lock().unwrap().pending.is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Unchanged,
+    Added,
+    Removed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DiffLine {
+    pub kind: ChangeKind,
+    // 1-based line numbers, missing on the side the line doesn't exist in
+    pub old_line: Option<usize>,
+    pub new_line: Option<usize>,
+    pub text: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct DiffStats {
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl DiffStats {
+    pub fn new(lines: &[DiffLine]) -> Self {
+        let count = |kind| lines.iter().filter(|l| l.kind == kind).count();
+        Self {
+            added: count(ChangeKind::Added),
+            removed: count(ChangeKind::Removed),
+        }
+    }
+}
+
+// Line based diff built on the longest common subsequence. The common prefix
+// and suffix are skipped before filling the table, which keeps the usual
+// small edit of a long post cheap
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(o, n)| o == n)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(o, n)| o == n)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    // lengths[i][j] is the LCS length of old_middle[i..] and new_middle[j..]
+    let mut lengths = vec![vec![0u32; new_middle.len() + 1]; old_middle.len() + 1];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            lengths[i][j] = if old_middle[i] == new_middle[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    let mut push = |kind, old_index: Option<usize>, new_index: Option<usize>, text: &str| {
+        diff.push(DiffLine {
+            kind,
+            old_line: old_index.map(|i| i + 1),
+            new_line: new_index.map(|i| i + 1),
+            text: text.to_owned(),
+        })
+    };
+    for (i, line) in old[..prefix].iter().enumerate() {
+        push(ChangeKind::Unchanged, Some(i), Some(i), line);
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < old_middle.len() || j < new_middle.len() {
+        if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+            push(
+                ChangeKind::Unchanged,
+                Some(prefix + i),
+                Some(prefix + j),
+                old_middle[i],
+            );
+            i += 1;
+            j += 1;
+        } else if i < old_middle.len()
+            && (j == new_middle.len() || lengths[i + 1][j] >= lengths[i][j + 1])
+        {
+            push(ChangeKind::Removed, Some(prefix + i), None, old_middle[i]);
+            i += 1;
+        } else {
+            push(ChangeKind::Added, None, Some(prefix + j), new_middle[j]);
+            j += 1;
+        }
+    }
+    let old_suffix_start = old.len() - suffix;
+    let new_suffix_start = new.len() - suffix;
+    for k in 0..suffix {
+        push(
+            ChangeKind::Unchanged,
+            Some(old_suffix_start + k),
+            Some(new_suffix_start + k),
+            old[old_suffix_start + k],
+        );
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unified diff style, one line per DiffLine
+    fn render(diff: &[DiffLine]) -> Vec<String> {
+        diff.iter()
+            .map(|line| {
+                let marker = match line.kind {
+                    ChangeKind::Unchanged => ' ',
+                    ChangeKind::Added => '+',
+                    ChangeKind::Removed => '-',
+                };
+                format!("{marker}{}", line.text)
+            })
+            .collect()
+    }
+
+    // Both sides can be read back from the diff, numbered in order
+    fn assert_reconstructs(old: &str, new: &str) {
+        let diff = diff_lines(old, new);
+        let side = |kind, number: fn(&DiffLine) -> Option<usize>| {
+            let lines: Vec<_> = diff.iter().filter(|l| l.kind != kind).collect();
+            for (i, line) in lines.iter().enumerate() {
+                assert_eq!(number(line), Some(i + 1));
+            }
+            lines.iter().map(|l| l.text.as_str()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            side(ChangeKind::Added, |l| l.old_line),
+            old.lines().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            side(ChangeKind::Removed, |l| l.new_line),
+            new.lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn marks_insertions() {
+        let diff = diff_lines("a\nc", "a\nb\nc\nd");
+        assert_eq!(render(&diff), [" a", "+b", " c", "+d"]);
+        assert_eq!(diff[1].old_line, None);
+        assert_eq!(diff[1].new_line, Some(2));
+        assert_eq!(diff[2].old_line, Some(2));
+        assert_eq!(diff[2].new_line, Some(3));
+        let stats = DiffStats::new(&diff);
+        assert_eq!((stats.added, stats.removed), (2, 0));
+    }
+
+    #[test]
+    fn marks_deletions() {
+        let diff = diff_lines("a\nb\nc\nd", "b\nd");
+        assert_eq!(render(&diff), ["-a", " b", "-c", " d"]);
+        assert_eq!(diff[2].old_line, Some(3));
+        assert_eq!(diff[2].new_line, None);
+        let stats = DiffStats::new(&diff);
+        assert_eq!((stats.added, stats.removed), (0, 2));
+    }
+
+    #[test]
+    fn keeps_unchanged_text_unchanged() {
+        let diff = diff_lines("a\nb\n", "a\nb");
+        assert_eq!(render(&diff), [" a", " b"]);
+        assert!(diff_lines("", "").is_empty());
+        let stats = DiffStats::new(&diff);
+        assert_eq!((stats.added, stats.removed), (0, 0));
+    }
+
+    #[test]
+    fn shows_replacements_as_removed_then_added() {
+        let diff = diff_lines("title\nold line\nend", "title\nnew line\nend");
+        assert_eq!(render(&diff), [" title", "-old line", "+new line", " end"]);
+        assert_eq!(render(&diff_lines("", "a")), ["+a"]);
+        assert_eq!(render(&diff_lines("a", "")), ["-a"]);
+    }
+
+    #[test]
+    fn numbers_both_sides_consistently() {
+        for (old, new) in [
+            ("a\nb\nc", "a\nb\nc"),
+            ("a\nb\nc\nd\ne", "x\nb\ny\nd\nz\nw"),
+            ("a\na\na", "a\na"),
+            ("a\nb\na\nb", "b\na\nb\na"),
+            ("", "a\nb"),
+        ] {
+            assert_reconstructs(old, new);
+        }
+    }
+}
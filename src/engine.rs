@@ -0,0 +1,823 @@
+use std::{
+    collections::HashMap,
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use log::{info, warn};
+use notify::RecommendedWatcher;
+use tokio::{runtime::Handle, task::JoinHandle};
+use warp::{filters::BoxedFilter, reply::Response};
+
+use crate::{
+    artifact_store::ArtifactStore,
+    blog_config::{BlogConfig, CONFIG_FILE},
+    blog_storage::{BlogInfo, BlogStorage},
+    cdn::{Cdn, CdnConfig},
+    chaos::{Chaos, ChaosConfig},
+    clock::{SharedClock, SystemClock},
+    compression::{Compressor, DEFAULT_MIN_COMPRESSED_SIZE},
+    counters::PersistentCounters,
+    event_bus::{EventBus, UpdateEvent},
+    file_server::{FilePolicies, FileServer},
+    handlebars_support::HandlebarsSupport,
+    identity::WebfingerConfig,
+    images::ResponsiveImages,
+    incidents::Incidents,
+    journal::Journal,
+    outbox::{JobHandlers, Outbox, CDN_PURGE},
+    page_storage::PageStorage,
+    plaintext,
+    purge::{entry_key, PurgeRegistry, LISTINGS_KEY},
+    readiness::Readiness,
+    referrers::Referrers,
+    retries::{ReadRetries, DEFAULT_READ_ATTEMPTS, DEFAULT_READ_BACKOFF},
+    routes::{self, EntrySettings},
+    signing::Signer,
+    theme::{accent_color, THEME_STATIC_DIR},
+    uploads::{Uploads, DEFAULT_UPLOAD_LIMIT},
+    watchers,
+};
+
+const DEFAULT_STALE_AFTER_DAYS: i64 = 3 * 365;
+const DEFAULT_SITE_URL: &str = "http://localhost:8080";
+// The counters journals are appended to this often, and folded into their
+// snapshots every COUNTERS_COMPACT_EVERY appends
+const COUNTERS_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const COUNTERS_COMPACT_EVERY: u32 = 120;
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(256).unwrap();
+const DEFAULT_MAX_ENTRIES: NonZeroUsize = NonZeroUsize::new(10).unwrap();
+const DEFAULT_ARTIFACTS_BUDGET: u64 = 256 * 1024 * 1024;
+// Entries parsed at once while starting
+const STARTUP_CONCURRENCY: usize = 16;
+
+/// Builds a [`BlogEngine`]. Every setting has the same default as the
+/// matching command line flag of the `swes` binary
+pub struct BlogEngineBuilder {
+    base_path: PathBuf,
+    config_path: Option<PathBuf>,
+    files_path: PathBuf,
+    follow_symlinks: bool,
+    theme_path: PathBuf,
+    pages_path: PathBuf,
+    pages_under_prefix: bool,
+    blog_info: Option<BlogInfo>,
+    site_url: Option<String>,
+    mount_path: String,
+    admin_token: Option<String>,
+    share_secret: Option<String>,
+    journal_path: Option<PathBuf>,
+    outbox_path: Option<PathBuf>,
+    cache_file: Option<PathBuf>,
+    artifacts_path: Option<PathBuf>,
+    artifacts_budget: u64,
+    artifact_category_budgets: HashMap<String, u64>,
+    ready_file: Option<PathBuf>,
+    referrer_tracking: bool,
+    referrers_path: Option<PathBuf>,
+    views_path: Option<PathBuf>,
+    referrer_denylist: Vec<String>,
+    disabled_feed_aliases: Vec<String>,
+    plaintext_width: usize,
+    stale_after_days: i64,
+    cache_size: NonZeroUsize,
+    max_entries: NonZeroUsize,
+    highlight_theme: Option<String>,
+    words_per_minute: Option<NonZeroU32>,
+    upload_limit: u64,
+    compression_min_size: usize,
+    read_attempts: u32,
+    read_backoff: Duration,
+    cdn: Option<CdnConfig>,
+    clock: SharedClock,
+    show_future: bool,
+    serve_drafts: bool,
+    dev: bool,
+    chaos: ChaosConfig,
+}
+
+impl Default for BlogEngineBuilder {
+    fn default() -> Self {
+        Self {
+            base_path: PathBuf::from("blog"),
+            config_path: None,
+            files_path: PathBuf::from("files"),
+            follow_symlinks: false,
+            theme_path: Path::new("themes").join("default"),
+            pages_path: PathBuf::from("pages"),
+            pages_under_prefix: false,
+            blog_info: None,
+            site_url: None,
+            mount_path: String::new(),
+            admin_token: None,
+            share_secret: None,
+            journal_path: None,
+            outbox_path: None,
+            cache_file: None,
+            artifacts_path: None,
+            artifacts_budget: DEFAULT_ARTIFACTS_BUDGET,
+            artifact_category_budgets: HashMap::new(),
+            ready_file: None,
+            referrer_tracking: true,
+            referrers_path: None,
+            views_path: None,
+            referrer_denylist: vec![],
+            disabled_feed_aliases: vec![],
+            plaintext_width: plaintext::DEFAULT_WIDTH,
+            stale_after_days: DEFAULT_STALE_AFTER_DAYS,
+            cache_size: DEFAULT_CACHE_SIZE,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            highlight_theme: None,
+            words_per_minute: None,
+            upload_limit: DEFAULT_UPLOAD_LIMIT,
+            compression_min_size: DEFAULT_MIN_COMPRESSED_SIZE,
+            read_attempts: DEFAULT_READ_ATTEMPTS,
+            read_backoff: DEFAULT_READ_BACKOFF,
+            cdn: None,
+            clock: Arc::new(SystemClock),
+            show_future: false,
+            serve_drafts: false,
+            dev: false,
+            chaos: ChaosConfig::default(),
+        }
+    }
+}
+
+impl BlogEngineBuilder {
+    /// Directory holding the entries
+    pub fn base_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.base_path = path.into();
+        self
+    }
+
+    /// Site wide settings, blog.toml in the base path by default
+    pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Directory served under /files
+    pub fn files_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.files_path = path.into();
+        self
+    }
+
+    /// Serve the symlinks of the files directory that lead outside of it,
+    /// which are refused by default
+    pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+        self.follow_symlinks = enabled;
+        self
+    }
+
+    /// Directory of the handlebars theme, e.g. themes/default
+    pub fn theme_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.theme_path = path.into();
+        self
+    }
+
+    /// Directory holding the standalone pages, served at /{slug}
+    pub fn pages_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pages_path = path.into();
+        self
+    }
+
+    /// Serve the standalone pages at /pages/{slug} instead
+    pub fn pages_under_prefix(mut self, enabled: bool) -> Self {
+        self.pages_under_prefix = enabled;
+        self
+    }
+
+    /// Replaces the name, description, author and accent color of the config
+    /// file, which then isn't watched for changes
+    pub fn blog_info(mut self, mut info: BlogInfo) -> Self {
+        info.accent_color = accent_color(info.accent_color, "the blog info");
+        self.blog_info = Some(info);
+        self
+    }
+
+    /// Public url of the site, used for absolute links
+    pub fn site_url(mut self, url: impl Into<String>) -> Self {
+        self.site_url = Some(url.into());
+        self
+    }
+
+    /// The path the routes are mounted under by the application embedding
+    /// the engine, e.g. `/journal`, for the links of the templates
+    pub fn mount_path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        let path = path.trim_matches('/');
+        self.mount_path = if path.is_empty() {
+            String::new()
+        } else {
+            format!("/{path}")
+        };
+        self
+    }
+
+    /// Bearer token of the /admin routes, which are disabled without one
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Secret signing the shared preview links
+    pub fn share_secret(mut self, secret: impl Into<String>) -> Self {
+        self.share_secret = Some(secret.into());
+        self
+    }
+
+    /// JSONL file recording every content change, served by /api/changes
+    pub fn journal_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.journal_path = Some(path.into());
+        self
+    }
+
+    /// Directory where the notifications to other services (e.g. the CDN
+    /// purges) wait to be sent, so that they survive a restart
+    pub fn outbox_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.outbox_path = Some(path.into());
+        self
+    }
+
+    /// File keeping the parsed entries across restarts: the ones whose files
+    /// didn't change since are loaded from it instead of being parsed again
+    pub fn cache_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cache_file = Some(path.into());
+        self
+    }
+
+    /// Directory caching derived files, within a total budget in bytes
+    pub fn artifacts(mut self, path: impl Into<PathBuf>, budget: u64) -> Self {
+        self.artifacts_path = Some(path.into());
+        self.artifacts_budget = budget;
+        self
+    }
+
+    /// Budget in bytes of a single artifact category
+    pub fn artifact_budget(mut self, category: impl Into<String>, budget: u64) -> Self {
+        self.artifact_category_budgets
+            .insert(category.into(), budget);
+        self
+    }
+
+    /// File created once the engine is ready, and removed on shutdown
+    pub fn ready_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ready_file = Some(path.into());
+        self
+    }
+
+    pub fn referrer_tracking(mut self, enabled: bool) -> Self {
+        self.referrer_tracking = enabled;
+        self
+    }
+
+    /// JSON file where the referrer counts are persisted
+    pub fn referrers_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.referrers_path = Some(path.into());
+        self
+    }
+
+    /// JSON file where the view counts are persisted, next to their journal
+    pub fn views_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.views_path = Some(path.into());
+        self
+    }
+
+    /// Referring domains (and their subdomains) that are never recorded
+    pub fn referrer_denylist(mut self, domains: Vec<String>) -> Self {
+        self.referrer_denylist = domains;
+        self
+    }
+
+    /// Conventional feed path (e.g. rss.xml) that should not redirect
+    pub fn disable_feed_alias(mut self, alias: impl Into<String>) -> Self {
+        self.disabled_feed_aliases.push(alias.into());
+        self
+    }
+
+    /// Column at which the ?format=txt rendering of entries is wrapped
+    pub fn plaintext_width(mut self, width: usize) -> Self {
+        self.plaintext_width = width;
+        self
+    }
+
+    /// Entries not updated for this many days get an outdated warning
+    pub fn stale_after_days(mut self, days: i64) -> Self {
+        self.stale_after_days = days;
+        self
+    }
+
+    /// How many rendered entries are kept in memory
+    pub fn cache_size(mut self, size: NonZeroUsize) -> Self {
+        self.cache_size = size;
+        self
+    }
+
+    /// How many of the newest entries the feeds carry
+    pub fn max_entries(mut self, count: NonZeroUsize) -> Self {
+        self.max_entries = count;
+        self
+    }
+
+    /// The syntect theme of the code blocks, instead of the one of blog.toml
+    pub fn highlight_theme(mut self, theme: impl Into<String>) -> Self {
+        self.highlight_theme = Some(theme.into());
+        self
+    }
+
+    /// The reading speed behind the reading times, instead of the one of
+    /// blog.toml
+    pub fn words_per_minute(mut self, words_per_minute: NonZeroU32) -> Self {
+        self.words_per_minute = Some(words_per_minute);
+        self
+    }
+
+    /// Biggest body in bytes accepted by PUT /admin/content/{filename}
+    pub fn upload_limit(mut self, bytes: u64) -> Self {
+        self.upload_limit = bytes;
+        self
+    }
+
+    /// Smallest response body in bytes that gets compressed
+    pub fn compression_min_size(mut self, bytes: usize) -> Self {
+        self.compression_min_size = bytes;
+        self
+    }
+
+    /// How many times an entry or a file is read when the reads fail with
+    /// ESTALE or EIO, as they transiently do on NFS, and the wait before the
+    /// second attempt, doubled at each of the next ones
+    pub fn read_retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.read_attempts = attempts;
+        self.read_backoff = backoff;
+        self
+    }
+
+    /// Makes the HTML pages cacheable by a CDN, tagged with surrogate keys,
+    /// and purges them through the configured webhook when they change
+    pub fn cdn(mut self, config: CdnConfig) -> Self {
+        self.cdn = Some(config);
+        self
+    }
+
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Show the entries whose publish date is still in the future
+    pub fn show_future(mut self, enabled: bool) -> Self {
+        self.show_future = enabled;
+        self
+    }
+
+    /// Serve the entries marked with `draft: true`, still leaving them out
+    /// of the feeds and the sitemap
+    pub fn serve_drafts(mut self, enabled: bool) -> Self {
+        self.serve_drafts = enabled;
+        self
+    }
+
+    /// Enable the routes meant for writing posts locally
+    pub fn dev(mut self, enabled: bool) -> Self {
+        self.dev = enabled;
+        self
+    }
+
+    /// Inject failures into the file reads, the renders and the file
+    /// watchers. Only taken together with `dev`
+    pub fn chaos(mut self, config: ChaosConfig) -> Self {
+        self.chaos = config;
+        self
+    }
+
+    /// Loads the config, the entries, the pages and the theme
+    pub async fn build(self) -> anyhow::Result<BlogEngine> {
+        let chaos = if self.chaos.is_enabled() {
+            if !self.dev {
+                anyhow::bail!("Chaos can only be enabled in dev mode");
+            }
+            self.chaos.validate()?;
+            Some(Arc::new(Chaos::new(self.chaos)))
+        } else {
+            None
+        };
+        let clock = self.clock;
+        let journal = match &self.journal_path {
+            Some(path) => Some(Arc::new(Journal::open(path, clock.clone()).await?)),
+            None => None,
+        };
+
+        let config_path = self
+            .config_path
+            .unwrap_or_else(|| self.base_path.join(CONFIG_FILE));
+        let mut config = BlogConfig::load(&config_path).await?;
+        if let Some(theme) = self.highlight_theme {
+            config.markdown.highlight_theme = theme;
+        }
+        config.markdown.validate()?;
+        if let Some(words_per_minute) = self.words_per_minute {
+            config.words_per_minute = words_per_minute;
+        }
+
+        let artifacts = match self.artifacts_path {
+            Some(path) => {
+                let store = ArtifactStore::open(
+                    path,
+                    self.artifacts_budget,
+                    self.artifact_category_budgets,
+                    &[
+                        &self.base_path,
+                        &self.files_path,
+                        &self.theme_path,
+                        &self.pages_path,
+                    ],
+                )
+                .await?;
+                Some(Arc::new(store))
+            }
+            None => None,
+        };
+
+        let images = if config.image_widths.is_empty() {
+            None
+        } else {
+            let images = ResponsiveImages::new(
+                &self.files_path,
+                self.follow_symlinks,
+                config.image_widths.clone(),
+                artifacts.clone(),
+            );
+            Some(Arc::new(images))
+        };
+
+        let markdown = config.markdown.options();
+        let views = Arc::new(PersistentCounters::open(self.views_path).await?);
+        let mut read_retries = ReadRetries::new(self.read_attempts, self.read_backoff);
+        if let Some(chaos) = &chaos {
+            read_retries = read_retries.with_chaos(chaos.clone());
+        }
+        let read_retries = Arc::new(read_retries);
+        let mut storage = BlogStorage::new(&self.base_path, self.cache_size)
+            .with_max_most_recent_entries(self.max_entries.get())
+            .with_words_per_minute(config.words_per_minute.get())
+            .with_retries(read_retries.clone())
+            .with_clock(clock.clone())
+            .with_markdown(markdown.clone())
+            .with_views(views.clone());
+        if self.show_future {
+            storage = storage.show_future_entries();
+        }
+        if self.serve_drafts {
+            storage = storage.serve_drafts();
+        }
+        if self.dev {
+            storage = storage.show_snippet_errors();
+        }
+        if config.markdown.demote_headings {
+            storage = storage.demote_headings();
+        }
+        if let Some(journal) = &journal {
+            storage = storage.with_journal(journal.clone());
+        }
+        if let Some(images) = &images {
+            storage = storage.with_images(images.clone());
+        }
+        let outbox = match &self.outbox_path {
+            Some(path) => Some(Arc::new(Outbox::open(path, clock.clone()).await?)),
+            None => None,
+        };
+        let mut cdn = self.cdn.map(Cdn::new).transpose()?;
+        if let Some(outbox) = &outbox {
+            cdn = cdn.map(|cdn| cdn.with_outbox(outbox.clone()));
+        }
+        let cdn = cdn.map(Arc::new);
+        if let Some(cdn) = &cdn {
+            storage = storage.with_cdn(cdn.clone());
+        }
+        let storage = Arc::new(storage);
+        if let Some(path) = &self.cache_file {
+            match storage.load_cache(path).await {
+                Ok(0) => {}
+                Ok(count) => info!("Loaded {count} cached entries from {path:?}"),
+                Err(e) => warn!("Ignoring the entry cache {path:?}, parsing every entry: {e:#}"),
+            }
+        }
+        watchers::add_most_recent_entries(storage.clone(), &self.base_path, STARTUP_CONCURRENCY)
+            .await?;
+        storage.forget_cache();
+        let fixed_info = self.blog_info.is_some();
+        storage.set_blog_info(self.blog_info.unwrap_or_else(|| config.info()));
+
+        let mut pages = PageStorage::new(&self.pages_path, !self.pages_under_prefix)
+            .with_markdown(markdown.clone());
+        if let Some(images) = &images {
+            pages = pages.with_images(images.clone());
+        }
+        let pages = Arc::new(pages);
+        pages.scan().await?;
+
+        // The site url is baked into the referrer tracking and the canonical
+        // urls, so unlike the rest of the config it only gets read at startup
+        let site_url = self
+            .site_url
+            .or(config.base_url)
+            .unwrap_or(DEFAULT_SITE_URL.to_owned());
+        let referrers = if self.referrer_tracking {
+            let referrers =
+                Referrers::load(self.referrers_path, &site_url, self.referrer_denylist).await?;
+            Some(Arc::new(referrers))
+        } else {
+            None
+        };
+
+        let identity = config.identity.clone().unwrap_or_default();
+        let handlebars_support = HandlebarsSupport::new(&self.theme_path)?
+            .with_profile_links(identity.profile_links)
+            .with_site_url(&site_url)
+            .with_mount_path(&self.mount_path);
+        let handlebars_support = Arc::new(RwLock::new(handlebars_support));
+
+        let file_policies = FilePolicies::new(config.files.clone())?;
+
+        let mut purge_registry = PurgeRegistry::default();
+        purge_registry.register("entry", storage.clone());
+        if let Some(artifacts) = &artifacts {
+            purge_registry.register("render", artifacts.clone());
+        }
+        if let Some(cdn) = cdn.as_ref().filter(|cdn| cdn.purges()) {
+            purge_registry.register("cdn", cdn.clone());
+        }
+
+        Ok(BlogEngine {
+            entry_settings: EntrySettings {
+                plaintext_width: self.plaintext_width,
+                stale_after_days: self.stale_after_days,
+                clock: clock.clone(),
+                artifacts: artifacts.clone(),
+                markdown: markdown.clone(),
+                dev: self.dev,
+            },
+            storage,
+            pages,
+            handlebars_support,
+            event_bus: Arc::new(EventBus::new()),
+            journal,
+            artifacts,
+            images,
+            counters: referrers
+                .iter()
+                .map(|referrers| referrers.persisted())
+                .chain([views])
+                .collect(),
+            referrers,
+            readiness: Arc::new(Readiness::from_env(self.ready_file)),
+            purge_registry: Arc::new(purge_registry),
+            file_server: Arc::new(
+                FileServer::new(&self.files_path, self.follow_symlinks)
+                    .with_retries(read_retries.clone())
+                    .with_policies(file_policies),
+            ),
+            theme_file_server: Arc::new(
+                FileServer::new(self.theme_path.join(THEME_STATIC_DIR), self.follow_symlinks)
+                    .with_retries(read_retries.clone()),
+            ),
+            read_retries,
+            webfinger: identity.webfinger.map(Arc::new),
+            compressor: Arc::new(Compressor::new(self.compression_min_size)),
+            incidents: Arc::new(Incidents::new(clock.clone())),
+            cdn,
+            outbox,
+            uploads: Arc::new(Uploads::new(
+                &self.base_path,
+                &self.files_path,
+                &site_url,
+                self.upload_limit,
+                markdown,
+            )),
+            clock,
+            signer: self
+                .share_secret
+                .map(|secret| Arc::new(Signer::new(secret))),
+            admin_token: self.admin_token.map(Arc::new),
+            site_url: Arc::new(site_url),
+            disabled_feed_aliases: Arc::new(self.disabled_feed_aliases),
+            stats_page: config.stats_page,
+            pages_under_prefix: self.pages_under_prefix,
+            show_future: self.show_future,
+            dev: self.dev,
+            chaos,
+            cache_file: self.cache_file,
+            base_path: self.base_path,
+            config_path: (!fixed_info).then_some(config_path),
+            theme_path: self.theme_path,
+            stylesheet: self.files_path.join("style.css"),
+            watchers: Default::default(),
+            tasks: Default::default(),
+        })
+    }
+}
+
+/// The whole blog: its content, the background work keeping it up to date
+/// and the warp routes serving it.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use warp::Filter;
+///
+/// let engine = swes::BlogEngine::builder().base_path("blog").build().await?;
+/// engine.start_watchers(tokio::runtime::Handle::current())?;
+/// let routes = warp::path("journal").and(engine.routes());
+/// warp::serve(routes).run(([127, 0, 0, 1], 8080)).await;
+/// engine.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlogEngine {
+    pub(crate) storage: Arc<BlogStorage>,
+    pub(crate) pages: Arc<PageStorage>,
+    pub(crate) handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+    pub(crate) event_bus: Arc<EventBus>,
+    pub(crate) journal: Option<Arc<Journal>>,
+    pub(crate) artifacts: Option<Arc<ArtifactStore>>,
+    pub(crate) images: Option<Arc<ResponsiveImages>>,
+    pub(crate) referrers: Option<Arc<Referrers>>,
+    counters: Vec<Arc<PersistentCounters>>,
+    pub(crate) readiness: Arc<Readiness>,
+    pub(crate) purge_registry: Arc<PurgeRegistry>,
+    pub(crate) file_server: Arc<FileServer>,
+    // The static directory of the theme, served under /theme
+    pub(crate) theme_file_server: Arc<FileServer>,
+    pub(crate) uploads: Arc<Uploads>,
+    pub(crate) webfinger: Option<Arc<WebfingerConfig>>,
+    pub(crate) compressor: Arc<Compressor>,
+    pub(crate) read_retries: Arc<ReadRetries>,
+    pub(crate) incidents: Arc<Incidents>,
+    pub(crate) cdn: Option<Arc<Cdn>>,
+    pub(crate) outbox: Option<Arc<Outbox>>,
+    pub(crate) clock: SharedClock,
+    pub(crate) signer: Option<Arc<Signer>>,
+    pub(crate) admin_token: Option<Arc<String>>,
+    pub(crate) site_url: Arc<String>,
+    pub(crate) entry_settings: EntrySettings,
+    pub(crate) disabled_feed_aliases: Arc<Vec<String>>,
+    pub(crate) stats_page: bool,
+    pub(crate) pages_under_prefix: bool,
+    pub(crate) dev: bool,
+    pub(crate) chaos: Option<Arc<Chaos>>,
+    show_future: bool,
+    // Saved on shutdown, see BlogStorage::persist
+    cache_file: Option<PathBuf>,
+    base_path: PathBuf,
+    // None when the blog info was given to the builder
+    config_path: Option<PathBuf>,
+    theme_path: PathBuf,
+    stylesheet: PathBuf,
+    // Dropping a watcher stops it
+    watchers: Mutex<Vec<RecommendedWatcher>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl BlogEngine {
+    pub fn builder() -> BlogEngineBuilder {
+        BlogEngineBuilder::default()
+    }
+
+    /// Every route of the blog, at the root of the filter. Requests matching
+    /// none of them are rejected, so that other routes can follow with `or`
+    pub fn routes(&self) -> BoxedFilter<(Response,)> {
+        routes::routes(self)
+    }
+
+    pub fn storage(&self) -> Arc<BlogStorage> {
+        self.storage.clone()
+    }
+
+    /// Drives /readyz, the ready file and the systemd notifications
+    pub fn readiness(&self) -> Arc<Readiness> {
+        self.readiness.clone()
+    }
+
+    /// Records the panics as incidents, visible at /admin/incidents/{id}.
+    /// The panic hook is global, so the host application decides whether to
+    /// install it
+    pub fn capture_panics(&self) {
+        self.incidents.clone().capture_panics();
+    }
+
+    /// Follows the changes to the entries, pages, config and theme, and
+    /// starts the periodic tasks (counter flushes, scheduled entries, CDN
+    /// purges, the outbox)
+    pub fn start_watchers(&self, handle: Handle) -> anyhow::Result<()> {
+        let mut watchers = self.watchers.lock().expect("Poisoned watchers");
+        watchers.push(watchers::watch_entries(
+            &self.base_path,
+            self.storage.clone(),
+            self.event_bus.clone(),
+            handle.clone(),
+            self.chaos.clone(),
+        )?);
+        watchers.extend(watchers::watch_pages(
+            self.pages.clone(),
+            self.event_bus.clone(),
+            handle.clone(),
+            self.chaos.clone(),
+        )?);
+        if let Some(config_path) = &self.config_path {
+            watchers.extend(watchers::watch_config(
+                config_path.clone(),
+                self.storage.clone(),
+                self.event_bus.clone(),
+                handle.clone(),
+                self.chaos.clone(),
+            )?);
+        }
+        watchers.push(watchers::watch_theme(
+            &self.theme_path,
+            &self.stylesheet,
+            self.handlebars_support.clone(),
+            self.event_bus.clone(),
+            self.chaos.clone(),
+        )?);
+
+        let mut tasks = self.tasks.lock().expect("Poisoned tasks");
+        let counters = self.counters.clone();
+        tasks.push(handle.spawn(async move {
+            let mut interval = tokio::time::interval(COUNTERS_FLUSH_INTERVAL);
+            for flushes in 1.. {
+                interval.tick().await;
+                for counters in &counters {
+                    if flushes % COUNTERS_COMPACT_EVERY == 0 {
+                        counters.compact().await;
+                    } else {
+                        counters.flush().await;
+                    }
+                }
+            }
+        }));
+        if let Some(cdn) = self.cdn.clone().filter(|cdn| cdn.purges()) {
+            tasks.push(handle.spawn(async move { cdn.run_purges().await }));
+        }
+        if let Some(outbox) = self.outbox.clone() {
+            let mut handlers = JobHandlers::new();
+            if let Some(cdn) = self.cdn.clone().filter(|cdn| cdn.purges()) {
+                handlers.insert(CDN_PURGE, cdn);
+            }
+            tasks.push(handle.spawn(async move { outbox.run(handlers).await }));
+        }
+        if !self.show_future {
+            let storage = self.storage.clone();
+            let event_bus = self.event_bus.clone();
+            let cdn = self.cdn.clone();
+            let clock = self.clock.clone();
+            tasks.push(handle.spawn(async move {
+                let mut interval = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+                let mut last_check = clock.now();
+                loop {
+                    interval.tick().await;
+                    let now = clock.now();
+                    let published = storage.published_between(last_check, now).await;
+                    if !published.is_empty() {
+                        info!("Scheduled entries went live: {published:?}");
+                        event_bus.publish(UpdateEvent::Reload);
+                        if let Some(cdn) = &cdn {
+                            let mut keys: Vec<_> =
+                                published.iter().map(|slug| entry_key(slug)).collect();
+                            keys.push(LISTINGS_KEY.to_owned());
+                            cdn.queue(keys);
+                        }
+                    }
+                    last_check = now;
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    /// Ends the /events streams and long polls, which would otherwise keep a
+    /// graceful shutdown of the server waiting for them
+    pub fn close_events(&self) {
+        self.event_bus.close();
+    }
+
+    /// Stops the watchers and the periodic tasks, and saves what needs to be
+    pub async fn shutdown(&self) {
+        self.readiness.set_stopping();
+        self.close_events();
+        self.watchers.lock().expect("Poisoned watchers").clear();
+        for task in self.tasks.lock().expect("Poisoned tasks").drain(..) {
+            task.abort();
+        }
+        for counters in &self.counters {
+            counters.compact().await;
+        }
+        if let Some(path) = &self.cache_file {
+            if let Err(e) = self.storage.persist(path).await {
+                warn!("Failed to save the entry cache to {path:?}: {e:#}");
+            }
+        }
+    }
+}
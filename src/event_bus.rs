@@ -0,0 +1,138 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures_util::Future;
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError, Receiver, Sender},
+    watch,
+};
+
+const EVENT_QUEUE_SIZE: usize = 500;
+const MAX_RETAINED_EVENTS: usize = 100;
+
+#[derive(Clone, Copy, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateEvent {
+    Reload,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SequencedEvent {
+    pub sequence: u64,
+    pub event: UpdateEvent,
+}
+
+#[derive(Serialize)]
+pub struct PollResult {
+    pub cursor: u64,
+    pub events: Vec<UpdateEvent>,
+}
+
+struct History {
+    last_sequence: u64,
+    retained: VecDeque<SequencedEvent>,
+}
+
+// Broadcasts the update events to the SSE streams, and keeps the latest ones
+// around for the clients that long-poll instead
+pub struct EventBus {
+    sender: Sender<SequencedEvent>,
+    history: Mutex<History>,
+    // Set when the server stops: the streams and the long polls never end by
+    // themselves, and would keep a graceful shutdown waiting
+    closed: watch::Sender<bool>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+        Self {
+            sender,
+            history: Mutex::new(History {
+                last_sequence: 0,
+                retained: VecDeque::new(),
+            }),
+            closed: watch::channel(false).0,
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    // Resolves once the bus is closed, right away if it already is
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closed = self.closed.subscribe();
+        async move {
+            let _ = closed.wait_for(|closed| *closed).await;
+        }
+    }
+
+    // The sequence number is assigned and the event sent while holding the
+    // lock, so that subscribers always see increasing sequence numbers
+    pub fn publish(&self, event: UpdateEvent) {
+        let mut history = self.history.lock().expect("Poisoned event history");
+        history.last_sequence += 1;
+        let event = SequencedEvent {
+            sequence: history.last_sequence,
+            event,
+        };
+        history.retained.push_back(event);
+        if history.retained.len() > MAX_RETAINED_EVENTS {
+            history.retained.pop_front();
+        }
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> Receiver<SequencedEvent> {
+        self.sender.subscribe()
+    }
+
+    // Returns every retained event newer than the cursor, waiting up to
+    // `timeout` for one when there's none yet. A missing cursor, or one that
+    // doesn't come from this process, just returns the current cursor
+    pub async fn poll(&self, cursor: Option<u64>, timeout: Duration) -> PollResult {
+        // Subscribing before looking at the history ensures that an event
+        // published in between isn't missed
+        let mut receiver = self.subscribe();
+        let Some(cursor) = cursor else {
+            return self.events_since(u64::MAX);
+        };
+        let result = self.events_since(cursor);
+        if !result.events.is_empty() || cursor > result.cursor {
+            return result;
+        }
+        let deadline = Instant::now() + timeout;
+        let closed = self.closed();
+        tokio::pin!(closed);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            tokio::select! {
+                received = tokio::time::timeout(remaining, receiver.recv()) => match received {
+                    Ok(Ok(event)) if event.sequence <= cursor => continue,
+                    Ok(Ok(_) | Err(RecvError::Lagged(_) | RecvError::Closed)) | Err(_) => {
+                        return self.events_since(cursor)
+                    }
+                },
+                _ = &mut closed => return self.events_since(cursor),
+            }
+        }
+    }
+
+    fn events_since(&self, cursor: u64) -> PollResult {
+        let history = self.history.lock().expect("Poisoned event history");
+        PollResult {
+            cursor: history.last_sequence,
+            events: history
+                .retained
+                .iter()
+                .filter(|e| e.sequence > cursor)
+                .map(|e| e.event)
+                .collect(),
+        }
+    }
+}
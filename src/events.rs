@@ -0,0 +1,53 @@
+use std::{convert::Infallible, time::Duration};
+
+use futures_util::StreamExt;
+use log::error;
+use serde::Deserialize;
+use warp::{filters::sse::Event, Reply};
+
+use crate::event_bus::{EventBus, SequencedEvent, UpdateEvent};
+
+pub(crate) const EVENTS_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+// Sent on the SSE stream so that clients can tell a quiet stream from one
+// that's being buffered by a proxy
+const EVENTS_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+pub(crate) struct PollQuery {
+    pub cursor: Option<u64>,
+}
+
+fn sse_data(evt: SequencedEvent) -> Result<Event, Infallible> {
+    let event = match evt.event {
+        UpdateEvent::Reload => "reload",
+    };
+    Ok(Event::default()
+        .id(evt.sequence.to_string())
+        .data(event.to_string()))
+}
+
+// The stream ends when the bus is closed
+pub(crate) fn sse_update(event_bus: &EventBus) -> impl Reply {
+    let receiver = event_bus.subscribe();
+    let stream = tokio_stream::wrappers::BroadcastStream::new(receiver);
+
+    let stream = stream.map(move |event| match event {
+        Ok(event) => sse_data(event),
+        Err(e) => {
+            error!("While receiving reload event: {e}");
+            Ok(Event::default().data("reload"))
+        }
+    });
+    let pings =
+        tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(EVENTS_PING_INTERVAL))
+            .map(|_| Ok(Event::default().event("ping").data("")));
+    // Proxies honoring this header (e.g. nginx) won't buffer the stream
+    warp::reply::with_header(
+        warp::sse::reply(
+            futures_util::stream::select(stream, pings).take_until(event_bus.closed()),
+        ),
+        "x-accel-buffering",
+        "no",
+    )
+}
@@ -0,0 +1,428 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::blog_storage::{BlogEntry, BlogInfo, ContributorRole};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+// Conventional feed locations probed by readers, each redirected to the
+// canonical endpoint of its format
+pub const FEED_ALIASES: &[(&str, FeedFormat)] = &[
+    ("feed", FeedFormat::Rss),
+    ("rss", FeedFormat::Rss),
+    ("rss.xml", FeedFormat::Rss),
+    ("index.xml", FeedFormat::Rss),
+    ("feed.json", FeedFormat::Json),
+];
+
+// Feed locations served as they are rather than redirected
+pub const FEED_FILES: &[(&str, FeedFormat)] = &[
+    ("feed.xml", FeedFormat::Rss),
+    ("atom.xml", FeedFormat::Atom),
+];
+
+impl FeedFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rss" => Some(FeedFormat::Rss),
+            "atom" => Some(FeedFormat::Atom),
+            "json" => Some(FeedFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "rss",
+            FeedFormat::Atom => "atom",
+            FeedFormat::Json => "json",
+        }
+    }
+
+    pub fn canonical_path(&self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "/feed/rss",
+            FeedFormat::Atom => "/feed/atom",
+            FeedFormat::Json => "/feed/json",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "application/rss+xml",
+            FeedFormat::Atom => "application/atom+xml",
+            FeedFormat::Json => "application/feed+json",
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/rss+xml" | "application/xml" | "text/xml" => Some(FeedFormat::Rss),
+            "application/atom+xml" => Some(FeedFormat::Atom),
+            "application/feed+json" | "application/json" => Some(FeedFormat::Json),
+            _ => None,
+        }
+    }
+
+    // Picks the supported format with the highest quality value, RSS being the
+    // choice whenever the client doesn't care (no header, */*) or asks for
+    // nothing we can produce
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let Some(accept) = accept else {
+            return FeedFormat::Rss;
+        };
+        let mut best: Option<(f32, FeedFormat)> = None;
+        for media_range in accept.split(',') {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media_type.as_str() {
+                "*/*" | "application/*" => Some(FeedFormat::Rss),
+                media_type => Self::from_media_type(media_type),
+            };
+            if let Some(format) = format {
+                if quality > 0.0 && best.is_none_or(|(best_quality, _)| quality > best_quality) {
+                    best = Some((quality, format));
+                }
+            }
+        }
+        best.map(|(_, format)| format).unwrap_or(FeedFormat::Rss)
+    }
+}
+
+pub fn alias_format(alias: &str, disabled_aliases: &[String]) -> Option<FeedFormat> {
+    if disabled_aliases.iter().any(|a| a == alias) {
+        return None;
+    }
+    FEED_ALIASES
+        .iter()
+        .find(|(name, _)| *name == alias)
+        .map(|(_, format)| *format)
+}
+
+pub fn file_format(name: &str) -> Option<FeedFormat> {
+    FEED_FILES
+        .iter()
+        .find(|(file, _)| *file == name)
+        .map(|(_, format)| *format)
+}
+
+pub fn generate(
+    format: FeedFormat,
+    info: &BlogInfo,
+    site_url: &str,
+    entries: &[BlogEntry],
+) -> String {
+    match format {
+        FeedFormat::Rss => generate_rss(info, site_url, entries),
+        FeedFormat::Atom => generate_atom(info, site_url, entries),
+        FeedFormat::Json => generate_json(info, site_url, entries),
+    }
+}
+
+fn entry_url(site_url: &str, entry: &BlogEntry) -> String {
+    format!("{}/blog/{}", site_url.trim_end_matches('/'), entry.slug)
+}
+
+// The address of the entry before slugs existed, kept as its identifier so
+// that feed readers don't see every entry as new. It redirects to entry_url
+fn entry_id(site_url: &str, entry: &BlogEntry) -> String {
+    format!("{}/blog/{}", site_url.trim_end_matches('/'), entry.filename)
+}
+
+// Posts behind a content warning only get the warning and a link in feeds,
+// so that nobody reads them by accident in a feed reader
+fn entry_content(site_url: &str, entry: &BlogEntry) -> String {
+    match &entry.description.content_warning {
+        Some(warning) => format!(
+            "<p>Content warning: {}</p><p><a href=\"{}\">Read the post</a></p>",
+            escape_html(warning),
+            entry_url(site_url, entry)
+        ),
+        None => entry.html.clone(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn generate_rss(info: &BlogInfo, site_url: &str, entries: &[BlogEntry]) -> String {
+    let items = entries
+        .iter()
+        .map(|entry| rss::Item {
+            title: Some(entry.description.title.clone()),
+            link: Some(entry_url(site_url, entry)),
+            author: Some(
+                entry
+                    .description
+                    .names_with_role(ContributorRole::Author)
+                    .join(", "),
+            ),
+            guid: Some(rss::Guid {
+                value: entry_id(site_url, entry),
+                permalink: true,
+            }),
+            pub_date: Some(entry.description.publish_date.to_rfc2822()),
+            description: Some(entry_content(site_url, entry)),
+            ..Default::default()
+        })
+        .collect();
+    let last_build_date = entries
+        .iter()
+        .map(|e| {
+            e.description
+                .updated_date
+                .unwrap_or(e.description.publish_date)
+        })
+        .max()
+        .map(|date| date.to_rfc2822());
+    let channel = rss::Channel {
+        title: info.name.clone(),
+        link: format!("{}/blog", site_url.trim_end_matches('/')),
+        description: info.description.clone().unwrap_or(info.name.clone()),
+        last_build_date,
+        items,
+        ..Default::default()
+    };
+    channel.to_string()
+}
+
+pub fn generate_atom(info: &BlogInfo, site_url: &str, entries: &[BlogEntry]) -> String {
+    let atom_entries: Vec<_> = entries
+        .iter()
+        .map(|entry| atom_syndication::Entry {
+            title: entry.description.title.clone().into(),
+            id: entry_id(site_url, entry),
+            updated: entry
+                .description
+                .updated_date
+                .unwrap_or(entry.description.publish_date)
+                .into(),
+            published: Some(entry.description.publish_date.into()),
+            authors: entry
+                .description
+                .authors
+                .iter()
+                .filter(|c| c.role == ContributorRole::Author)
+                .map(|c| atom_syndication::Person {
+                    name: c.name.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            contributors: entry
+                .description
+                .authors
+                .iter()
+                .filter(|c| c.role != ContributorRole::Author)
+                .map(|c| atom_syndication::Person {
+                    name: c.name.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            links: vec![atom_syndication::Link {
+                href: entry_url(site_url, entry),
+                rel: "alternate".to_owned(),
+                ..Default::default()
+            }],
+            content: Some(atom_syndication::Content {
+                value: Some(entry_content(site_url, entry)),
+                content_type: Some("html".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .collect();
+    let updated = atom_entries
+        .iter()
+        .map(|e| e.updated)
+        .max()
+        .unwrap_or_else(|| DateTime::<Utc>::UNIX_EPOCH.into());
+    let feed = atom_syndication::Feed {
+        title: info.name.clone().into(),
+        subtitle: info.description.clone().map(Into::into),
+        id: format!("{}/blog", site_url.trim_end_matches('/')),
+        updated,
+        links: vec![
+            atom_syndication::Link {
+                href: format!("{}/blog", site_url.trim_end_matches('/')),
+                rel: "alternate".to_owned(),
+                ..Default::default()
+            },
+            atom_syndication::Link {
+                href: format!(
+                    "{}{}",
+                    site_url.trim_end_matches('/'),
+                    FeedFormat::Atom.canonical_path()
+                ),
+                rel: "self".to_owned(),
+                mime_type: Some(FeedFormat::Atom.content_type().to_owned()),
+                ..Default::default()
+            },
+        ],
+        entries: atom_entries,
+        ..Default::default()
+    };
+    feed.to_string()
+}
+
+#[derive(Serialize)]
+struct JsonFeed {
+    version: &'static str,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    home_page_url: String,
+    feed_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    title: String,
+    content_html: String,
+    date_published: DateTime<Utc>,
+    authors: Vec<JsonFeedAuthor>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor {
+    name: String,
+}
+
+pub fn generate_json(info: &BlogInfo, site_url: &str, entries: &[BlogEntry]) -> String {
+    let site_url = site_url.trim_end_matches('/');
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: info.name.clone(),
+        description: info.description.clone(),
+        home_page_url: format!("{site_url}/blog"),
+        feed_url: format!("{site_url}{}", FeedFormat::Json.canonical_path()),
+        items: entries
+            .iter()
+            .map(|entry| JsonFeedItem {
+                id: entry_id(site_url, entry),
+                url: entry_url(site_url, entry),
+                title: entry.description.title.clone(),
+                content_html: entry_content(site_url, entry),
+                date_published: entry.description.publish_date,
+                authors: entry
+                    .description
+                    .names_with_role(ContributorRole::Author)
+                    .into_iter()
+                    .map(|name| JsonFeedAuthor { name })
+                    .collect(),
+            })
+            .collect(),
+    };
+    serde_json::to_string(&feed).expect("JSON feeds are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_alias_maps_to_its_format() {
+        for (alias, format) in FEED_ALIASES {
+            assert_eq!(alias_format(alias, &[]), Some(*format), "{alias}");
+        }
+        assert_eq!(alias_format("feed", &[]), Some(FeedFormat::Rss));
+        assert_eq!(alias_format("feed.json", &[]), Some(FeedFormat::Json));
+        assert_eq!(alias_format("atom", &[]), None);
+    }
+
+    #[test]
+    fn disabled_aliases_are_not_redirected() {
+        let disabled = vec!["rss.xml".to_owned()];
+        assert_eq!(alias_format("rss.xml", &disabled), None);
+        assert_eq!(alias_format("rss", &disabled), Some(FeedFormat::Rss));
+    }
+
+    #[test]
+    fn feed_files_are_served_in_their_format() {
+        assert_eq!(file_format("feed.xml"), Some(FeedFormat::Rss));
+        assert_eq!(file_format("atom.xml"), Some(FeedFormat::Atom));
+        assert_eq!(file_format("rss"), None);
+    }
+
+    #[test]
+    fn negotiates_each_media_type() {
+        let cases = [
+            (None, FeedFormat::Rss),
+            (Some(""), FeedFormat::Rss),
+            (Some("*/*"), FeedFormat::Rss),
+            (Some("application/*"), FeedFormat::Rss),
+            (Some("application/rss+xml"), FeedFormat::Rss),
+            (Some("application/xml"), FeedFormat::Rss),
+            (Some("text/xml"), FeedFormat::Rss),
+            (Some("application/atom+xml"), FeedFormat::Atom),
+            (Some("APPLICATION/ATOM+XML"), FeedFormat::Atom),
+            (Some("application/feed+json"), FeedFormat::Json),
+            (Some("application/json"), FeedFormat::Json),
+            (Some("text/html"), FeedFormat::Rss),
+        ];
+        for (accept, format) in cases {
+            assert_eq!(FeedFormat::negotiate(accept), format, "{accept:?}");
+        }
+    }
+
+    #[test]
+    fn negotiation_honors_quality_values() {
+        assert_eq!(
+            FeedFormat::negotiate(Some("application/rss+xml;q=0.5, application/atom+xml")),
+            FeedFormat::Atom
+        );
+        assert_eq!(
+            FeedFormat::negotiate(Some("text/html, application/json;q=0.9, */*;q=0.1")),
+            FeedFormat::Json
+        );
+        // Ties go to the first listed
+        assert_eq!(
+            FeedFormat::negotiate(Some("application/atom+xml, application/json")),
+            FeedFormat::Atom
+        );
+        assert_eq!(
+            FeedFormat::negotiate(Some("application/atom+xml;q=0, application/json;q=bad")),
+            FeedFormat::Json
+        );
+    }
+
+    #[test]
+    fn rss_credits_the_authors_and_atom_the_contributors_too() {
+        let entry = crate::test_support::entry(
+            "credited.md",
+            "title: Credited\nauthors: [Crax, {name: Ada, role: translator}]\n\
+             publish_date: 2024-01-01T08:00:00Z",
+        );
+        let info = crate::blog_config::BlogConfig::default().info();
+        let entries = [entry];
+
+        let rss = generate_rss(&info, "https://example.com", &entries);
+        assert!(rss.contains("<author>Crax</author>"), "{rss}");
+        assert!(!rss.contains("Ada"), "{rss}");
+
+        let atom = generate_atom(&info, "https://example.com", &entries);
+        assert!(
+            atom.contains("<author><name>Crax</name></author>"),
+            "{atom}"
+        );
+        assert!(
+            atom.contains("<contributor><name>Ada</name></contributor>"),
+            "{atom}"
+        );
+    }
+}
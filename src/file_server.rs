@@ -1,36 +1,510 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
 
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use log::info;
 use mime_guess::Mime;
+use serde::Deserialize;
+use warp::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+
+use crate::{conditional::ConditionalRequest, retries::ReadRetries};
 
 pub struct FileServer {
     base_path: PathBuf,
+    follow_symlinks: bool,
+    retries: Arc<ReadRetries>,
+    policies: FilePolicies,
+}
+
+// A [[files]] table of blog.toml, read at startup only: how the files under
+// a prefix of the files directory are served. Where several prefixes match a
+// file, the longest one wins, setting by setting and header by header
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilePolicyConfig {
+    // e.g. photos, matching photos/a.jpg but not photos2/a.jpg. Empty for
+    // every file
+    pub prefix: String,
+    // e.g. X-Robots-Tag = "noimageindex"
+    pub headers: BTreeMap<String, String>,
+    // Refuses the requests referred by other sites. The requests without a
+    // referer, or referred by the blog itself, are always served
+    pub hotlink_protection: Option<bool>,
+    // Sites, and their subdomains, that may embed the files anyway
+    pub hotlink_allowlist: Option<Vec<String>>,
+}
+
+struct FilePolicy {
+    prefix: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    hotlink_protection: Option<bool>,
+    hotlink_allowlist: Option<Vec<String>>,
+}
+
+impl FilePolicy {
+    fn matches(&self, path: &str) -> bool {
+        self.prefix.is_empty()
+            || path == self.prefix
+            || path
+                .strip_prefix(&self.prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+#[derive(Default)]
+pub struct FilePolicies {
+    // Shortest prefix first, so that the longer ones are applied over them
+    policies: Vec<FilePolicy>,
+}
+
+impl FilePolicies {
+    pub fn new(configs: Vec<FilePolicyConfig>) -> anyhow::Result<Self> {
+        let mut policies = configs
+            .into_iter()
+            .map(|config| {
+                let prefix = config.prefix.trim_matches('/').to_owned();
+                let headers = config
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        let header = HeaderName::try_from(name.as_str())
+                            .ok()
+                            .zip(HeaderValue::try_from(value.as_str()).ok());
+                        header.with_context(|| {
+                            format!("Invalid header {name}: {value} for the files under {prefix:?}")
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(FilePolicy {
+                    prefix,
+                    headers,
+                    hotlink_protection: config.hotlink_protection,
+                    hotlink_allowlist: config.hotlink_allowlist.map(|allowlist| {
+                        allowlist
+                            .into_iter()
+                            .map(|site| site.to_ascii_lowercase())
+                            .collect()
+                    }),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        policies.sort_by_key(|policy| policy.prefix.len());
+        Ok(Self { policies })
+    }
+
+    pub fn resolve(&self, path: &Path) -> ResolvedFilePolicy {
+        let path = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let mut resolved = ResolvedFilePolicy::default();
+        for policy in self.policies.iter().filter(|policy| policy.matches(&path)) {
+            for (name, value) in &policy.headers {
+                resolved.headers.insert(name.clone(), value.clone());
+            }
+            if let Some(protection) = policy.hotlink_protection {
+                resolved.hotlink_protection = protection;
+            }
+            if let Some(allowlist) = &policy.hotlink_allowlist {
+                resolved.hotlink_allowlist = allowlist.clone();
+            }
+        }
+        resolved
+    }
 }
 
+#[derive(Default)]
+pub struct ResolvedFilePolicy {
+    pub headers: HeaderMap,
+    hotlink_protection: bool,
+    hotlink_allowlist: Vec<String>,
+}
+
+impl ResolvedFilePolicy {
+    // own_hosts are the ones the blog is reached at: the Host of the request
+    // and the one of the site url
+    pub fn allows_referer(&self, referer: Option<&str>, own_hosts: &[&str]) -> bool {
+        if !self.hotlink_protection {
+            return true;
+        }
+        let Some(referer) = referer.filter(|referer| !referer.trim().is_empty()) else {
+            return true;
+        };
+        // Not even a url, so not a page of another site either
+        let Some(host) = referer
+            .trim()
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_ascii_lowercase))
+        else {
+            return true;
+        };
+        let own = own_hosts.iter().any(|own| {
+            let own = own.rsplit_once(':').map_or(*own, |(host, port)| {
+                if port.chars().all(|c| c.is_ascii_digit()) {
+                    host
+                } else {
+                    own
+                }
+            });
+            own.eq_ignore_ascii_case(&host)
+        });
+        own || self
+            .hotlink_allowlist
+            .iter()
+            .any(|allowed| host == *allowed || host.ends_with(&format!(".{allowed}")))
+    }
+}
+
+#[derive(Debug)]
+pub enum FileServerError {
+    PathTraversal(PathBuf),
+    SymlinkEscape(PathBuf),
+}
+
+impl std::fmt::Display for FileServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileServerError::PathTraversal(path) => {
+                write!(f, "{path:?} escapes the served directory")
+            }
+            FileServerError::SymlinkEscape(path) => {
+                write!(f, "{path:?} is a link to outside the served directory")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FileServerError {}
+
 pub struct ServedFile {
-    pub data: Vec<u8>,
+    // None when the client's copy is still fresh: the file isn't read at all
+    pub data: Option<Vec<u8>>,
     pub mime_type: Mime,
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+// Where a requested path leads within base_path, if it stays there. Cleaning
+// resolves every '..', and an absolute path replaces the base when joined, so
+// both fail the prefix check. Canonicalizing then resolves the symlinks, which
+// may only lead outside when following them was asked for
+pub async fn resolve_within(
+    base_path: &Path,
+    path: &Path,
+    follow_symlinks: bool,
+) -> anyhow::Result<PathBuf> {
+    let base_path = tokio::fs::canonicalize(base_path).await?;
+    let path = path_clean::clean(base_path.join(path));
+    if !path.starts_with(&base_path) {
+        return Err(FileServerError::PathTraversal(path).into());
+    }
+    if follow_symlinks {
+        return Ok(path);
+    }
+    let resolved = tokio::fs::canonicalize(&path).await?;
+    if !resolved.starts_with(&base_path) {
+        return Err(FileServerError::SymlinkEscape(path).into());
+    }
+    Ok(resolved)
 }
 
 impl FileServer {
-    pub fn new<P: Into<PathBuf>>(base_path: P) -> Self {
+    pub fn new<P: Into<PathBuf>>(base_path: P, follow_symlinks: bool) -> Self {
         Self {
             base_path: base_path.into(),
+            follow_symlinks,
+            retries: Default::default(),
+            policies: Default::default(),
         }
     }
 
-    pub async fn serve(&self, path: &Path) -> anyhow::Result<ServedFile> {
-        let path = self.base_path.join(path);
-        let path = path_clean::clean(path);
+    pub fn with_policies(mut self, policies: FilePolicies) -> Self {
+        self.policies = policies;
+        self
+    }
+
+    pub fn policy(&self, path: &Path) -> ResolvedFilePolicy {
+        self.policies.resolve(path)
+    }
+
+    pub fn with_retries(mut self, retries: Arc<ReadRetries>) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub async fn serve(
+        &self,
+        path: &Path,
+        conditions: &ConditionalRequest,
+    ) -> anyhow::Result<ServedFile> {
+        let path = resolve_within(&self.base_path, path, self.follow_symlinks).await?;
         info!("Try serving file {path:?}");
-        let file = tokio::fs::read(&path).await.map(|content| {
-            let content_guess = mime_guess::from_path(&path).first_or(mime_guess::mime::TEXT_PLAIN);
-            info!("Serving file {path:?} of type {content_guess}");
-            ServedFile {
-                data: content,
-                mime_type: content_guess,
-            }
-        })?;
+        let metadata = self
+            .retries
+            .read(&path, || tokio::fs::metadata(&path))
+            .await?;
+        let last_modified: DateTime<Utc> = metadata.modified()?.into();
+        // Weak, as it only tells that the file looks the same on disk. The
+        // nanoseconds tell apart two writes of the same size within a second
+        let etag = format!(
+            "W/\"{:x}.{:x}-{:x}\"",
+            last_modified.timestamp(),
+            last_modified.timestamp_subsec_nanos(),
+            metadata.len()
+        );
+        let mime_type = mime_guess::from_path(&path).first_or(mime_guess::mime::TEXT_PLAIN);
+        let data = if conditions.is_fresh(&etag, Some(last_modified)) {
+            info!("File {path:?} not modified");
+            None
+        } else {
+            info!("Serving file {path:?} of type {mime_type}");
+            Some(self.retries.read(&path, || tokio::fs::read(&path)).await?)
+        };
+        let file = ServedFile {
+            data,
+            mime_type,
+            etag,
+            last_modified,
+        };
         Ok(file)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use warp::http::Method;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn get() -> ConditionalRequest {
+        ConditionalRequest {
+            method: Method::GET,
+            if_none_match: None,
+            if_modified_since: None,
+        }
+    }
+
+    // A served directory next to a file that must stay private
+    fn served(name: &str) -> (TempDir, FileServer) {
+        let dir = TempDir::new(name);
+        dir.write("secret.txt", "secret");
+        dir.write("files/a/b/c/inner.txt", "inner");
+        let server = FileServer::new(dir.join("files"), false);
+        (dir, server)
+    }
+
+    fn is_traversal(result: anyhow::Result<ServedFile>) -> bool {
+        result.is_err_and(|e| {
+            matches!(
+                e.downcast_ref::<FileServerError>(),
+                Some(FileServerError::PathTraversal(_))
+            )
+        })
+    }
+
+    #[tokio::test]
+    async fn serves_files_within_the_directory() {
+        let (_dir, server) = served("files-within");
+        let file = server.serve(Path::new("a/b/c/inner.txt"), &get()).await;
+        assert_eq!(file.unwrap().data.unwrap(), b"inner");
+        // Going up is fine as long as it stays inside
+        let file = server
+            .serve(Path::new("a/b/../b/c/inner.txt"), &get())
+            .await;
+        assert_eq!(file.unwrap().data.unwrap(), b"inner");
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_escaping_the_directory() {
+        let (_dir, server) = served("files-traversal");
+        for path in [
+            "../secret.txt",
+            "a/../../secret.txt",
+            "a/b/c/../../../../secret.txt",
+            "a/b/c/../../../../../../../../../../etc/passwd",
+            "./../secret.txt",
+        ] {
+            let served = server.serve(Path::new(path), &get()).await;
+            assert!(is_traversal(served), "{path}");
+        }
+        let secret = std::env::temp_dir().join("etc/passwd");
+        assert!(is_traversal(server.serve(&secret, &get()).await));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn follows_links_outside_only_when_asked() {
+        let (dir, server) = served("files-symlinks");
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("files/link.txt")).unwrap();
+        let refused = server.serve(Path::new("link.txt"), &get()).await;
+        assert!(refused.is_err_and(|e| matches!(
+            e.downcast_ref::<FileServerError>(),
+            Some(FileServerError::SymlinkEscape(_))
+        )));
+
+        let server = FileServer::new(dir.join("files"), true);
+        let file = server.serve(Path::new("link.txt"), &get()).await;
+        assert_eq!(file.unwrap().data.unwrap(), b"secret");
+    }
+
+    #[tokio::test]
+    async fn skips_reading_fresh_files() {
+        let (_dir, server) = served("files-fresh");
+        let file = server
+            .serve(Path::new("a/b/c/inner.txt"), &get())
+            .await
+            .unwrap();
+        let conditions = ConditionalRequest {
+            if_none_match: Some(file.etag.clone()),
+            ..get()
+        };
+        let fresh = server
+            .serve(Path::new("a/b/c/inner.txt"), &conditions)
+            .await;
+        assert!(fresh.unwrap().data.is_none());
+    }
+
+    fn policies(config: &str) -> FilePolicies {
+        let config: crate::blog_config::BlogConfig = toml::from_str(config).unwrap();
+        FilePolicies::new(config.files).unwrap()
+    }
+
+    fn header(policy: &ResolvedFilePolicy, name: &str) -> Option<String> {
+        policy
+            .headers
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn prefixes_match_whole_path_components() {
+        let policies = policies(concat!(
+            "[[files]]\n",
+            "prefix = \"/photos/\"\n",
+            "headers = { X-Robots-Tag = \"noimageindex\" }\n",
+        ));
+        for path in [
+            "photos",
+            "photos/a.jpg",
+            "photos/2024/a.jpg",
+            "./photos/a.jpg",
+        ] {
+            let policy = policies.resolve(Path::new(path));
+            assert_eq!(
+                header(&policy, "x-robots-tag").as_deref(),
+                Some("noimageindex"),
+                "{path}"
+            );
+        }
+        for path in ["photos2/a.jpg", "photo", "a/photos/b.jpg", "a.jpg"] {
+            assert!(
+                policies.resolve(Path::new(path)).headers.is_empty(),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn the_longest_prefix_wins_setting_by_setting() {
+        // Listed longest first, to show the order doesn't matter
+        let policies = policies(concat!(
+            "[[files]]\n",
+            "prefix = \"photos/raw\"\n",
+            "headers = { X-A = \"raw\" }\n",
+            "hotlink_protection = true\n",
+            "[[files]]\n",
+            "prefix = \"photos\"\n",
+            "headers = { X-A = \"photos\", Link = \"</license>; rel=\\\"license\\\"\" }\n",
+            "hotlink_allowlist = [\"Friend.example\"]\n",
+            "[[files]]\n",
+            "headers = { X-A = \"all\", X-B = \"all\" }\n",
+        ));
+
+        let raw = policies.resolve(Path::new("photos/raw/1.jpg"));
+        assert_eq!(header(&raw, "x-a").as_deref(), Some("raw"));
+        assert_eq!(header(&raw, "x-b").as_deref(), Some("all"));
+        assert_eq!(
+            header(&raw, "link").as_deref(),
+            Some("</license>; rel=\"license\"")
+        );
+        assert!(raw.hotlink_protection);
+        // The allowlist comes from the shorter prefix
+        assert_eq!(raw.hotlink_allowlist, ["friend.example"]);
+
+        let photo = policies.resolve(Path::new("photos/1.jpg"));
+        assert_eq!(header(&photo, "x-a").as_deref(), Some("photos"));
+        assert!(!photo.hotlink_protection);
+
+        let other = policies.resolve(Path::new("notes.txt"));
+        assert_eq!(header(&other, "x-a").as_deref(), Some("all"));
+        assert_eq!(header(&other, "link"), None);
+    }
+
+    #[test]
+    fn longer_prefixes_can_turn_the_protection_off() {
+        let policies = policies(concat!(
+            "[[files]]\n",
+            "hotlink_protection = true\n",
+            "[[files]]\n",
+            "prefix = \"public\"\n",
+            "hotlink_protection = false\n",
+        ));
+        let referer = Some("https://other.example/page");
+        assert!(!policies
+            .resolve(Path::new("a.jpg"))
+            .allows_referer(referer, &[]));
+        assert!(policies
+            .resolve(Path::new("public/a.jpg"))
+            .allows_referer(referer, &[]));
+    }
+
+    #[test]
+    fn hotlinks_are_refused_from_other_sites_only() {
+        let policies = policies(concat!(
+            "[[files]]\n",
+            "hotlink_protection = true\n",
+            "hotlink_allowlist = [\"friend.example\"]\n",
+        ));
+        let policy = policies.resolve(Path::new("a.jpg"));
+        let own = ["blog.example:8080", "BLOG.example"];
+        for (referer, allowed) in [
+            (None, true),
+            (Some(""), true),
+            (Some("not a url"), true),
+            (Some("https://blog.example/blog/post"), true),
+            (Some("http://Blog.Example:8080/"), true),
+            (Some("https://friend.example/"), true),
+            (Some("https://www.friend.example/"), true),
+            (Some("https://notfriend.example/"), false),
+            (Some("https://other.example/"), false),
+            (Some("https://blog.example.other.example/"), false),
+        ] {
+            assert_eq!(policy.allows_referer(referer, &own), allowed, "{referer:?}");
+        }
+        // Without the protection, anything goes
+        let open = FilePolicies::default().resolve(Path::new("a.jpg"));
+        assert!(open.allows_referer(Some("https://other.example/"), &own));
+    }
+
+    #[test]
+    fn refuses_invalid_headers() {
+        let config: crate::blog_config::BlogConfig = toml::from_str(concat!(
+            "[[files]]\n",
+            "prefix = \"photos\"\n",
+            "headers = { \"Bad Name\" = \"x\" }\n",
+        ))
+        .unwrap();
+        let error = FilePolicies::new(config.files).err().unwrap();
+        assert!(error.to_string().contains("Bad Name"), "{error}");
+    }
+}
@@ -1,24 +1,117 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use handlebars::Handlebars;
+use chrono::{DateTime, Datelike, Utc};
+use handlebars::{Handlebars, RenderError};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::Serialize;
 
-use crate::blog_storage::{BlogEntry, BlogInfo};
+use crate::blog_storage::{
+    author_slug, AdminEntry, AuthorSummary, BlogEntry, BlogEntryRef, BlogInfo, Breadcrumb,
+    PostMetadata, Section,
+};
+use crate::diff::{DiffLine, DiffStats};
+use crate::markdown::cut_text;
+use crate::page_storage::Page;
+use crate::paging::Pagination;
+use crate::search::SearchResult;
+use crate::stats::PublicStats;
+use crate::template_helpers;
+use crate::theme::Theme;
 
+const ADMIN_ENTRIES: &str = "admin_entries";
+const ARCHIVE: &str = "archive";
+const ARCHIVE_FOOTER: &str = "archive_footer";
+const ARCHIVE_HEADER: &str = "archive_header";
+const ARCHIVE_YEAR: &str = "archive_year";
+const AUTHOR: &str = "author";
 const BLOG_ENTRY: &str = "blog_entry";
 const BLOG_ENTRY_NOT_FOUND: &str = "entry_not_found";
+const DIFF: &str = "diff";
+const ERROR: &str = "error";
+const FORBIDDEN: &str = "forbidden";
 const HOME: &str = "home";
+const PAGE: &str = "page";
+const SEARCH: &str = "search";
+const SECTION: &str = "section";
+const STATS: &str = "stats";
+const TAG_LISTING: &str = "tag_listing";
 
 const HANDLEBARS_RELOAD_SCRIPT: &str = include_str!("../static/hot_reload.js");
 const HANDLEBARS_RELOAD_PARTIAL: &str = "hot_reload_script";
+const SKIP_LINK_PARTIAL: &str = "skip_link";
+const META_PARTIAL: &str = "meta";
+const PAGINATION_PARTIAL: &str = "pagination";
+pub const PARTIALS_DIR: &str = "partials";
+// About what the link previews show before cutting it themselves
+const OG_DESCRIPTION_CHARS: usize = 160;
+// Themes made before the skip links don't have one
+const SKIP_LINK_FALLBACK: &str = include_str!("../static/skip_link.handlebars");
+// The theme-color and the icons, for themes made before them
+const META_FALLBACK: &str = include_str!("../static/meta.handlebars");
+// The links to the other pages of a listing, given its flattened Pagination
+const PAGINATION_FALLBACK: &str = include_str!("../static/pagination.handlebars");
+// Development only page, used when the theme doesn't bother providing its own
+const DIFF_FALLBACK: &str = include_str!("../static/diff.handlebars");
+// Themes made before the incident IDs don't have an error page
+const ERROR_FALLBACK: &str = include_str!("../static/error.handlebars");
+// The archive is rendered in pieces, so that big ones can be streamed. The
+// whole page is only their composition, which keeps both ways of rendering it
+// byte for byte the same
+const ARCHIVE_TEMPLATE: &str =
+    "{{> archive_header}}{{#each years}}{{> archive_year}}{{/each}}{{> archive_footer}}";
+// Themes made before the archive don't have its pieces
+const ARCHIVE_HEADER_FALLBACK: &str = include_str!("../static/archive_header.handlebars");
+const ARCHIVE_YEAR_FALLBACK: &str = include_str!("../static/archive_year.handlebars");
+const ARCHIVE_FOOTER_FALLBACK: &str = include_str!("../static/archive_footer.handlebars");
+// For the themes written before the author pages
+const AUTHOR_FALLBACK: &str = include_str!("../static/author.handlebars");
+// Meant for the author only, so it isn't part of the themes
+const ADMIN_ENTRIES_TEMPLATE: &str = include_str!("../static/admin_entries.handlebars");
+
+// Every partials/{name}.handlebars of the theme is available as {{> name}},
+// and may replace the built-in ones
+fn register_theme_partials(handlebars: &mut Handlebars, dir: &Path) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("handlebars") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        handlebars.register_partial(name, std::fs::read_to_string(&path)?)?;
+    }
+    Ok(())
+}
 
 fn load_handlebars_theme<P: AsRef<Path>>(path: P) -> anyhow::Result<Handlebars<'static>> {
+    const AUTHOR_FILE: &str = "author.handlebars";
     const BLOG_ENTRY_FILE: &str = "blog_entry.handlebars";
     const BLOG_ENTRY_NOT_FOUND_FILE: &str = "entry_not_found.handlebars";
+    const DIFF_FILE: &str = "diff.handlebars";
+    const ERROR_FILE: &str = "error.handlebars";
+    const FORBIDDEN_FILE: &str = "forbidden.handlebars";
     const HOME_FILE: &str = "home.handlebars";
+    const PAGE_FILE: &str = "page.handlebars";
+    const SEARCH_FILE: &str = "search.handlebars";
+    const SECTION_FILE: &str = "section.handlebars";
+    const STATS_FILE: &str = "stats.handlebars";
+    const TAG_LISTING_FILE: &str = "tag_listing.handlebars";
 
     let mut handlebars = Handlebars::new();
+    template_helpers::register(&mut handlebars);
     handlebars.register_partial(HANDLEBARS_RELOAD_PARTIAL, HANDLEBARS_RELOAD_SCRIPT)?;
+    handlebars.register_partial(SKIP_LINK_PARTIAL, SKIP_LINK_FALLBACK)?;
+    handlebars.register_partial(META_PARTIAL, META_FALLBACK)?;
+    handlebars.register_partial(PAGINATION_PARTIAL, PAGINATION_FALLBACK)?;
+    register_theme_partials(&mut handlebars, &path.as_ref().join(PARTIALS_DIR))?;
+    handlebars.register_template_string(ADMIN_ENTRIES, ADMIN_ENTRIES_TEMPLATE)?;
     handlebars.register_template_string(
         BLOG_ENTRY,
         std::fs::read_to_string(path.as_ref().join(BLOG_ENTRY_FILE))?,
@@ -29,28 +122,333 @@ fn load_handlebars_theme<P: AsRef<Path>>(path: P) -> anyhow::Result<Handlebars<'
         std::fs::read_to_string(path.as_ref().join(BLOG_ENTRY_NOT_FOUND_FILE))?,
     )?;
 
+    handlebars.register_template_string(ARCHIVE, ARCHIVE_TEMPLATE)?;
+    for (name, fallback) in [
+        (ARCHIVE_HEADER, ARCHIVE_HEADER_FALLBACK),
+        (ARCHIVE_YEAR, ARCHIVE_YEAR_FALLBACK),
+        (ARCHIVE_FOOTER, ARCHIVE_FOOTER_FALLBACK),
+    ] {
+        let piece_path = path.as_ref().join(format!("{name}.handlebars"));
+        let piece = if piece_path.exists() {
+            std::fs::read_to_string(piece_path)?
+        } else {
+            fallback.to_owned()
+        };
+        handlebars.register_template_string(name, piece)?;
+    }
+
+    let author_path = path.as_ref().join(AUTHOR_FILE);
+    let author = if author_path.exists() {
+        std::fs::read_to_string(author_path)?
+    } else {
+        AUTHOR_FALLBACK.to_owned()
+    };
+    handlebars.register_template_string(AUTHOR, author)?;
+
+    let diff_path = path.as_ref().join(DIFF_FILE);
+    let diff = if diff_path.exists() {
+        std::fs::read_to_string(diff_path)?
+    } else {
+        DIFF_FALLBACK.to_owned()
+    };
+    handlebars.register_template_string(DIFF, diff)?;
+
+    let error_path = path.as_ref().join(ERROR_FILE);
+    let error = if error_path.exists() {
+        std::fs::read_to_string(error_path)?
+    } else {
+        ERROR_FALLBACK.to_owned()
+    };
+    handlebars.register_template_string(ERROR, error)?;
+
+    handlebars.register_template_string(
+        FORBIDDEN,
+        std::fs::read_to_string(path.as_ref().join(FORBIDDEN_FILE))?,
+    )?;
+
     handlebars.register_template_string(
         HOME,
         std::fs::read_to_string(path.as_ref().join(HOME_FILE))?,
     )?;
+
+    handlebars.register_template_string(
+        PAGE,
+        std::fs::read_to_string(path.as_ref().join(PAGE_FILE))?,
+    )?;
+
+    handlebars.register_template_string(
+        SEARCH,
+        std::fs::read_to_string(path.as_ref().join(SEARCH_FILE))?,
+    )?;
+
+    handlebars.register_template_string(
+        SECTION,
+        std::fs::read_to_string(path.as_ref().join(SECTION_FILE))?,
+    )?;
+
+    handlebars.register_template_string(
+        STATS,
+        std::fs::read_to_string(path.as_ref().join(STATS_FILE))?,
+    )?;
+
+    handlebars.register_template_string(
+        TAG_LISTING,
+        std::fs::read_to_string(path.as_ref().join(TAG_LISTING_FILE))?,
+    )?;
     Ok(handlebars)
 }
 
 pub struct HandlebarsSupport {
     handlebars: Handlebars<'static>,
+    theme: Theme,
     theme_path: PathBuf,
+    profile_links: Vec<String>,
+    // Without a trailing slash
+    site_url: String,
+    // Where the routes are mounted, "" at the root. Added by {{link_to}}
+    mount_path: String,
+}
+
+// What the link previews of social networks show, through the Open Graph and
+// Twitter card <meta> tags of the meta partial. Flattened into the pages
+// having one: {{canonical_url}}, {{og_title}}, {{og_description}} and
+// {{og_image}}, the urls being absolute
+#[derive(Serialize)]
+struct OpenGraph {
+    canonical_url: String,
+    og_title: String,
+    og_description: String,
+    og_image: Option<String>,
 }
 
 #[derive(Serialize)]
 struct HomeContent {
     blog_info: BlogInfo,
     important_entries: Vec<BlogEntry>,
+    #[serde(flatten)]
+    pagination: Pagination,
+    // For an author menu
+    authors: Vec<AuthorSummary>,
+    // Set when previewing the blog as it was at that date
+    as_of: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    open_graph: OpenGraph,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ArchiveEntry {
+    slug: String,
+    title: String,
+    publish_date: DateTime<Utc>,
+    // e.g. Mar 02
+    date: String,
+    // e.g. March
+    month: String,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveMonth {
+    month: u32,
+    // e.g. March
+    month_name: String,
+    entries: Vec<ArchiveEntry>,
+}
+
+// The entries of the year both at once and by month, newest first. Themes
+// made before the months list only the entries
+#[derive(Serialize)]
+pub struct ArchiveYear {
+    year: i32,
+    months: Vec<ArchiveMonth>,
+    entries: Vec<ArchiveEntry>,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveContent {
+    blog_info: BlogInfo,
+    total: usize,
+    years: Vec<ArchiveYear>,
+    as_of: Option<DateTime<Utc>>,
+}
+
+impl ArchiveContent {
+    // The pieces of the page, in order
+    pub fn parts(&self) -> Vec<ArchivePart> {
+        std::iter::once(ArchivePart::Header)
+            .chain((0..self.years.len()).map(ArchivePart::Year))
+            .chain(std::iter::once(ArchivePart::Footer))
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ArchivePart {
+    Header,
+    Year(usize),
+    Footer,
+}
+
+#[derive(Serialize)]
+struct SharedPreview {
+    expires_at: DateTime<Utc>,
+}
+
+// Computed when rendering rather than when parsing, so that it stays correct
+// while the server keeps running
+#[derive(Serialize)]
+pub struct EntryAge {
+    pub age_days: i64,
+    pub is_stale: bool,
+}
+
+impl EntryAge {
+    pub fn new(blog_entry: &BlogEntry, stale_after_days: Option<i64>, now: DateTime<Utc>) -> Self {
+        let metadata = &blog_entry.description;
+        let last_change = metadata.updated_date.unwrap_or(metadata.publish_date);
+        let age_days = (now - last_change).num_days().max(0);
+        let is_stale = !metadata.evergreen
+            && stale_after_days.is_some_and(|stale_after_days| age_days > stale_after_days);
+        Self { age_days, is_stale }
+    }
 }
 
 #[derive(Serialize)]
 struct BlogContent {
     blog_info: BlogInfo,
     blog_entry: BlogEntry,
+    byline: String,
+    author_links: Vec<AuthorLink>,
+    // The entries published right before and right after
+    prev_entry: Option<BlogEntryRef>,
+    next_entry: Option<BlogEntryRef>,
+    breadcrumbs: Vec<Breadcrumb>,
+    #[serde(flatten)]
+    age: EntryAge,
+    shared_preview: Option<SharedPreview>,
+    // Shown while writing only
+    accessibility_warnings: Vec<String>,
+    #[serde(flatten)]
+    open_graph: OpenGraph,
+}
+
+#[derive(Serialize)]
+struct SectionContent {
+    blog_info: BlogInfo,
+    section: Section,
+    entries: Vec<BlogEntry>,
+    #[serde(flatten)]
+    pagination: Pagination,
+    breadcrumbs: Vec<Breadcrumb>,
+}
+
+// The page of everybody credited by an entry
+#[derive(Serialize)]
+struct AuthorLink {
+    name: String,
+    url: String,
+}
+
+fn author_links(metadata: &PostMetadata) -> Vec<AuthorLink> {
+    let mut links: Vec<AuthorLink> = vec![];
+    for contributor in &metadata.authors {
+        let url = format!("/blog/author/{}", author_slug(&contributor.name));
+        if !links.iter().any(|link| link.url == url) {
+            links.push(AuthorLink {
+                name: contributor.name.clone(),
+                url,
+            });
+        }
+    }
+    links
+}
+
+#[derive(Serialize)]
+struct AuthorContent {
+    blog_info: BlogInfo,
+    author: String,
+    // Newest first
+    entries: Vec<BlogEntry>,
+    #[serde(flatten)]
+    pagination: Pagination,
+    as_of: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct TagListingContent {
+    blog_info: BlogInfo,
+    tag: String,
+    entries: Vec<BlogEntry>,
+    #[serde(flatten)]
+    pagination: Pagination,
+    as_of: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct StatsContent<'a> {
+    blog_info: BlogInfo,
+    stats: &'a PublicStats,
+}
+
+#[derive(Serialize)]
+struct SearchContent {
+    blog_info: BlogInfo,
+    query: String,
+    // The query as it goes in the links to the other pages
+    query_param: String,
+    results: Vec<SearchResult>,
+    #[serde(flatten)]
+    pagination: Pagination,
+}
+
+#[derive(Serialize)]
+struct PageContent {
+    blog_info: BlogInfo,
+    page: Page,
+}
+
+#[derive(Serialize)]
+struct DiffContent {
+    blog_info: BlogInfo,
+    entry: String,
+    changed: bool,
+    stats: DiffStats,
+    lines: Vec<DiffLine>,
+}
+
+// Only the links leading to routes that can actually serve the entry
+#[derive(Serialize, Default)]
+pub struct AdminEntryLinks {
+    pub view: Option<String>,
+    pub preview: Option<String>,
+    pub diff: Option<String>,
+    pub purge: bool,
+}
+
+#[derive(Serialize)]
+pub struct AdminRow {
+    #[serde(flatten)]
+    pub entry: AdminEntry,
+    pub links: AdminEntryLinks,
+}
+
+#[derive(Serialize)]
+struct AdminEntriesContent<'a> {
+    blog_info: BlogInfo,
+    sort: &'a str,
+    total: usize,
+    entries: Vec<AdminRow>,
+}
+
+#[derive(Serialize)]
+struct ErrorContent {
+    blog_info: BlogInfo,
+    incident: String,
+}
+
+#[derive(Serialize)]
+struct ForbiddenContent {
+    blog_info: BlogInfo,
+    reason: String,
 }
 
 #[derive(Serialize)]
@@ -61,42 +459,398 @@ struct NotFoundContent {
 
 impl HandlebarsSupport {
     pub fn new<P: AsRef<Path>>(theme_path: P) -> anyhow::Result<Self> {
-        let handlebars = load_handlebars_theme(&theme_path)?;
+        let mut handlebars = load_handlebars_theme(&theme_path)?;
+        template_helpers::register_link_to(&mut handlebars, "");
         Ok(Self {
             handlebars,
+            theme: Theme::load(theme_path.as_ref())?,
             theme_path: theme_path.as_ref().to_path_buf(),
+            profile_links: vec![],
+            site_url: String::new(),
+            mount_path: String::new(),
         })
     }
 
+    // For an engine whose routes are mounted under a prefix, e.g. /journal
+    pub fn with_mount_path(mut self, mount_path: &str) -> Self {
+        self.mount_path = mount_path.trim_end_matches('/').to_owned();
+        template_helpers::register_link_to(&mut self.handlebars, &self.mount_path);
+        self
+    }
+
+    // Linked as rel="me" from every page
+    pub fn with_profile_links(mut self, links: Vec<String>) -> Self {
+        self.profile_links = links;
+        self
+    }
+
+    // Makes the canonical and Open Graph urls absolute
+    pub fn with_site_url(mut self, site_url: &str) -> Self {
+        self.site_url = site_url.trim_end_matches('/').to_owned();
+        self
+    }
+
     pub fn reload_theme(&mut self) -> anyhow::Result<()> {
-        let handlebars = load_handlebars_theme(&self.theme_path)?;
+        let mut handlebars = load_handlebars_theme(&self.theme_path)?;
+        template_helpers::register_link_to(&mut handlebars, &self.mount_path);
+        self.theme = Theme::load(&self.theme_path)?;
         self.handlebars = handlebars;
         Ok(())
     }
 
-    pub fn format_blog_entry(&self, blog_info: BlogInfo, blog_entry: &BlogEntry) -> String {
+    // What the theme and the startup config add to every page. The theme's
+    // accent color wins over the blog's one
+    fn themed(&self, mut blog_info: BlogInfo) -> BlogInfo {
+        if self.theme.accent_color.is_some() {
+            blog_info.accent_color = self.theme.accent_color.clone();
+        }
+        blog_info.favicons = self.theme.favicons.clone();
+        blog_info.profile_links = self.profile_links.clone();
+        blog_info
+    }
+
+    fn absolute_url(&self, url: &str) -> String {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return url.to_owned();
+        }
+        format!("{}/{}", self.site_url, url.trim_start_matches('/'))
+    }
+
+    fn entry_open_graph(&self, entry: &BlogEntry) -> OpenGraph {
+        OpenGraph {
+            canonical_url: self.absolute_url(&format!("blog/{}", entry.slug)),
+            og_title: entry.description.title.clone(),
+            // Link previews are summaries too, so the warning replaces them
+            og_description: match (
+                &entry.description.description,
+                &entry.description.content_warning,
+            ) {
+                (Some(description), _) => description.clone(),
+                (None, Some(warning)) => format!("Content warning: {warning}"),
+                (None, None) => cut_text(&entry.excerpt_text, OG_DESCRIPTION_CHARS),
+            },
+            og_image: entry.description.image.as_deref().map(|image| {
+                if image.starts_with('/') || image.contains("://") {
+                    self.absolute_url(image)
+                } else {
+                    self.absolute_url(&format!("files/{image}"))
+                }
+            }),
+        }
+    }
+
+    // And the entry's one wins over both
+    fn themed_for_entry(&self, blog_info: BlogInfo, entry: &BlogEntry) -> BlogInfo {
+        let mut blog_info = self.themed(blog_info);
+        if entry.description.accent_color.is_some() {
+            blog_info.accent_color = entry.description.accent_color.clone();
+        }
+        blog_info
+    }
+
+    pub fn format_blog_entry(
+        &self,
+        blog_info: BlogInfo,
+        blog_entry: &BlogEntry,
+        breadcrumbs: Vec<Breadcrumb>,
+        (prev_entry, next_entry): (Option<BlogEntryRef>, Option<BlogEntryRef>),
+        age: EntryAge,
+        dev: bool,
+    ) -> Result<String, RenderError> {
         let entry_info = BlogContent {
-            blog_info,
+            blog_info: self.themed_for_entry(blog_info, blog_entry),
             blog_entry: blog_entry.clone(),
+            byline: blog_entry.description.byline(),
+            author_links: author_links(&blog_entry.description),
+            prev_entry,
+            next_entry,
+            breadcrumbs,
+            age,
+            shared_preview: None,
+            accessibility_warnings: if dev {
+                blog_entry.accessibility_warnings.clone()
+            } else {
+                vec![]
+            },
+            open_graph: self.entry_open_graph(blog_entry),
         };
-        self.handlebars.render(BLOG_ENTRY, &entry_info).unwrap()
+        self.handlebars.render(BLOG_ENTRY, &entry_info)
     }
 
-    pub fn format_home(&self, blog_info: BlogInfo, important_entries: Vec<BlogEntry>) -> String {
+    pub fn format_shared_preview(
+        &self,
+        blog_info: BlogInfo,
+        blog_entry: &BlogEntry,
+        breadcrumbs: Vec<Breadcrumb>,
+        age: EntryAge,
+        expires_at: DateTime<Utc>,
+    ) -> Result<String, RenderError> {
+        let entry_info = BlogContent {
+            blog_info: self.themed_for_entry(blog_info, blog_entry),
+            blog_entry: blog_entry.clone(),
+            byline: blog_entry.description.byline(),
+            author_links: author_links(&blog_entry.description),
+            // Not published yet, it isn't part of the sequence
+            prev_entry: None,
+            next_entry: None,
+            breadcrumbs,
+            age,
+            shared_preview: Some(SharedPreview { expires_at }),
+            accessibility_warnings: vec![],
+            open_graph: self.entry_open_graph(blog_entry),
+        };
+        self.handlebars.render(BLOG_ENTRY, &entry_info)
+    }
+
+    pub fn format_home(
+        &self,
+        blog_info: BlogInfo,
+        important_entries: Vec<BlogEntry>,
+        pagination: Pagination,
+        authors: Vec<AuthorSummary>,
+        as_of: Option<DateTime<Utc>>,
+    ) -> Result<String, RenderError> {
+        let canonical_path = match pagination.current_page {
+            1 => "blog".to_owned(),
+            page => format!("blog?page={page}"),
+        };
+        let open_graph = OpenGraph {
+            canonical_url: self.absolute_url(&canonical_path),
+            og_title: blog_info.name.clone(),
+            og_description: blog_info.description.clone().unwrap_or_default(),
+            og_image: None,
+        };
         let home_info = HomeContent {
-            blog_info,
+            blog_info: self.themed(blog_info),
             important_entries,
+            pagination,
+            authors,
+            as_of,
+            open_graph,
+        };
+        self.handlebars.render(HOME, &home_info)
+    }
+
+    pub fn format_section(
+        &self,
+        blog_info: BlogInfo,
+        section: Section,
+        entries: Vec<BlogEntry>,
+        pagination: Pagination,
+        breadcrumbs: Vec<Breadcrumb>,
+    ) -> Result<String, RenderError> {
+        let section_info = SectionContent {
+            blog_info: self.themed(blog_info),
+            section,
+            entries,
+            pagination,
+            breadcrumbs,
+        };
+        self.handlebars.render(SECTION, &section_info)
+    }
+
+    pub fn format_tag_listing(
+        &self,
+        blog_info: BlogInfo,
+        tag: String,
+        entries: Vec<BlogEntry>,
+        pagination: Pagination,
+        as_of: Option<DateTime<Utc>>,
+    ) -> Result<String, RenderError> {
+        let tag_info = TagListingContent {
+            blog_info: self.themed(blog_info),
+            tag,
+            entries,
+            pagination,
+            as_of,
         };
-        self.handlebars.render(HOME, &home_info).unwrap()
+        self.handlebars.render(TAG_LISTING, &tag_info)
     }
 
-    pub fn format_not_found(&self, blog_info: BlogInfo, entry_not_found: String) -> String {
+    pub fn format_author(
+        &self,
+        blog_info: BlogInfo,
+        author: String,
+        entries: Vec<BlogEntry>,
+        pagination: Pagination,
+        as_of: Option<DateTime<Utc>>,
+    ) -> Result<String, RenderError> {
+        let author_info = AuthorContent {
+            blog_info: self.themed(blog_info),
+            author,
+            entries,
+            pagination,
+            as_of,
+        };
+        self.handlebars.render(AUTHOR, &author_info)
+    }
+
+    pub fn format_stats(
+        &self,
+        blog_info: BlogInfo,
+        stats: &PublicStats,
+    ) -> Result<String, RenderError> {
+        let stats_info = StatsContent {
+            blog_info: self.themed(blog_info),
+            stats,
+        };
+        self.handlebars.render(STATS, &stats_info)
+    }
+
+    pub fn format_search_results(
+        &self,
+        blog_info: BlogInfo,
+        query: String,
+        results: Vec<SearchResult>,
+        pagination: Pagination,
+    ) -> Result<String, RenderError> {
+        let search_info = SearchContent {
+            blog_info: self.themed(blog_info),
+            query_param: utf8_percent_encode(&query, NON_ALPHANUMERIC).to_string(),
+            query,
+            results,
+            pagination,
+        };
+        self.handlebars.render(SEARCH, &search_info)
+    }
+
+    // Entries newest first, grouped by year and month
+    pub fn archive_content(
+        &self,
+        blog_info: BlogInfo,
+        entries: &[Arc<BlogEntry>],
+        as_of: Option<DateTime<Utc>>,
+    ) -> ArchiveContent {
+        let mut years: Vec<ArchiveYear> = vec![];
+        for entry in entries {
+            let publish_date = entry.description.publish_date;
+            let archived = ArchiveEntry {
+                slug: entry.slug.clone(),
+                title: entry.description.title.clone(),
+                publish_date,
+                date: publish_date.format("%b %d").to_string(),
+                month: publish_date.format("%B").to_string(),
+            };
+            let year = match years.last_mut() {
+                Some(year) if year.year == publish_date.year() => year,
+                _ => {
+                    years.push(ArchiveYear {
+                        year: publish_date.year(),
+                        months: vec![],
+                        entries: vec![],
+                    });
+                    years.last_mut().expect("A year was just added")
+                }
+            };
+            match year.months.last_mut() {
+                Some(month) if month.month == publish_date.month() => {
+                    month.entries.push(archived.clone())
+                }
+                _ => year.months.push(ArchiveMonth {
+                    month: publish_date.month(),
+                    month_name: archived.month.clone(),
+                    entries: vec![archived.clone()],
+                }),
+            }
+            year.entries.push(archived);
+        }
+        ArchiveContent {
+            blog_info: self.themed(blog_info),
+            total: entries.len(),
+            years,
+            as_of,
+        }
+    }
+
+    pub fn format_archive(&self, content: &ArchiveContent) -> Result<String, RenderError> {
+        self.handlebars.render(ARCHIVE, content)
+    }
+
+    pub fn format_archive_part(
+        &self,
+        content: &ArchiveContent,
+        part: ArchivePart,
+    ) -> Result<String, RenderError> {
+        match part {
+            ArchivePart::Header => self.handlebars.render(ARCHIVE_HEADER, content),
+            ArchivePart::Year(i) => self.handlebars.render(ARCHIVE_YEAR, &content.years[i]),
+            ArchivePart::Footer => self.handlebars.render(ARCHIVE_FOOTER, content),
+        }
+    }
+
+    pub fn format_page(&self, blog_info: BlogInfo, page: Page) -> Result<String, RenderError> {
+        let page_info = PageContent {
+            blog_info: self.themed(blog_info),
+            page,
+        };
+        self.handlebars.render(PAGE, &page_info)
+    }
+
+    pub fn format_not_found(
+        &self,
+        blog_info: BlogInfo,
+        entry_not_found: String,
+    ) -> Result<String, RenderError> {
         let entry_info = NotFoundContent {
-            blog_info,
+            blog_info: self.themed(blog_info),
             entry_not_found,
         };
-        self.handlebars
-            .render(BLOG_ENTRY_NOT_FOUND, &entry_info)
-            .unwrap()
+        self.handlebars.render(BLOG_ENTRY_NOT_FOUND, &entry_info)
+    }
+
+    pub fn format_diff(
+        &self,
+        blog_info: BlogInfo,
+        entry: String,
+        lines: Vec<DiffLine>,
+    ) -> Result<String, RenderError> {
+        let stats = DiffStats::new(&lines);
+        let diff_info = DiffContent {
+            blog_info: self.themed(blog_info),
+            entry,
+            changed: stats.added + stats.removed > 0,
+            stats,
+            lines,
+        };
+        self.handlebars.render(DIFF, &diff_info)
+    }
+
+    pub fn format_admin_entries(
+        &self,
+        blog_info: BlogInfo,
+        sort: &str,
+        entries: Vec<AdminRow>,
+    ) -> Result<String, RenderError> {
+        let admin_info = AdminEntriesContent {
+            blog_info: self.themed(blog_info),
+            sort,
+            total: entries.len(),
+            entries,
+        };
+        self.handlebars.render(ADMIN_ENTRIES, &admin_info)
+    }
+
+    pub fn format_forbidden(
+        &self,
+        blog_info: BlogInfo,
+        reason: String,
+    ) -> Result<String, RenderError> {
+        let forbidden_info = ForbiddenContent {
+            blog_info: self.themed(blog_info),
+            reason,
+        };
+        self.handlebars.render(FORBIDDEN, &forbidden_info)
+    }
+
+    pub fn format_error(
+        &self,
+        blog_info: BlogInfo,
+        incident: String,
+    ) -> Result<String, RenderError> {
+        let error_info = ErrorContent {
+            blog_info: self.themed(blog_info),
+            incident,
+        };
+        self.handlebars.render(ERROR, &error_info)
     }
 }
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::{HeaderValue, StatusCode},
+    reply::{Reply, Response},
+};
+
+// The [identity] table of blog.toml, read at startup only
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    // Profiles elsewhere linking back here, emitted as rel="me" so that
+    // Mastodon and the IndieWeb tools can verify them
+    pub profile_links: Vec<String>,
+    pub webfinger: Option<WebfingerConfig>,
+}
+
+// Lets the blog's domain stand for an account hosted elsewhere: a lookup of
+// acct:me@blog.example can point at acct:me@mastodon.social
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebfingerConfig {
+    // The account itself, e.g. acct:me@mastodon.social
+    pub subject: String,
+    // Other names of the same account, answered too, e.g. acct:me@blog.example
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    // The page showing the account
+    pub profile_page: Option<String>,
+    // Its ActivityPub actor, which Mastodon follows
+    pub actor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebfingerQuery {
+    resource: Option<String>,
+}
+
+// A JSON Resource Descriptor, RFC 7033
+#[derive(Serialize)]
+struct Jrd<'a> {
+    subject: &'a str,
+    aliases: &'a [String],
+    links: Vec<JrdLink<'a>>,
+}
+
+#[derive(Serialize)]
+struct JrdLink<'a> {
+    rel: &'static str,
+    #[serde(rename = "type")]
+    mime_type: &'static str,
+    href: &'a str,
+}
+
+impl WebfingerConfig {
+    fn answers(&self, resource: &str) -> bool {
+        self.subject == resource || self.aliases.iter().any(|alias| alias == resource)
+    }
+
+    fn jrd(&self) -> Jrd<'_> {
+        let profile_page = self.profile_page.as_deref().map(|href| JrdLink {
+            rel: "http://webfinger.net/rel/profile-page",
+            mime_type: "text/html",
+            href,
+        });
+        let actor = self.actor.as_deref().map(|href| JrdLink {
+            rel: "self",
+            mime_type: "application/activity+json",
+            href,
+        });
+        Jrd {
+            subject: &self.subject,
+            aliases: &self.aliases,
+            links: profile_page.into_iter().chain(actor).collect(),
+        }
+    }
+}
+
+pub(crate) fn webfinger(query: WebfingerQuery, config: &WebfingerConfig) -> Response {
+    let mut response = match query.resource {
+        Some(resource) if config.answers(&resource) => {
+            let mut response = warp::reply::json(&config.jrd()).into_response();
+            response.headers_mut().insert(
+                "content-type",
+                HeaderValue::from_static("application/jrd+json"),
+            );
+            response
+        }
+        Some(resource) => warp::reply::with_status(
+            format!("No account named {resource} here"),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response(),
+        None => warp::reply::with_status(
+            "The resource parameter is required",
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response(),
+    };
+    // Looked up from the browser by the clients of other instances
+    response
+        .headers_mut()
+        .insert("access-control-allow-origin", HeaderValue::from_static("*"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> WebfingerConfig {
+        toml::from_str(concat!(
+            "subject = \"acct:crax@mastodon.social\"\n",
+            "aliases = [\"acct:crax@blog.example\"]\n",
+            "profile_page = \"https://mastodon.social/@crax\"\n",
+            "actor = \"https://mastodon.social/users/crax\"\n",
+        ))
+        .unwrap()
+    }
+
+    async fn lookup(
+        resource: Option<&str>,
+        config: &WebfingerConfig,
+    ) -> (StatusCode, String, String) {
+        let response = webfinger(
+            WebfingerQuery {
+                resource: resource.map(str::to_owned),
+            },
+            config,
+        );
+        let status = response.status();
+        let headers = response.headers().clone();
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let content_type = headers
+            .get("content-type")
+            .map(|value| value.to_str().unwrap().to_owned())
+            .unwrap_or_default();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn answers_for_the_subject_and_its_aliases() {
+        for resource in ["acct:crax@mastodon.social", "acct:crax@blog.example"] {
+            let (status, content_type, body) = lookup(Some(resource), &config()).await;
+            assert_eq!(status, StatusCode::OK, "{resource}");
+            assert_eq!(content_type, "application/jrd+json");
+            let jrd: serde_json::Value = serde_json::from_str(&body).unwrap();
+            // Always the account itself, whichever name was looked up
+            assert_eq!(
+                jrd,
+                serde_json::json!({
+                    "subject": "acct:crax@mastodon.social",
+                    "aliases": ["acct:crax@blog.example"],
+                    "links": [
+                        {
+                            "rel": "http://webfinger.net/rel/profile-page",
+                            "type": "text/html",
+                            "href": "https://mastodon.social/@crax",
+                        },
+                        {
+                            "rel": "self",
+                            "type": "application/activity+json",
+                            "href": "https://mastodon.social/users/crax",
+                        },
+                    ],
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn links_are_only_given_when_configured() {
+        let config: WebfingerConfig = toml::from_str("subject = \"acct:me@blog.example\"").unwrap();
+        let (status, _, body) = lookup(Some("acct:me@blog.example"), &config).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            "{\"subject\":\"acct:me@blog.example\",\"aliases\":[],\"links\":[]}"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_other_resources() {
+        for resource in [
+            "acct:someone@mastodon.social",
+            "acct:CRAX@mastodon.social",
+            "crax@mastodon.social",
+            "",
+        ] {
+            let (status, _, body) = lookup(Some(resource), &config()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{resource:?}");
+            assert_eq!(body, format!("No account named {resource} here"));
+        }
+    }
+
+    #[tokio::test]
+    async fn requires_the_resource_parameter() {
+        let (status, _, body) = lookup(None, &config()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "The resource parameter is required");
+    }
+}
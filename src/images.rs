@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use image::{imageops::FilterType, ImageFormat};
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+
+use crate::{
+    artifact_store::{ArtifactStore, THUMBNAIL_CATEGORY},
+    file_server::resolve_within,
+};
+
+pub const DEFAULT_WIDTHS: &[u32] = &[480, 960];
+
+// Added to the title of an image to keep it out of the rewriting
+const NO_RESIZE: &str = "{.no-resize}";
+pub const RESIZABLE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp"];
+
+// Width and height in pixels
+type Dimensions = (u32, u32);
+
+pub struct Thumbnail {
+    pub data: Vec<u8>,
+    pub mime_type: &'static str,
+}
+
+// Rewrites the local images of the rendered posts so that browsers can pick a
+// scaled down copy (served by /files/thumb/{width}/{name}) and reserve their
+// space before they load
+pub struct ResponsiveImages {
+    files_path: PathBuf,
+    follow_symlinks: bool,
+    widths: Vec<u32>,
+    artifacts: Option<Arc<ArtifactStore>>,
+    // Image name -> modification time and size, so that images are only
+    // probed again when they change
+    dimensions: Mutex<HashMap<String, (SystemTime, Dimensions)>>,
+}
+
+impl ResponsiveImages {
+    pub fn new<P: Into<PathBuf>>(
+        files_path: P,
+        follow_symlinks: bool,
+        mut widths: Vec<u32>,
+        artifacts: Option<Arc<ArtifactStore>>,
+    ) -> Self {
+        widths.sort_unstable();
+        widths.dedup();
+        Self {
+            files_path: files_path.into(),
+            follow_symlinks,
+            widths,
+            artifacts,
+            dimensions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn widths(&self) -> &[u32] {
+        &self.widths
+    }
+
+    pub fn rewrite_html(&self, html: &str) -> String {
+        let mut rewritten = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find("<img ") {
+            rewritten.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(end) = rest.find('>') else {
+                break;
+            };
+            let tag = &rest[..=end];
+            rewritten.push_str(&self.rewrite_tag(tag).unwrap_or_else(|| tag.to_owned()));
+            rest = &rest[end + 1..];
+        }
+        rewritten.push_str(rest);
+        rewritten
+    }
+
+    fn rewrite_tag(&self, tag: &str) -> Option<String> {
+        let mut attributes = parse_attributes(tag)?;
+        let title = attributes.iter_mut().find(|(name, _)| name == "title");
+        if let Some((_, title)) = title {
+            if title.contains(NO_RESIZE) {
+                *title = title.replace(NO_RESIZE, "").trim().to_owned();
+                attributes.retain(|(name, value)| name != "title" || !value.is_empty());
+                return Some(format_tag(&attributes));
+            }
+        }
+        let src = attributes.iter().find(|(name, _)| name == "src")?.1.clone();
+        let name = local_image_name(&src)?;
+        let (width, height) = self.dimensions(&name)?;
+        attributes.push(("width".to_owned(), width.to_string()));
+        attributes.push(("height".to_owned(), height.to_string()));
+
+        let mut candidates: Vec<_> = self
+            .widths
+            .iter()
+            .filter(|w| **w < width)
+            .map(|w| format!("/files/thumb/{w}/{} {w}w", &src["/files/".len()..]))
+            .collect();
+        if !candidates.is_empty() {
+            candidates.push(format!("{src} {width}w"));
+            attributes.push(("srcset".to_owned(), candidates.join(", ")));
+            attributes.push((
+                "sizes".to_owned(),
+                format!("(max-width: {width}px) 100vw, {width}px"),
+            ));
+        }
+        Some(format_tag(&attributes))
+    }
+
+    // Only reads the image header. This runs while rendering, which is
+    // synchronous, but the result is cached until the file changes
+    fn dimensions(&self, name: &str) -> Option<Dimensions> {
+        let path = self.files_path.join(name);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        let mut dimensions = self.dimensions.lock().expect("Poisoned image dimensions");
+        if let Some((cached_modified, size)) = dimensions.get(name) {
+            if *cached_modified == modified {
+                return Some(*size);
+            }
+        }
+        let size = match imagesize::size(&path) {
+            Ok(size) => (size.width as u32, size.height as u32),
+            Err(e) => {
+                warn!("Failed to read the size of {path:?}: {e}");
+                return None;
+            }
+        };
+        dimensions.insert(name.to_owned(), (modified, size));
+        Some(size)
+    }
+
+    pub async fn thumbnail(&self, width: u32, name: &str) -> anyhow::Result<Option<Thumbnail>> {
+        let name = percent_decode_str(name).decode_utf8()?;
+        if !self.widths.contains(&width) || !is_resizable(&name) {
+            return Ok(None);
+        }
+        // The name is decoded above, so it may well contain '../'
+        let Ok(path) = resolve_within(
+            &self.files_path,
+            Path::new(name.as_ref()),
+            self.follow_symlinks,
+        )
+        .await
+        else {
+            return Ok(None);
+        };
+        let Ok(metadata) = tokio::fs::metadata(&path).await else {
+            return Ok(None);
+        };
+        let format = ImageFormat::from_path(&path)?;
+        let mime_type = format.to_mime_type();
+        let modified = metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let artifact_name = format!("{width}-{modified}-{name}");
+        if let Some(artifacts) = &self.artifacts {
+            if let Some(data) = artifacts.read(THUMBNAIL_CATEGORY, &artifact_name).await {
+                return Ok(Some(Thumbnail { data, mime_type }));
+            }
+        }
+
+        let source = tokio::fs::read(&path).await?;
+        let data = tokio::task::spawn_blocking(move || resize(&source, format, width)).await??;
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        info!("Generated a {width}px thumbnail of {path:?}");
+        if let Some(artifacts) = &self.artifacts {
+            artifacts
+                .write(THUMBNAIL_CATEGORY, &artifact_name, &data)
+                .await;
+        }
+        Ok(Some(Thumbnail { data, mime_type }))
+    }
+}
+
+// Never scales up: the srcset only lists widths below the original one
+fn resize(source: &[u8], format: ImageFormat, width: u32) -> anyhow::Result<Option<Vec<u8>>> {
+    let image = image::load_from_memory_with_format(source, format)
+        .context("Failed to decode the image")?;
+    if width >= image.width() {
+        return Ok(None);
+    }
+    let height = (image.height() as u64 * width as u64 / image.width() as u64).max(1) as u32;
+    let resized = image.resize_exact(width, height, FilterType::Triangle);
+    let mut data = Cursor::new(vec![]);
+    resized.write_to(&mut data, format)?;
+    Ok(Some(data.into_inner()))
+}
+
+// Images served by the file server: external images are left alone, and so
+// are SVGs, which scale on their own
+fn local_image_name(src: &str) -> Option<String> {
+    let name = src.strip_prefix("/files/")?;
+    let name = percent_decode_str(name).decode_utf8().ok()?;
+    is_resizable(&name).then(|| name.into_owned())
+}
+
+fn is_resizable(name: &str) -> bool {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return false;
+    }
+    Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| RESIZABLE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+// Parses the attributes of the tags comrak writes, which are always double
+// quoted and escaped
+fn parse_attributes(tag: &str) -> Option<Vec<(String, String)>> {
+    let mut rest = tag
+        .strip_prefix("<img")?
+        .trim_end_matches('>')
+        .trim_end_matches('/');
+    let mut attributes = vec![];
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Some(attributes);
+        }
+        let (name, value) = rest.split_once("=\"")?;
+        let (value, tail) = value.split_once('"')?;
+        attributes.push((name.trim().to_owned(), value.to_owned()));
+        rest = tail;
+    }
+}
+
+fn format_tag(attributes: &[(String, String)]) -> String {
+    let attributes: String = attributes
+        .iter()
+        .map(|(name, value)| format!(" {name}=\"{value}\""))
+        .collect();
+    format!("<img{attributes} />")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn write_image(dir: &TempDir, name: &str, width: u32, height: u32) {
+        let image = ImageBuffer::from_pixel(width, height, Rgb([200u8, 100, 50]));
+        image.save(dir.join(name)).unwrap();
+    }
+
+    fn images(dir: &TempDir) -> ResponsiveImages {
+        write_image(dir, "big.png", 1200, 800);
+        write_image(dir, "small.png", 300, 200);
+        write_image(dir, "medium.jpg", 700, 350);
+        ResponsiveImages::new(dir.join(""), false, vec![960, 480, 480], None)
+    }
+
+    #[test]
+    fn probes_the_dimensions_and_lists_the_smaller_widths() {
+        let dir = TempDir::new("images-srcset");
+        let images = images(&dir);
+        assert_eq!(images.widths(), [480, 960]);
+        assert_eq!(
+            images.rewrite_html(r#"<p><img src="/files/big.png" alt="Big" /></p>"#),
+            "<p><img src=\"/files/big.png\" alt=\"Big\" width=\"1200\" height=\"800\" \
+             srcset=\"/files/thumb/480/big.png 480w, /files/thumb/960/big.png 960w, \
+             /files/big.png 1200w\" sizes=\"(max-width: 1200px) 100vw, 1200px\" /></p>"
+        );
+        assert_eq!(
+            images.rewrite_html(r#"<img src="/files/medium.jpg" alt="" />"#),
+            "<img src=\"/files/medium.jpg\" alt=\"\" width=\"700\" height=\"350\" \
+             srcset=\"/files/thumb/480/medium.jpg 480w, /files/medium.jpg 700w\" \
+             sizes=\"(max-width: 700px) 100vw, 700px\" />"
+        );
+        // Nothing smaller to offer
+        assert_eq!(
+            images.rewrite_html(r#"<img src="/files/small.png" alt="Small" />"#),
+            "<img src=\"/files/small.png\" alt=\"Small\" width=\"300\" height=\"200\" />"
+        );
+    }
+
+    #[test]
+    fn leaves_other_images_alone() {
+        let dir = TempDir::new("images-untouched");
+        let images = images(&dir);
+        dir.write("logo.svg", "<svg/>");
+        for html in [
+            r#"<img src="https://example.com/big.png" alt="" />"#,
+            r#"<img src="/files/logo.svg" alt="" />"#,
+            r#"<img src="/files/missing.png" alt="" />"#,
+            r#"<img src="/files/../big.png" alt="" />"#,
+            r#"<p>No image at all</p>"#,
+        ] {
+            assert_eq!(images.rewrite_html(html), html);
+        }
+        assert_eq!(
+            images.rewrite_html(r#"<img src="/files/big.png" alt="" title="{.no-resize}" />"#),
+            r#"<img src="/files/big.png" alt="" />"#
+        );
+        assert_eq!(
+            images.rewrite_html(
+                r#"<img src="/files/big.png" alt="" title="A title {.no-resize}" />"#
+            ),
+            r#"<img src="/files/big.png" alt="" title="A title" />"#
+        );
+    }
+
+    #[test]
+    fn probes_again_when_an_image_changes() {
+        let dir = TempDir::new("images-changed");
+        let images = images(&dir);
+        assert_eq!(images.dimensions("small.png"), Some((300, 200)));
+        write_image(&dir, "small.png", 100, 50);
+        // Modification times may be coarse
+        let later = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("small.png"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(images.dimensions("small.png"), Some((100, 50)));
+    }
+
+    #[tokio::test]
+    async fn scales_images_down_only() {
+        let dir = TempDir::new("images-thumbnails");
+        let images = images(&dir);
+        let thumbnail = images.thumbnail(480, "big.png").await.unwrap().unwrap();
+        assert_eq!(thumbnail.mime_type, "image/png");
+        let scaled = image::load_from_memory(&thumbnail.data).unwrap();
+        assert_eq!((scaled.width(), scaled.height()), (480, 320));
+
+        // Not a configured width, or not smaller than the image
+        assert!(images.thumbnail(300, "big.png").await.unwrap().is_none());
+        assert!(images.thumbnail(480, "small.png").await.unwrap().is_none());
+        assert!(images
+            .thumbnail(480, "missing.png")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(images
+            .thumbnail(480, "..%2fbig.png")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
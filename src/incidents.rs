@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use warp::{
+    http::StatusCode,
+    reply::{Reply, Response},
+};
+
+use crate::clock::SharedClock;
+
+// Enough to look up the ones from a recent user report
+pub const INCIDENTS_KEPT: usize = 256;
+
+// Attached to a 500 response by the handler that gave up. The routes turn it
+// into an incident and replace the body with the error page
+pub(crate) struct Failure {
+    what: String,
+    entry: Option<String>,
+    template: Option<String>,
+    errors: Vec<String>,
+}
+
+impl Failure {
+    pub(crate) fn new(what: impl Into<String>, e: &anyhow::Error) -> Self {
+        Self {
+            what: what.into(),
+            entry: None,
+            template: None,
+            errors: e.chain().map(ToString::to_string).collect(),
+        }
+    }
+
+    pub(crate) fn render(e: &handlebars::RenderError) -> Self {
+        Self {
+            what: "Failed to render a template".to_owned(),
+            entry: None,
+            template: e.template_name.clone(),
+            errors: error_chain(e),
+        }
+    }
+
+    pub(crate) fn entry(mut self, entry: impl Into<String>) -> Self {
+        self.entry = Some(entry.into());
+        self
+    }
+}
+
+impl Reply for Failure {
+    fn into_response(self) -> Response {
+        let mut response =
+            warp::reply::with_status("Internal server error", StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+// Names the entry being served, when a response failed without knowing it
+pub(crate) fn for_entry(mut response: Response, entry: &str) -> Response {
+    if let Some(failure) = response.extensions_mut().get_mut::<Failure>() {
+        failure.entry = Some(entry.to_owned());
+    }
+    response
+}
+
+fn error_chain(e: &dyn Error) -> Vec<String> {
+    let mut chain = vec![e.to_string()];
+    let mut source = e.source();
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    chain
+}
+
+#[derive(Serialize, Clone)]
+pub struct Incident {
+    pub id: String,
+    pub at: DateTime<Utc>,
+    // None for panics, which happen outside of any known request
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub what: String,
+    pub entry: Option<String>,
+    pub template: Option<String>,
+    // Outermost first
+    pub errors: Vec<String>,
+}
+
+// The most recent incidents, so that an ID from an error page can be looked
+// up without searching the logs
+pub struct Incidents {
+    kept: Mutex<VecDeque<Incident>>,
+    counter: AtomicU64,
+    clock: SharedClock,
+}
+
+impl Incidents {
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            kept: Mutex::new(VecDeque::with_capacity(INCIDENTS_KEPT)),
+            counter: AtomicU64::new(0),
+            clock,
+        }
+    }
+
+    pub(crate) fn record(&self, request: Option<(String, String)>, failure: Failure) -> String {
+        let (method, path) = request.unzip();
+        let incident = Incident {
+            id: self.next_id(),
+            at: self.clock.now(),
+            method,
+            path,
+            what: failure.what,
+            entry: failure.entry,
+            template: failure.template,
+            errors: failure.errors,
+        };
+        error!(
+            "Incident {}: {} while serving {} {} (entry {}, template {}): {}",
+            incident.id,
+            incident.what,
+            incident.method.as_deref().unwrap_or("-"),
+            incident.path.as_deref().unwrap_or("-"),
+            incident.entry.as_deref().unwrap_or("-"),
+            incident.template.as_deref().unwrap_or("-"),
+            incident.errors.join(": ")
+        );
+        let id = incident.id.clone();
+        // Also called from the panic hook, where panicking again would abort
+        let mut kept = self.kept.lock().unwrap_or_else(PoisonError::into_inner);
+        if kept.len() == INCIDENTS_KEPT {
+            kept.pop_front();
+        }
+        kept.push_back(incident);
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<Incident> {
+        self.kept
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|i| i.id == id)
+            .cloned()
+    }
+
+    // Panics don't reach the routes: the hook records them, then hands over to
+    // the previous one, which prints them as usual
+    pub(crate) fn capture_panics(self: Arc<Self>) {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let message = info
+                .payload()
+                .downcast_ref::<&str>()
+                .map(|m| m.to_string())
+                .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unknown panic payload".to_owned());
+            let location = info
+                .location()
+                .map(|l| format!("at {}:{}", l.file(), l.line()))
+                .unwrap_or_default();
+            let failure = Failure {
+                what: "Panicked".to_owned(),
+                entry: None,
+                template: None,
+                errors: vec![message, location],
+            };
+            self.record(None, failure);
+            previous(info);
+        }));
+    }
+
+    // Short enough to be read out of a screenshot. The wall clock keeps them
+    // from repeating across restarts, the counter within one
+    fn next_id(&self) -> String {
+        let count = self.counter.fetch_add(1, Ordering::Relaxed);
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let digest = Sha256::digest(format!("{nanos}-{count}").as_bytes());
+        format!("{digest:x}")[..10].to_owned()
+    }
+}
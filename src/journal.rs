@@ -0,0 +1,427 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use crate::clock::SharedClock;
+
+const MAX_JOURNAL_SIZE: u64 = 1024 * 1024;
+const MAX_RETAINED_CHANGES: usize = 1000;
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Stored,
+    Updated,
+    Removed,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Change {
+    pub cursor: u64,
+    pub kind: ChangeKind,
+    // The file name of the entry. Older journals only have the slug, which
+    // was the file name then
+    #[serde(default)]
+    pub entry: String,
+    pub slug: String,
+    pub timestamp: DateTime<Utc>,
+    // Lets a restarted server tell whether a rescanned entry actually changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ChangesPage {
+    pub changes: Vec<Change>,
+    pub next_cursor: u64,
+    // Some changes after the cursor are no longer retained, or the cursor
+    // isn't one of this journal: the client has to read everything again
+    pub resync: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct KnownEntry {
+    slug: String,
+    content_hash: String,
+}
+
+// Starts every file after a rotation, so that the entries whose last change
+// was rotated away are still known after a restart
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    cursor: u64,
+    entries: HashMap<String, KnownEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Snapshot { snapshot: Snapshot },
+    Change(Change),
+}
+
+struct JournalState {
+    last_cursor: u64,
+    retained: VecDeque<Change>,
+    known: HashMap<String, KnownEntry>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    clock: SharedClock,
+    max_size: u64,
+    max_retained: usize,
+    state: Mutex<JournalState>,
+}
+
+impl Journal {
+    pub async fn open<P: AsRef<Path>>(path: P, clock: SharedClock) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = JournalState {
+            last_cursor: 0,
+            retained: VecDeque::new(),
+            known: HashMap::new(),
+        };
+
+        // The rotated file holds older changes, so it's replayed first
+        for file in [rotated_path(&path), path.clone()] {
+            let content = match tokio::fs::read_to_string(&file).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                match serde_json::from_str::<Record>(line) {
+                    Ok(Record::Snapshot { snapshot }) => state.restore(snapshot),
+                    Ok(Record::Change(change)) => state.apply(change, MAX_RETAINED_CHANGES),
+                    Err(e) => warn!("Skipping malformed journal line in {file:?}: {e}"),
+                }
+            }
+        }
+
+        Ok(Self {
+            path,
+            clock,
+            max_size: MAX_JOURNAL_SIZE,
+            max_retained: MAX_RETAINED_CHANGES,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub async fn record_stored(&self, entry: &str, slug: &str, content_hash: &str) {
+        let mut state = self.state.lock().await;
+        let kind = match state.known.get(entry) {
+            Some(known) if known.content_hash == content_hash && known.slug == slug => return,
+            Some(_) => ChangeKind::Updated,
+            None => ChangeKind::Stored,
+        };
+        self.append(&mut state, kind, entry, slug, Some(content_hash.to_owned()))
+            .await;
+    }
+
+    pub async fn record_removed(&self, entry: &str) {
+        let mut state = self.state.lock().await;
+        let Some(known) = state.known.get(entry) else {
+            return;
+        };
+        let slug = known.slug.clone();
+        self.append(&mut state, ChangeKind::Removed, entry, &slug, None)
+            .await;
+    }
+
+    pub async fn last_cursor(&self) -> u64 {
+        self.state.lock().await.last_cursor
+    }
+
+    pub async fn changes_since(&self, since: u64) -> ChangesPage {
+        let state = self.state.lock().await;
+        let changes: Vec<Change> = state
+            .retained
+            .iter()
+            .filter(|c| c.cursor > since)
+            .cloned()
+            .collect();
+        let next_cursor = changes.last().map(|c| c.cursor).unwrap_or(since);
+        let oldest_retained = state
+            .retained
+            .front()
+            .map(|c| c.cursor)
+            .unwrap_or(state.last_cursor + 1);
+        ChangesPage {
+            changes,
+            next_cursor: next_cursor.min(state.last_cursor),
+            resync: since > state.last_cursor || since + 1 < oldest_retained,
+        }
+    }
+
+    async fn append(
+        &self,
+        state: &mut JournalState,
+        kind: ChangeKind,
+        entry: &str,
+        slug: &str,
+        content_hash: Option<String>,
+    ) {
+        let change = Change {
+            cursor: state.last_cursor + 1,
+            kind,
+            entry: entry.to_owned(),
+            slug: slug.to_owned(),
+            timestamp: self.clock.now(),
+            content_hash,
+        };
+        if let Err(e) = self.write(state, &change).await {
+            error!("Failed to append to journal {:?}: {e}", self.path);
+        }
+        state.apply(change, self.max_retained);
+    }
+
+    async fn write(&self, state: &JournalState, change: &Change) -> anyhow::Result<()> {
+        let size = match tokio::fs::metadata(&self.path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        };
+        let mut line = String::new();
+        if size > self.max_size {
+            tokio::fs::rename(&self.path, rotated_path(&self.path)).await?;
+            let snapshot = Snapshot {
+                cursor: state.last_cursor,
+                entries: state.known.clone(),
+            };
+            line = serde_json::to_string(&Record::Snapshot { snapshot })?;
+            line.push('\n');
+        }
+
+        line.push_str(&serde_json::to_string(change)?);
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+impl JournalState {
+    fn apply(&mut self, mut change: Change, max_retained: usize) {
+        if change.entry.is_empty() {
+            change.entry = change.slug.clone();
+        }
+        self.last_cursor = self.last_cursor.max(change.cursor);
+        match (&change.kind, &change.content_hash) {
+            (ChangeKind::Removed, _) => {
+                self.known.remove(&change.entry);
+            }
+            (_, Some(hash)) => {
+                self.known.insert(
+                    change.entry.clone(),
+                    KnownEntry {
+                        slug: change.slug.clone(),
+                        content_hash: hash.clone(),
+                    },
+                );
+            }
+            _ => {}
+        }
+        self.retained.push_back(change);
+        if self.retained.len() > max_retained {
+            self.retained.pop_front();
+        }
+    }
+
+    // What the rotated files knew, the changes after it follow
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.last_cursor = self.last_cursor.max(snapshot.cursor);
+        self.known = snapshot.entries;
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{clock::FixedClock, test_support::TempDir};
+
+    async fn open(dir: &TempDir) -> Journal {
+        let clock = Arc::new(FixedClock(
+            DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        ));
+        Journal::open(dir.join("journal.jsonl"), clock)
+            .await
+            .unwrap()
+    }
+
+    fn summary(page: &ChangesPage) -> Vec<(u64, ChangeKind, &str)> {
+        page.changes
+            .iter()
+            .map(|c| (c.cursor, c.kind, c.slug.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_changes_in_order_after_the_cursor() {
+        let dir = TempDir::new("journal-order");
+        let journal = open(&dir).await;
+        journal.record_stored("a.md", "a", "1").await;
+        journal.record_stored("b.md", "b", "1").await;
+        journal.record_stored("a.md", "a", "2").await;
+        journal.record_removed("b.md").await;
+
+        let page = journal.changes_since(0).await;
+        assert_eq!(
+            summary(&page),
+            vec![
+                (1, ChangeKind::Stored, "a"),
+                (2, ChangeKind::Stored, "b"),
+                (3, ChangeKind::Updated, "a"),
+                (4, ChangeKind::Removed, "b"),
+            ]
+        );
+        assert_eq!(page.next_cursor, 4);
+        assert!(!page.resync);
+
+        let page = journal.changes_since(2).await;
+        assert_eq!(
+            summary(&page).iter().map(|c| c.0).collect::<Vec<_>>(),
+            [3, 4]
+        );
+        let page = journal.changes_since(4).await;
+        assert!(page.changes.is_empty());
+        assert_eq!(page.next_cursor, 4);
+        assert!(!page.resync);
+    }
+
+    #[tokio::test]
+    async fn ignores_unchanged_entries_and_unknown_removals() {
+        let dir = TempDir::new("journal-unchanged");
+        let journal = open(&dir).await;
+        journal.record_stored("a.md", "a", "1").await;
+        journal.record_stored("a.md", "a", "1").await;
+        journal.record_removed("missing.md").await;
+        assert_eq!(journal.last_cursor().await, 1);
+
+        // A new slug for the same content is a change
+        journal.record_stored("a.md", "renamed", "1").await;
+        journal.record_removed("a.md").await;
+        let page = journal.changes_since(1).await;
+        assert_eq!(
+            summary(&page),
+            vec![
+                (2, ChangeKind::Updated, "renamed"),
+                (3, ChangeKind::Removed, "renamed")
+            ]
+        );
+        assert_eq!(page.changes[1].entry, "a.md");
+    }
+
+    #[tokio::test]
+    async fn keeps_its_cursors_and_hashes_across_restarts() {
+        let dir = TempDir::new("journal-restart");
+        {
+            let journal = open(&dir).await;
+            journal.record_stored("a.md", "a", "1").await;
+            journal.record_stored("b.md", "b", "1").await;
+        }
+        let journal = open(&dir).await;
+        assert_eq!(journal.last_cursor().await, 2);
+        // The startup scan storing the same entries again
+        journal.record_stored("a.md", "a", "1").await;
+        journal.record_stored("b.md", "b", "2").await;
+        assert_eq!(
+            summary(&journal.changes_since(0).await),
+            vec![
+                (1, ChangeKind::Stored, "a"),
+                (2, ChangeKind::Stored, "b"),
+                (3, ChangeKind::Updated, "b"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn remembers_entries_rotated_away_twice() {
+        let dir = TempDir::new("journal-rotation");
+        {
+            let mut journal = open(&dir).await;
+            journal.max_size = 200;
+            journal.record_stored("old.md", "old", "1").await;
+            for i in 0..20 {
+                journal
+                    .record_stored("busy.md", "busy", &i.to_string())
+                    .await;
+            }
+            assert_eq!(journal.last_cursor().await, 21);
+        }
+        // old.md's only change is in neither file anymore
+        let files = [dir.join("journal.jsonl"), dir.join("journal.jsonl.1")];
+        for file in &files {
+            let content = std::fs::read_to_string(file).unwrap();
+            assert!(!content.contains(r#""cursor":1,"#), "{file:?}: {content}");
+        }
+
+        let journal = open(&dir).await;
+        assert_eq!(journal.last_cursor().await, 21);
+        journal.record_stored("old.md", "old", "1").await;
+        journal.record_stored("busy.md", "busy", "19").await;
+        assert_eq!(journal.last_cursor().await, 21);
+        journal.record_removed("old.md").await;
+        let page = journal.changes_since(21).await;
+        assert_eq!(summary(&page), vec![(22, ChangeKind::Removed, "old")]);
+        assert!(!page.resync);
+    }
+
+    #[tokio::test]
+    async fn asks_for_a_resync_when_changes_were_dropped() {
+        let dir = TempDir::new("journal-resync");
+        let mut journal = open(&dir).await;
+        journal.max_retained = 3;
+        for i in 0..5 {
+            journal.record_stored("a.md", "a", &i.to_string()).await;
+        }
+        // Cursors 1 and 2 are gone
+        let page = journal.changes_since(0).await;
+        assert!(page.resync);
+        assert_eq!(
+            summary(&page).iter().map(|c| c.0).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        assert_eq!(page.next_cursor, 5);
+        assert!(journal.changes_since(1).await.resync);
+        assert!(!journal.changes_since(2).await.resync);
+        assert!(!journal.changes_since(5).await.resync);
+
+        // A cursor of another journal, e.g. after it was deleted
+        let page = journal.changes_since(42).await;
+        assert!(page.resync);
+        assert!(page.changes.is_empty());
+        assert_eq!(page.next_cursor, 5);
+    }
+
+    #[tokio::test]
+    async fn reads_the_journals_of_older_versions() {
+        let dir = TempDir::new("journal-legacy");
+        dir.write(
+            "journal.jsonl",
+            r#"{"cursor":1,"kind":"stored","slug":"a.md","timestamp":"2024-01-01T00:00:00Z","content_hash":"1"}"#,
+        );
+        let journal = open(&dir).await;
+        journal.record_removed("a.md").await;
+        let page = journal.changes_since(1).await;
+        assert_eq!(summary(&page), vec![(2, ChangeKind::Removed, "a.md")]);
+    }
+}
@@ -0,0 +1,56 @@
+//! A small blog engine: markdown entries with a YAML front matter, rendered
+//! through a handlebars theme and served with warp.
+//!
+//! [`BlogEngine`] bundles everything, and can be mounted inside a larger warp
+//! application; the `swes` binary is a thin wrapper driving it from the
+//! command line.
+
+pub mod access_log;
+mod admin;
+mod api;
+mod artifact_store;
+mod blog_config;
+pub mod blog_storage;
+pub mod cdn;
+pub mod chaos;
+pub mod clock;
+mod compression;
+mod conditional;
+mod counters;
+mod diff;
+mod engine;
+mod event_bus;
+mod events;
+mod feed;
+mod file_server;
+mod handlebars_support;
+mod identity;
+mod images;
+mod incidents;
+mod journal;
+pub mod listeners;
+mod markdown;
+mod outbox;
+mod page_storage;
+mod paging;
+mod plaintext;
+mod purge;
+pub mod readiness;
+mod referrers;
+mod retries;
+pub mod routes;
+mod search;
+mod signing;
+mod sitemap;
+pub mod smoke;
+mod snippets;
+mod stats;
+mod template_helpers;
+#[cfg(test)]
+mod test_support;
+mod theme;
+mod time_travel;
+mod uploads;
+mod watchers;
+
+pub use engine::{BlogEngine, BlogEngineBuilder};
@@ -0,0 +1,122 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    os::fd::{FromRawFd, RawFd},
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use socket2::{Domain, Socket, Type};
+
+// File descriptors passed by the supervisor start right after stdin, stdout
+// and stderr, as in systemd's socket activation
+const LISTEN_FDS_START: RawFd = 3;
+const LISTEN_BACKLOG: i32 = 1024;
+
+// Sockets already listening, handed over by a supervisor through LISTEN_FDS
+// (and LISTEN_PID, when set, must be this process). The variables are
+// cleared so that they don't leak into anything spawned later
+pub fn inherited_listeners() -> anyhow::Result<Vec<TcpListener>> {
+    listeners_from(LISTEN_FDS_START)
+}
+
+fn listeners_from(first_fd: RawFd) -> anyhow::Result<Vec<TcpListener>> {
+    let Ok(count) = std::env::var("LISTEN_FDS") else {
+        return Ok(vec![]);
+    };
+    if let Ok(pid) = std::env::var("LISTEN_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            warn!("Ignoring LISTEN_FDS meant for process {pid}");
+            return Ok(vec![]);
+        }
+    }
+    let count: RawFd = count
+        .parse()
+        .with_context(|| format!("Invalid LISTEN_FDS '{count}'"))?;
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    (first_fd..first_fd + count)
+        .map(|fd| {
+            // The supervisor gives us ownership of these descriptors
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener
+                .local_addr()
+                .with_context(|| format!("Inherited file descriptor {fd} is not a TCP socket"))?;
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+// With SO_REUSEPORT a new instance can bind the same address while the old
+// one still runs. Restarting without dropping connections then goes:
+// 1. start the new instance with --reuse-port
+// 2. wait for it to be ready (/readyz, --ready-file or sd_notify)
+// 3. send SIGTERM to the old instance, which stops accepting and drains
+pub fn bind_reuse_port(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to bind {addr}"))?;
+    socket.listen(LISTEN_BACKLOG)?;
+    info!("Bound {addr} with SO_REUSEPORT");
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpStream, os::fd::IntoRawFd};
+
+    use super::*;
+
+    fn accepts_connections(listener: &TcpListener) {
+        listener.set_nonblocking(false).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"hello").unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    // The only test touching the variables, which the whole process shares
+    #[test]
+    fn takes_over_the_listeners_passed_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd();
+
+        std::env::set_var("LISTEN_FDS", "1");
+        std::env::set_var("LISTEN_PID", "1");
+        assert!(listeners_from(fd).unwrap().is_empty());
+        assert!(std::env::var("LISTEN_FDS").is_ok());
+
+        std::env::set_var("LISTEN_PID", std::process::id().to_string());
+        let listeners = listeners_from(fd).unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr().unwrap(), addr);
+        accepts_connections(&listeners[0]);
+        // Not passed on to anything spawned later
+        assert!(std::env::var("LISTEN_FDS").is_err());
+        assert!(std::env::var("LISTEN_PID").is_err());
+        assert!(listeners_from(fd).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reused_ports_can_be_bound_twice() {
+        let first = bind_reuse_port("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_reuse_port(addr).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+        // Which a plain bind can't
+        assert!(TcpListener::bind(addr).is_err());
+        // The kernel spreads the connections, either one may get them
+        drop(first);
+        accepts_connections(&second);
+    }
+}
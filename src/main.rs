@@ -1,371 +1,650 @@
-mod blog_storage;
-mod file_server;
-mod handlebars_support;
-
-use futures_util::StreamExt;
 use std::{
-    convert::Infallible,
-    net::SocketAddr,
-    path::{Path, PathBuf},
-    sync::{Arc, RwLock},
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    path::Path,
+    sync::Arc,
+    time::Duration,
 };
 
-use blog_storage::BlogInfo;
-use clap::Parser;
-use file_server::FileServer;
-use handlebars_support::HandlebarsSupport;
-use log::{error, info, warn};
-use notify::{
-    event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode},
-    RecursiveMode, Watcher,
-};
-use tokio::{
-    runtime::Handle,
-    sync::broadcast::{Receiver, Sender},
-};
-use warp::{
-    filters::sse::Event,
-    reply::{Html, Reply, Response},
-    Filter,
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use log::{info, warn};
+use tokio::signal::unix::SignalKind;
+use warp::Filter;
+
+use server_config::ServerConfig;
+use swes::{
+    access_log::{with_access_log, AccessLog},
+    chaos::ChaosConfig,
+    clock::FixedClock,
+    listeners,
+    routes::rejection_response,
+    smoke::{self, SmokeConfig},
+    BlogEngine,
 };
 
-use crate::blog_storage::BlogStorage;
+mod server_config;
 
-fn blog_info() -> BlogInfo {
-    BlogInfo {
-        name: "Crax's blog".to_owned(),
-    }
-}
-
-#[derive(Clone)]
-pub enum UpdateEvent {
-    Reload,
-}
+const DEFAULT_ARTIFACTS_BUDGET_MB: u64 = 256;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 5;
+// Every interface, so that the blog can be reached from outside a container
+const DEFAULT_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_READ_ATTEMPTS: u32 = 3;
+const DEFAULT_READ_BACKOFF_MS: u64 = 50;
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file setting these flags (base_path, port...), defaults to ./swes.toml when it exists
+    #[arg(long)]
+    server_config: Option<String>,
+
     #[arg(short, long)]
     base_path: Option<String>,
 
+    /// Site wide settings (name, description...), defaults to blog.toml in the base path
+    #[arg(long)]
+    config: Option<String>,
+
     #[arg(short, long)]
     file_server_path: Option<String>,
 
+    /// Serve the symlinks in the files directory that point outside of it
     #[arg(long)]
-    handlebars_theme: Option<String>,
+    follow_symlinks: bool,
 
     #[arg(long)]
+    handlebars_theme: Option<String>,
+
+    /// Address to listen on, 0.0.0.0 (every interface) by default
+    #[arg(long, visible_alias = "bind")]
     address: Option<String>,
 
+    /// Port to listen on, 8080 by default
     #[arg(long)]
     port: Option<u16>,
-}
-fn create_entry(p: PathBuf, storage: Arc<BlogStorage>, handle: Handle) {
-    handle.spawn(async move {
-        let blog_entry = BlogStorage::parse_file_to_html(&p).await;
-        let entry_name = p.file_name().unwrap();
-        let entry_name = entry_name.to_string_lossy();
-        if !is_valid_filename_entry(&entry_name) {
-            info!("Ignoring entry {entry_name} for insertion");
-            return;
-        }
-        let blog_entry = match blog_entry {
-            Ok(e) => e,
-            Err(e) => {
-                error!("Failed to read entry {entry_name}: {e}");
-                return;
-            }
-        };
-        info!("Storing new entry {entry_name}");
-        storage
-            .try_store_entry(&entry_name, Arc::new(blog_entry))
-            .await;
-    });
+
+    /// Additional addr:port to listen on, can be repeated (e.g. [::1]:8080)
+    #[arg(long)]
+    listen: Vec<String>,
+
+    /// Bearer token required by the /admin routes, which are disabled when unset
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// Secret used to sign shared preview links; changing it revokes every link
+    #[arg(long)]
+    share_secret: Option<String>,
+
+    /// JSONL file recording every content change, served by /api/changes
+    #[arg(long)]
+    journal_path: Option<String>,
+
+    /// Directory where the CDN purges wait to be sent, so that they survive a restart
+    #[arg(long)]
+    outbox_path: Option<String>,
+
+    /// File keeping the parsed entries across restarts, so that unchanged ones aren't parsed again
+    #[arg(long)]
+    cache_file: Option<String>,
+
+    /// Column at which the ?format=txt rendering of entries is wrapped
+    #[arg(long)]
+    plaintext_width: Option<usize>,
+
+    /// Entries not updated for this many days get an outdated content warning
+    #[arg(long)]
+    stale_after_days: Option<i64>,
+
+    /// File receiving an access log in combined format, reopened on SIGUSR2
+    #[arg(long)]
+    access_log: Option<String>,
+
+    /// Public url of the site, used to build absolute links in feeds
+    #[arg(long)]
+    site_url: Option<String>,
+
+    /// Conventional feed path (e.g. rss.xml) that should not redirect to the feeds
+    #[arg(long)]
+    disable_feed_alias: Vec<String>,
+
+    /// Don't record which sites readers come from
+    #[arg(long)]
+    no_referrer_tracking: bool,
+
+    /// JSON file where the referrer counts are persisted
+    #[arg(long)]
+    referrers_path: Option<String>,
+
+    /// JSON file where the view counts are persisted
+    #[arg(long)]
+    views_path: Option<String>,
+
+    /// Referring domain (and its subdomains) that should never be recorded
+    #[arg(long)]
+    referrer_denylist: Vec<String>,
+
+    /// Directory holding standalone pages (about, contacts...), served at /{slug}
+    #[arg(long)]
+    pages_path: Option<String>,
+
+    /// Serve the standalone pages at /pages/{slug} instead of /{slug}
+    #[arg(long)]
+    pages_under_prefix: bool,
+
+    /// Directory where derived files (e.g. plain text renderings) get cached
+    #[arg(long)]
+    artifacts_path: Option<String>,
+
+    /// Total size in megabytes the cached derived files may take
+    #[arg(long)]
+    artifacts_budget_mb: Option<u64>,
+
+    /// Size budget of a single artifact category, as category=megabytes
+    #[arg(long)]
+    artifact_budget: Vec<String>,
+
+    /// File created once the server is ready, and removed when it shuts down
+    #[arg(long)]
+    ready_file: Option<String>,
+
+    /// Bind with SO_REUSEPORT, so that a new instance can start listening before this one stops
+    #[arg(long)]
+    reuse_port: bool,
+
+    /// Pretend it's always this instant (RFC 3339), to get reproducible pages
+    #[arg(long)]
+    pinned_time: Option<DateTime<Utc>>,
+
+    /// How many rendered entries are kept in memory, the least recently read are dropped first
+    #[arg(long)]
+    cache_size: Option<NonZeroUsize>,
+
+    /// How many of the newest entries the feeds carry [default: 10]
+    #[arg(long)]
+    max_entries: Option<NonZeroUsize>,
+
+    /// syntect theme of the code blocks, e.g. base16-ocean.dark, instead of the highlight_theme of blog.toml
+    #[arg(long)]
+    highlight_theme: Option<String>,
+
+    /// Reading speed behind the reading times of the entries, instead of the words_per_minute of blog.toml [default: 200]
+    #[arg(long)]
+    words_per_minute: Option<NonZeroU32>,
+
+    /// Biggest file in megabytes accepted by PUT /admin/content/{filename}
+    #[arg(long)]
+    upload_limit_mb: Option<u64>,
+
+    /// Let a CDN cache the pages (s-maxage, Surrogate-Key), tuned by the [cdn] table of swes.toml
+    #[arg(long)]
+    cdn_mode: bool,
+
+    /// Plain http url receiving the surrogate keys to purge when entries change, implies --cdn-mode
+    #[arg(long)]
+    cdn_purge_webhook: Option<String>,
+
+    /// Show the entries whose publish date is still in the future
+    #[arg(long)]
+    show_future: bool,
+
+    /// Serve the entries marked with `draft: true` in their front matter, though never in feeds or the sitemap
+    #[arg(long)]
+    serve_drafts: bool,
+
+    /// Enable the routes meant for writing posts locally (e.g. /preview/{entry}/diff)
+    #[arg(long)]
+    dev: bool,
+
+    /// Smallest response in bytes worth compressing, 1024 by default
+    #[arg(long)]
+    compression_min_bytes: Option<usize>,
+
+    /// Seconds the requests in flight get to complete on shutdown, before exiting with an error
+    #[arg(long)]
+    shutdown_grace_secs: Option<u64>,
+
+    /// Times an entry or a file is read when it fails with ESTALE or EIO, as on a flaky NFS mount, 3 by default
+    #[arg(long)]
+    read_attempts: Option<u32>,
+
+    /// Milliseconds before reading again after a transient failure, doubled at each retry, 50 by default
+    #[arg(long)]
+    read_backoff_ms: Option<u64>,
+
+    /// Share of the file reads failing with EIO, from 0 to 1. Only with --dev, on a loopback address
+    #[arg(long)]
+    chaos_fs_error_rate: Option<f64>,
+
+    /// Milliseconds added to every rendered page. Only with --dev, on a loopback address
+    #[arg(long)]
+    chaos_render_delay_ms: Option<u64>,
+
+    /// Share of the file watcher events dropped, from 0 to 1. Only with --dev, on a loopback address
+    #[arg(long)]
+    chaos_drop_events_rate: Option<f64>,
 }
 
-fn reload_entry(path: PathBuf, watcher_storage: Arc<BlogStorage>, handle: Handle) {
-    handle.spawn(async move {
-        let filename = path.file_name();
-        let entry_name = if let Some(filename) = filename {
-            filename.to_string_lossy().to_string()
-        } else {
-            return;
-        };
-        if !is_valid_filename_entry(&entry_name) {
-            info!("Ignoring entry {entry_name} for reload");
-            return;
-        }
-        if watcher_storage.contains_entry(&entry_name).await {
-            {
-                info!("Reloading entry {entry_name}");
-                let blog_entry = BlogStorage::parse_file_to_html(&path).await;
-                let entry_name = path.file_name().unwrap();
-                let entry_name = entry_name.to_string_lossy();
-                let blog_entry = match blog_entry {
-                    Ok(e) => e,
-                    Err(e) => {
-                        error!("Failed to read entry {entry_name}: {e}");
-                        return;
-                    }
-                };
-                watcher_storage
-                    .try_store_entry(&entry_name, Arc::new(blog_entry))
-                    .await;
-            }
-        }
-    });
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that a running instance serves its pages, feeds and files, e.g. a new deployment
+    Smoke(SmokeArgs),
 }
 
-fn is_valid_filename_entry(filename: &str) -> bool {
-    filename.ends_with(".md") && !filename.starts_with('_')
+#[derive(clap::Args, Debug)]
+struct SmokeArgs {
+    /// Where the instance is served, over plain http, e.g. http://10.0.0.2:8080
+    #[arg(long)]
+    url: String,
+
+    /// Blog name expected on the home page
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Static file expected to be served
+    #[arg(long, default_value = smoke::DEFAULT_STATIC_FILE)]
+    static_file: String,
+
+    /// How many entries of the feed are fetched
+    #[arg(long, default_value_t = smoke::DEFAULT_SAMPLE_SIZE)]
+    sample: usize,
+
+    /// Slowest acceptable response, in milliseconds
+    #[arg(long, default_value_t = smoke::DEFAULT_BUDGET.as_millis() as u64)]
+    budget_ms: u64,
 }
 
-fn remove_entry(path: PathBuf, watcher_storage: Arc<BlogStorage>, handle: Handle) {
-    handle.spawn(async move {
-        let filename = path.file_name();
-        let filename = if let Some(filename) = filename {
-            filename.to_string_lossy().to_string()
-        } else {
-            return;
-        };
-        if !filename.ends_with(".md") {
-            info!("Ignoring file removal {path:?}");
-            return;
-        }
-        info!("Removing entry {filename}");
-        watcher_storage.remove_entry(filename.to_owned()).await;
-    });
+async fn smoke_test(args: SmokeArgs) -> anyhow::Result<()> {
+    let config = SmokeConfig {
+        blog_name: args.name,
+        static_file: args.static_file,
+        sample_size: args.sample,
+        budget: Duration::from_millis(args.budget_ms),
+        ..SmokeConfig::new(args.url)
+    };
+    let report = smoke::run(&config).await?;
+    println!("{report}");
+    if !report.passed() {
+        anyhow::bail!("{} smoke checks failed", report.failures());
+    }
+    Ok(())
 }
 
-async fn add_most_recent_entries(
-    storage: &mut BlogStorage,
-    _max_entries: usize,
-    base_path: &impl AsRef<Path>,
-) -> anyhow::Result<()> {
-    let entries_iterator = std::fs::read_dir(base_path)?
-        .filter_map(|e| e.ok())
-        .filter(|e| match e.file_type() {
-            Ok(t) => t.is_file(),
-            Err(_) => false,
-        }); // for now ignore the max entries param
-
-    for entry in entries_iterator {
-        let entry_name = entry.file_name();
-        let entry_name = entry_name.to_string_lossy();
-        let entry_name = entry_name.to_string();
-
-        let blog_entry = tokio::task::block_in_place(move || {
-            tokio::runtime::Handle::current()
-                .block_on(async move { BlogStorage::parse_file_to_html(&entry.path()).await })
-        });
-        let blog_entry = match blog_entry {
-            Ok(e) => e,
-            Err(e) => {
-                warn!("Failed to read blog entry {}", e);
-                continue;
-            }
-        };
-        if !is_valid_filename_entry(&entry_name) {
-            info!("Ignoring entry {entry_name}");
-            continue;
+impl Args {
+    fn with_server_config(mut self, config: ServerConfig) -> Self {
+        self.base_path = self.base_path.or(config.base_path);
+        self.config = self.config.or(config.blog_config);
+        self.file_server_path = self.file_server_path.or(config.file_server_path);
+        self.handlebars_theme = self.handlebars_theme.or(config.handlebars_theme);
+        self.address = self.address.or(config.address);
+        self.port = self.port.or(config.port);
+        if self.listen.is_empty() {
+            self.listen = config.listen;
         }
-        info!("Added entry {}", entry_name);
-        storage
-            .try_store_entry(&entry_name, Arc::new(blog_entry))
-            .await;
+        self.site_url = self.site_url.or(config.site_url);
+        self.admin_token = self.admin_token.or(config.admin_token);
+        self.share_secret = self.share_secret.or(config.share_secret);
+        self.journal_path = self.journal_path.or(config.journal_path);
+        self.outbox_path = self.outbox_path.or(config.outbox_path);
+        self.cache_file = self.cache_file.or(config.cache_file);
+        self.access_log = self.access_log.or(config.access_log);
+        self.pages_path = self.pages_path.or(config.pages_path);
+        self.referrers_path = self.referrers_path.or(config.referrers_path);
+        self.views_path = self.views_path.or(config.views_path);
+        self.artifacts_path = self.artifacts_path.or(config.artifacts_path);
+        self.artifacts_budget_mb = self.artifacts_budget_mb.or(config.artifacts_budget_mb);
+        self.ready_file = self.ready_file.or(config.ready_file);
+        self.plaintext_width = self.plaintext_width.or(config.plaintext_width);
+        self.stale_after_days = self.stale_after_days.or(config.stale_after_days);
+        self.cache_size = self.cache_size.or(config.cache_size);
+        self.max_entries = self.max_entries.or(config.max_entries);
+        self.upload_limit_mb = self.upload_limit_mb.or(config.upload_limit_mb);
+        self.compression_min_bytes = self.compression_min_bytes.or(config.compression_min_bytes);
+        self.shutdown_grace_secs = self.shutdown_grace_secs.or(config.shutdown_grace_secs);
+        self.read_attempts = self.read_attempts.or(config.read_attempts);
+        self.read_backoff_ms = self.read_backoff_ms.or(config.read_backoff_ms);
+        self
     }
-
-    Ok(())
 }
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
-    let args = Args::parse();
-
-    let base_path = args.base_path.unwrap_or("blog".to_owned());
-    let file_path = args.file_server_path.unwrap_or("files".to_owned());
-    let handlebars_theme = args.handlebars_theme.unwrap_or("default".to_owned());
-
-    let handlebars_path = Path::new("themes").join(handlebars_theme);
-
-    let mut storage = BlogStorage::new(base_path.clone());
-    add_most_recent_entries(&mut storage, 10, &base_path).await?;
-    let storage = Arc::new(storage);
-
-    let file_server = FileServer::new(file_path);
-    let file_server = Arc::new(file_server);
-
-    let handlebars_support = HandlebarsSupport::new(&handlebars_path)?;
-    let handlebars_support = Arc::new(RwLock::new(handlebars_support));
-
-    let watcher_storage = storage.clone();
-    let handle = tokio::runtime::Handle::current();
-
-    let (send, _): (Sender<UpdateEvent>, Receiver<UpdateEvent>) =
-        tokio::sync::broadcast::channel(500);
-
-    let md_sender = send.clone();
-    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
-        match res {
-            Ok(evt) => match evt.kind {
-                notify::EventKind::Create(f) if f == CreateKind::File => {
-                    create_entry(
-                        evt.paths[0].clone(),
-                        watcher_storage.clone(),
-                        handle.clone(),
-                    );
-                    let _ = md_sender.send(UpdateEvent::Reload);
-                }
-                notify::EventKind::Modify(f)
-                    if matches!(
-                        f,
-                        ModifyKind::Name(RenameMode::To)
-                            | ModifyKind::Data(DataChange::Any | DataChange::Content)
-                    ) =>
-                {
-                    reload_entry(
-                        evt.paths[0].clone(),
-                        watcher_storage.clone(),
-                        handle.clone(),
-                    );
-                    let _ = md_sender.send(UpdateEvent::Reload);
-                }
-                notify::EventKind::Remove(f) if f == RemoveKind::File => remove_entry(
-                    evt.paths[0].clone(),
-                    watcher_storage.clone(),
-                    handle.clone(),
-                ),
-                _ => {}
-            },
-            Err(e) => println!("err {e:?}"),
-        };
-    })
-    .expect("watcher");
-    watcher.watch(Path::new(&base_path), RecursiveMode::NonRecursive)?;
-
-    let handlebars_support_watcher = handlebars_support.clone();
-    let handlebars_sender = send.clone();
-    let mut handlebars_watcher =
-        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
-            Ok(evt) => {
-                if let notify::EventKind::Modify(_) = evt.kind {
-                    info!("Reloading handlebars theme");
-                    handlebars_support_watcher
-                        .write()
-                        .expect("Failed to write hb support")
-                        .reload_theme()
-                        .unwrap_or_else(|e| error!("Handlebars reload failed: {e}"));
-                    let _ = handlebars_sender.send(UpdateEvent::Reload);
-                }
-            }
-            Err(e) => error!("err {e:?}"),
-        })
-        .expect("handlebars watcher");
-    handlebars_watcher.watch(&handlebars_path, RecursiveMode::NonRecursive)?;
-    handlebars_watcher.watch(Path::new("files/style.css"), RecursiveMode::NonRecursive)?;
-
-    let blog = warp::path!("blog" / String).and_then({
-        let storage = storage.clone();
-
-        let handlebars_support = handlebars_support.clone();
-        move |entry| {
-            let storage = storage.clone();
-            let handlebars_support = handlebars_support.clone();
-            async move { Ok::<_, Infallible>(blog(entry, storage, handlebars_support).await) }
+    let mut args = Args::parse();
+    if let Some(Command::Smoke(smoke_args)) = args.command.take() {
+        return smoke_test(smoke_args).await;
+    }
+    let mut server_config = ServerConfig::load(args.server_config.as_deref())?;
+    let blog_section = server_config.blog.take();
+    let cdn_section = server_config.cdn.take();
+    let args = args.with_server_config(server_config);
+
+    let mut builder = BlogEngine::builder()
+        .pages_under_prefix(args.pages_under_prefix)
+        .referrer_tracking(!args.no_referrer_tracking)
+        .referrer_denylist(args.referrer_denylist)
+        .follow_symlinks(args.follow_symlinks)
+        .show_future(args.show_future)
+        .serve_drafts(args.serve_drafts)
+        .dev(args.dev);
+    if let Some(blog) = blog_section {
+        builder = builder.blog_info(blog.info());
+    }
+    if let Some(base_path) = args.base_path {
+        builder = builder.base_path(base_path);
+    }
+    if let Some(config) = args.config {
+        builder = builder.config_path(config);
+    }
+    if let Some(file_server_path) = args.file_server_path {
+        builder = builder.files_path(file_server_path);
+    }
+    if let Some(theme) = args.handlebars_theme {
+        builder = builder.theme_path(Path::new("themes").join(theme));
+    }
+    if let Some(pages_path) = args.pages_path {
+        builder = builder.pages_path(pages_path);
+    }
+    if let Some(admin_token) = args.admin_token {
+        builder = builder.admin_token(admin_token);
+    }
+    if let Some(share_secret) = args.share_secret {
+        builder = builder.share_secret(share_secret);
+    }
+    if let Some(journal_path) = args.journal_path {
+        builder = builder.journal_path(journal_path);
+    }
+    if let Some(outbox_path) = args.outbox_path {
+        builder = builder.outbox_path(outbox_path);
+    }
+    if let Some(cache_file) = args.cache_file {
+        builder = builder.cache_file(cache_file);
+    }
+    if let Some(width) = args.plaintext_width {
+        builder = builder.plaintext_width(width);
+    }
+    if let Some(days) = args.stale_after_days {
+        builder = builder.stale_after_days(days);
+    }
+    if let Some(site_url) = args.site_url {
+        builder = builder.site_url(site_url);
+    }
+    for alias in args.disable_feed_alias {
+        builder = builder.disable_feed_alias(alias);
+    }
+    if let Some(referrers_path) = args.referrers_path {
+        builder = builder.referrers_path(referrers_path);
+    }
+    if let Some(views_path) = args.views_path {
+        builder = builder.views_path(views_path);
+    }
+    if let Some(path) = args.artifacts_path {
+        let megabytes = args
+            .artifacts_budget_mb
+            .unwrap_or(DEFAULT_ARTIFACTS_BUDGET_MB);
+        builder = builder.artifacts(path, bytes("--artifacts-budget-mb", megabytes)?);
+        for budget in &args.artifact_budget {
+            let (category, megabytes) = budget
+                .split_once('=')
+                .context("Artifact budgets look like category=megabytes")?;
+            let megabytes: u64 = megabytes
+                .parse()
+                .with_context(|| format!("Invalid artifact budget {budget}"))?;
+            builder = builder.artifact_budget(category, bytes("--artifact-budget", megabytes)?);
         }
-    });
-    let home = warp::path!("blog").and_then({
-        let storage = storage.clone();
-        let handlebars_support = handlebars_support.clone();
-
-        move || {
-            let storage = storage.clone();
-            let handlebars_support = handlebars_support.clone();
-            async move { Result::<_, Infallible>::Ok(home(storage, handlebars_support).await) }
+    }
+    if let Some(ready_file) = args.ready_file {
+        builder = builder.ready_file(ready_file);
+    }
+    if let Some(now) = args.pinned_time {
+        warn!("The clock is pinned to {now}");
+        builder = builder.clock(Arc::new(FixedClock(now)));
+    }
+    if let Some(cache_size) = args.cache_size {
+        builder = builder.cache_size(cache_size);
+    }
+    if let Some(max_entries) = args.max_entries {
+        builder = builder.max_entries(max_entries);
+    }
+    if let Some(theme) = args.highlight_theme {
+        builder = builder.highlight_theme(theme);
+    }
+    if let Some(words_per_minute) = args.words_per_minute {
+        builder = builder.words_per_minute(words_per_minute);
+    }
+    if let Some(megabytes) = args.upload_limit_mb {
+        builder = builder.upload_limit(bytes("--upload-limit-mb", megabytes)?);
+    }
+    if let Some(bytes) = args.compression_min_bytes {
+        builder = builder.compression_min_size(bytes);
+    }
+    if args.read_attempts.is_some() || args.read_backoff_ms.is_some() {
+        builder = builder.read_retries(
+            args.read_attempts.unwrap_or(DEFAULT_READ_ATTEMPTS),
+            Duration::from_millis(args.read_backoff_ms.unwrap_or(DEFAULT_READ_BACKOFF_MS)),
+        );
+    }
+    let chaos = ChaosConfig {
+        fs_error_rate: args.chaos_fs_error_rate.unwrap_or_default(),
+        render_delay: Duration::from_millis(args.chaos_render_delay_ms.unwrap_or_default()),
+        drop_events_rate: args.chaos_drop_events_rate.unwrap_or_default(),
+    };
+    if chaos.is_enabled() {
+        if !args.dev {
+            anyhow::bail!("The --chaos-* flags need --dev");
         }
-    });
-    let files = warp::path!("files" / String).and_then(move |path| {
-        let file_server = file_server.clone();
-        async move { Ok::<_, Infallible>(file(PathBuf::from(path), file_server.clone()).await) }
-    });
-    let events = warp::path!("events").and(warp::get()).map(move || {
-        let receiver = send.subscribe();
-        sse_update(receiver)
-    });
+        for addr in listen_addresses(args.address.clone(), args.port, &args.listen)? {
+            ensure_loopback(addr)?;
+        }
+        builder = builder.chaos(chaos);
+    }
+    if args.cdn_mode || cdn_section.is_some() || args.cdn_purge_webhook.is_some() {
+        let mut cdn = cdn_section.unwrap_or_default();
+        if let Some(webhook) = args.cdn_purge_webhook {
+            cdn.purge_webhook = Some(webhook);
+        }
+        builder = builder.cdn(cdn);
+    }
+
+    let engine = builder.build().await?;
+    engine.capture_panics();
+    engine.start_watchers(tokio::runtime::Handle::current())?;
+
+    let routes = engine.routes().recover(rejection_response);
+    let access_log = match args.access_log {
+        Some(path) => Some(AccessLog::spawn(path.into()).await?),
+        None => None,
+    };
+    let routes = with_access_log(routes, access_log);
+
+    let (shutdown_send, shutdown_recv) = tokio::sync::watch::channel(());
+    let mut servers = vec![];
+    let mut listeners = listeners::inherited_listeners()?;
+    if !listeners.is_empty() {
+        info!("Using {} inherited listening sockets", listeners.len());
+    } else if args.reuse_port {
+        for addr in listen_addresses(args.address, args.port, &args.listen)? {
+            listeners.push(listeners::bind_reuse_port(addr).map_err(|e| listen_error(addr, e))?);
+        }
+    } else {
+        for addr in listen_addresses(args.address, args.port, &args.listen)? {
+            let mut shutdown = shutdown_recv.clone();
+            let (addr, server) = warp::serve(routes.clone())
+                .try_bind_with_graceful_shutdown(addr, async move {
+                    let _ = shutdown.changed().await;
+                })
+                .map_err(|e| listen_error(addr, e.into()))?;
+            info!("Listening on {addr}");
+            servers.push(tokio::spawn(server));
+        }
+    }
+    // warp only knows the peer address of the connections it accepts itself,
+    // so the access log shows '-' for the ones coming from these sockets
+    for listener in listeners {
+        let addr = listener.local_addr()?;
+        if chaos.is_enabled() {
+            ensure_loopback(addr)?;
+        }
+        let incoming = tokio_stream::wrappers::TcpListenerStream::new(
+            tokio::net::TcpListener::from_std(listener)?,
+        );
+        let mut shutdown = shutdown_recv.clone();
+        let server = warp::serve(routes.clone()).serve_incoming_with_graceful_shutdown(
+            incoming,
+            async move {
+                let _ = shutdown.changed().await;
+            },
+        );
+        info!("Listening on {addr}");
+        servers.push(tokio::spawn(server));
+    }
     info!("Serve ready");
+    engine.readiness().set_ready();
 
-    let addr = args.address.unwrap_or("127.0.0.1".to_owned());
-    let port = args.port.unwrap_or(8080);
-    warp::serve(blog.or(home).or(files).or(events))
-        .run(SocketAddr::new(addr.parse().unwrap(), port))
-        .await;
-    Ok(())
+    let mut terminate = tokio::signal::unix::signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    info!("Shutting down");
+    engine.readiness().set_stopping();
+    engine.close_events();
+    let _ = shutdown_send.send(());
+    let grace = Duration::from_secs(
+        args.shutdown_grace_secs
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+    );
+    let drained = tokio::time::timeout(grace, futures_util::future::join_all(servers)).await;
+    // Saved either way, only the requests still running are given up on
+    engine.shutdown().await;
+    match drained {
+        Ok(_) => {
+            info!("Shut down cleanly");
+            Ok(())
+        }
+        Err(_) => Err(anyhow::anyhow!(
+            "Requests were still running after {}s, exiting anyway",
+            grace.as_secs()
+        )),
+    }
 }
 
-async fn blog(
-    entry: String,
-    storage: Arc<BlogStorage>,
-    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
-) -> Html<String> {
-    let entry_name = entry.clone();
-    let entry = storage.get_entry(&entry).await;
-    let handlebars_support = handlebars_support
-        .read()
-        .expect("Failed to open handlebars support");
-    if let Ok(entry) = entry {
-        info!("Serving entry {entry_name}");
-        warp::reply::html(handlebars_support.format_blog_entry(blog_info(), &entry))
+// The OS error alone doesn't tell what to change
+fn listen_error(addr: SocketAddr, e: anyhow::Error) -> anyhow::Error {
+    let in_use = e.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::AddrInUse)
+    });
+    if in_use {
+        e.context(format!(
+            "{addr} is already in use, stop what listens there or pick another --port"
+        ))
     } else {
-        info!("Entry {entry_name} not found");
-        warp::reply::html(handlebars_support.format_not_found(blog_info(), entry_name))
+        e.context(format!("Failed to listen on {addr}"))
     }
 }
 
-async fn home(
-    storage: Arc<BlogStorage>,
-    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
-) -> Html<String> {
-    let mut accum = Vec::new();
-    storage
-        .iterate_most_recent_entries(|e| accum.push(e.clone()))
-        .await;
-    let home = handlebars_support
-        .read()
-        .expect("Poised handlebars support")
-        .format_home(blog_info(), accum);
-    warp::reply::html(home)
+// Sizes are given in megabytes, which a large enough number overflows
+fn bytes(flag: &str, megabytes: u64) -> anyhow::Result<u64> {
+    megabytes
+        .checked_mul(1024 * 1024)
+        .with_context(|| format!("{flag} {megabytes} is too large, the size in bytes overflows"))
 }
 
-async fn file(path: PathBuf, file_server: Arc<FileServer>) -> Response {
-    match file_server.serve(&path).await {
-        Ok(file) => warp::reply::with_header(file.data, "content-type", file.mime_type.to_string())
-            .into_response(),
-        Err(e) => {
-            error!("While serving request {path:?} error '{e}' happened");
-            warp::reply::with_status(
-                warp::reply::html("<h1>Not found</h1>"),
-                warp::http::StatusCode::NOT_FOUND,
-            )
-            .into_response()
-        }
+// The failures injected by the chaos flags must never reach real readers
+fn ensure_loopback(addr: SocketAddr) -> anyhow::Result<()> {
+    if !addr.ip().is_loopback() {
+        anyhow::bail!("The --chaos-* flags only work on a loopback address, not {addr}");
     }
+    Ok(())
 }
 
-fn sse_data(evt: UpdateEvent) -> Result<Event, Infallible> {
-    let event = match evt {
-        UpdateEvent::Reload => "reload",
-    };
-    Ok(Event::default().data(event.to_string()))
+fn listen_addresses(
+    address: Option<String>,
+    port: Option<u16>,
+    listen: &[String],
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut addresses = listen
+        .iter()
+        .map(|l| {
+            l.parse::<SocketAddr>()
+                .with_context(|| format!("Invalid --listen address '{l}', expected addr:port"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if addresses.is_empty() || address.is_some() || port.is_some() {
+        let address = address.unwrap_or(DEFAULT_ADDRESS.to_owned());
+        // Accept bracketed IPv6 literals too, since that's how they appear in urls
+        let ip = address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid --address '{address}'"))?;
+        addresses.push(SocketAddr::new(ip, port.unwrap_or(DEFAULT_PORT)));
+    }
+    Ok(addresses)
 }
 
-fn sse_update(receiver: Receiver<UpdateEvent>) -> impl Reply {
-    let stream = tokio_stream::wrappers::BroadcastStream::new(receiver);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let stream = stream.map(move |event| match event {
-        Ok(event) => sse_data(event),
-        Err(e) => {
-            error!("While receiving reload event: {e}");
-            sse_data(UpdateEvent::Reload)
+    fn args(flags: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("swes").chain(flags.iter().copied()))
+    }
+
+    #[test]
+    fn max_entries_comes_from_the_flag_then_the_server_config() {
+        let config = || ServerConfig {
+            max_entries: NonZeroUsize::new(5),
+            ..Default::default()
+        };
+        let flag = args(&["--max-entries", "3"]).unwrap();
+        assert_eq!(
+            flag.with_server_config(config()).max_entries,
+            NonZeroUsize::new(3)
+        );
+        let none = args(&[]).unwrap();
+        assert_eq!(
+            none.with_server_config(config()).max_entries,
+            NonZeroUsize::new(5)
+        );
+        // The builder's default is kept otherwise
+        let none = args(&[]).unwrap();
+        assert_eq!(
+            none.with_server_config(ServerConfig::default()).max_entries,
+            None
+        );
+    }
+
+    #[test]
+    fn max_entries_must_be_positive() {
+        assert!(args(&["--max-entries", "0"]).is_err());
+        assert!(args(&["--max-entries", "-1"]).is_err());
+        assert!(toml::from_str::<ServerConfig>("max_entries = 0").is_err());
+    }
+
+    #[test]
+    fn chaos_only_listens_on_loopback() {
+        for addr in ["127.0.0.1:8080", "[::1]:8080"] {
+            assert!(ensure_loopback(addr.parse().unwrap()).is_ok(), "{addr}");
         }
-    });
-    warp::sse::reply(stream)
+        for addr in ["0.0.0.0:8080", "192.168.1.2:8080", "[::]:8080"] {
+            assert!(ensure_loopback(addr.parse().unwrap()).is_err(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn sizes_too_large_for_bytes_are_rejected() {
+        assert_eq!(bytes("--upload-limit-mb", 3).unwrap(), 3 * 1024 * 1024);
+        assert_eq!(bytes("--upload-limit-mb", 0).unwrap(), 0);
+        let largest = u64::MAX / (1024 * 1024);
+        assert!(bytes("--upload-limit-mb", largest).is_ok());
+        let error = bytes("--upload-limit-mb", largest + 1).unwrap_err();
+        assert!(error.to_string().contains("--upload-limit-mb"), "{error}");
+    }
 }
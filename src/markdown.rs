@@ -0,0 +1,547 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Write},
+    ops::Deref,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use comrak::{
+    adapters::{HeadingAdapter, HeadingMeta},
+    nodes::{AstNode, NodeValue, Sourcepos},
+    plugins::syntect::SyntectAdapter,
+    Anchorizer, Arena, Plugins,
+};
+use serde::{Deserialize, Serialize};
+use syntect::highlighting::ThemeSet;
+
+use crate::{plaintext::inline_text, search::escape_html};
+
+// Longer first paragraphs are cut, as plain text, for the listings
+const MAX_EXCERPT_CHARS: usize = 500;
+
+pub const DEFAULT_HIGHLIGHT_THEME: &str = "InspiredGitHub";
+
+// The comrak extensions used for entries, sections and pages, set by the
+// [markdown] table of blog.toml. Read at startup only. The defaults are the
+// GitHub flavoured set
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownConfig {
+    pub tables: bool,
+    pub footnotes: bool,
+    pub strikethrough: bool,
+    pub autolink: bool,
+    pub tasklist: bool,
+    // The templates show the title of an entry as its <h1>: with this on,
+    // the headings of the entries containing an h1 are moved one level down
+    pub demote_headings: bool,
+    // The code blocks are highlighted with inline styles, so that themes
+    // need no stylesheet for them
+    pub syntax_highlighting: bool,
+    // One of the themes bundled with syntect, e.g. "base16-ocean.dark"
+    pub highlight_theme: String,
+}
+
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        Self {
+            tables: true,
+            footnotes: true,
+            strikethrough: true,
+            autolink: true,
+            tasklist: true,
+            demote_headings: false,
+            syntax_highlighting: true,
+            highlight_theme: DEFAULT_HIGHLIGHT_THEME.to_owned(),
+        }
+    }
+}
+
+// Built once and shared by everything parsing markdown
+pub type MarkdownOptions = Arc<Markdown>;
+
+impl MarkdownConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.syntax_highlighting {
+            return Ok(());
+        }
+        let themes = ThemeSet::load_defaults().themes;
+        if !themes.contains_key(&self.highlight_theme) {
+            let known = themes.keys().cloned().collect::<Vec<_>>().join(", ");
+            anyhow::bail!(
+                "Unknown highlight theme \"{}\", expected one of {known}",
+                self.highlight_theme
+            );
+        }
+        Ok(())
+    }
+
+    pub fn options(&self) -> MarkdownOptions {
+        let mut options = comrak::Options::default();
+        options.extension.table = self.tables;
+        options.extension.footnotes = self.footnotes;
+        options.extension.strikethrough = self.strikethrough;
+        options.extension.autolink = self.autolink;
+        options.extension.tasklist = self.tasklist;
+        Arc::new(Markdown {
+            options,
+            highlight_theme: self
+                .syntax_highlighting
+                .then(|| self.highlight_theme.clone()),
+            highlighter: OnceLock::new(),
+        })
+    }
+}
+
+// The comrak options, and the highlighter of the code blocks, whose syntaxes
+// are only loaded by the first render that needs them
+pub struct Markdown {
+    options: comrak::Options,
+    highlight_theme: Option<String>,
+    highlighter: OnceLock<SyntectAdapter>,
+}
+
+impl Markdown {
+    pub fn plugins(&self) -> Plugins<'_> {
+        let mut plugins = Plugins::default();
+        if let Some(theme) = &self.highlight_theme {
+            let highlighter = self
+                .highlighter
+                .get_or_init(|| SyntectAdapter::new(Some(theme)));
+            plugins.render.codefence_syntax_highlighter = Some(highlighter);
+        }
+        plugins
+    }
+}
+
+impl Deref for Markdown {
+    type Target = comrak::Options;
+
+    fn deref(&self) -> &comrak::Options {
+        &self.options
+    }
+}
+
+// Part of the fingerprint of the entry cache, the syntaxes are left out
+impl fmt::Debug for Markdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Markdown")
+            .field("options", &self.options)
+            .field("highlight_theme", &self.highlight_theme)
+            .finish()
+    }
+}
+
+pub struct RenderedEntry {
+    pub html: String,
+    pub excerpt: String,
+    // The excerpt as plain text, for the <meta> descriptions
+    pub excerpt_text: String,
+    pub accessibility_warnings: Vec<String>,
+    pub toc: Vec<TocItem>,
+    pub words: WordCount,
+}
+
+// Code is read more slowly than prose, so the reading time counts it apart
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WordCount {
+    pub prose: usize,
+    pub code: usize,
+}
+
+// A h2 or h3 of an entry, linked from its table of contents
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TocItem {
+    pub level: u8,
+    pub title: String,
+    pub anchor: String,
+}
+
+// Entries are parsed once for the HTML, the table of contents and the
+// accessibility lint. Without toc, the headings still get their ids
+pub fn render_entry(
+    markdown: &str,
+    options: &Markdown,
+    demote_headings: bool,
+    toc: bool,
+) -> anyhow::Result<RenderedEntry> {
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, markdown, options);
+    let accessibility_warnings = accessibility_lint(root);
+    if demote_headings {
+        demote_h1(root);
+    }
+    let headings = toc_items(root);
+    let words = count_words(root);
+    let (excerpt, excerpt_text) = match root
+        .children()
+        .find(|node| matches!(node.data.borrow().value, NodeValue::Paragraph))
+    {
+        Some(paragraph) => excerpt(paragraph, options)?,
+        None => (String::new(), String::new()),
+    };
+    let heading_ids = HeadingIds(Mutex::new(
+        headings.iter().map(|item| item.anchor.clone()).collect(),
+    ));
+    let mut plugins = options.plugins();
+    plugins.render.heading_adapter = Some(&heading_ids);
+    let mut html = vec![];
+    comrak::format_html_with_plugins(root, options, &mut html, &plugins)?;
+    Ok(RenderedEntry {
+        html: String::from_utf8(html)?,
+        excerpt,
+        excerpt_text,
+        accessibility_warnings,
+        toc: if toc { headings } else { vec![] },
+        words,
+    })
+}
+
+fn count_words<'a>(root: &'a AstNode<'a>) -> WordCount {
+    let mut prose = String::new();
+    let mut code = 0;
+    for node in root.descendants() {
+        match &node.data.borrow().value {
+            NodeValue::CodeBlock(block) => code += block.literal.split_whitespace().count(),
+            NodeValue::Text(text) => prose.push_str(text),
+            NodeValue::Code(inline) => prose.push_str(&inline.literal),
+            NodeValue::SoftBreak | NodeValue::LineBreak => prose.push(' '),
+            // e.g. the end of a paragraph and the start of the next one
+            value if value.block() => prose.push(' '),
+            _ => {}
+        }
+    }
+    WordCount {
+        prose: prose.split_whitespace().count(),
+        code,
+    }
+}
+
+// The anchors are unique within the entry: a repeated title gets -1, -2...
+fn toc_items<'a>(root: &'a AstNode<'a>) -> Vec<TocItem> {
+    let mut anchorizer = Anchorizer::new();
+    root.descendants()
+        .filter_map(|node| match &node.data.borrow().value {
+            NodeValue::Heading(heading) if matches!(heading.level, 2 | 3) => {
+                let title = inline_text(node);
+                Some(TocItem {
+                    level: heading.level,
+                    anchor: anchorizer.anchorize(title.clone()),
+                    title,
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Gives the h2 and h3 their anchors, in the order toc_items found them
+struct HeadingIds(Mutex<VecDeque<String>>);
+
+impl HeadingAdapter for HeadingIds {
+    fn enter(
+        &self,
+        output: &mut dyn Write,
+        heading: &HeadingMeta,
+        _sourcepos: Option<Sourcepos>,
+    ) -> io::Result<()> {
+        let anchor = match heading.level {
+            2 | 3 => self.0.lock().expect("Poisoned heading ids").pop_front(),
+            _ => None,
+        };
+        match anchor {
+            Some(anchor) => write!(
+                output,
+                "<h{} id=\"{}\">",
+                heading.level,
+                escape_html(&anchor)
+            ),
+            None => write!(output, "<h{}>", heading.level),
+        }
+    }
+
+    fn exit(&self, output: &mut dyn Write, heading: &HeadingMeta) -> io::Result<()> {
+        writeln!(output, "</h{}>", heading.level)
+    }
+}
+
+// The first paragraph as HTML, or its text cut at a word boundary when it's
+// too long, since the markup can't be cut safely. Along with its plain text
+fn excerpt<'a>(
+    paragraph: &'a AstNode<'a>,
+    options: &comrak::Options,
+) -> anyhow::Result<(String, String)> {
+    let text = inline_text(paragraph);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= MAX_EXCERPT_CHARS {
+        let mut html = vec![];
+        comrak::format_html(paragraph, options, &mut html)?;
+        return Ok((String::from_utf8(html)?.trim_end().to_owned(), text));
+    }
+    let cut = cut_text(&text, MAX_EXCERPT_CHARS);
+    Ok((format!("<p>{}</p>", escape_html(&cut)), cut))
+}
+
+// At most max_chars, cut at a word boundary and marked with an ellipsis when
+// it's longer
+pub(crate) fn cut_text(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_owned();
+    }
+    let cut: String = text.chars().take(max_chars).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(end) => &cut[..end],
+        None => &cut,
+    };
+    format!("{}…", cut.trim_end())
+}
+
+// Images without an alt text, and headings skipping a level. The title comes
+// first as the h1, so the content is expected to start at h2 at most
+fn accessibility_lint<'a>(root: &'a AstNode<'a>) -> Vec<String> {
+    let mut warnings = vec![];
+    let mut previous_level = 1;
+    for node in root.descendants() {
+        match &node.data.borrow().value {
+            NodeValue::Heading(heading) => {
+                if heading.level > previous_level + 1 {
+                    warnings.push(format!(
+                        "Heading \"{}\" skips from h{previous_level} to h{}",
+                        inline_text(node),
+                        heading.level
+                    ));
+                }
+                previous_level = heading.level;
+            }
+            NodeValue::Image(image) if inline_text(node).trim().is_empty() => {
+                warnings.push(format!("Image {} has no alt text", image.url));
+            }
+            _ => {}
+        }
+    }
+    warnings
+}
+
+// Content already starting at h2 is left as it is
+fn demote_h1<'a>(root: &'a AstNode<'a>) {
+    let has_h1 = root
+        .descendants()
+        .any(|node| matches!(&node.data.borrow().value, NodeValue::Heading(h) if h.level == 1));
+    if !has_h1 {
+        return;
+    }
+    for node in root.descendants() {
+        if let NodeValue::Heading(heading) = &mut node.data.borrow_mut().value {
+            heading.level = (heading.level + 1).min(6);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(markdown: &str, config: &MarkdownConfig) -> String {
+        render_entry(markdown, &config.options(), false, true)
+            .unwrap()
+            .html
+    }
+
+    const TABLE: &str = "| a | b |\n|---|---|\n| 1 | 2 |\n";
+
+    #[test]
+    fn renders_tables_when_enabled() {
+        let html = html(TABLE, &MarkdownConfig::default());
+        assert!(html.contains("<table>"), "{html}");
+        assert!(html.contains("<td>1</td>"), "{html}");
+    }
+
+    #[test]
+    fn leaves_tables_as_text_when_disabled() {
+        let config: MarkdownConfig = toml::from_str("tables = false").unwrap();
+        let html = html(TABLE, &config);
+        assert!(!html.contains("<table>"), "{html}");
+        assert!(html.contains("<p>| a | b |"), "{html}");
+    }
+
+    #[test]
+    fn each_extension_can_be_turned_off() {
+        let cases = [
+            ("strikethrough", "~~gone~~", "<del>gone</del>"),
+            (
+                "autolink",
+                "see https://example.com",
+                "<a href=\"https://example.com\">",
+            ),
+            ("tasklist", "- [x] done", "type=\"checkbox\""),
+            ("footnotes", "a[^1]\n\n[^1]: note", "class=\"footnotes\""),
+        ];
+        for (extension, markdown, rendered) in cases {
+            assert!(
+                html(markdown, &MarkdownConfig::default()).contains(rendered),
+                "{extension}"
+            );
+            let config: MarkdownConfig = toml::from_str(&format!("{extension} = false")).unwrap();
+            assert!(!html(markdown, &config).contains(rendered), "{extension}");
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_settings() {
+        assert!(toml::from_str::<MarkdownConfig>("table = false").is_err());
+        let config = MarkdownConfig {
+            highlight_theme: "nope".to_owned(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(MarkdownConfig::default().validate().is_ok());
+    }
+
+    fn render(markdown: &str, demote_headings: bool) -> RenderedEntry {
+        let options = MarkdownConfig::default().options();
+        render_entry(markdown, &options, demote_headings, true).unwrap()
+    }
+
+    #[test]
+    fn demotes_every_heading_when_there_is_an_h1() {
+        let entry = render("# One\n\n## Two\n\n###### Six\n", true);
+        assert!(
+            entry.html.contains("<h2 id=\"one\">One</h2>"),
+            "{}",
+            entry.html
+        );
+        assert!(
+            entry.html.contains("<h3 id=\"two\">Two</h3>"),
+            "{}",
+            entry.html
+        );
+        // There's no h7
+        assert!(entry.html.contains("<h6>Six</h6>"), "{}", entry.html);
+        assert!(!entry.html.contains("<h1"), "{}", entry.html);
+        // The table of contents follows the new levels
+        let toc: Vec<_> = entry
+            .toc
+            .iter()
+            .map(|i| (i.level, i.title.as_str()))
+            .collect();
+        assert_eq!(toc, [(2, "One"), (3, "Two")]);
+    }
+
+    #[test]
+    fn leaves_entries_starting_at_h2_alone() {
+        let markdown = "## Two\n\n### Three\n";
+        let demoted = render(markdown, true);
+        assert_eq!(demoted.html, render(markdown, false).html);
+        assert!(demoted.html.contains("<h2 id=\"two\">Two</h2>"));
+        assert!(demoted.html.contains("<h3 id=\"three\">Three</h3>"));
+    }
+
+    #[test]
+    fn demotion_only_touches_headings() {
+        // Hashes in code, and lines only looking like headings, are kept
+        let markdown = concat!(
+            "# Title\n\n",
+            "```sh\n# a comment\n```\n\n",
+            "`# inline`\n\n",
+            "\\# escaped\n\n",
+            "Setext\n======\n",
+        );
+        let html = render(markdown, true).html;
+        assert!(html.contains("# a comment\n"), "{html}");
+        assert!(html.contains("<code># inline</code>"), "{html}");
+        assert!(html.contains("<p># escaped</p>"), "{html}");
+        assert!(html.contains("<h2 id=\"setext\">Setext</h2>"), "{html}");
+        assert_eq!(html.matches("<h2").count(), 2, "{html}");
+    }
+
+    #[test]
+    fn without_demotion_h1_are_kept() {
+        let html = render("# One\n\n## Two\n", false).html;
+        assert!(html.contains("<h1>One</h1>"), "{html}");
+        assert!(html.contains("<h2 id=\"two\">Two</h2>"), "{html}");
+    }
+
+    #[test]
+    fn warns_about_images_without_alt_text() {
+        let markdown = concat!(
+            "![](/files/bare.png)\n\n",
+            "![   ](/files/blank.png)\n\n",
+            "![A cat](/files/cat.png)\n\n",
+            "[![](/files/linked.png)](https://example.com)\n\n",
+            "![**Bold** alt](/files/bold.png)\n",
+        );
+        assert_eq!(
+            render(markdown, false).accessibility_warnings,
+            [
+                "Image /files/bare.png has no alt text",
+                "Image /files/blank.png has no alt text",
+                "Image /files/linked.png has no alt text",
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_about_skipped_heading_levels() {
+        assert!(render("## A\n\n### B\n\n## C\n\n#### D\n", false)
+            .accessibility_warnings
+            .contains(&"Heading \"D\" skips from h2 to h4".to_owned()));
+        // The title is the h1, so the content starts at h2 at most
+        assert_eq!(
+            render("### Deep\n", false).accessibility_warnings,
+            ["Heading \"Deep\" skips from h1 to h3"]
+        );
+        // Going back up is fine
+        assert!(render("## A\n\n### B\n\n## C\n", false)
+            .accessibility_warnings
+            .is_empty());
+        // The levels are checked as written, demotion moves them all
+        assert!(render("# A\n\n## B\n", true)
+            .accessibility_warnings
+            .is_empty());
+    }
+
+    fn anchors(entry: &RenderedEntry) -> Vec<(&str, &str)> {
+        entry
+            .toc
+            .iter()
+            .map(|i| (i.title.as_str(), i.anchor.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn repeated_headings_get_numbered_anchors() {
+        let entry = render("## Setup\n\n### Setup\n\n## Usage\n\n## Setup\n", false);
+        assert_eq!(
+            anchors(&entry),
+            [
+                ("Setup", "setup"),
+                ("Setup", "setup-1"),
+                ("Usage", "usage"),
+                ("Setup", "setup-2"),
+            ]
+        );
+        // The ids in the page are the ones the table of contents links to
+        for id in ["setup", "setup-1", "usage", "setup-2"] {
+            assert!(
+                entry.html.contains(&format!(" id=\"{id}\">")),
+                "{}",
+                entry.html
+            );
+        }
+    }
+
+    #[test]
+    fn without_toc_only_the_list_is_left_out() {
+        let options = MarkdownConfig::default().options();
+        let markdown = "## One\n\n## One\n";
+        let entry = render_entry(markdown, &options, false, false).unwrap();
+        assert!(entry.toc.is_empty());
+        assert_eq!(entry.html, render(markdown, false).html);
+        assert!(
+            entry.html.contains("<h2 id=\"one-1\">One</h2>"),
+            "{}",
+            entry.html
+        );
+    }
+}
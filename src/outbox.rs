@@ -0,0 +1,600 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncWriteExt, sync::Notify, time::Instant};
+
+use crate::clock::SharedClock;
+
+// A job failing this many times in a row is given up and moved to failed/
+const MAX_ATTEMPTS: u32 = 8;
+const FIRST_RETRY: Duration = Duration::from_secs(2);
+const MAX_RETRY: Duration = Duration::from_secs(15 * 60);
+// The completed jobs are remembered this long, so that the same key isn't
+// sent twice
+const DONE_KEPT: Duration = Duration::from_secs(30 * 24 * 3600);
+
+pub const CDN_PURGE: &str = "cdn_purge";
+
+const PENDING_DIR: &str = "pending";
+const DONE_DIR: &str = "done";
+const FAILED_DIR: &str = "failed";
+
+// Something to tell another service, which must not get lost if the server
+// restarts before it's been told
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboundJob {
+    CdnPurge { keys: Vec<String> },
+}
+
+impl OutboundJob {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutboundJob::CdnPurge { .. } => CDN_PURGE,
+        }
+    }
+}
+
+// Runs the jobs of one kind. An error is retried later, so a handler should
+// only fail when trying again has a chance to succeed
+pub trait JobHandler: Send + Sync {
+    fn run<'a>(&'a self, job: &'a OutboundJob) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+pub type JobHandlers = HashMap<&'static str, Arc<dyn JobHandler>>;
+
+// What a job file holds, in pending/, done/ or failed/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct QueuedJob {
+    key: String,
+    job: OutboundJob,
+    enqueued_at: DateTime<Utc>,
+    attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+struct Pending {
+    id: String,
+    queued: QueuedJob,
+    due: Instant,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    pending: Vec<Pending>,
+    // The ids of the jobs in done/
+    completed: HashSet<String>,
+    failed: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Serialize)]
+pub struct OutboxStats {
+    pub pending: usize,
+    pub pending_by_kind: BTreeMap<&'static str, usize>,
+    // Jobs given up since the server started, by kind
+    pub failed_by_kind: BTreeMap<&'static str, u64>,
+    pub retries: u64,
+    pub completed_remembered: usize,
+}
+
+// A directory of job files: enqueuing writes one to pending/, and a single
+// worker runs them in order, moving each to done/ or, once its attempts ran
+// out, to failed/. Whatever is still pending on startup is run again.
+//
+// Every job has a key, and a key found in done/ is never run twice: a job
+// is recorded as done before its pending file goes away, so a restart in
+// between doesn't repeat it. The handlers still have to be safe to run again
+// for a restart in the middle of a job, as there's no telling whether the
+// other service got it
+pub struct Outbox {
+    dir: PathBuf,
+    clock: SharedClock,
+    state: Mutex<OutboxState>,
+    notify: Notify,
+    retries: AtomicU64,
+}
+
+impl Outbox {
+    pub async fn open(dir: impl Into<PathBuf>, clock: SharedClock) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        for sub in [PENDING_DIR, DONE_DIR, FAILED_DIR] {
+            tokio::fs::create_dir_all(dir.join(sub)).await?;
+        }
+        let mut state = OutboxState::default();
+
+        let mut done = tokio::fs::read_dir(dir.join(DONE_DIR)).await?;
+        while let Some(file) = done.next_entry().await? {
+            let path = file.path();
+            let Some(id) = job_id_of(&path) else {
+                continue;
+            };
+            let age = file
+                .metadata()
+                .await?
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            if age.is_some_and(|age| age > DONE_KEPT) {
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    warn!("Failed to remove the old outbox job {path:?}: {e}");
+                }
+                continue;
+            }
+            state.completed.insert(id);
+        }
+
+        let now = Instant::now();
+        let mut pending = tokio::fs::read_dir(dir.join(PENDING_DIR)).await?;
+        while let Some(file) = pending.next_entry().await? {
+            let path = file.path();
+            let Some(id) = job_id_of(&path) else {
+                continue;
+            };
+            // Done, but the server stopped before the pending file was removed
+            if state.completed.contains(&id) {
+                tokio::fs::remove_file(&path).await?;
+                continue;
+            }
+            let content = tokio::fs::read(&path).await?;
+            match serde_json::from_slice::<QueuedJob>(&content) {
+                Ok(queued) => state.pending.push(Pending {
+                    id,
+                    queued,
+                    due: now,
+                }),
+                Err(e) => warn!("Skipping malformed outbox job {path:?}: {e}"),
+            }
+        }
+        state
+            .pending
+            .sort_by_key(|pending| pending.queued.enqueued_at);
+        if !state.pending.is_empty() {
+            info!(
+                "{} outbound jobs were still pending in {dir:?}",
+                state.pending.len()
+            );
+        }
+
+        Ok(Self {
+            dir,
+            clock,
+            state: Mutex::new(state),
+            notify: Notify::new(),
+            retries: AtomicU64::new(0),
+        })
+    }
+
+    // False when a job with the same key is already pending or done
+    pub async fn enqueue(&self, key: impl Into<String>, job: OutboundJob) -> anyhow::Result<bool> {
+        let key = key.into();
+        let id = job_id(&key);
+        if self.is_known(&id) {
+            return Ok(false);
+        }
+        let queued = QueuedJob {
+            key,
+            job,
+            enqueued_at: self.clock.now(),
+            attempts: 0,
+            last_error: None,
+        };
+        self.write(PENDING_DIR, &id, &queued).await?;
+        {
+            let mut state = self.state.lock().expect("Poisoned outbox");
+            // Enqueued concurrently while the file was written
+            if state.pending.iter().any(|pending| pending.id == id) {
+                return Ok(false);
+            }
+            state.pending.push(Pending {
+                id,
+                queued,
+                due: Instant::now(),
+            });
+        }
+        self.notify.notify_one();
+        Ok(true)
+    }
+
+    fn is_known(&self, id: &str) -> bool {
+        let state = self.state.lock().expect("Poisoned outbox");
+        state.completed.contains(id) || state.pending.iter().any(|pending| pending.id == id)
+    }
+
+    pub async fn run(&self, handlers: JobHandlers) {
+        loop {
+            let next = {
+                let state = self.state.lock().expect("Poisoned outbox");
+                state
+                    .pending
+                    .iter()
+                    .min_by_key(|pending| pending.due)
+                    .map(|pending| (pending.id.clone(), pending.queued.clone(), pending.due))
+            };
+            match next {
+                None => self.notify.notified().await,
+                Some((_, _, due)) if due > Instant::now() => {
+                    // Woken up early by a new job, which may be due sooner
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(due) => {}
+                        _ = self.notify.notified() => {}
+                    }
+                }
+                Some((id, queued, _)) => self.execute(&id, queued, &handlers).await,
+            }
+        }
+    }
+
+    async fn execute(&self, id: &str, mut queued: QueuedJob, handlers: &JobHandlers) {
+        let kind = queued.job.kind();
+        let Some(handler) = handlers.get(kind) else {
+            // e.g. the CDN webhook was removed from the config since, trying
+            // again won't help
+            let e = anyhow::anyhow!("Nothing handles {kind} jobs anymore");
+            error!("Gave up the outbound job {}: {e}", queued.key);
+            queued.last_error = Some(e.to_string());
+            self.give_up(id, queued).await;
+            return;
+        };
+        queued.attempts = queued.attempts.saturating_add(1);
+        match handler.run(&queued.job).await {
+            Ok(()) => {
+                queued.last_error = None;
+                if let Err(e) = self.write(DONE_DIR, id, &queued).await {
+                    // Still pending, it's run again once the disk is back
+                    error!("Failed to record the outbound job {}: {e}", queued.key);
+                    self.postpone(id, queued);
+                    return;
+                }
+                self.remove_pending(id).await;
+                let mut state = self.state.lock().expect("Poisoned outbox");
+                state.pending.retain(|pending| pending.id != id);
+                state.completed.insert(id.to_owned());
+            }
+            Err(e) if queued.attempts >= MAX_ATTEMPTS => {
+                error!(
+                    "Gave up the outbound job {} after {} attempts: {e:#}",
+                    queued.key, queued.attempts
+                );
+                queued.last_error = Some(format!("{e:#}"));
+                self.give_up(id, queued).await;
+            }
+            Err(e) => {
+                warn!(
+                    "Outbound job {} failed (attempt {}/{MAX_ATTEMPTS}): {e:#}",
+                    queued.key, queued.attempts
+                );
+                queued.last_error = Some(format!("{e:#}"));
+                // Keeps the attempts across restarts
+                if let Err(e) = self.write(PENDING_DIR, id, &queued).await {
+                    error!("Failed to update the outbound job {}: {e}", queued.key);
+                }
+                self.retries.fetch_add(1, Ordering::Relaxed);
+                self.postpone(id, queued);
+            }
+        }
+    }
+
+    async fn give_up(&self, id: &str, queued: QueuedJob) {
+        if let Err(e) = self.write(FAILED_DIR, id, &queued).await {
+            error!("Failed to record the outbound job {}: {e}", queued.key);
+        }
+        self.remove_pending(id).await;
+        let mut state = self.state.lock().expect("Poisoned outbox");
+        state.pending.retain(|pending| pending.id != id);
+        *state.failed.entry(queued.job.kind()).or_default() += 1;
+    }
+
+    fn postpone(&self, id: &str, queued: QueuedJob) {
+        let due = Instant::now() + retry_delay(queued.attempts);
+        let mut state = self.state.lock().expect("Poisoned outbox");
+        if let Some(pending) = state.pending.iter_mut().find(|pending| pending.id == id) {
+            pending.queued = queued;
+            pending.due = due;
+        }
+    }
+
+    async fn remove_pending(&self, id: &str) {
+        let path = self.job_path(PENDING_DIR, id);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            error!("Failed to remove the outbound job {path:?}: {e}");
+        }
+    }
+
+    async fn write(&self, sub: &str, id: &str, queued: &QueuedJob) -> anyhow::Result<()> {
+        let path = self.job_path(sub, id);
+        let temp_path = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        file.write_all(&serde_json::to_vec(queued)?).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    fn job_path(&self, sub: &str, id: &str) -> PathBuf {
+        self.dir.join(sub).join(format!("{id}.json"))
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        let state = self.state.lock().expect("Poisoned outbox");
+        let mut pending_by_kind = BTreeMap::new();
+        for pending in &state.pending {
+            *pending_by_kind
+                .entry(pending.queued.job.kind())
+                .or_default() += 1;
+        }
+        OutboxStats {
+            pending: state.pending.len(),
+            pending_by_kind,
+            failed_by_kind: state.failed.clone(),
+            retries: self.retries.load(Ordering::Relaxed),
+            completed_remembered: state.completed.len(),
+        }
+    }
+}
+
+// Keys are free form, the file names are their hashes
+fn job_id(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn job_id_of(path: &Path) -> Option<String> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    path.file_stem()?.to_str().map(str::to_owned)
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    FIRST_RETRY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+    use crate::{clock::SystemClock, test_support::TempDir};
+
+    // Fails its first runs, and records every job it's given
+    #[derive(Default)]
+    struct FakeHandler {
+        failures: AtomicU32,
+        runs: Mutex<Vec<OutboundJob>>,
+    }
+
+    impl FakeHandler {
+        fn failing(times: u32) -> Arc<Self> {
+            Arc::new(Self {
+                failures: AtomicU32::new(times),
+                ..Default::default()
+            })
+        }
+
+        fn runs(&self) -> Vec<OutboundJob> {
+            self.runs.lock().unwrap().clone()
+        }
+    }
+
+    impl JobHandler for FakeHandler {
+        fn run<'a>(&'a self, job: &'a OutboundJob) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                self.runs.lock().unwrap().push(job.clone());
+                let failed = self
+                    .failures
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    .is_ok();
+                if failed {
+                    anyhow::bail!("The service is down");
+                }
+                Ok(())
+            })
+        }
+    }
+
+    async fn open(dir: &TempDir) -> Outbox {
+        Outbox::open(dir.join("outbox"), Arc::new(SystemClock))
+            .await
+            .unwrap()
+    }
+
+    fn purge(key: &str) -> OutboundJob {
+        OutboundJob::CdnPurge {
+            keys: vec![key.to_owned()],
+        }
+    }
+
+    fn handlers(handler: &Arc<FakeHandler>) -> JobHandlers {
+        let handler: Arc<dyn JobHandler> = handler.clone();
+        HashMap::from([(CDN_PURGE, handler)])
+    }
+
+    // What the worker picks next, run whether it's due or not
+    async fn run_next(outbox: &Outbox, handlers: &JobHandlers) {
+        let (id, queued) = {
+            let state = outbox.state.lock().unwrap();
+            let next = state.pending.iter().min_by_key(|p| p.due).unwrap();
+            (next.id.clone(), next.queued.clone())
+        };
+        outbox.execute(&id, queued, handlers).await;
+    }
+
+    fn files(dir: &TempDir, sub: &str) -> Vec<QueuedJob> {
+        std::fs::read_dir(dir.join("outbox").join(sub))
+            .unwrap()
+            .map(|file| serde_json::from_slice(&std::fs::read(file.unwrap().path()).unwrap()))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_are_retried_later() {
+        let dir = TempDir::new("outbox-retry");
+        let outbox = open(&dir).await;
+        let handler = FakeHandler::failing(2);
+        let handlers = handlers(&handler);
+        assert!(outbox.enqueue("purge-1", purge("a")).await.unwrap());
+
+        run_next(&outbox, &handlers).await;
+        let pending = files(&dir, PENDING_DIR);
+        assert_eq!(pending.len(), 1);
+        // Kept on disk, for a restart
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(
+            pending[0].last_error.as_deref(),
+            Some("The service is down")
+        );
+        {
+            let state = outbox.state.lock().unwrap();
+            assert!(state.pending[0].due > Instant::now() + FIRST_RETRY / 2);
+        }
+
+        run_next(&outbox, &handlers).await;
+        run_next(&outbox, &handlers).await;
+        assert_eq!(handler.runs(), [purge("a"), purge("a"), purge("a")]);
+        assert!(files(&dir, PENDING_DIR).is_empty());
+        let done = files(&dir, DONE_DIR);
+        assert_eq!((done[0].attempts, done[0].last_error.as_ref()), (3, None));
+        let stats = outbox.stats();
+        assert_eq!((stats.pending, stats.retries), (0, 2));
+        assert_eq!(stats.completed_remembered, 1);
+    }
+
+    #[tokio::test]
+    async fn jobs_are_given_up_after_their_attempts() {
+        let dir = TempDir::new("outbox-give-up");
+        let outbox = open(&dir).await;
+        let handler = FakeHandler::failing(u32::MAX);
+        let handlers = handlers(&handler);
+        outbox.enqueue("purge-1", purge("a")).await.unwrap();
+        for _ in 0..MAX_ATTEMPTS {
+            run_next(&outbox, &handlers).await;
+        }
+        assert_eq!(handler.runs().len(), MAX_ATTEMPTS as usize);
+        assert!(files(&dir, PENDING_DIR).is_empty());
+        assert_eq!(files(&dir, FAILED_DIR)[0].attempts, MAX_ATTEMPTS);
+        let stats = outbox.stats();
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.failed_by_kind[CDN_PURGE], 1);
+        assert_eq!(stats.retries, u64::from(MAX_ATTEMPTS) - 1);
+
+        // Without a handler, trying again is pointless
+        outbox.enqueue("purge-2", purge("b")).await.unwrap();
+        run_next(&outbox, &HashMap::new()).await;
+        assert_eq!(outbox.stats().failed_by_kind[CDN_PURGE], 2);
+        assert_eq!(files(&dir, FAILED_DIR).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn pending_jobs_are_reloaded() {
+        let dir = TempDir::new("outbox-reload");
+        let outbox = open(&dir).await;
+        outbox.enqueue("purge-1", purge("a")).await.unwrap();
+        outbox.enqueue("purge-2", purge("b")).await.unwrap();
+        run_next(&outbox, &handlers(&FakeHandler::failing(1))).await;
+        drop(outbox);
+        dir.write("outbox/pending/junk.json", "{");
+        dir.write("outbox/pending/notes.txt", "not a job");
+
+        let outbox = open(&dir).await;
+        assert_eq!(outbox.stats().pending, 2);
+        let handler = FakeHandler::failing(0);
+        let handlers = handlers(&handler);
+        run_next(&outbox, &handlers).await;
+        run_next(&outbox, &handlers).await;
+        // In the order they were enqueued, the attempts carried over
+        assert_eq!(handler.runs(), [purge("a"), purge("b")]);
+        let mut attempts: Vec<_> = files(&dir, DONE_DIR).iter().map(|j| j.attempts).collect();
+        attempts.sort();
+        assert_eq!(attempts, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn keys_are_only_ever_run_once() {
+        let dir = TempDir::new("outbox-keys");
+        let outbox = open(&dir).await;
+        let handler = FakeHandler::failing(0);
+        let handlers = handlers(&handler);
+        assert!(outbox.enqueue("purge-1", purge("a")).await.unwrap());
+        // Pending
+        assert!(!outbox.enqueue("purge-1", purge("b")).await.unwrap());
+        run_next(&outbox, &handlers).await;
+        // Done
+        assert!(!outbox.enqueue("purge-1", purge("a")).await.unwrap());
+        drop(outbox);
+
+        let outbox = open(&dir).await;
+        assert!(!outbox.enqueue("purge-1", purge("a")).await.unwrap());
+        assert_eq!(handler.runs(), [purge("a")]);
+    }
+
+    #[tokio::test]
+    async fn jobs_recorded_as_done_are_not_run_after_a_crash() {
+        let dir = TempDir::new("outbox-crash");
+        let outbox = open(&dir).await;
+        outbox.enqueue("purge-1", purge("a")).await.unwrap();
+        drop(outbox);
+        // As if the server stopped between the two steps of a success
+        let pending = dir.join("outbox").join(PENDING_DIR);
+        let file = std::fs::read_dir(&pending)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        std::fs::copy(
+            file.path(),
+            dir.join("outbox").join(DONE_DIR).join(file.file_name()),
+        )
+        .unwrap();
+
+        let outbox = open(&dir).await;
+        assert_eq!(outbox.stats().pending, 0);
+        assert!(files(&dir, PENDING_DIR).is_empty());
+        assert!(!outbox.enqueue("purge-1", purge("a")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn the_worker_runs_new_jobs() {
+        let dir = TempDir::new("outbox-worker");
+        let outbox = Arc::new(open(&dir).await);
+        let handler = FakeHandler::failing(0);
+        let worker = tokio::spawn({
+            let outbox = outbox.clone();
+            let handlers = handlers(&handler);
+            async move { outbox.run(handlers).await }
+        });
+        outbox.enqueue("purge-1", purge("a")).await.unwrap();
+        let done = tokio::time::timeout(Duration::from_secs(5), async {
+            while outbox.stats().completed_remembered == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await;
+        worker.abort();
+        assert!(done.is_ok(), "The job never ran");
+        assert_eq!(handler.runs(), [purge("a")]);
+    }
+
+    #[test]
+    fn retries_back_off_up_to_a_limit() {
+        assert_eq!(retry_delay(1), FIRST_RETRY);
+        assert_eq!(retry_delay(2), FIRST_RETRY * 2);
+        assert_eq!(retry_delay(4), FIRST_RETRY * 8);
+        assert_eq!(retry_delay(30), MAX_RETRY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY);
+    }
+}
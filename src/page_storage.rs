@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    blog_storage::parse_document,
+    feed::{FEED_ALIASES, FEED_FILES},
+    images::ResponsiveImages,
+    markdown::{MarkdownConfig, MarkdownOptions},
+};
+
+// Top level paths already taken by the server, a page can't be served there
+const RESERVED_SLUGS: &[&str] = &[
+    "blog",
+    "files",
+    "events",
+    "admin",
+    "preview",
+    "api",
+    "feed",
+    "pages",
+    "sitemap.xml",
+];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PageMetadata {
+    pub title: String,
+    #[serde(default)]
+    pub date: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Page {
+    pub slug: String,
+    pub metadata: PageMetadata,
+    pub html: String,
+}
+
+// Standalone pages (about, contacts...) live apart from the blog entries, so
+// that they never show up in the feeds or on the home
+pub struct PageStorage {
+    base_path: PathBuf,
+    at_root: bool,
+    images: Option<Arc<ResponsiveImages>>,
+    markdown: MarkdownOptions,
+    pages: RwLock<HashMap<String, Arc<Page>>>,
+}
+
+impl PageStorage {
+    pub fn new<P: AsRef<Path>>(base: P, at_root: bool) -> Self {
+        Self {
+            base_path: base.as_ref().to_path_buf(),
+            at_root,
+            images: None,
+            markdown: MarkdownConfig::default().options(),
+            pages: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_images(mut self, images: Arc<ResponsiveImages>) -> Self {
+        self.images = Some(images);
+        self
+    }
+
+    pub fn with_markdown(mut self, markdown: MarkdownOptions) -> Self {
+        self.markdown = markdown;
+        self
+    }
+
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    pub async fn scan(&self) -> anyhow::Result<()> {
+        let mut dir = match tokio::fs::read_dir(&self.base_path).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Pages directory {:?} not found", self.base_path);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                self.load(&entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn slug_for_path(path: &Path) -> Option<String> {
+        if path.extension()? != "md" {
+            return None;
+        }
+        let slug = path.file_stem()?.to_str()?;
+        if slug.starts_with('_') {
+            return None;
+        }
+        Some(slug.to_owned())
+    }
+
+    pub async fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let Some(slug) = Self::slug_for_path(path) else {
+            return Ok(());
+        };
+        if self.at_root && is_reserved(&slug) {
+            anyhow::bail!("Page {path:?} collides with the /{slug} route, rename it");
+        }
+        let content = tokio::fs::read_to_string(path).await?;
+        let document = parse_document::<PageMetadata>(&content, &self.markdown)?;
+        info!("Storing page {slug}");
+        let html = match &self.images {
+            Some(images) => images.rewrite_html(&document.html),
+            None => document.html,
+        };
+        let page = Page {
+            slug: slug.clone(),
+            metadata: document.metadata,
+            html,
+        };
+        self.pages.write().await.insert(slug, Arc::new(page));
+        Ok(())
+    }
+
+    pub async fn remove(&self, path: &Path) {
+        if let Some(slug) = Self::slug_for_path(path) {
+            info!("Removing page {slug}");
+            self.pages.write().await.remove(&slug);
+        }
+    }
+
+    pub async fn get_page(&self, slug: &str) -> Option<Arc<Page>> {
+        self.pages.read().await.get(slug).cloned()
+    }
+
+    pub async fn pages(&self) -> Vec<Arc<Page>> {
+        let mut pages: Vec<_> = self.pages.read().await.values().cloned().collect();
+        pages.sort_by(|a, b| a.slug.cmp(&b.slug));
+        pages
+    }
+
+    pub fn url_path(&self, slug: &str) -> String {
+        if self.at_root {
+            format!("/{slug}")
+        } else {
+            format!("/pages/{slug}")
+        }
+    }
+}
+
+fn is_reserved(slug: &str) -> bool {
+    RESERVED_SLUGS.contains(&slug)
+        || FEED_ALIASES
+            .iter()
+            .chain(FEED_FILES)
+            .any(|(name, _)| *name == slug)
+}
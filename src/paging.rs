@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use warp::{Filter, Rejection};
+
+// Whatever the query asks for, a page never has more items than this
+pub const MAX_PER_PAGE: usize = 50;
+
+// A page of a listing, as asked by ?page=N&per_page=M. Pages are 1-based
+#[derive(Clone, Copy, Debug)]
+pub struct PageRequest {
+    pub page: usize,
+    pub per_page: usize,
+    // Carried over to the links of the other pages, only when it isn't the
+    // listing's default
+    pub per_page_param: Option<usize>,
+}
+
+impl PageRequest {
+    pub fn new(page: usize, per_page: Option<usize>, default_per_page: usize) -> Self {
+        let per_page_param = per_page
+            .map(|per_page| per_page.clamp(1, MAX_PER_PAGE))
+            .filter(|per_page| *per_page != default_per_page);
+        Self {
+            page,
+            per_page: per_page_param.unwrap_or(default_per_page),
+            per_page_param,
+        }
+    }
+
+    pub fn first(per_page: usize) -> Self {
+        Self::new(1, None, per_page)
+    }
+}
+
+// The paging of every listing. Values that aren't numbers are ignored rather
+// than rejected, and ?size= is the older name of ?per_page=
+pub fn page_query(
+    default_per_page: usize,
+) -> impl Filter<Extract = (PageRequest,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().map(move |query: HashMap<String, String>| {
+        let number = |name: &str| query.get(name).and_then(|value| value.parse().ok());
+        PageRequest::new(
+            number("page").unwrap_or(1),
+            number("per_page").or_else(|| number("size")),
+            default_per_page,
+        )
+    })
+}
+
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    pub page: usize,
+    pub total_pages: usize,
+    pub total_items: usize,
+    per_page_param: Option<usize>,
+}
+
+impl<T> Paged<T> {
+    // An out of range page is clamped to the closest one, so that a listing
+    // that shrank still shows its last page
+    pub fn new(items: Vec<T>, request: PageRequest) -> Self {
+        let total_items = items.len();
+        let per_page = request.per_page.max(1);
+        let total_pages = total_items.div_ceil(per_page).max(1);
+        let page = request.page.clamp(1, total_pages);
+        let items = items
+            .into_iter()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .collect();
+        Self {
+            items,
+            page,
+            total_pages,
+            total_items,
+            per_page_param: request.per_page_param,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Paged<U> {
+        Paged {
+            items: self.items.into_iter().map(f).collect(),
+            page: self.page,
+            total_pages: self.total_pages,
+            total_items: self.total_items,
+            per_page_param: self.per_page_param,
+        }
+    }
+
+    // page_url is the path of the listing, which the links add ?page= to
+    pub fn pagination(&self, page_url: impl Into<String>) -> Pagination {
+        Pagination {
+            current_page: self.page,
+            total_pages: self.total_pages,
+            total_items: self.total_items,
+            has_prev: self.page > 1,
+            has_next: self.page < self.total_pages,
+            prev_page: self.page.saturating_sub(1).max(1),
+            next_page: (self.page + 1).min(self.total_pages),
+            page_size: self.per_page_param,
+            page_url: page_url.into(),
+        }
+    }
+}
+
+// Flattened into the context of the listings, for the pagination partial
+#[derive(Serialize, Clone)]
+pub struct Pagination {
+    pub current_page: usize,
+    pub total_pages: usize,
+    pub total_items: usize,
+    pub has_prev: bool,
+    pub has_next: bool,
+    pub prev_page: usize,
+    pub next_page: usize,
+    pub page_size: Option<usize>,
+    pub page_url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn requested(query: &str) -> PageRequest {
+        warp::test::request()
+            .path(&format!("/{query}"))
+            .filter(&page_query(20))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_the_page_and_its_size() {
+        for (query, page, per_page, param) in [
+            ("", 1, 20, None),
+            ("?page=3", 3, 20, None),
+            ("?page=2&per_page=5", 2, 5, Some(5)),
+            ("?size=5", 1, 5, Some(5)),
+            ("?per_page=5&size=7", 1, 5, Some(5)),
+            // The default isn't carried over to the links
+            ("?per_page=20", 1, 20, None),
+        ] {
+            let request = requested(query).await;
+            assert_eq!(
+                (request.page, request.per_page, request.per_page_param),
+                (page, per_page, param),
+                "{query}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn clamps_the_page_size() {
+        let request = requested("?per_page=500").await;
+        assert_eq!(
+            (request.per_page, request.per_page_param),
+            (MAX_PER_PAGE, Some(MAX_PER_PAGE))
+        );
+        let request = requested("?per_page=0").await;
+        assert_eq!((request.per_page, request.per_page_param), (1, Some(1)));
+    }
+
+    #[tokio::test]
+    async fn ignores_values_that_are_not_numbers() {
+        for query in [
+            "?page=two",
+            "?page=-1&per_page=many",
+            "?per_page=",
+            "?other=1",
+        ] {
+            let request = requested(query).await;
+            assert_eq!((request.page, request.per_page), (1, 20), "{query}");
+        }
+    }
+
+    fn paged(items: usize, page: usize, per_page: usize) -> Paged<usize> {
+        Paged::new(
+            (0..items).collect(),
+            PageRequest::new(page, Some(per_page), 10),
+        )
+    }
+
+    #[test]
+    fn pages_are_clamped_to_the_listing() {
+        let first = paged(5, 0, 2);
+        assert_eq!((first.page, first.items), (1, vec![0, 1]));
+        let last = paged(5, 3, 2);
+        assert_eq!((last.page, last.total_pages, last.items), (3, 3, vec![4]));
+        let past = paged(5, 99, 2);
+        assert_eq!((past.page, past.items), (3, vec![4]));
+        let empty = paged(0, 2, 2);
+        assert_eq!(
+            (empty.page, empty.total_pages, empty.total_items),
+            (1, 1, 0)
+        );
+        assert!(empty.items.is_empty());
+    }
+
+    #[test]
+    fn pagination_links_stay_in_range() {
+        let middle = paged(5, 2, 2).map(|item| item * 10);
+        assert_eq!(middle.items, [20, 30]);
+        let pagination = middle.pagination("/blog/tag/rust");
+        assert!(pagination.has_prev && pagination.has_next);
+        assert_eq!((pagination.prev_page, pagination.next_page), (1, 3));
+        assert_eq!(pagination.page_size, Some(2));
+        assert_eq!(pagination.page_url, "/blog/tag/rust");
+
+        let only = Paged::new(vec![1], PageRequest::first(10)).pagination("/blog");
+        assert!(!only.has_prev && !only.has_next);
+        assert_eq!(
+            (only.prev_page, only.next_page, only.page_size),
+            (1, 1, None)
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use comrak::{
+    nodes::{AstNode, ListType, NodeValue},
+    Arena, Options,
+};
+
+pub const DEFAULT_WIDTH: usize = 78;
+
+pub fn markdown_to_plaintext(markdown: &str, options: &Options, width: usize) -> String {
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, markdown, options);
+    let mut text = render_children(root, width.max(1), false).join("\n");
+    text.push('\n');
+    text
+}
+
+fn render_children<'a>(node: &'a AstNode<'a>, width: usize, tight: bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    for child in node.children() {
+        let block = render_block(child, width);
+        if block.is_empty() {
+            continue;
+        }
+        if !lines.is_empty() && !tight {
+            lines.push(String::new());
+        }
+        lines.extend(block);
+    }
+    lines
+}
+
+fn render_block<'a>(node: &'a AstNode<'a>, width: usize) -> Vec<String> {
+    match &node.data.borrow().value {
+        NodeValue::Paragraph => wrap(&inline_text(node), width),
+        NodeValue::Heading(heading) => {
+            let mut lines = wrap(&inline_text(node), width);
+            let underline_len = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+            let underline = if heading.level == 1 { "=" } else { "-" };
+            lines.push(underline.repeat(underline_len));
+            lines
+        }
+        NodeValue::CodeBlock(code) => code.literal.lines().map(|l| format!("    {l}")).collect(),
+        NodeValue::HtmlBlock(_) | NodeValue::FrontMatter(_) => vec![],
+        NodeValue::ThematicBreak => vec!["-".repeat(width)],
+        NodeValue::BlockQuote => {
+            let lines = render_children(node, width.saturating_sub(2).max(1), false);
+            indent(lines, "> ", "> ")
+        }
+        NodeValue::List(list) => {
+            let mut lines = Vec::new();
+            for (i, item) in node.children().enumerate() {
+                let mut marker = match list.list_type {
+                    ListType::Bullet => "* ".to_owned(),
+                    ListType::Ordered => format!("{}. ", list.start + i),
+                };
+                if let NodeValue::TaskItem(checked) = &item.data.borrow().value {
+                    marker.push_str(if checked.is_some() { "[x] " } else { "[ ] " });
+                }
+                let marker_len = marker.chars().count();
+                let item_lines =
+                    render_children(item, width.saturating_sub(marker_len).max(1), list.tight);
+                if !lines.is_empty() && !list.tight {
+                    lines.push(String::new());
+                }
+                lines.extend(indent(item_lines, &marker, &" ".repeat(marker_len)));
+            }
+            lines
+        }
+        NodeValue::Table(_) => node
+            .children()
+            .map(|row| {
+                row.children()
+                    .map(|cell| inline_text(cell))
+                    .collect::<Vec<_>>()
+                    .join(" | ")
+            })
+            .collect(),
+        NodeValue::FootnoteDefinition(footnote) => {
+            let marker = format!("[^{}]: ", footnote.name);
+            let marker_len = marker.chars().count();
+            let lines = render_children(node, width.saturating_sub(marker_len).max(1), false);
+            indent(lines, &marker, &" ".repeat(marker_len))
+        }
+        _ => render_children(node, width, false),
+    }
+}
+
+pub(crate) fn inline_text<'a>(node: &'a AstNode<'a>) -> String {
+    let mut text = String::new();
+    for child in node.children() {
+        match &child.data.borrow().value {
+            NodeValue::Text(t) => text.push_str(t),
+            NodeValue::Code(code) => text.push_str(&code.literal),
+            NodeValue::SoftBreak => text.push(' '),
+            NodeValue::LineBreak => text.push('\n'),
+            NodeValue::HtmlInline(_) => {}
+            NodeValue::Link(link) => {
+                let label = inline_text(child);
+                if label.is_empty() || label == link.url {
+                    text.push_str(&link.url);
+                } else {
+                    text.push_str(&format!("{label} ({})", link.url));
+                }
+            }
+            NodeValue::Image(link) => {
+                let alt = inline_text(child);
+                text.push_str(&format!("[{alt}] ({})", link.url));
+            }
+            NodeValue::FootnoteReference(footnote) => {
+                text.push_str(&format!("[^{}]", footnote.name))
+            }
+            _ => text.push_str(&inline_text(child)),
+        }
+    }
+    text
+}
+
+// Greedy word wrapping: words are never split, so an URL longer than the
+// available width simply overflows its line
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for segment in text.split('\n') {
+        let mut line = String::new();
+        let mut line_len = 0;
+        for word in segment.split_whitespace() {
+            let word_len = word.chars().count();
+            if line_len > 0 && line_len + 1 + word_len > width {
+                lines.push(std::mem::take(&mut line));
+                line_len = 0;
+            }
+            if line_len > 0 {
+                line.push(' ');
+                line_len += 1;
+            }
+            line.push_str(word);
+            line_len += word_len;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn indent(lines: Vec<String>, first: &str, rest: &str) -> Vec<String> {
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let prefix = if i == 0 { first } else { rest };
+            if line.is_empty() {
+                prefix.trim_end().to_owned()
+            } else {
+                format!("{prefix}{line}")
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Options {
+        let mut options = Options::default();
+        options.extension.table = true;
+        options.extension.footnotes = true;
+        options.extension.tasklist = true;
+        options.extension.front_matter_delimiter = Some("---".to_owned());
+        options
+    }
+
+    const FIXTURE: &str = "---
+title: Everything at once
+---
+# A title
+
+Some *emphasis*, `code` and a [link](https://example.com) with a footnote[^1].
+
+## A section
+
+- one
+- [x] done
+- [ ] to do
+
+1. first
+2. second
+
+> quoted
+> text
+
+    let x = 1;
+    let y = 2;
+
+<div>dropped</div>
+
+| a | b |
+|---|---|
+| 1 | 2 |
+
+---
+
+![a cat](cat.png) https://example.com
+
+[^1]: The note.
+";
+
+    #[test]
+    fn renders_every_block_kind() {
+        assert_eq!(
+            markdown_to_plaintext(FIXTURE, &options(), 20),
+            "A title
+=======
+
+Some emphasis, code
+and a link
+(https://example.com)
+with a footnote[^1].
+
+A section
+---------
+
+* one
+* [x] done
+* [ ] to do
+
+1. first
+2. second
+
+> quoted text
+
+    let x = 1;
+    let y = 2;
+
+a | b
+1 | 2
+
+--------------------
+
+[a cat] (cat.png)
+https://example.com
+
+[^1]: The note.
+"
+        );
+    }
+
+    #[test]
+    fn wraps_nested_blocks_to_the_remaining_width() {
+        let markdown = "> aaa bbb ccc ddd\n\n- eee fff ggg hhh\n";
+        assert_eq!(
+            markdown_to_plaintext(markdown, &options(), 9),
+            "> aaa bbb\n> ccc ddd\n\n* eee fff\n  ggg hhh\n"
+        );
+    }
+
+    #[test]
+    fn never_splits_a_word_longer_than_the_line() {
+        assert_eq!(
+            markdown_to_plaintext("a https://example.com/long b", &options(), 5),
+            "a\nhttps://example.com/long\nb\n"
+        );
+        // Not even with a width of zero
+        assert_eq!(markdown_to_plaintext("a b", &options(), 0), "a\nb\n");
+    }
+
+    #[test]
+    fn keeps_hard_line_breaks() {
+        assert_eq!(
+            markdown_to_plaintext("one  \ntwo\nthree", &options(), 78),
+            "one\ntwo three\n"
+        );
+    }
+}
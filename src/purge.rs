@@ -0,0 +1,112 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use futures_util::future::BoxFuture;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
+
+use crate::blog_storage::BlogEntry;
+
+// Every listing (home, tags, sections) shows several entries, and any change
+// to one of them invalidates all of them
+pub const LISTINGS_KEY: &str = "listings";
+
+// Keys naming what a cached response was built from, shared by the purge API
+// and the CDN surrogate keys. Tags match regardless of case, and keys can't
+// contain spaces
+pub fn entry_key(slug: &str) -> String {
+    format!("entry:{}", utf8_percent_encode(slug, KEY_ESCAPED))
+}
+
+pub fn tag_key(tag: &str) -> String {
+    format!(
+        "tag:{}",
+        utf8_percent_encode(&tag.to_lowercase(), KEY_ESCAPED)
+    )
+}
+
+pub fn entry_keys(entry: &BlogEntry) -> Vec<String> {
+    let tags = entry.description.tags.iter().map(|tag| tag_key(tag));
+    std::iter::once(entry_key(&entry.filename))
+        .chain(tags)
+        .collect()
+}
+
+const KEY_ESCAPED: &AsciiSet = &CONTROLS.add(b' ').add(b',').add(b'%').add(b'"');
+
+#[derive(Deserialize, Default)]
+pub struct PurgeRequest {
+    #[serde(default)]
+    pub slugs: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub paths_prefix: Option<String>,
+    // Every registered cache when empty
+    #[serde(default)]
+    pub kinds: Vec<String>,
+}
+
+impl PurgeRequest {
+    pub fn has_selectors(&self) -> bool {
+        !self.slugs.is_empty() || !self.tags.is_empty() || self.paths_prefix.is_some()
+    }
+
+    // The keys of the selected slugs and tags, as entry_keys computes them
+    pub fn keys(&self) -> Vec<String> {
+        let slugs = self.slugs.iter().map(|slug| entry_key(slug));
+        let tags = self.tags.iter().map(|tag| tag_key(tag));
+        slugs.chain(tags).collect()
+    }
+
+    pub fn matches(&self, entry: &BlogEntry) -> bool {
+        let keys = self.keys();
+        entry_keys(entry).iter().any(|key| keys.contains(key))
+            || self
+                .paths_prefix
+                .as_ref()
+                .is_some_and(|prefix| entry.filename.starts_with(prefix))
+    }
+}
+
+// Anything caching data derived from entries. A cache is handed the entries
+// matched by a purge request and drops whatever it derived from them
+pub trait PurgeableCache: Send + Sync {
+    fn purge<'a>(&'a self, entries: &'a [Arc<BlogEntry>]) -> BoxFuture<'a, usize>;
+}
+
+#[derive(Default)]
+pub struct PurgeRegistry {
+    caches: Vec<(&'static str, Arc<dyn PurgeableCache>)>,
+}
+
+impl PurgeRegistry {
+    pub fn register(&mut self, kind: &'static str, cache: Arc<dyn PurgeableCache>) {
+        self.caches.push((kind, cache));
+    }
+
+    pub fn unknown_kind<'a>(&self, request: &'a PurgeRequest) -> Option<&'a str> {
+        request
+            .kinds
+            .iter()
+            .find(|kind| !self.caches.iter().any(|(k, _)| k == kind))
+            .map(String::as_str)
+    }
+
+    pub fn kinds(&self) -> Vec<&'static str> {
+        self.caches.iter().map(|(kind, _)| *kind).collect()
+    }
+
+    pub async fn purge(
+        &self,
+        request: &PurgeRequest,
+        entries: &[Arc<BlogEntry>],
+    ) -> BTreeMap<&'static str, usize> {
+        let mut purged = BTreeMap::new();
+        for (kind, cache) in &self.caches {
+            if request.kinds.is_empty() || request.kinds.iter().any(|k| k == kind) {
+                purged.insert(*kind, cache.purge(entries).await);
+            }
+        }
+        purged
+    }
+}
@@ -0,0 +1,180 @@
+use std::{os::unix::net::UnixDatagram, path::PathBuf, sync::Mutex};
+
+use log::{error, info};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReadinessState {
+    Starting,
+    Ready,
+    Stopping,
+}
+
+// The single source of truth about readiness: /readyz, the systemd
+// notifications and the ready file all follow its transitions
+pub struct Readiness {
+    state: Mutex<ReadinessState>,
+    notify_socket: Option<String>,
+    ready_file: Option<PathBuf>,
+}
+
+impl Readiness {
+    pub fn new(notify_socket: Option<String>, ready_file: Option<PathBuf>) -> Self {
+        Self {
+            state: Mutex::new(ReadinessState::Starting),
+            notify_socket,
+            ready_file,
+        }
+    }
+
+    pub fn from_env(ready_file: Option<PathBuf>) -> Self {
+        Self::new(std::env::var("NOTIFY_SOCKET").ok(), ready_file)
+    }
+
+    pub fn state(&self) -> ReadinessState {
+        *self.state.lock().expect("Poisoned readiness state")
+    }
+
+    pub fn set_ready(&self) {
+        let mut state = self.state.lock().expect("Poisoned readiness state");
+        if *state != ReadinessState::Starting {
+            return;
+        }
+        *state = ReadinessState::Ready;
+        if let Some(ready_file) = &self.ready_file {
+            if let Err(e) = std::fs::write(ready_file, std::process::id().to_string()) {
+                error!("Failed to create the ready file {ready_file:?}: {e}");
+            }
+        }
+        self.notify("READY=1");
+    }
+
+    pub fn set_stopping(&self) {
+        let mut state = self.state.lock().expect("Poisoned readiness state");
+        let previous = std::mem::replace(&mut *state, ReadinessState::Stopping);
+        if previous == ReadinessState::Stopping {
+            return;
+        }
+        self.notify("STOPPING=1");
+        // Only created once ready
+        if let Some(ready_file) = self
+            .ready_file
+            .as_ref()
+            .filter(|_| previous == ReadinessState::Ready)
+        {
+            if let Err(e) = std::fs::remove_file(ready_file) {
+                error!("Failed to remove the ready file {ready_file:?}: {e}");
+            }
+        }
+    }
+
+    // The sd_notify protocol: a datagram with newline separated assignments,
+    // sent to the socket named by $NOTIFY_SOCKET ('@' for abstract sockets)
+    fn notify(&self, message: &str) {
+        let Some(socket_path) = &self.notify_socket else {
+            return;
+        };
+        let result =
+            UnixDatagram::unbound().and_then(|socket| match socket_path.strip_prefix('@') {
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                    socket.send_to_addr(message.as_bytes(), &addr)
+                }
+                None => socket.send_to(message.as_bytes(), socket_path),
+            });
+        match result {
+            Ok(_) => info!("Notified the service manager: {message}"),
+            Err(e) => error!("Failed to notify {socket_path}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    // Stands in for the service manager
+    fn fake_socket(dir: &TempDir) -> (UnixDatagram, String) {
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        (socket, path.to_str().unwrap().to_owned())
+    }
+
+    // The notifications are sent synchronously, so they're all there already
+    fn received(socket: &UnixDatagram) -> Vec<String> {
+        socket.set_nonblocking(true).unwrap();
+        let mut messages = vec![];
+        let mut buffer = [0; 256];
+        while let Ok(len) = socket.recv(&mut buffer) {
+            messages.push(String::from_utf8_lossy(&buffer[..len]).into_owned());
+        }
+        messages
+    }
+
+    #[test]
+    fn notifies_each_transition_once() {
+        let dir = TempDir::new("readiness-notify");
+        let (socket, path) = fake_socket(&dir);
+        let ready_file = dir.join("swes.ready");
+        let readiness = Readiness::new(Some(path), Some(ready_file.clone()));
+        assert_eq!(readiness.state(), ReadinessState::Starting);
+        assert!(!ready_file.exists());
+
+        readiness.set_ready();
+        readiness.set_ready();
+        assert_eq!(readiness.state(), ReadinessState::Ready);
+        assert_eq!(received(&socket), ["READY=1"]);
+        assert_eq!(
+            std::fs::read_to_string(&ready_file).unwrap(),
+            std::process::id().to_string()
+        );
+
+        readiness.set_stopping();
+        readiness.set_stopping();
+        // Never ready again once stopping
+        readiness.set_ready();
+        assert_eq!(readiness.state(), ReadinessState::Stopping);
+        assert_eq!(received(&socket), ["STOPPING=1"]);
+        assert!(!ready_file.exists());
+    }
+
+    #[test]
+    fn stopping_before_ready_leaves_no_ready_file() {
+        let dir = TempDir::new("readiness-early-stop");
+        let (socket, path) = fake_socket(&dir);
+        let ready_file = dir.join("swes.ready");
+        let readiness = Readiness::new(Some(path), Some(ready_file.clone()));
+        readiness.set_stopping();
+        assert_eq!(received(&socket), ["STOPPING=1"]);
+        assert!(!ready_file.exists());
+    }
+
+    #[test]
+    fn works_without_a_service_manager() {
+        let dir = TempDir::new("readiness-missing");
+        // Nobody listening there: logged, never fatal
+        let readiness = Readiness::new(
+            Some(dir.join("none.sock").to_str().unwrap().to_owned()),
+            None,
+        );
+        readiness.set_ready();
+        assert_eq!(readiness.state(), ReadinessState::Ready);
+        let readiness = Readiness::new(None, None);
+        readiness.set_ready();
+        readiness.set_stopping();
+        assert_eq!(readiness.state(), ReadinessState::Stopping);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifies_abstract_sockets() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("swes-test-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        let readiness = Readiness::new(Some(format!("@{name}")), None);
+        readiness.set_ready();
+        assert_eq!(received(&socket), ["READY=1"]);
+    }
+}
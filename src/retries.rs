@@ -0,0 +1,286 @@
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::chaos::Chaos;
+
+pub const DEFAULT_READ_ATTEMPTS: u32 = 3;
+pub const DEFAULT_READ_BACKOFF: Duration = Duration::from_millis(50);
+
+// What a network filesystem returns for a while when its server hiccups, or
+// when a file was replaced under a handle it still holds
+const ESTALE: i32 = 116;
+pub(crate) const EIO: i32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadErrorKind {
+    NotFound,
+    // Worth trying again shortly
+    Transient,
+    Permanent,
+}
+
+impl ReadErrorKind {
+    pub fn of(e: &io::Error) -> Self {
+        match (e.kind(), e.raw_os_error()) {
+            (io::ErrorKind::NotFound, _) => ReadErrorKind::NotFound,
+            (io::ErrorKind::Interrupted, _) | (_, Some(ESTALE | EIO)) => ReadErrorKind::Transient,
+            _ => ReadErrorKind::Permanent,
+        }
+    }
+}
+
+// A read that still failed transiently once the attempts ran out
+#[derive(Debug)]
+pub struct TransientReadError {
+    pub path: PathBuf,
+    pub attempts: u32,
+    pub source: io::Error,
+}
+
+impl std::fmt::Display for TransientReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Reading {:?} failed {} times in a row",
+            self.path, self.attempts
+        )
+    }
+}
+
+impl std::error::Error for TransientReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReadRetryStats {
+    pub retries: u64,
+    // Reads that failed even after retrying
+    pub exhausted: u64,
+}
+
+// Retries the reads failing with a transient error a few times, the backoff
+// doubling between attempts. Any other error is returned right away
+pub struct ReadRetries {
+    attempts: u32,
+    backoff: Duration,
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+    chaos: Option<Arc<Chaos>>,
+}
+
+impl Default for ReadRetries {
+    fn default() -> Self {
+        Self::new(DEFAULT_READ_ATTEMPTS, DEFAULT_READ_BACKOFF)
+    }
+}
+
+impl ReadRetries {
+    // Attempts includes the first read, so 1 never retries
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            backoff,
+            retries: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+            chaos: None,
+        }
+    }
+
+    // Every read goes through here, which makes it where failures are injected
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    pub async fn read<T, F, Fut>(&self, path: &Path, mut read: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            let result = match &self.chaos {
+                Some(chaos) => chaos.read(read()).await,
+                None => read().await,
+            };
+            let e = match result {
+                Ok(value) => return Ok(value),
+                Err(e) if ReadErrorKind::of(&e) == ReadErrorKind::Transient => e,
+                Err(e) => return Err(e.into()),
+            };
+            if attempt >= self.attempts {
+                self.exhausted.fetch_add(1, Ordering::Relaxed);
+                return Err(TransientReadError {
+                    path: path.to_path_buf(),
+                    attempts: attempt,
+                    source: e,
+                }
+                .into());
+            }
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            warn!("Reading {path:?} failed with '{e}', retrying in {backoff:?}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    pub fn stats(&self) -> ReadRetryStats {
+        ReadRetryStats {
+            retries: self.retries.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicU32, time::Instant};
+
+    use super::*;
+
+    // A file failing its first reads with the given error
+    struct FlakyFile {
+        failures: u32,
+        error: fn() -> io::Error,
+        reads: AtomicU32,
+    }
+
+    impl FlakyFile {
+        fn new(failures: u32, error: fn() -> io::Error) -> Self {
+            Self {
+                failures,
+                error,
+                reads: AtomicU32::new(0),
+            }
+        }
+
+        async fn read(&self) -> io::Result<&'static str> {
+            let read = self.reads.fetch_add(1, Ordering::Relaxed);
+            if read < self.failures {
+                Err((self.error)())
+            } else {
+                Ok("content")
+            }
+        }
+
+        fn reads(&self) -> u32 {
+            self.reads.load(Ordering::Relaxed)
+        }
+    }
+
+    fn stale() -> io::Error {
+        io::Error::from_raw_os_error(ESTALE)
+    }
+
+    fn retries(attempts: u32) -> ReadRetries {
+        ReadRetries::new(attempts, Duration::from_millis(1))
+    }
+
+    #[test]
+    fn classifies_errors() {
+        for (error, kind) in [
+            (io::ErrorKind::NotFound.into(), ReadErrorKind::NotFound),
+            (io::ErrorKind::Interrupted.into(), ReadErrorKind::Transient),
+            (stale(), ReadErrorKind::Transient),
+            (io::Error::from_raw_os_error(EIO), ReadErrorKind::Transient),
+            (
+                io::ErrorKind::PermissionDenied.into(),
+                ReadErrorKind::Permanent,
+            ),
+            (io::ErrorKind::InvalidData.into(), ReadErrorKind::Permanent),
+        ] {
+            assert_eq!(ReadErrorKind::of(&error), kind, "{error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_within_the_budget() {
+        let retries = retries(3);
+        let file = FlakyFile::new(2, stale);
+        let content = retries.read(Path::new("a.md"), || file.read()).await;
+        assert_eq!(content.unwrap(), "content");
+        assert_eq!(file.reads(), 3);
+        let stats = retries.stats();
+        assert_eq!((stats.retries, stats.exhausted), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn reads_still_failing_exhaust_the_budget() {
+        let retries = retries(3);
+        let file = FlakyFile::new(10, stale);
+        let error = retries
+            .read(Path::new("a.md"), || file.read())
+            .await
+            .unwrap_err();
+        assert_eq!(file.reads(), 3);
+        let error = error.downcast::<TransientReadError>().unwrap();
+        assert_eq!(error.path, Path::new("a.md"));
+        assert_eq!(error.attempts, 3);
+        assert_eq!(error.source.raw_os_error(), Some(ESTALE));
+        let stats = retries.stats();
+        assert_eq!((stats.retries, stats.exhausted), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn other_errors_fail_right_away() {
+        for error in [
+            || io::Error::from(io::ErrorKind::NotFound),
+            || io::Error::from(io::ErrorKind::PermissionDenied),
+        ] {
+            let retries = retries(3);
+            let file = FlakyFile::new(1, error);
+            let failed = retries
+                .read(Path::new("a.md"), || file.read())
+                .await
+                .unwrap_err();
+            assert_eq!(file.reads(), 1);
+            let failed = failed.downcast::<io::Error>().unwrap();
+            assert_eq!(failed.kind(), error().kind());
+            let stats = retries.stats();
+            assert_eq!((stats.retries, stats.exhausted), (0, 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_single_attempt_never_retries() {
+        for attempts in [0, 1] {
+            let retries = retries(attempts);
+            let file = FlakyFile::new(1, stale);
+            let error = retries
+                .read(Path::new("a.md"), || file.read())
+                .await
+                .unwrap_err();
+            assert_eq!(file.reads(), 1);
+            assert_eq!(error.downcast::<TransientReadError>().unwrap().attempts, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_backoff_doubles() {
+        let retries = ReadRetries::new(4, Duration::from_millis(20));
+        let file = FlakyFile::new(3, stale);
+        let start = Instant::now();
+        retries
+            .read(Path::new("a.md"), || file.read())
+            .await
+            .unwrap();
+        // 20 + 40 + 80
+        assert!(start.elapsed() >= Duration::from_millis(140));
+        assert_eq!(retries.stats().retries, 3);
+    }
+}
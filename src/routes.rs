@@ -0,0 +1,2657 @@
+use std::{
+    convert::Infallible,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+use handlebars::RenderError;
+use log::{error, info};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use warp::{
+    filters::{
+        path::{FullPath, Tail},
+        BoxedFilter,
+    },
+    http::{HeaderValue, Method, StatusCode, Uri},
+    hyper::Body,
+    reply::{Reply, Response},
+    Filter, Rejection,
+};
+
+use crate::{
+    admin::{
+        admin_artifacts, admin_entries, admin_entries_purge, admin_incident, admin_outbox,
+        admin_parse_front_matter, admin_purge, admin_reads, admin_referrers, admin_upload,
+        admin_upload_multipart, share, AdminEntriesQuery, AdminLinks, PurgeForm, ShareQuery,
+    },
+    api,
+    artifact_store::{ArtifactStore, PLAINTEXT_CATEGORY},
+    blog_storage::{author_slug, BlogEntry, BlogInfo, BlogStorage, BlogStorageError},
+    cdn::{listing_keys, with_keys},
+    clock::SharedClock,
+    conditional::{conditional_request, http_date, ConditionalRequest},
+    diff,
+    engine::BlogEngine,
+    events::{sse_update, PollQuery, EVENTS_POLL_TIMEOUT},
+    feed::{self, FeedFormat},
+    file_server::{FileServer, FileServerError, ResolvedFilePolicy},
+    handlebars_support::{EntryAge, HandlebarsSupport},
+    identity::{self, WebfingerQuery},
+    images::ResponsiveImages,
+    incidents::{for_entry, Failure, Incidents},
+    journal::Journal,
+    markdown::MarkdownOptions,
+    page_storage::{Page, PageStorage},
+    paging::{page_query, PageRequest, Paged},
+    plaintext,
+    purge::{entry_keys, tag_key, PurgeRequest},
+    readiness::ReadinessState,
+    referrers::Referrers,
+    signing::Signer,
+    sitemap::{self, ChangeFrequency, SitemapUrl},
+    time_travel::{self, no_store, AsOf},
+    watchers,
+};
+
+const HOME_PAGE_SIZE: usize = 10;
+// The tags, authors and sections only list the titles
+const LISTING_PAGE_SIZE: usize = 20;
+const SEARCH_PAGE_SIZE: usize = 10;
+// Smaller archives are rendered at once, bigger ones a year at a time while
+// the first ones are already being sent
+const STREAMED_ARCHIVE_MIN_ENTRIES: usize = 200;
+const FILE_CACHE_CONTROL: &str = "max-age=3600";
+
+// Every route of the blog. Requests nothing matches are rejected, so that the
+// routes can be combined with others: the binary recovers them with
+// rejection_response
+pub(crate) fn routes(engine: &BlogEngine) -> BoxedFilter<(Response,)> {
+    let storage = engine.storage.clone();
+    let pages = engine.pages.clone();
+    let handlebars_support = engine.handlebars_support.clone();
+    let event_bus = engine.event_bus.clone();
+    let journal = engine.journal.clone();
+    let artifacts = engine.artifacts.clone();
+    let read_retries = engine.read_retries.clone();
+    let outbox = engine.outbox.clone();
+    let images = engine.images.clone();
+    let referrers = engine.referrers.clone();
+    let readiness = engine.readiness.clone();
+    let file_server = engine.file_server.clone();
+    let theme_file_server = engine.theme_file_server.clone();
+    let incidents = engine.incidents.clone();
+    let cdn = engine.cdn.clone();
+    let clock = engine.clock.clone();
+    let signer = engine.signer.clone();
+    let admin_token = engine.admin_token.clone();
+    let site_url = engine.site_url.clone();
+    let entry_settings = engine.entry_settings.clone();
+    let disabled_feed_aliases = engine.disabled_feed_aliases.clone();
+    let stats_page = engine.stats_page;
+    let dev = engine.dev;
+    let as_of = time_travel::as_of(dev, admin_token.clone());
+
+    let tag = warp::path!("blog" / "tag" / String)
+        .and(page_query(LISTING_PAGE_SIZE))
+        .and(as_of.clone())
+        .and_then({
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            move |tag: String, paging, as_of| {
+                let storage = storage.clone();
+                let handlebars_support = handlebars_support.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        tag_listing(tag, paging, as_of, storage, handlebars_support).await,
+                    )
+                }
+            }
+        });
+    let author = warp::path!("blog" / "author" / String)
+        .and(page_query(LISTING_PAGE_SIZE))
+        .and(as_of.clone())
+        .and_then({
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            move |author: String, paging, as_of| {
+                let storage = storage.clone();
+                let handlebars_support = handlebars_support.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        author_page(author, paging, as_of, storage, handlebars_support).await,
+                    )
+                }
+            }
+        });
+    let blog = warp::path("blog")
+        .and(entry_path())
+        .and(
+            warp::query::<BlogQuery>()
+                .and(page_query(LISTING_PAGE_SIZE))
+                .map(|query, paging| BlogQuery { paging, ..query }),
+        )
+        .and(warp::header::optional::<String>("referer"))
+        .and_then({
+            let storage = storage.clone();
+            let referrers = referrers.clone();
+
+            let handlebars_support = handlebars_support.clone();
+            move |entry, query, referer| {
+                let storage = storage.clone();
+                let referrers = referrers.clone();
+                let entry_settings = entry_settings.clone();
+                let handlebars_support = handlebars_support.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        blog(
+                            entry,
+                            query,
+                            referer,
+                            entry_settings,
+                            storage,
+                            referrers,
+                            handlebars_support,
+                        )
+                        .await,
+                    )
+                }
+            }
+        });
+    let home_page = warp::path!("blog")
+        .and(page_query(HOME_PAGE_SIZE))
+        .or(warp::path!("blog" / "page" / usize)
+            .map(|page| PageRequest::new(page, None, HOME_PAGE_SIZE)))
+        .unify();
+    let stats = warp::path!("blog" / "stats").and_then({
+        let storage = storage.clone();
+        let handlebars_support = handlebars_support.clone();
+        move || {
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            async move {
+                if !stats_page {
+                    return Err(warp::reject::not_found());
+                }
+                Ok(public_stats(storage, handlebars_support).await)
+            }
+        }
+    });
+    let archive = warp::path!("blog" / "archive")
+        .and(warp::method())
+        .and(as_of.clone())
+        .and_then({
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            let incidents = incidents.clone();
+            move |method, as_of| {
+                let storage = storage.clone();
+                let handlebars_support = handlebars_support.clone();
+                let incidents = incidents.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        archive(method, as_of, storage, handlebars_support, incidents).await,
+                    )
+                }
+            }
+        });
+    let search = warp::path!("blog" / "search")
+        .and(warp::query::<SearchQuery>())
+        .and(page_query(SEARCH_PAGE_SIZE))
+        .and_then({
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            move |query, paging| {
+                let storage = storage.clone();
+                let handlebars_support = handlebars_support.clone();
+                async move {
+                    Ok::<_, Infallible>(search(query, paging, storage, handlebars_support).await)
+                }
+            }
+        });
+    let home = home_page.and(as_of.clone()).and_then({
+        let storage = storage.clone();
+        let handlebars_support = handlebars_support.clone();
+
+        move |page, as_of| {
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            async move {
+                Result::<_, Infallible>::Ok(home(page, as_of, storage, handlebars_support).await)
+            }
+        }
+    });
+
+    // The hosts the blog is reached at, besides the one a request names
+    let site_host = Arc::new(
+        site_url
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_owned))
+            .unwrap_or_default(),
+    );
+    // Scaled down copies of the files, under the same policies
+    let thumb = warp::path!("files" / "thumb" / u32 / String)
+        .and(referral())
+        .and_then({
+            let images = images.clone();
+            let file_server = file_server.clone();
+            let site_host = site_host.clone();
+            move |width, name: String, referral| {
+                let images = images.clone();
+                let file_server = file_server.clone();
+                let site_host = site_host.clone();
+                async move {
+                    let path =
+                        PathBuf::from(percent_decode_str(&name).decode_utf8_lossy().as_ref());
+                    let policy = file_server.policy(&path);
+                    if let Some(refused) = refuse_hotlink(&policy, &referral, &site_host) {
+                        return Ok::<_, Infallible>(refused);
+                    }
+                    Ok(with_policy_headers(
+                        thumbnail(width, name, images).await,
+                        policy,
+                    ))
+                }
+            }
+        });
+    let files = warp::path("files")
+        .and(file_path())
+        .and(conditional_request())
+        .and(referral())
+        .and_then({
+            let file_server = file_server.clone();
+            let site_host = site_host.clone();
+            move |path, conditions, referral| {
+                let file_server = file_server.clone();
+                let site_host = site_host.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        file(path, conditions, referral, &site_host, file_server).await,
+                    )
+                }
+            }
+        });
+    let theme_files = warp::path("theme")
+        .and(file_path())
+        .and(conditional_request())
+        .and(referral())
+        .and_then({
+            let site_host = site_host.clone();
+            move |path, conditions, referral| {
+                let theme_file_server = theme_file_server.clone();
+                let site_host = site_host.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        file(path, conditions, referral, &site_host, theme_file_server).await,
+                    )
+                }
+            }
+        });
+    let events = warp::path!("events").and(warp::get()).map({
+        let event_bus = event_bus.clone();
+        move || sse_update(&event_bus)
+    });
+    let events_poll = warp::path!("events" / "poll")
+        .and(warp::get())
+        .and(warp::query::<PollQuery>())
+        .and_then(move |query: PollQuery| {
+            let event_bus = event_bus.clone();
+            async move {
+                let result = event_bus.poll(query.cursor, EVENTS_POLL_TIMEOUT).await;
+                Ok::<_, Infallible>(warp::reply::json(&result))
+            }
+        });
+    let share = warp::path!("admin" / "share" / ..)
+        .and(entry_path())
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<ShareQuery>())
+        .map({
+            let signer = signer.clone();
+            let admin_token = admin_token.clone();
+            let clock = clock.clone();
+            move |entry, authorization, query| {
+                share(
+                    entry,
+                    authorization,
+                    query,
+                    admin_token.clone(),
+                    signer.clone(),
+                    clock.clone(),
+                )
+            }
+        });
+    let preview_diff = warp::path!("preview" / String / "diff")
+        .and(warp::get())
+        .and_then({
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            move |entry: String| {
+                let storage = storage.clone();
+                let handlebars_support = handlebars_support.clone();
+                async move {
+                    if !dev {
+                        return Err(warp::reject::not_found());
+                    }
+                    Ok(preview_diff(entry, storage, handlebars_support).await)
+                }
+            }
+        });
+    let preview = warp::path("preview")
+        .and(entry_path())
+        .and(warp::query::<PreviewQuery>())
+        .and_then({
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            let signer = signer.clone();
+            let clock = clock.clone();
+            move |entry, query| {
+                let storage = storage.clone();
+                let handlebars_support = handlebars_support.clone();
+                let signer = signer.clone();
+                let clock = clock.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        preview(entry, query, storage, signer, clock, handlebars_support).await,
+                    )
+                }
+            }
+        });
+    let changes = warp::path!("api" / "changes")
+        .and(warp::get())
+        .and(warp::query::<ChangesQuery>())
+        .and_then(move |query| {
+            let journal = journal.clone();
+            async move { Ok::<_, Infallible>(changes(query, journal).await) }
+        });
+    let api_entries = warp::path!("api" / "v1" / "entries")
+        .and(warp::get())
+        .and_then({
+            let storage = storage.clone();
+            move || {
+                let storage = storage.clone();
+                async move { Ok::<_, Infallible>(api::entries(storage).await) }
+            }
+        });
+    let api_entry = warp::path!("api" / "v1" / "entries" / ..)
+        .and(entry_path())
+        .and(warp::get())
+        .and_then({
+            let storage = storage.clone();
+            move |slug| {
+                let storage = storage.clone();
+                async move { Ok::<_, Infallible>(api::entry(slug, storage).await) }
+            }
+        });
+    let feeds = warp::path!("feed" / String)
+        .and(conditional_request())
+        .and(as_of.clone())
+        .and_then({
+            let storage = storage.clone();
+            let site_url = site_url.clone();
+            move |format: String, conditions, as_of| {
+                let storage = storage.clone();
+                let site_url = site_url.clone();
+                async move {
+                    match FeedFormat::from_name(&format) {
+                        Some(format) => {
+                            Ok(feed(format, conditions, as_of, site_url, storage).await)
+                        }
+                        None => Err(warp::reject::not_found()),
+                    }
+                }
+            }
+        });
+    let feed_file = warp::path!(String)
+        .and(conditional_request())
+        .and(as_of.clone())
+        .and_then({
+            let storage = storage.clone();
+            let site_url = site_url.clone();
+            move |name: String, conditions, as_of| {
+                let storage = storage.clone();
+                let site_url = site_url.clone();
+                async move {
+                    match feed::file_format(&name) {
+                        Some(format) => {
+                            Ok(feed(format, conditions, as_of, site_url, storage).await)
+                        }
+                        None => Err(warp::reject::not_found()),
+                    }
+                }
+            }
+        });
+    let negotiated_feed = warp::path!("blog" / "feed")
+        .and(warp::header::optional::<String>("accept"))
+        .and(conditional_request())
+        .and(as_of)
+        .and_then({
+            let storage = storage.clone();
+            let site_url = site_url.clone();
+            move |accept: Option<String>, conditions, as_of| {
+                let storage = storage.clone();
+                let site_url = site_url.clone();
+                async move {
+                    let format = FeedFormat::negotiate(accept.as_deref());
+                    let feed = feed(format, conditions, as_of, site_url, storage).await;
+                    Ok::<_, Infallible>(warp::reply::with_header(feed, "vary", "accept"))
+                }
+            }
+        });
+    let feed_alias = warp::path!(String).and_then(move |alias: String| {
+        let disabled_feed_aliases = disabled_feed_aliases.clone();
+        async move {
+            match feed::alias_format(&alias, &disabled_feed_aliases) {
+                Some(format) => Ok(warp::reply::with_header(
+                    warp::http::StatusCode::MOVED_PERMANENTLY,
+                    "location",
+                    relative_location(&alias, format.canonical_path()),
+                )),
+                None => Err(warp::reject::not_found()),
+            }
+        }
+    });
+
+    let sitemap = warp::path!("sitemap.xml").and(warp::get()).and_then({
+        let storage = storage.clone();
+        let pages = pages.clone();
+        move || {
+            let site_url = site_url.clone();
+            let storage = storage.clone();
+            let pages = pages.clone();
+            async move { Ok::<_, Infallible>(sitemap(site_url, storage, pages).await) }
+        }
+    });
+
+    let page_path = if engine.pages_under_prefix {
+        warp::path!("pages" / String).boxed()
+    } else {
+        warp::path!(String).boxed()
+    };
+    let page = page_path.and_then({
+        let storage = storage.clone();
+        let handlebars_support = handlebars_support.clone();
+        move |slug: String| {
+            let pages = pages.clone();
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            async move {
+                match pages.get_page(&slug).await {
+                    Some(page) => Ok(page_response(
+                        page.as_ref().clone(),
+                        storage.blog_info(),
+                        handlebars_support,
+                    )),
+                    None => Err(warp::reject::not_found()),
+                }
+            }
+        }
+    });
+
+    let purge_registry = engine.purge_registry.clone();
+    let admin_entries = warp::path!("admin" / "entries")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<AdminEntriesQuery>())
+        .and_then({
+            let admin_token = admin_token.clone();
+            let storage = storage.clone();
+            let handlebars_support = handlebars_support.clone();
+            let links = AdminLinks {
+                signer: signer.clone(),
+                clock: clock.clone(),
+                dev,
+            };
+            move |authorization, query| {
+                let admin_token = admin_token.clone();
+                let storage = storage.clone();
+                let handlebars_support = handlebars_support.clone();
+                let links = links.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        admin_entries(
+                            authorization,
+                            admin_token,
+                            query,
+                            links,
+                            storage,
+                            handlebars_support,
+                        )
+                        .await,
+                    )
+                }
+            }
+        });
+    let admin_entries_purge = warp::path!("admin" / "entries" / "purge")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::form::<PurgeForm>())
+        .and_then({
+            let admin_token = admin_token.clone();
+            let storage = storage.clone();
+            let purge_registry = purge_registry.clone();
+            move |authorization, form| {
+                let admin_token = admin_token.clone();
+                let storage = storage.clone();
+                let purge_registry = purge_registry.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        admin_entries_purge(
+                            authorization,
+                            admin_token,
+                            form,
+                            storage,
+                            purge_registry,
+                        )
+                        .await,
+                    )
+                }
+            }
+        });
+    let admin_purge = warp::path!("admin" / "purge")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::json::<PurgeRequest>())
+        .and_then({
+            let admin_token = admin_token.clone();
+            let storage = storage.clone();
+            move |authorization, request| {
+                let admin_token = admin_token.clone();
+                let storage = storage.clone();
+                let purge_registry = purge_registry.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        admin_purge(authorization, admin_token, request, storage, purge_registry)
+                            .await,
+                    )
+                }
+            }
+        });
+
+    // Multipart bodies carry an entry and an image, anything else is the file
+    let uploads = engine.uploads.clone();
+    let front_matter_limit = uploads.limit();
+    let upload_target = warp::path!("admin" / "content" / ..)
+        .and(entry_path())
+        .map(|filename: String| {
+            percent_decode_str(&filename)
+                .decode_utf8_lossy()
+                .to_string()
+        })
+        .and(warp::put())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("if-match"));
+    let admin_upload_multipart = upload_target
+        .clone()
+        .and(multipart_body(true))
+        .and(warp::multipart::form().max_length(uploads.limit()))
+        .and_then({
+            let admin_token = admin_token.clone();
+            let uploads = uploads.clone();
+            move |filename, authorization, if_match, form| {
+                let admin_token = admin_token.clone();
+                let uploads = uploads.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        admin_upload_multipart(
+                            filename,
+                            authorization,
+                            admin_token,
+                            if_match,
+                            form,
+                            uploads,
+                        )
+                        .await,
+                    )
+                }
+            }
+        });
+    let admin_upload = upload_target
+        .and(multipart_body(false))
+        .and(warp::body::content_length_limit(uploads.limit()))
+        .and(warp::body::bytes())
+        .and_then({
+            let admin_token = admin_token.clone();
+            move |filename, authorization, if_match, body| {
+                let admin_token = admin_token.clone();
+                let uploads = uploads.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        admin_upload(
+                            filename,
+                            authorization,
+                            admin_token,
+                            if_match,
+                            body,
+                            uploads,
+                        )
+                        .await,
+                    )
+                }
+            }
+        });
+
+    let webfinger = warp::path!(".well-known" / "webfinger")
+        .and(warp::get())
+        .and(warp::query::<WebfingerQuery>())
+        .and_then({
+            let webfinger = engine.webfinger.clone();
+            move |query| {
+                let webfinger = webfinger.clone();
+                async move {
+                    match webfinger {
+                        Some(config) => Ok(identity::webfinger(query, &config)),
+                        None => Err(warp::reject::not_found()),
+                    }
+                }
+            }
+        });
+
+    let readyz = warp::path!("readyz").map({
+        let readiness = readiness.clone();
+        move || match readiness.state() {
+            ReadinessState::Ready => warp::reply::with_status("ready", StatusCode::OK),
+            ReadinessState::Starting => {
+                warp::reply::with_status("starting", StatusCode::SERVICE_UNAVAILABLE)
+            }
+            ReadinessState::Stopping => {
+                warp::reply::with_status("stopping", StatusCode::SERVICE_UNAVAILABLE)
+            }
+        }
+    });
+
+    let admin_artifacts = warp::path!("admin" / "artifacts")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .map({
+            let admin_token = admin_token.clone();
+            move |authorization| {
+                admin_artifacts(authorization, admin_token.clone(), artifacts.clone())
+            }
+        });
+
+    let admin_reads = warp::path!("admin" / "reads")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .map({
+            let admin_token = admin_token.clone();
+            move |authorization| {
+                admin_reads(authorization, admin_token.clone(), read_retries.clone())
+            }
+        });
+
+    let admin_outbox = warp::path!("admin" / "outbox")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .map({
+            let admin_token = admin_token.clone();
+            move |authorization| admin_outbox(authorization, admin_token.clone(), outbox.clone())
+        });
+
+    let admin_referrers = warp::path!("admin" / "referrers")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .map({
+            let admin_token = admin_token.clone();
+            move |authorization| {
+                admin_referrers(authorization, admin_token.clone(), referrers.clone())
+            }
+        });
+
+    let admin_parse_front_matter = warp::path!("admin" / "parse-frontmatter")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(front_matter_limit))
+        .and(warp::body::bytes())
+        .map({
+            let admin_token = admin_token.clone();
+            move |authorization, body| {
+                admin_parse_front_matter(authorization, admin_token.clone(), body)
+            }
+        });
+
+    let admin_incident = warp::path!("admin" / "incidents" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .map({
+            let admin_token = admin_token.clone();
+            let incidents = incidents.clone();
+            move |id: String, authorization| {
+                admin_incident(id, authorization, admin_token.clone(), incidents.clone())
+            }
+        });
+
+    // Boxed on their own, a single chain of every route is too deep a type
+    // for the compiler
+    let admin = admin_referrers
+        .or(admin_incident)
+        .or(admin_artifacts)
+        .or(admin_reads)
+        .or(admin_outbox)
+        .or(admin_parse_front_matter)
+        .or(admin_purge)
+        .or(admin_entries_purge)
+        .or(admin_entries)
+        .or(admin_upload_multipart)
+        .or(admin_upload)
+        .map(Reply::into_response)
+        .boxed();
+
+    let routes = feeds
+        .or(feed_file)
+        .or(negotiated_feed)
+        .or(feed_alias)
+        .or(tag)
+        .or(author)
+        .or(home)
+        .or(stats)
+        .or(archive)
+        .or(search)
+        .or(blog)
+        .or(thumb)
+        .or(files)
+        .or(theme_files)
+        .or(events)
+        .or(events_poll)
+        .or(share)
+        .or(preview_diff)
+        .or(preview)
+        .or(changes)
+        .or(api_entries)
+        .or(api_entry)
+        .or(admin)
+        .or(webfinger)
+        .or(readyz)
+        .or(sitemap)
+        .or(page)
+        .map(Reply::into_response);
+
+    let compressor = engine.compressor.clone();
+    let chaos = engine.chaos.clone();
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(routes)
+        .then(move |method: Method, path: FullPath, headers, response| {
+            let requested = path.as_str().to_owned();
+            let mut response = capture_incident(
+                method.clone(),
+                path,
+                response,
+                &incidents,
+                &storage,
+                &handlebars_support,
+            );
+            if let Some(cdn) = &cdn {
+                cdn.apply(&mut response);
+            }
+            let compressor = compressor.clone();
+            let chaos = chaos.clone();
+            async move {
+                if let Some(chaos) = &chaos {
+                    chaos.delay_render(&response).await;
+                }
+                compressor
+                    .apply(&method, &requested, &headers, response)
+                    .await
+            }
+        })
+        .boxed()
+}
+
+// Feed readers tend to poll aggressively: the validators are checked before
+// building anything, and HEAD requests never build the feed at all
+async fn feed(
+    format: FeedFormat,
+    conditions: ConditionalRequest,
+    as_of: AsOf,
+    site_url: Arc<String>,
+    storage: Arc<BlogStorage>,
+) -> Response {
+    // The validators describe the current feed, so a past one is always built
+    match as_of {
+        Ok(Some(as_of)) => return past_feed(format, as_of, &site_url, &storage).await,
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+    let version = storage.content_version().await;
+    let etag = format!("\"{}-{}\"", version.tag, format.name());
+    let mut response = if conditions.is_fresh(&etag, version.last_modified) {
+        warp::http::StatusCode::NOT_MODIFIED.into_response()
+    } else if conditions.is_head() {
+        warp::reply::with_header("", "content-type", format.content_type()).into_response()
+    } else {
+        let mut entries = Vec::new();
+        storage
+            .iterate_most_recent_entries(|e| {
+                // Served for previewing, but never announced
+                if !e.description.draft {
+                    entries.push(e.clone())
+                }
+            })
+            .await;
+        let feed = feed::generate(format, &storage.blog_info(), &site_url, &entries);
+        warp::reply::with_header(feed, "content-type", format.content_type()).into_response()
+    };
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert("etag", etag);
+    }
+    if let Some(last_modified) = version.last_modified {
+        if let Ok(last_modified) = HeaderValue::from_str(&http_date(last_modified)) {
+            headers.insert("last-modified", last_modified);
+        }
+    }
+    response
+}
+
+// The listings only hold summaries, the feeds need the content too
+async fn past_feed(
+    format: FeedFormat,
+    as_of: DateTime<Utc>,
+    site_url: &str,
+    storage: &BlogStorage,
+) -> Response {
+    let mut entries = vec![];
+    // As many as the current feed carries
+    let count = storage.max_most_recent_entries();
+    for summary in storage.entries_page(0, count, Some(as_of)).await {
+        if summary.description.draft {
+            continue;
+        }
+        match storage.get_entry(&summary.filename).await {
+            Ok(entry) => entries.push(entry.as_ref().clone()),
+            Err(e) => error!("Failed to load {} for a past feed: {e}", summary.filename),
+        }
+    }
+    let feed = feed::generate(format, &storage.blog_info(), site_url, &entries);
+    let response = warp::reply::with_header(feed, "content-type", format.content_type());
+    no_store(response.into_response(), Some(as_of))
+}
+
+// Built on every request, from every published entry rather than only the
+// most recent ones, so that it follows the watcher without any bookkeeping
+async fn sitemap(
+    site_url: Arc<String>,
+    storage: Arc<BlogStorage>,
+    pages: Arc<PageStorage>,
+) -> Response {
+    let mut entries = storage.entries_page(0, usize::MAX, None).await;
+    entries.retain(|e| !e.description.draft);
+    let mut urls = vec![SitemapUrl {
+        path: "/blog".to_owned(),
+        last_modified: entries.iter().map(|e| last_change(e)).max(),
+        change_frequency: ChangeFrequency::Weekly,
+    }];
+    urls.extend(entries.iter().map(|e| SitemapUrl {
+        path: format!("/blog/{}", e.slug),
+        last_modified: Some(last_change(e)),
+        change_frequency: ChangeFrequency::Monthly,
+    }));
+    urls.extend(pages.pages().await.iter().map(|p| SitemapUrl {
+        path: pages.url_path(&p.slug),
+        last_modified: p.metadata.date,
+        change_frequency: ChangeFrequency::Yearly,
+    }));
+    warp::reply::with_header(
+        sitemap::generate(&site_url, &urls),
+        "content-type",
+        "application/xml",
+    )
+    .into_response()
+}
+
+fn last_change(entry: &BlogEntry) -> DateTime<Utc> {
+    entry
+        .description
+        .updated_date
+        .unwrap_or(entry.description.publish_date)
+}
+
+pub async fn rejection_response(rejection: Rejection) -> Result<Response, Infallible> {
+    let status = if rejection.is_not_found() {
+        warp::http::StatusCode::NOT_FOUND
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        warp::http::StatusCode::METHOD_NOT_ALLOWED
+    } else if rejection.find::<warp::reject::InvalidQuery>().is_some()
+        || rejection.find::<warp::reject::InvalidHeader>().is_some()
+        || rejection.find::<warp::reject::MissingHeader>().is_some()
+        || rejection
+            .find::<warp::filters::body::BodyDeserializeError>()
+            .is_some()
+    {
+        warp::http::StatusCode::BAD_REQUEST
+    } else if rejection
+        .find::<warp::reject::UnsupportedMediaType>()
+        .is_some()
+    {
+        warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE
+    } else if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        warp::http::StatusCode::PAYLOAD_TOO_LARGE
+    } else if rejection.find::<warp::reject::LengthRequired>().is_some() {
+        warp::http::StatusCode::LENGTH_REQUIRED
+    } else {
+        error!("Unhandled rejection {rejection:?}");
+        warp::http::StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok(
+        warp::reply::with_status(status.canonical_reason().unwrap_or_default(), status)
+            .into_response(),
+    )
+}
+
+#[derive(Deserialize)]
+struct BlogQuery {
+    format: Option<String>,
+    // For the sections, filled in by page_query
+    #[serde(skip, default = "first_listing_page")]
+    paging: PageRequest,
+}
+
+fn first_listing_page() -> PageRequest {
+    PageRequest::first(LISTING_PAGE_SIZE)
+}
+
+fn entry_path() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::path::tail().and_then(|tail: Tail| async move {
+        if tail.as_str().is_empty() {
+            Err(warp::reject::not_found())
+        } else {
+            Ok(tail.as_str().to_owned())
+        }
+    })
+}
+
+// Any depth under /files, each segment decoded on its own. Whatever they
+// decode to, FileServer keeps the result within the files directory
+fn file_path() -> impl Filter<Extract = (PathBuf,), Error = Rejection> + Clone {
+    warp::path::tail().and_then(|tail: Tail| async move {
+        let path: PathBuf = tail
+            .as_str()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| percent_decode_str(segment).decode_utf8_lossy().to_string())
+            .collect();
+        if path.as_os_str().is_empty() {
+            Err(warp::reject::not_found())
+        } else {
+            Ok(path)
+        }
+    })
+}
+
+fn multipart_body(expected: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and_then(move |content_type: Option<String>| async move {
+            let multipart = content_type.is_some_and(|c| c.starts_with("multipart/form-data"));
+            if multipart == expected {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Clone)]
+pub(crate) struct EntrySettings {
+    pub(crate) plaintext_width: usize,
+    pub(crate) stale_after_days: i64,
+    pub(crate) clock: SharedClock,
+    pub(crate) artifacts: Option<Arc<ArtifactStore>>,
+    pub(crate) markdown: MarkdownOptions,
+    pub(crate) dev: bool,
+}
+
+async fn blog(
+    entry: String,
+    query: BlogQuery,
+    referer: Option<String>,
+    settings: EntrySettings,
+    storage: Arc<BlogStorage>,
+    referrers: Option<Arc<Referrers>>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    if let Some(section_path) = entry.strip_suffix('/') {
+        return section(section_path, query.paging, storage, handlebars_support).await;
+    }
+    let requested = entry;
+    let entry_name = storage.resolve_slug(&requested).await;
+    let entry = storage.get_entry(&entry_name).await;
+    if entry.is_err() && storage.get_section(&requested).await.is_some() {
+        return section(&requested, query.paging, storage, handlebars_support).await;
+    }
+    // The file names keep working, the slugs are what gets linked
+    if let Ok(entry) = &entry {
+        if requested != entry.slug {
+            let format = query
+                .format
+                .map(|format| format!("?format={format}"))
+                .unwrap_or_default();
+            return warp::reply::with_header(
+                warp::http::StatusCode::MOVED_PERMANENTLY,
+                "location",
+                relative_location(&requested, &format!("{}{format}", entry.slug)),
+            )
+            .into_response();
+        }
+    }
+    let breadcrumbs = storage.breadcrumbs(&entry_name).await;
+    let adjacent = storage.get_adjacent_entries(&entry_name).await;
+    if entry.is_ok() {
+        storage.record_view(&entry_name);
+    }
+    if let (Ok(_), Some(referrers)) = (&entry, referrers) {
+        referrers.record(&entry_name, referer.as_deref());
+    }
+    if let (Ok(entry), Some("txt")) = (&entry, query.format.as_deref()) {
+        info!("Serving entry {entry_name} as plain text");
+        let text = entry_plaintext(entry, &settings).await;
+        return warp::reply::with_header(text, "content-type", "text/plain; charset=utf-8")
+            .into_response();
+    }
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    match entry {
+        Ok(entry) => {
+            info!("Serving entry {entry_name}");
+            let response = html_response(
+                handlebars_support.format_blog_entry(
+                    storage.blog_info(),
+                    &entry,
+                    breadcrumbs,
+                    adjacent,
+                    EntryAge::new(
+                        &entry,
+                        Some(settings.stale_after_days),
+                        settings.clock.now(),
+                    ),
+                    settings.dev,
+                ),
+                warp::http::StatusCode::OK,
+            );
+            // The links to the adjacent entries change with the listings
+            let keys = [entry_keys(&entry), listing_keys()].concat();
+            with_keys(for_entry(response, &entry_name), keys)
+        }
+        // Anything else requested under /blog is just not there
+        Err(BlogStorageError::ParseError(e)) if watchers::is_valid_filename_entry(&entry_name) => {
+            Failure::new("Failed to load an entry", &e)
+                .entry(entry_name)
+                .into_response()
+        }
+        Err(_) => {
+            info!("Entry {entry_name} not found");
+            html_response(
+                handlebars_support.format_not_found(storage.blog_info(), entry_name),
+                warp::http::StatusCode::NOT_FOUND,
+            )
+        }
+    }
+}
+
+// Redirects stay relative to the request, so that they keep working when
+// the routes are mounted under a prefix. requested and target are both
+// relative to the same directory, e.g. /blog/
+pub(crate) fn relative_location(requested: &str, target: &str) -> String {
+    let depth = requested.matches('/').count();
+    format!("{}{}", "../".repeat(depth), target.trim_start_matches('/'))
+}
+
+// A template mistake (e.g. while editing a theme with hot reload on) must
+// never take the server down: it becomes an incident and a 500
+pub(crate) fn html_response(rendered: Result<String, RenderError>, status: StatusCode) -> Response {
+    match rendered {
+        Ok(html) => warp::reply::with_status(warp::reply::html(html), status).into_response(),
+        Err(e) => Failure::render(&e).into_response(),
+    }
+}
+
+// Records the failures the handlers attached to their 500s, and answers with
+// the error page showing the incident ID instead
+fn capture_incident(
+    method: Method,
+    path: FullPath,
+    mut response: Response,
+    incidents: &Incidents,
+    storage: &BlogStorage,
+    handlebars_support: &RwLock<HandlebarsSupport>,
+) -> Response {
+    let Some(failure) = response.extensions_mut().remove::<Failure>() else {
+        return response;
+    };
+    let request = (method.to_string(), path.as_str().to_owned());
+    let id = incidents.record(Some(request), failure);
+    let page = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support")
+        .format_error(storage.blog_info(), id.clone());
+    let mut response = match page {
+        Ok(html) => warp::reply::html(html).into_response(),
+        Err(e) => {
+            error!("Failed to render the error page of incident {id}: {e}");
+            format!("Internal server error, incident {id}").into_response()
+        }
+    };
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-incident-id", id);
+    }
+    response
+}
+
+async fn entry_plaintext(entry: &BlogEntry, settings: &EntrySettings) -> String {
+    let artifact_name = format!("{}-{}.txt", entry.content_hash, settings.plaintext_width);
+    if let Some(artifacts) = &settings.artifacts {
+        if let Some(text) = artifacts.read(PLAINTEXT_CATEGORY, &artifact_name).await {
+            if let Ok(text) = String::from_utf8(text) {
+                return text;
+            }
+        }
+    }
+    let text = plaintext::markdown_to_plaintext(
+        &entry.markdown,
+        &settings.markdown,
+        settings.plaintext_width,
+    );
+    if let Some(artifacts) = &settings.artifacts {
+        artifacts
+            .write(PLAINTEXT_CATEGORY, &artifact_name, text.as_bytes())
+            .await;
+    }
+    text
+}
+
+async fn section(
+    section_path: &str,
+    paging: PageRequest,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let Some(section) = storage.get_section(section_path).await else {
+        info!("Section {section_path} not found");
+        return html_response(
+            handlebars_support
+                .read()
+                .expect("Failed to open handlebars support")
+                .format_not_found(storage.blog_info(), section_path.to_owned()),
+            warp::http::StatusCode::NOT_FOUND,
+        );
+    };
+    let entries = storage
+        .section_entries(&section, paging)
+        .await
+        .map(|e| e.as_ref().clone());
+    let pagination = entries.pagination(format!("/blog/{section_path}/"));
+    let breadcrumbs = storage.breadcrumbs(section_path).await;
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    info!("Serving section {section_path}");
+    let response = html_response(
+        handlebars_support.format_section(
+            storage.blog_info(),
+            section.as_ref().clone(),
+            entries.items,
+            pagination,
+            breadcrumbs,
+        ),
+        warp::http::StatusCode::OK,
+    );
+    with_keys(response, listing_keys())
+}
+
+async fn tag_listing(
+    tag: String,
+    paging: PageRequest,
+    as_of: AsOf,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let as_of = match as_of {
+        Ok(as_of) => as_of,
+        Err(e) => return e.into_response(),
+    };
+    let tag = percent_decode_str(&tag).decode_utf8_lossy().to_string();
+    let entries = storage
+        .tagged_entries(&tag, as_of, paging)
+        .await
+        .map(|e| e.as_ref().clone());
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    if entries.total_items == 0 {
+        info!("Tag {tag} not found");
+        let response = html_response(
+            handlebars_support.format_not_found(storage.blog_info(), tag),
+            warp::http::StatusCode::NOT_FOUND,
+        );
+        return no_store(response, as_of);
+    }
+    info!("Serving tag {tag}");
+    let keys = [listing_keys(), vec![tag_key(&tag)]].concat();
+    let pagination = entries.pagination(format!(
+        "/blog/tag/{}",
+        utf8_percent_encode(&tag, NON_ALPHANUMERIC)
+    ));
+    let response = html_response(
+        handlebars_support.format_tag_listing(
+            storage.blog_info(),
+            tag,
+            entries.items,
+            pagination,
+            as_of,
+        ),
+        warp::http::StatusCode::OK,
+    );
+    no_store(with_keys(response, keys), as_of)
+}
+
+async fn author_page(
+    author: String,
+    paging: PageRequest,
+    as_of: AsOf,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let as_of = match as_of {
+        Ok(as_of) => as_of,
+        Err(e) => return e.into_response(),
+    };
+    let author = percent_decode_str(&author).decode_utf8_lossy().to_string();
+    let found = storage.author_entries(&author, as_of, paging).await;
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    let Some((name, entries)) = found else {
+        info!("Author {author} not found");
+        let response = html_response(
+            handlebars_support.format_not_found(storage.blog_info(), author),
+            warp::http::StatusCode::NOT_FOUND,
+        );
+        return no_store(response, as_of);
+    };
+    info!("Serving the entries of {name}");
+    let entries = entries.map(|e| e.as_ref().clone());
+    let pagination = entries.pagination(format!("/blog/author/{}", author_slug(&name)));
+    let response = html_response(
+        handlebars_support.format_author(
+            storage.blog_info(),
+            name,
+            entries.items,
+            pagination,
+            as_of,
+        ),
+        warp::http::StatusCode::OK,
+    );
+    no_store(with_keys(response, listing_keys()), as_of)
+}
+
+async fn home(
+    paging: PageRequest,
+    as_of: AsOf,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let as_of = match as_of {
+        Ok(as_of) => as_of,
+        Err(e) => return e.into_response(),
+    };
+    let entries = storage
+        .listed_entries(as_of, paging)
+        .await
+        .map(|e| e.as_ref().clone());
+    let pagination = entries.pagination("/blog");
+    let authors = storage.authors(as_of).await;
+    let home = handlebars_support
+        .read()
+        .expect("Poised handlebars support")
+        .format_home(
+            storage.blog_info(),
+            entries.items,
+            pagination,
+            authors,
+            as_of,
+        );
+    let response = html_response(home, warp::http::StatusCode::OK);
+    no_store(with_keys(response, listing_keys()), as_of)
+}
+
+async fn archive(
+    method: Method,
+    as_of: AsOf,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+    incidents: Arc<Incidents>,
+) -> Response {
+    let as_of = match as_of {
+        Ok(as_of) => as_of,
+        Err(e) => return e.into_response(),
+    };
+    let entries = storage.entries_page(0, usize::MAX, as_of).await;
+    let content = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support")
+        .archive_content(storage.blog_info(), &entries, as_of);
+    if entries.len() < STREAMED_ARCHIVE_MIN_ENTRIES {
+        let page = handlebars_support
+            .read()
+            .expect("Failed to open handlebars support")
+            .format_archive(&content);
+        let response = html_response(page, StatusCode::OK);
+        return no_store(with_keys(response, listing_keys()), as_of);
+    }
+
+    let mut parts = content.parts().into_iter();
+    let render = move |handlebars_support: &RwLock<HandlebarsSupport>, part| {
+        handlebars_support
+            .read()
+            .expect("Failed to open handlebars support")
+            .format_archive_part(&content, part)
+    };
+    // Until the header is out, a failure still gets the usual error page
+    let header = parts.next().map(|part| render(&handlebars_support, part));
+    let header = match header.transpose() {
+        Ok(header) => header.unwrap_or_default(),
+        Err(e) => return Failure::render(&e).into_response(),
+    };
+    info!("Streaming the archive of {} entries", entries.len());
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(header.into()).await.is_err() {
+            return;
+        }
+        for part in parts {
+            match render(&handlebars_support, part) {
+                Ok(chunk) => {
+                    if sender.send_data(chunk.into()).await.is_err() {
+                        // The client went away
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let request = (method.to_string(), "/blog/archive".to_owned());
+                    let id = incidents.record(Some(request), Failure::render(&e));
+                    // A marker for whoever reads the source, and an aborted
+                    // body so that clients don't take the page as complete
+                    let marker = format!("\n<!-- Incomplete page, incident {id} -->\n");
+                    if sender.send_data(marker.into()).await.is_ok() {
+                        // Once it's been taken, or aborting would drop it
+                        let _ = futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await;
+                    }
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+    let response = warp::reply::with_header(
+        Response::new(body),
+        "content-type",
+        "text/html; charset=utf-8",
+    )
+    .into_response();
+    no_store(with_keys(response, listing_keys()), as_of)
+}
+
+async fn public_stats(
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let stats = storage.public_stats().await;
+    let page = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support")
+        .format_stats(storage.blog_info(), &stats);
+    html_response(page, warp::http::StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+}
+
+// An empty query only shows the search form
+async fn search(
+    query: SearchQuery,
+    paging: PageRequest,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let query = query.q.unwrap_or_default().trim().to_owned();
+    let results = storage.search(&query).await;
+    info!("Search for '{query}' found {} entries", results.len());
+    let results = Paged::new(results, paging);
+    let pagination = results.pagination("/blog/search");
+    let page = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support")
+        .format_search_results(storage.blog_info(), query, results.items, pagination);
+    html_response(page, warp::http::StatusCode::OK)
+}
+
+fn page_response(
+    page: Page,
+    blog_info: BlogInfo,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    info!("Serving page {}", page.slug);
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    html_response(
+        handlebars_support.format_page(blog_info, page),
+        warp::http::StatusCode::OK,
+    )
+}
+
+// The page a request comes from, and the host it was sent to
+pub(crate) struct Referral {
+    referer: Option<String>,
+    host: Option<String>,
+}
+
+fn referral() -> impl Filter<Extract = (Referral,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("referer")
+        .and(warp::header::optional::<String>("host"))
+        .map(|referer, host| Referral { referer, host })
+}
+
+fn refuse_hotlink(
+    policy: &ResolvedFilePolicy,
+    referral: &Referral,
+    site_host: &str,
+) -> Option<Response> {
+    let own_hosts: Vec<&str> = [referral.host.as_deref(), Some(site_host)]
+        .into_iter()
+        .flatten()
+        .collect();
+    if policy.allows_referer(referral.referer.as_deref(), &own_hosts) {
+        return None;
+    }
+    info!("Refused a hotlink from {:?}", referral.referer);
+    Some(
+        warp::reply::with_status("Hotlinking is not allowed", StatusCode::FORBIDDEN)
+            .into_response(),
+    )
+}
+
+fn with_policy_headers(mut response: Response, policy: ResolvedFilePolicy) -> Response {
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        response.headers_mut().extend(policy.headers);
+    }
+    response
+}
+
+async fn file(
+    path: PathBuf,
+    conditions: ConditionalRequest,
+    referral: Referral,
+    site_host: &str,
+    file_server: Arc<FileServer>,
+) -> Response {
+    let policy = file_server.policy(&path);
+    if let Some(refused) = refuse_hotlink(&policy, &referral, site_host) {
+        return refused;
+    }
+    let response = serve_file(&path, &conditions, &file_server).await;
+    with_policy_headers(response, policy)
+}
+
+async fn serve_file(
+    path: &Path,
+    conditions: &ConditionalRequest,
+    file_server: &FileServer,
+) -> Response {
+    match file_server.serve(path, conditions).await {
+        Ok(file) => {
+            let mut response = match file.data {
+                Some(data) => {
+                    warp::reply::with_header(data, "content-type", file.mime_type.to_string())
+                        .into_response()
+                }
+                None => warp::http::StatusCode::NOT_MODIFIED.into_response(),
+            };
+            let headers = response.headers_mut();
+            headers.insert(
+                "cache-control",
+                HeaderValue::from_static(FILE_CACHE_CONTROL),
+            );
+            if let Ok(etag) = HeaderValue::from_str(&file.etag) {
+                headers.insert("etag", etag);
+            }
+            if let Ok(last_modified) = HeaderValue::from_str(&http_date(file.last_modified)) {
+                headers.insert("last-modified", last_modified);
+            }
+            response
+        }
+        Err(e) if !is_missing_file(&e) => {
+            Failure::new(format!("Failed to serve the file {}", path.display()), &e).into_response()
+        }
+        Err(e) => {
+            info!("While serving request {path:?} error '{e}' happened");
+            warp::reply::with_status(
+                warp::reply::html("<h1>Not found</h1>"),
+                warp::http::StatusCode::NOT_FOUND,
+            )
+            .into_response()
+        }
+    }
+}
+
+// Paths outside the directory, or leading nowhere, are the client's mistake
+fn is_missing_file(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.is::<FileServerError>()
+            || e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::IsADirectory
+                )
+            })
+    })
+}
+
+async fn thumbnail(width: u32, name: String, images: Option<Arc<ResponsiveImages>>) -> Response {
+    let not_found = || {
+        warp::reply::with_status(
+            warp::reply::html("<h1>Not found</h1>"),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response()
+    };
+    let Some(images) = images else {
+        return not_found();
+    };
+    match images.thumbnail(width, &name).await {
+        Ok(Some(thumbnail)) => {
+            warp::reply::with_header(thumbnail.data, "content-type", thumbnail.mime_type)
+                .into_response()
+        }
+        Ok(None) => not_found(),
+        Err(e) => {
+            error!("Failed to generate a {width}px thumbnail of {name}: {e:#}");
+            not_found()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    sig: Option<String>,
+    exp: Option<i64>,
+}
+
+async fn preview(
+    entry: String,
+    query: PreviewQuery,
+    storage: Arc<BlogStorage>,
+    signer: Option<Arc<Signer>>,
+    clock: SharedClock,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let verified = match (signer, query.sig, query.exp) {
+        (Some(signer), Some(sig), Some(exp)) => signer
+            .verify(&entry, exp, &sig, clock.now())
+            .map_err(|e| e.to_string()),
+        _ => Err("missing signature".to_owned()),
+    };
+    let blog_entry = match verified {
+        Ok(expires_at) => storage
+            .load_uncached(&entry)
+            .await
+            .map(|e| (e, expires_at))
+            .map_err(|e| {
+                error!("Failed to load shared entry {entry}: {e}");
+                "entry unavailable".to_owned()
+            }),
+        Err(e) => Err(e),
+    };
+
+    let breadcrumbs = storage.breadcrumbs(&entry).await;
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    match blog_entry {
+        Ok((blog_entry, expires_at)) => {
+            info!("Serving shared preview of {entry}");
+            html_response(
+                handlebars_support.format_shared_preview(
+                    storage.blog_info(),
+                    &blog_entry,
+                    breadcrumbs,
+                    EntryAge::new(&blog_entry, None, clock.now()),
+                    expires_at,
+                ),
+                warp::http::StatusCode::OK,
+            )
+        }
+        Err(reason) => {
+            info!("Refusing shared preview of {entry}: {reason}");
+            html_response(
+                handlebars_support.format_forbidden(storage.blog_info(), reason),
+                warp::http::StatusCode::FORBIDDEN,
+            )
+        }
+    }
+}
+
+// Compares the published entry with the file being edited, without touching
+// the cache
+async fn preview_diff(
+    entry: String,
+    storage: Arc<BlogStorage>,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+) -> Response {
+    let published = storage.cached_entry(&entry).await;
+    let edited = storage.load_uncached(&entry).await;
+    let handlebars_support = handlebars_support
+        .read()
+        .expect("Failed to open handlebars support");
+    match (published, edited) {
+        (Some(published), Ok(edited)) => {
+            let lines = diff::diff_lines(&published.markdown, &edited.markdown);
+            html_response(
+                handlebars_support.format_diff(storage.blog_info(), entry, lines),
+                warp::http::StatusCode::OK,
+            )
+        }
+        (None, _) => html_response(
+            handlebars_support.format_not_found(storage.blog_info(), entry),
+            warp::http::StatusCode::NOT_FOUND,
+        ),
+        (Some(_), Err(e)) => {
+            error!("Failed to read the edited entry {entry}: {e}");
+            warp::reply::with_status(
+                format!("Failed to read {entry}: {e}"),
+                warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+            )
+            .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: Option<u64>,
+}
+
+async fn changes(query: ChangesQuery, journal: Option<Arc<Journal>>) -> Response {
+    match journal {
+        Some(journal) => warp::reply::json(&journal.changes_since(query.since.unwrap_or(0)).await)
+            .into_response(),
+        None => warp::reply::with_status(
+            "The change journal is disabled, set --journal-path to enable it",
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use super::*;
+    use crate::{chaos::ChaosConfig, test_support::TempDir, BlogEngineBuilder};
+
+    fn entry(title: &str, date: &str) -> String {
+        format!("---\ntitle: {title}\nauthor: Crax\npublish_date: {date}\n---\n\nAbout {title}\n")
+    }
+
+    // A blog of a single entry
+    fn builder(dir: &TempDir) -> BlogEngineBuilder {
+        dir.write("first.md", entry("First", "2024-01-01T08:00:00Z"));
+        BlogEngine::builder()
+            .base_path(dir.join(""))
+            .referrer_tracking(false)
+    }
+
+    async fn engine(dir: &TempDir) -> BlogEngine {
+        builder(dir).build().await.unwrap()
+    }
+
+    // What the watcher does when a file is saved
+    async fn publish(engine: &BlogEngine, dir: &TempDir, name: &str, content: &str) {
+        dir.write(name, content);
+        let storage = engine.storage();
+        let entry = storage.parse_entry(name).await.unwrap();
+        storage.try_store_entry(name, Arc::new(entry)).await;
+    }
+
+    #[tokio::test]
+    async fn feed_aliases_redirect_relatively() {
+        let dir = TempDir::new("feed-aliases");
+        let routes = engine(&dir).await.routes();
+        for (alias, location) in [
+            ("/feed", "feed/rss"),
+            ("/rss", "feed/rss"),
+            ("/rss.xml", "feed/rss"),
+            ("/index.xml", "feed/rss"),
+            ("/feed.json", "feed/json"),
+        ] {
+            let response = warp::test::request().path(alias).reply(&routes).await;
+            assert_eq!(response.status(), 301, "{alias}");
+            assert_eq!(response.headers()["location"], location, "{alias}");
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiated_feed_follows_the_accept_header() {
+        let dir = TempDir::new("feed-negotiation");
+        let routes = engine(&dir).await.routes();
+        for (accept, content_type) in [
+            (None, "application/rss+xml"),
+            (Some("*/*"), "application/rss+xml"),
+            (Some("application/atom+xml"), "application/atom+xml"),
+            (Some("application/json"), "application/feed+json"),
+            (Some("text/html"), "application/rss+xml"),
+        ] {
+            let mut request = warp::test::request().path("/blog/feed");
+            if let Some(accept) = accept {
+                request = request.header("accept", accept);
+            }
+            let response = request.reply(&routes).await;
+            assert_eq!(response.status(), 200, "{accept:?}");
+            assert_eq!(
+                response.headers()["content-type"],
+                content_type,
+                "{accept:?}"
+            );
+            assert_eq!(response.headers()["vary"], "accept");
+        }
+    }
+
+    struct Poll {
+        status: u16,
+        etag: String,
+        last_modified: String,
+        body: String,
+    }
+
+    async fn poll(
+        routes: &BoxedFilter<(Response,)>,
+        method: &str,
+        header: Option<(&str, &str)>,
+    ) -> Poll {
+        let mut request = warp::test::request().method(method).path("/feed/rss");
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        let response = request.reply(routes).await;
+        let header = |name| response.headers()[name].to_str().unwrap().to_owned();
+        Poll {
+            status: response.status().as_u16(),
+            etag: header("etag"),
+            last_modified: header("last-modified"),
+            body: String::from_utf8_lossy(response.body()).into_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn polling_client_only_downloads_changed_feeds() {
+        for journal in [false, true] {
+            let dir = TempDir::new("feed-polling");
+            let mut builder = builder(&dir);
+            if journal {
+                builder = builder.journal_path(dir.join("journal.jsonl"));
+            }
+            let engine = builder.build().await.unwrap();
+            let routes = engine.routes();
+
+            let first = poll(&routes, "GET", None).await;
+            assert_eq!(first.status, 200);
+            assert!(first.body.contains("First"));
+            assert_eq!(first.last_modified, "Mon, 01 Jan 2024 08:00:00 GMT");
+
+            // Nothing changed: no feed gets built
+            let unchanged = poll(&routes, "GET", Some(("if-none-match", &first.etag))).await;
+            assert_eq!(unchanged.status, 304);
+            assert_eq!(unchanged.etag, first.etag);
+            assert!(unchanged.body.is_empty());
+            let since = poll(
+                &routes,
+                "GET",
+                Some(("if-modified-since", &first.last_modified)),
+            )
+            .await;
+            assert_eq!(since.status, 304);
+            let head = poll(&routes, "HEAD", Some(("if-none-match", &first.etag))).await;
+            assert_eq!(head.status, 304);
+            let head = poll(&routes, "HEAD", None).await;
+            assert_eq!((head.status, head.body.as_str()), (200, ""));
+            assert_eq!(head.etag, first.etag);
+
+            // Saving an entry without changes keeps the feed fresh
+            publish(
+                &engine,
+                &dir,
+                "first.md",
+                &entry("First", "2024-01-01T08:00:00Z"),
+            )
+            .await;
+            let resaved = poll(&routes, "GET", Some(("if-none-match", &first.etag))).await;
+            assert_eq!(resaved.status, 304, "journal: {journal}");
+
+            publish(
+                &engine,
+                &dir,
+                "second.md",
+                &entry("Second", "2024-02-01T08:00:00Z"),
+            )
+            .await;
+            let second = poll(&routes, "GET", Some(("if-none-match", &first.etag))).await;
+            assert_eq!(second.status, 200);
+            assert_ne!(second.etag, first.etag);
+            assert!(second.body.contains("Second"));
+            assert_eq!(second.last_modified, "Thu, 01 Feb 2024 08:00:00 GMT");
+            let stale = poll(
+                &routes,
+                "GET",
+                Some(("if-modified-since", &first.last_modified)),
+            )
+            .await;
+            assert_eq!(stale.status, 200);
+
+            // An edit of an older entry changes the tag, if not the date
+            publish(
+                &engine,
+                &dir,
+                "first.md",
+                &entry("First, edited", "2024-01-01T08:00:00Z"),
+            )
+            .await;
+            let edited = poll(&routes, "GET", Some(("if-none-match", &second.etag))).await;
+            assert_eq!(edited.status, 200);
+            assert_ne!(edited.etag, second.etag);
+            assert!(edited.body.contains("First, edited"));
+
+            engine.storage().remove_entry("second.md".to_owned()).await;
+            let removed = poll(&routes, "GET", Some(("if-none-match", &edited.etag))).await;
+            assert_eq!(removed.status, 200);
+            assert!(!removed.body.contains("Second"));
+            assert_eq!(removed.last_modified, first.last_modified);
+            engine.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn content_warnings_replace_the_summaries() {
+        let dir = TempDir::new("content-warning");
+        dir.write(
+            "warned.md",
+            "---\ntitle: Warned\nauthor: Crax\npublish_date: 2024-02-01T08:00:00Z\n\
+             content_warning: spiders & <snakes>\n---\n\nThe gated body\n",
+        );
+        let engine = engine(&dir).await;
+        let routes = engine.routes();
+
+        for path in ["/feed/rss", "/feed/atom", "/feed/json"] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            let body = String::from_utf8_lossy(response.body());
+            assert!(!body.contains("The gated body"), "{path}");
+            assert!(body.contains("About First"), "{path}");
+            assert!(body.contains("Content warning: spiders"), "{path}");
+            assert!(!body.contains("<snakes>"), "{path}");
+            assert!(body.contains("http://localhost:8080/blog/warned"), "{path}");
+        }
+
+        let response = warp::test::request().path("/blog").reply(&routes).await;
+        let home = String::from_utf8_lossy(response.body());
+        assert!(!home.contains("The gated body"));
+        assert!(home.contains("About First"));
+        assert!(home.contains("(content warning: spiders &amp; &lt;snakes&gt;)"));
+
+        let response = warp::test::request()
+            .path("/blog/warned")
+            .reply(&routes)
+            .await;
+        let page = String::from_utf8_lossy(response.body());
+        assert!(page.contains("<summary>Content warning: spiders &amp; &lt;snakes&gt;</summary>"));
+        assert!(page.contains("The gated body"));
+        // Link previews are summaries too
+        assert!(page.contains("og:description\" content=\"Content warning: spiders"));
+
+        // Gated, not secret
+        let results = engine.storage().search("gated").await;
+        assert_eq!(results.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn files_stay_within_their_directory() {
+        let dir = TempDir::new("files-routes");
+        dir.write("secret.txt", "secret");
+        dir.write("files/a/b/public.txt", "public");
+        let engine = builder(&dir)
+            .files_path(dir.join("files"))
+            .build()
+            .await
+            .unwrap();
+        let routes = engine.routes();
+
+        let response = warp::test::request()
+            .path("/files/a/b/public.txt")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.body().as_ref(), b"public");
+        for path in [
+            "/files/../secret.txt",
+            "/files/%2e%2e/secret.txt",
+            "/files/%2E%2E%2Fsecret.txt",
+            "/files/a/%2e%2e/%2e%2e/secret.txt",
+            "/files/a/b/%2e%2e/%2e%2e/%2e%2e/%2e%2e/%2e%2e/secret.txt",
+            "/files/%2f..%2fsecret.txt",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_ne!(response.body().as_ref(), b"secret", "{path}");
+            assert_eq!(response.status(), 404, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn readyz_agrees_with_the_ready_file() {
+        let dir = TempDir::new("readyz");
+        let ready_file = dir.join("swes.ready");
+        let engine = builder(&dir).ready_file(&ready_file).build().await.unwrap();
+        let routes = engine.routes();
+        let readyz = || async { warp::test::request().path("/readyz").reply(&routes).await };
+
+        assert_eq!(readyz().await.status(), 503);
+        assert!(!ready_file.exists());
+        engine.readiness().set_ready();
+        assert_eq!(readyz().await.status(), 200);
+        assert!(ready_file.exists());
+        engine.shutdown().await;
+        let response = readyz().await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.body().as_ref(), b"stopping");
+        assert!(!ready_file.exists());
+    }
+
+    #[tokio::test]
+    async fn purging_a_tag_spares_the_other_entries() {
+        let dir = TempDir::new("purge-tag");
+        let artifacts = TempDir::new("purge-tag-artifacts");
+        let tagged = |title: &str, tag: &str| {
+            format!(
+                "---\ntitle: {title}\nauthor: Crax\npublish_date: 2024-02-01T08:00:00Z\n\
+                 tags: [{tag}]\n---\n\nAbout {title}\n"
+            )
+        };
+        dir.write("rust.md", tagged("Rust", "rust"));
+        dir.write("also_rust.md", tagged("Also Rust", "Rust"));
+        dir.write("go.md", tagged("Go", "go"));
+        let engine = builder(&dir)
+            .admin_token("token")
+            .artifacts(artifacts.join(""), 1 << 20)
+            .build()
+            .await
+            .unwrap();
+        let routes = engine.routes();
+        let storage = engine.storage();
+
+        let names = ["rust.md", "also_rust.md", "go.md", "first.md"];
+        let mut cached = vec![];
+        for name in names {
+            let slug = name.trim_end_matches(".md");
+            let response = warp::test::request()
+                .path(&format!("/blog/{slug}?format=txt"))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 200);
+            cached.push(storage.cached_entry(name).await.unwrap());
+        }
+        let rendered = |entry: &BlogEntry| {
+            artifacts
+                .join(PLAINTEXT_CATEGORY)
+                .join(format!(
+                    "{}-{}.txt",
+                    entry.content_hash,
+                    plaintext::DEFAULT_WIDTH
+                ))
+                .exists()
+        };
+        assert!(cached.iter().all(|entry| rendered(entry)));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/purge")
+            .header("authorization", "Bearer token")
+            .json(&serde_json::json!({ "tags": ["RUST"] }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let purged: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(purged, serde_json::json!({ "entry": 2, "render": 2 }));
+
+        for (name, before) in names.iter().zip(&cached) {
+            let after = storage.cached_entry(name).await.unwrap();
+            let spared = !name.contains("rust");
+            assert_eq!(Arc::ptr_eq(before, &after), spared, "{name}");
+            assert_eq!(rendered(before), spared, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn purging_needs_a_selector_and_known_kinds() {
+        let dir = TempDir::new("purge-refused");
+        let engine = builder(&dir).admin_token("token").build().await.unwrap();
+        let routes = engine.routes();
+        for (authorization, body, status) in [
+            ("Bearer wrong", serde_json::json!({ "tags": ["rust"] }), 401),
+            (
+                "Bearer token",
+                serde_json::json!({ "kinds": ["entry"] }),
+                400,
+            ),
+            (
+                "Bearer token",
+                serde_json::json!({ "tags": ["rust"], "kinds": ["render"] }),
+                400,
+            ),
+        ] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/admin/purge")
+                .header("authorization", authorization)
+                .json(&body)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status, "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_are_limited_in_size() {
+        let dir = TempDir::new("uploads-limit");
+        let engine = builder(&dir)
+            .files_path(dir.join("files"))
+            .admin_token("token")
+            .upload_limit(256)
+            .build()
+            .await
+            .unwrap();
+        let routes = engine.routes();
+        let upload = |name: &str, body: Vec<u8>| {
+            warp::test::request()
+                .method("PUT")
+                .path(&format!("/admin/content/{name}"))
+                .header("authorization", "Bearer token")
+                .body(body)
+        };
+
+        let response = upload("large.png", vec![0; 257]).reply(&routes).await;
+        assert_eq!(response.status(), 413);
+        assert!(!dir.join("files/large.png").exists());
+        let response = upload("small.png", vec![0; 256]).reply(&routes).await;
+        assert_eq!(response.status(), 201);
+
+        let response = upload("..%2Fescape.md", vec![]).reply(&routes).await;
+        assert_eq!(response.status(), 400);
+        let response = upload("first.md", vec![]).reply(&routes).await;
+        assert_eq!(response.status(), 422);
+        let response = upload(
+            "first.md",
+            entry("Replaced", "2024-01-01T08:00:00Z").into_bytes(),
+        )
+        .reply(&routes)
+        .await;
+        assert_eq!(response.status(), 428);
+        let response = upload("first.md", vec![])
+            .header("authorization", "Bearer wrong")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[test]
+    fn locations_are_relative_to_the_request() {
+        assert_eq!(relative_location("post.md", "post"), "post");
+        assert_eq!(relative_location("2024/01/post", "short"), "../../short");
+        assert_eq!(
+            relative_location("2024/post.md", "/2024/post"),
+            "../2024/post"
+        );
+        assert_eq!(relative_location("rss.xml", "/feed/rss"), "feed/rss");
+    }
+
+    #[tokio::test]
+    async fn file_names_redirect_to_the_slugs() {
+        let dir = TempDir::new("slug-redirects");
+        dir.write(
+            "2024/01/long_name.md",
+            "---\ntitle: Custom\nauthor: Crax\npublish_date: 2024-01-02T08:00:00Z\n\
+             slug: short\n---\n\nShort\n",
+        );
+        let engine = engine(&dir).await;
+        let routes = engine.routes();
+        for (path, location) in [
+            ("/blog/first.md", "first"),
+            ("/blog/first.md?format=txt", "first?format=txt"),
+            ("/blog/2024/01/long_name", "../../short"),
+            ("/blog/2024/01/long_name.md", "../../short"),
+        ] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(response.status(), 301, "{path}");
+            assert_eq!(response.headers()["location"], location, "{path}");
+        }
+        for path in ["/blog/first", "/blog/short"] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(response.status(), 200, "{path}");
+        }
+    }
+
+    // A copy of the default theme, to add a theme.toml to
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for file in std::fs::read_dir(from).unwrap() {
+            let file = file.unwrap();
+            if file.file_type().unwrap().is_dir() {
+                copy_dir(&file.path(), &to.join(file.file_name()));
+            } else {
+                std::fs::copy(file.path(), to.join(file.file_name())).unwrap();
+            }
+        }
+    }
+
+    async fn theme_color(routes: &BoxedFilter<(Response,)>, path: &str) -> Option<String> {
+        let response = warp::test::request().path(path).reply(routes).await;
+        assert_eq!(response.status(), 200, "{path}");
+        let body = String::from_utf8_lossy(response.body()).into_owned();
+        let (_, rest) = body.split_once("<meta name=\"theme-color\" content=\"")?;
+        Some(rest[..rest.find('"').unwrap()].to_owned())
+    }
+
+    fn colored_entry(title: &str, color: &str) -> String {
+        format!(
+            "---\ntitle: {title}\nauthor: Crax\npublish_date: 2024-01-02T08:00:00Z\n\
+             accent_color: \"{color}\"\n---\n\nAbout {title}\n"
+        )
+    }
+
+    #[tokio::test]
+    async fn accent_colors_go_from_the_entry_to_the_theme_to_the_blog() {
+        let dir = TempDir::new("accent-colors");
+        let theme = TempDir::new("accent-colors-theme");
+        copy_dir(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("themes/default"),
+            &theme.join(""),
+        );
+        dir.write("blog.toml", "accent_color = \"#111111\"");
+        dir.write("red.md", colored_entry("Red", "#C0392B"));
+        dir.write("typo.md", colored_entry("Typo", "#12345"));
+        let themed = || builder(&dir).theme_path(theme.join("")).build();
+
+        let routes = themed().await.unwrap().routes();
+        for (path, color) in [
+            ("/blog", "#111111"),
+            ("/blog/first", "#111111"),
+            ("/blog/red", "#c0392b"),
+            ("/blog/typo", "#111111"),
+        ] {
+            assert_eq!(
+                theme_color(&routes, path).await.as_deref(),
+                Some(color),
+                "{path}"
+            );
+        }
+
+        theme.write("theme.toml", "accent_color = \"#222\"");
+        let routes = themed().await.unwrap().routes();
+        for (path, color) in [
+            ("/blog", "#222"),
+            ("/blog/first", "#222"),
+            ("/blog/red", "#c0392b"),
+            ("/blog/typo", "#222"),
+        ] {
+            assert_eq!(
+                theme_color(&routes, path).await.as_deref(),
+                Some(color),
+                "{path}"
+            );
+        }
+
+        // An invalid theme color leaves the blog's one
+        theme.write("theme.toml", "accent_color = \"#22\"");
+        let routes = themed().await.unwrap().routes();
+        assert_eq!(
+            theme_color(&routes, "/blog").await.as_deref(),
+            Some("#111111")
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_blog_accent_colors_are_dropped() {
+        let dir = TempDir::new("accent-colors-invalid");
+        dir.write("blog.toml", "accent_color = \"not a color\"");
+        let engine = builder(&dir).build().await.unwrap();
+        let routes = engine.routes();
+        assert_eq!(theme_color(&routes, "/blog").await, None);
+        assert_eq!(theme_color(&routes, "/blog/first").await, None);
+    }
+
+    #[tokio::test]
+    async fn identity_is_only_served_when_configured() {
+        let dir = TempDir::new("identity-off");
+        let routes = engine(&dir).await.routes();
+        let response = warp::test::request()
+            .path("/.well-known/webfinger?resource=acct:crax@blog.example")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+        let response = warp::test::request().path("/blog").reply(&routes).await;
+        assert!(!String::from_utf8_lossy(response.body()).contains("rel=\"me\""));
+
+        let dir = TempDir::new("identity-on");
+        dir.write(
+            "blog.toml",
+            concat!(
+                "[identity]\n",
+                "profile_links = [\"https://mastodon.social/@crax\"]\n",
+                "[identity.webfinger]\n",
+                "subject = \"acct:crax@mastodon.social\"\n",
+                "aliases = [\"acct:crax@blog.example\"]\n",
+            ),
+        );
+        let routes = engine(&dir).await.routes();
+        for (query, status) in [
+            ("?resource=acct:crax@blog.example", 200),
+            ("?resource=acct%3Acrax%40mastodon.social", 200),
+            ("?resource=acct:other@blog.example", 400),
+            ("", 400),
+        ] {
+            let response = warp::test::request()
+                .path(&format!("/.well-known/webfinger{query}"))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status, "{query}");
+        }
+        for path in ["/blog", "/blog/first"] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            let body = String::from_utf8_lossy(response.body());
+            assert!(
+                body.contains("<link rel=\"me\" href=\"https://mastodon.social/@crax\">"),
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_entries_leave_the_home_page() {
+        let dir = TempDir::new("delete-home");
+        dir.write("second.md", entry("Second", "2024-01-02T08:00:00Z"));
+        dir.write("third.md", entry("Third", "2024-01-03T08:00:00Z"));
+        let engine = builder(&dir)
+            .max_entries(NonZeroUsize::new(2).unwrap())
+            .build()
+            .await
+            .unwrap();
+        let routes = engine.routes();
+        let get = |path: &'static str| {
+            let routes = routes.clone();
+            async move {
+                let response = warp::test::request().path(path).reply(&routes).await;
+                String::from_utf8_lossy(response.body()).into_owned()
+            }
+        };
+        // The titles of the home page, and of the feed holding the most
+        // recent entries only
+        let listed = |body: String, format: &str| {
+            ["First", "Second", "Third"]
+                .into_iter()
+                .filter(|title| body.contains(&format.replace("{}", title)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            listed(get("/blog").await, "{}</a>"),
+            ["First", "Second", "Third"]
+        );
+        assert_eq!(
+            listed(get("/feed/rss").await, "<title>{}</title>"),
+            ["Second", "Third"]
+        );
+
+        std::fs::remove_file(dir.join("third.md")).unwrap();
+        engine.storage().remove_entry("third.md".to_owned()).await;
+        assert_eq!(listed(get("/blog").await, "{}</a>"), ["First", "Second"]);
+        // Filled back up to the cap
+        assert_eq!(
+            listed(get("/feed/rss").await, "<title>{}</title>"),
+            ["First", "Second"]
+        );
+        let response = warp::test::request()
+            .path("/blog/third")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        std::fs::remove_file(dir.join("first.md")).unwrap();
+        engine.storage().remove_entry("first.md".to_owned()).await;
+        assert_eq!(listed(get("/blog").await, "{}</a>"), ["Second"]);
+        assert_eq!(
+            listed(get("/feed/rss").await, "<title>{}</title>"),
+            ["Second"]
+        );
+    }
+
+    #[tokio::test]
+    async fn feeds_carry_up_to_max_entries() {
+        let dir = TempDir::new("max-entries");
+        dir.write("second.md", entry("Second", "2024-01-02T08:00:00Z"));
+        dir.write("third.md", entry("Third", "2024-01-03T08:00:00Z"));
+        let titles = |routes: BoxedFilter<(Response,)>, path: &'static str| async move {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            let body = String::from_utf8_lossy(response.body()).into_owned();
+            ["First", "Second", "Third"]
+                .into_iter()
+                .filter(|title| body.contains(&format!("About {title}")))
+                .collect::<Vec<_>>()
+        };
+
+        // Loaded at startup, newest first
+        let capped = builder(&dir)
+            .max_entries(NonZeroUsize::new(2).unwrap())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(capped.storage().max_most_recent_entries(), 2);
+        for path in ["/feed/rss", "/feed/atom", "/feed/json"] {
+            assert_eq!(
+                titles(capped.routes(), path).await,
+                ["Second", "Third"],
+                "{path}"
+            );
+        }
+        // And when published later
+        publish(
+            &capped,
+            &dir,
+            "fourth.md",
+            &entry("Fourth", "2024-01-04T08:00:00Z"),
+        )
+        .await;
+        assert_eq!(titles(capped.routes(), "/feed/rss").await, ["Third"]);
+
+        let default = engine(&dir).await;
+        assert_eq!(default.storage().max_most_recent_entries(), 10);
+        assert_eq!(
+            titles(default.routes(), "/feed/rss").await,
+            ["First", "Second", "Third"]
+        );
+    }
+
+    #[tokio::test]
+    async fn tag_pages_are_paged_like_the_home_page() {
+        let dir = TempDir::new("paged-tags");
+        for (name, day) in [("a", 1), ("b", 2), ("c", 3)] {
+            dir.write(
+                format!("{name}.md"),
+                format!(
+                    "---\ntitle: Tagged {name}\nauthor: Crax\n\
+                     publish_date: 2024-01-0{day}T08:00:00Z\ntags: [rust]\n---\n\nAbout {name}\n"
+                ),
+            );
+        }
+        let routes = engine(&dir).await.routes();
+        let page = |query: &'static str| {
+            let routes = routes.clone();
+            async move {
+                let response = warp::test::request()
+                    .path(&format!("/blog/tag/rust{query}"))
+                    .reply(&routes)
+                    .await;
+                assert_eq!(response.status(), 200, "{query}");
+                let body = String::from_utf8_lossy(response.body()).into_owned();
+                let titles: Vec<_> = ["a", "b", "c"]
+                    .into_iter()
+                    .filter(|name| body.contains(&format!("Tagged {name}")))
+                    .collect();
+                (titles, body)
+            }
+        };
+
+        let (titles, body) = page("?per_page=2").await;
+        assert_eq!(titles, ["b", "c"]);
+        assert!(body.contains("Page 1 of 2"), "{body}");
+        assert!(
+            body.contains("/blog/tag/rust?page=2&amp;per_page=2\">Older posts"),
+            "{body}"
+        );
+        assert!(!body.contains("Newer posts"), "{body}");
+
+        let (titles, body) = page("?page=2&per_page=2").await;
+        assert_eq!(titles, ["a"]);
+        assert!(body.contains("Page 2 of 2"), "{body}");
+        assert!(
+            body.contains("/blog/tag/rust?page=1&amp;per_page=2\">Newer posts"),
+            "{body}"
+        );
+        // Past the end, the last page
+        assert_eq!(page("?page=9&size=2").await.0, ["a"]);
+        // All of them fit the default size, so no pagination at all
+        let (titles, body) = page("").await;
+        assert_eq!(titles, ["a", "b", "c"]);
+        assert!(!body.contains("class=\"pagination\""), "{body}");
+    }
+
+    fn failing_reads(rate: f64) -> ChaosConfig {
+        ChaosConfig {
+            fs_error_rate: rate,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn chaos_needs_dev_mode() {
+        let dir = TempDir::new("chaos-dev");
+        let error = builder(&dir)
+            .chaos(failing_reads(0.1))
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("dev mode"), "{error}");
+        let error = builder(&dir)
+            .dev(true)
+            .chaos(failing_reads(2.0))
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("between 0 and 1"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn cached_entries_are_served_through_read_errors() {
+        let dir = TempDir::new("chaos-reads");
+        dir.write("files/a.txt", "file");
+        // Enough attempts for a moderate rate to (almost) never exhaust them
+        let engine = builder(&dir)
+            .dev(true)
+            .files_path(dir.join("files"))
+            .read_retries(20, Duration::ZERO)
+            .chaos(failing_reads(0.3))
+            .build()
+            .await
+            .unwrap();
+        let routes = engine.routes();
+        for _ in 0..20 {
+            for path in ["/blog", "/blog/first", "/files/a.txt", "/feed/rss"] {
+                let response = warp::test::request().path(path).reply(&routes).await;
+                assert_eq!(response.status(), 200, "{path}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_reads_give_error_pages() {
+        let dir = TempDir::new("chaos-errors");
+        dir.write("files/a.txt", "file");
+        let engine = builder(&dir)
+            .dev(true)
+            .files_path(dir.join("files"))
+            .read_retries(2, Duration::ZERO)
+            .chaos(failing_reads(1.0))
+            .build()
+            .await
+            .unwrap();
+        let routes = engine.routes();
+        // Nothing could be loaded, the listings are just empty
+        let response = warp::test::request().path("/blog").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        for path in ["/blog/first", "/files/a.txt"] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(response.status(), 500, "{path}");
+            assert!(response.headers()["content-type"]
+                .to_str()
+                .unwrap()
+                .starts_with("text/html"));
+            let body = String::from_utf8_lossy(response.body());
+            assert!(body.trim_end().ends_with("</html>"), "{body}");
+            assert!(
+                body.contains("please mention the incident <code>"),
+                "{body}"
+            );
+        }
+    }
+
+    // Enough entries, over a few years, for the archive to be streamed
+    async fn archived_engine(dir: &TempDir) -> BlogEngine {
+        let engine = engine(dir).await;
+        let storage = engine.storage();
+        for i in 0..STREAMED_ARCHIVE_MIN_ENTRIES + 50 {
+            let name = format!("archived-{i}.md");
+            let front_matter = format!(
+                "title: Archived {i}\nauthor: Crax\npublish_date: {}-{:02}-{:02}T08:00:00Z",
+                2021 + i % 3,
+                1 + i / 3 % 12,
+                1 + i / 36
+            );
+            let entry = crate::test_support::entry(&name, &front_matter);
+            storage.try_store_entry(&name, Arc::new(entry)).await;
+        }
+        engine
+    }
+
+    fn theme_support(theme: &Path) -> Arc<RwLock<HandlebarsSupport>> {
+        Arc::new(RwLock::new(HandlebarsSupport::new(theme).unwrap()))
+    }
+
+    fn incidents() -> Arc<Incidents> {
+        Arc::new(Incidents::new(Arc::new(crate::clock::SystemClock)))
+    }
+
+    #[tokio::test]
+    async fn streamed_archive_matches_the_whole_page() {
+        let dir = TempDir::new("archive-streamed");
+        let storage = archived_engine(&dir).await.storage();
+        let handlebars_support =
+            theme_support(&Path::new(env!("CARGO_MANIFEST_DIR")).join("themes/default"));
+        let entries = storage.entries_page(0, usize::MAX, None).await;
+        assert!(entries.len() >= STREAMED_ARCHIVE_MIN_ENTRIES);
+        let whole = {
+            let handlebars_support = handlebars_support.read().unwrap();
+            let content = handlebars_support.archive_content(storage.blog_info(), &entries, None);
+            assert!(content.parts().len() > 3, "Not enough years");
+            handlebars_support.format_archive(&content).unwrap()
+        };
+
+        let response = archive(
+            Method::GET,
+            Ok(None),
+            storage,
+            handlebars_support,
+            incidents(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        let streamed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&streamed), whole);
+    }
+
+    #[tokio::test]
+    async fn failing_archive_year_marks_and_aborts_the_page() {
+        use hyper::body::HttpBody;
+
+        let dir = TempDir::new("archive-failing");
+        let storage = archived_engine(&dir).await.storage();
+        let theme = TempDir::new("archive-failing-theme");
+        copy_dir(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("themes/default"),
+            &theme.join(""),
+        );
+        theme.write("archive_year.handlebars", "{{> no_such_partial}}");
+        let incidents = incidents();
+
+        let response = archive(
+            Method::GET,
+            Ok(None),
+            storage,
+            theme_support(&theme.join("")),
+            incidents.clone(),
+        )
+        .await;
+        // The header went out before the years, so it's too late for a 500
+        assert_eq!(response.status(), 200);
+        let mut body = response.into_body();
+        let mut page = String::new();
+        let mut aborted = false;
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => page.push_str(&String::from_utf8_lossy(&chunk)),
+                Err(_) => {
+                    aborted = true;
+                    break;
+                }
+            }
+        }
+        assert!(aborted, "The body ended as if complete");
+        let (_, marker) = page
+            .split_once("<!-- Incomplete page, incident ")
+            .expect("No incident marker");
+        let id = marker.split_once(" -->").unwrap().0;
+        let incident = incidents.get(id).unwrap();
+        assert_eq!(incident.path.as_deref(), Some("/blog/archive"));
+        assert!(!page.contains("</html>"), "{page}");
+    }
+
+    #[tokio::test]
+    async fn drafts_of_subdirectories_can_be_shared() {
+        let dir = TempDir::new("share-nested");
+        dir.write(
+            "projects/_wip.md",
+            "---\ntitle: Work in progress\nauthor: Crax\n\
+             publish_date: 2024-01-01T08:00:00Z\ndraft: true\n---\n\nSoon\n",
+        );
+        let engine = builder(&dir)
+            .admin_token("secret")
+            .share_secret("share")
+            .build()
+            .await
+            .unwrap();
+        let routes = engine.routes();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/share/projects/_wip.md")
+            .header("authorization", "Bearer secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let shared: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let url = shared["url"].as_str().unwrap();
+        assert!(url.starts_with("/preview/projects/_wip.md?"), "{url}");
+
+        let response = warp::test::request().path(url).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert!(String::from_utf8_lossy(response.body()).contains("Work in progress"));
+        // Signed for that entry only
+        let other = url.replace("projects/_wip.md", "projects/../first.md");
+        let response = warp::test::request().path(&other).reply(&routes).await;
+        assert_eq!(response.status(), 403);
+    }
+}
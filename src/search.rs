@@ -0,0 +1,436 @@
+use std::{collections::HashMap, sync::Arc};
+
+use comrak::{nodes::NodeValue, Arena, Options};
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::{blog_storage::BlogEntry, markdown::MarkdownOptions};
+
+// Bytes of text shown around the matches
+const SNIPPET_WINDOW: usize = 160;
+
+// Character offsets, end excluded
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MatchSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    // A summary, without the content
+    pub entry: BlogEntry,
+    pub title_match: bool,
+    // Where the query terms are in the title, and the title already
+    // highlighted with them for the templates that don't want to do it
+    pub title_matches: Vec<MatchSpan>,
+    pub title_html: String,
+    // In the title and the text, which is what results are ranked by
+    pub occurrences: usize,
+    // HTML: the part of the text with the most query terms, them in <mark>
+    pub snippet: Option<String>,
+}
+
+// Anything able to look entries up by their text. Implementations are told
+// about every stored and removed entry, and only rank the candidates they're
+// given, which are the entries that may be shown
+pub trait SearchBackend: Send + Sync {
+    fn insert<'a>(&'a self, entry_name: &'a str, markdown: &'a str) -> BoxFuture<'a, ()>;
+    fn remove<'a>(&'a self, entry_name: &'a str) -> BoxFuture<'a, ()>;
+    fn search<'a>(
+        &'a self,
+        candidates: &'a [Arc<BlogEntry>],
+        query: &'a str,
+    ) -> BoxFuture<'a, Vec<SearchResult>>;
+}
+
+// The text of every entry with the markdown syntax stripped, scanned on every
+// search. Fine for a blog, an inverted index can replace it when it isn't
+pub struct SearchIndex {
+    texts: RwLock<HashMap<String, String>>,
+    markdown: MarkdownOptions,
+}
+
+impl SearchIndex {
+    pub fn new(markdown: MarkdownOptions) -> Self {
+        Self {
+            texts: Default::default(),
+            markdown,
+        }
+    }
+}
+
+impl SearchBackend for SearchIndex {
+    fn insert<'a>(&'a self, entry_name: &'a str, markdown: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let text = searchable_text(markdown, &self.markdown);
+            self.texts.write().await.insert(entry_name.to_owned(), text);
+        })
+    }
+
+    fn remove<'a>(&'a self, entry_name: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.texts.write().await.remove(entry_name);
+        })
+    }
+
+    // Case insensitive phrase search. Title hits come first, then the most
+    // occurrences, ties keeping the order of the candidates
+    fn search<'a>(
+        &'a self,
+        candidates: &'a [Arc<BlogEntry>],
+        query: &'a str,
+    ) -> BoxFuture<'a, Vec<SearchResult>> {
+        Box::pin(async move {
+            let query = query.trim().to_lowercase();
+            if query.is_empty() {
+                return vec![];
+            }
+            let terms = query_terms(&query);
+            let texts = self.texts.read().await;
+            let mut results: Vec<_> = candidates
+                .iter()
+                .filter_map(|entry| {
+                    let text = texts.get(&entry.filename).map(String::as_str).unwrap_or("");
+                    let title_occurrences = occurrences(&entry.description.title, &query);
+                    let occurrences = title_occurrences + occurrences(text, &query);
+                    if occurrences == 0 {
+                        return None;
+                    }
+                    let title = &entry.description.title;
+                    let title_matches = match_ranges(title, &terms);
+                    Some(SearchResult {
+                        entry: (**entry).clone(),
+                        title_match: title_occurrences > 0,
+                        title_matches: title_matches
+                            .iter()
+                            .map(|m| MatchSpan {
+                                start: title[..m.start].chars().count(),
+                                end: title[..m.end].chars().count(),
+                            })
+                            .collect(),
+                        title_html: highlight(title, &title_matches),
+                        occurrences,
+                        snippet: snippet(text, &terms),
+                    })
+                })
+                .collect();
+            results.sort_by(|a, b| {
+                b.title_match
+                    .cmp(&a.title_match)
+                    .then_with(|| b.occurrences.cmp(&a.occurrences))
+            });
+            results
+        })
+    }
+}
+
+fn searchable_text(markdown: &str, options: &Options) -> String {
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, markdown, options);
+    let mut text = String::new();
+    for node in root.descendants() {
+        match &node.data.borrow().value {
+            NodeValue::Text(literal) => text.push_str(literal),
+            NodeValue::Code(code) => text.push_str(&code.literal),
+            NodeValue::CodeBlock(code) => text.push_str(&code.literal),
+            _ => continue,
+        }
+        text.push(' ');
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Byte range of the first match of an already lowercased needle. Lowercasing
+// the haystack instead could move the offsets, e.g. for 'İ'
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<(usize, usize)> {
+    haystack.char_indices().find_map(|(start, _)| {
+        let mut needle_chars = needle.chars();
+        let mut end = start;
+        for c in haystack[start..].chars() {
+            for lower in c.to_lowercase() {
+                if needle_chars.next() != Some(lower) {
+                    return None;
+                }
+            }
+            end += c.len_utf8();
+            if needle_chars.as_str().is_empty() {
+                return Some((start, end));
+            }
+        }
+        None
+    })
+}
+
+fn occurrences(haystack: &str, needle: &str) -> usize {
+    let mut count = 0;
+    let mut rest = haystack;
+    while let Some((_, end)) = find_ignore_case(rest, needle) {
+        count += 1;
+        rest = &rest[end..];
+    }
+    count
+}
+
+// The lowercased words of the query, highlighted one by one: a phrase query
+// marks each of its words
+fn query_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = vec![];
+    for term in query.split_whitespace().map(str::to_lowercase) {
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms
+}
+
+// A match, as byte offsets, and the term it's for
+#[derive(Clone, Copy)]
+struct Match {
+    start: usize,
+    end: usize,
+    term: usize,
+}
+
+// Every match of every term, sorted and merged where they overlap (e.g. "a"
+// inside of "ab"), so that they can be wrapped in tags one after the other
+fn match_ranges(text: &str, terms: &[String]) -> Vec<Match> {
+    let mut matches = vec![];
+    for (term, needle) in terms.iter().enumerate() {
+        let mut offset = 0;
+        while let Some((start, end)) = find_ignore_case(&text[offset..], needle) {
+            matches.push(Match {
+                start: offset + start,
+                end: offset + end,
+                term,
+            });
+            offset += end;
+        }
+    }
+    matches.sort_by_key(|m| (m.start, m.end));
+    let mut merged: Vec<Match> = Vec::with_capacity(matches.len());
+    for m in matches {
+        match merged.last_mut() {
+            Some(last) if m.start < last.end => last.end = last.end.max(m.end),
+            _ => merged.push(m),
+        }
+    }
+    merged
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn highlight(text: &str, matches: &[Match]) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut written = 0;
+    for m in matches {
+        html.push_str(&escape_html(&text[written..m.start]));
+        html.push_str("<mark>");
+        html.push_str(&escape_html(&text[m.start..m.end]));
+        html.push_str("</mark>");
+        written = m.end;
+    }
+    html.push_str(&escape_html(&text[written..]));
+    html
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+// The window of SNIPPET_WINDOW bytes holding the most distinct terms, then
+// the most matches, earliest first. It's centered on its matches, and moved
+// back inside the text when it would go past either end
+fn snippet(text: &str, terms: &[String]) -> Option<String> {
+    let matches = match_ranges(text, terms);
+    let mut best: Option<(usize, usize, (usize, usize))> = None;
+    for first in 0..matches.len() {
+        let limit = matches[first].start + SNIPPET_WINDOW;
+        let last = (first..matches.len())
+            .take_while(|&i| matches[i].end <= limit)
+            .last()
+            // A single match longer than the window is still shown
+            .unwrap_or(first);
+        let mut found: Vec<_> = matches[first..=last].iter().map(|m| m.term).collect();
+        found.sort_unstable();
+        found.dedup();
+        let score = (found.len(), last + 1 - first);
+        if best.is_none_or(|(_, _, best)| score > best) {
+            best = Some((first, last, score));
+        }
+    }
+    let (first, last, _) = best?;
+    let (span_start, span_end) = (matches[first].start, matches[last].end);
+    let padding = SNIPPET_WINDOW.saturating_sub(span_end - span_start) / 2;
+    let mut start = span_start.saturating_sub(padding);
+    let mut end = (span_end + padding).min(text.len());
+    // Whatever doesn't fit on one side goes to the other
+    if start == 0 {
+        end = SNIPPET_WINDOW.max(span_end).min(text.len());
+    } else if end == text.len() {
+        start = text.len().saturating_sub(SNIPPET_WINDOW).min(span_start);
+    }
+    let start = floor_char_boundary(text, start);
+    let end = ceil_char_boundary(text, end);
+    let shown: Vec<_> = matches
+        .iter()
+        .filter(|m| m.start >= start && m.end <= end)
+        .map(|m| Match {
+            start: m.start - start,
+            end: m.end - start,
+            term: m.term,
+        })
+        .collect();
+    let mut html = highlight(&text[start..end], &shown);
+    if start > 0 {
+        html.insert(0, '…');
+    }
+    if end < text.len() {
+        html.push('…');
+    }
+    Some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snippet_for(text: &str, query: &str) -> Option<String> {
+        snippet(text, &query_terms(query))
+    }
+
+    fn filler(words: usize) -> String {
+        "lorem ".repeat(words)
+    }
+
+    // The text shown, without the tags or the ellipses
+    fn shown(snippet: &str) -> String {
+        snippet
+            .replace("<mark>", "")
+            .replace("</mark>", "")
+            .trim_matches('…')
+            .to_owned()
+    }
+
+    #[test]
+    fn short_texts_are_shown_whole() {
+        assert_eq!(
+            snippet_for("Writing Rust is fun", "rust").unwrap(),
+            "Writing <mark>Rust</mark> is fun"
+        );
+        assert_eq!(snippet_for("Writing Rust is fun", "python"), None);
+        assert_eq!(snippet_for("", "rust"), None);
+    }
+
+    #[test]
+    fn matches_at_the_start_fill_the_window_after_them() {
+        let text = format!("Rust {}", filler(60));
+        let snippet = snippet_for(&text, "rust").unwrap();
+        assert!(snippet.starts_with("<mark>Rust</mark> lorem"), "{snippet}");
+        assert!(snippet.ends_with('…'), "{snippet}");
+        assert_eq!(shown(&snippet).len(), SNIPPET_WINDOW);
+    }
+
+    #[test]
+    fn matches_at_the_end_fill_the_window_before_them() {
+        let text = format!("{}rust", filler(60));
+        let snippet = snippet_for(&text, "rust").unwrap();
+        assert!(snippet.starts_with('…'), "{snippet}");
+        assert!(snippet.ends_with("lorem <mark>rust</mark>"), "{snippet}");
+        assert_eq!(shown(&snippet).len(), SNIPPET_WINDOW);
+    }
+
+    #[test]
+    fn matches_in_the_middle_are_centered() {
+        let text = format!("{}rust {}", filler(60), filler(60));
+        let snippet = snippet_for(&text, "rust").unwrap();
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        let shown = shown(&snippet);
+        let at = shown.find("rust").unwrap();
+        let after = shown.len() - at - "rust".len();
+        assert!(at.abs_diff(after) <= 1, "{at} before and {after} after");
+    }
+
+    #[test]
+    fn the_window_with_the_most_terms_wins() {
+        // "alpha" alone comes first and more often, but "beta" is only
+        // found together with it later on
+        let text = format!(
+            "alpha alpha alpha {}alpha and beta {}",
+            filler(60),
+            filler(10)
+        );
+        let snippet = snippet_for(&text, "alpha beta").unwrap();
+        assert!(
+            snippet.contains("<mark>alpha</mark> and <mark>beta</mark>"),
+            "{snippet}"
+        );
+        assert!(!snippet.contains("alpha alpha"), "{snippet}");
+    }
+
+    #[test]
+    fn ties_go_to_the_window_with_more_matches() {
+        let text = format!("rust once {}rust rust twice {}", filler(60), filler(60));
+        let snippet = snippet_for(&text, "rust").unwrap();
+        assert!(
+            snippet.contains("<mark>rust</mark> <mark>rust</mark> twice"),
+            "{snippet}"
+        );
+        assert!(!snippet.contains("once"), "{snippet}");
+    }
+
+    #[test]
+    fn overlapping_terms_are_marked_once() {
+        assert_eq!(
+            snippet_for("a rustacean", "rust rustacean").unwrap(),
+            "a <mark>rustacean</mark>"
+        );
+        assert_eq!(snippet_for("abcd", "abc bcd").unwrap(), "<mark>abcd</mark>");
+        // Repeated terms don't matter
+        assert_eq!(
+            snippet_for("one Rust", "rust RUST").unwrap(),
+            "one <mark>Rust</mark>"
+        );
+    }
+
+    #[test]
+    fn the_text_is_escaped_around_the_marks() {
+        assert_eq!(
+            snippet_for("<b>Rust</b> & \"co\"", "rust").unwrap(),
+            "&lt;b&gt;<mark>Rust</mark>&lt;/b&gt; &amp; &quot;co&quot;"
+        );
+    }
+
+    #[test]
+    fn windows_end_on_char_boundaries() {
+        let text = format!("{}rust{}", "é".repeat(150), "ü".repeat(150));
+        let snippet = snippet_for(&text, "rust").unwrap();
+        assert!(snippet.contains("é<mark>rust</mark>ü"), "{snippet}");
+        // Widened to the next boundary, never shortened past the match
+        assert!(shown(&snippet).len() <= SNIPPET_WINDOW + 2);
+    }
+
+    #[test]
+    fn matches_longer_than_the_window_are_still_shown() {
+        let long = "x".repeat(SNIPPET_WINDOW * 2);
+        let text = format!("{} {long} {}", filler(10), filler(10));
+        let snippet = snippet_for(&text, &long).unwrap();
+        assert!(snippet.contains(&format!("<mark>{long}</mark>")));
+    }
+}
@@ -0,0 +1,92 @@
+use std::{num::NonZeroUsize, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use swes::{blog_storage::BlogInfo, cdn::CdnConfig};
+
+pub const SERVER_CONFIG_FILE: &str = "swes.toml";
+
+// The settings of the binary, so that a deployment doesn't need a long command
+// line. Each key has the name of the matching flag, which wins over it
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub base_path: Option<String>,
+    // The blog.toml to use, the --config flag
+    pub blog_config: Option<String>,
+    pub file_server_path: Option<String>,
+    #[serde(alias = "theme")]
+    pub handlebars_theme: Option<String>,
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub listen: Vec<String>,
+    pub site_url: Option<String>,
+    pub admin_token: Option<String>,
+    pub share_secret: Option<String>,
+    pub journal_path: Option<String>,
+    pub outbox_path: Option<String>,
+    pub cache_file: Option<String>,
+    pub access_log: Option<String>,
+    pub pages_path: Option<String>,
+    pub referrers_path: Option<String>,
+    pub views_path: Option<String>,
+    pub artifacts_path: Option<String>,
+    pub artifacts_budget_mb: Option<u64>,
+    pub ready_file: Option<String>,
+    pub plaintext_width: Option<usize>,
+    pub stale_after_days: Option<i64>,
+    pub cache_size: Option<NonZeroUsize>,
+    pub max_entries: Option<NonZeroUsize>,
+    pub upload_limit_mb: Option<u64>,
+    pub compression_min_bytes: Option<usize>,
+    pub shutdown_grace_secs: Option<u64>,
+    pub read_attempts: Option<u32>,
+    pub read_backoff_ms: Option<u64>,
+    // Replaces the name, description, author and accent color of blog.toml
+    pub blog: Option<BlogSection>,
+    // Enables the CDN mode like --cdn-mode does
+    pub cdn: Option<CdnConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BlogSection {
+    pub name: String,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub accent_color: Option<String>,
+}
+
+impl BlogSection {
+    pub fn info(self) -> BlogInfo {
+        BlogInfo {
+            name: self.name,
+            description: self.description,
+            author: self.author,
+            accent_color: self.accent_color,
+            favicons: vec![],
+            profile_links: vec![],
+        }
+    }
+}
+
+impl ServerConfig {
+    // A file given explicitly must exist, the default one is optional
+    pub fn load(path: Option<&str>) -> anyhow::Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (Path::new(path), true),
+            None => (Path::new(SERVER_CONFIG_FILE), false),
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read server config {path:?}"))
+            }
+        };
+        toml::from_str(&content).with_context(|| format!("Invalid server config {path:?}"))
+    }
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Tolerance for clocks of different machines not quite agreeing on the expiry
+const CLOCK_SKEW_SECONDS: i64 = 60;
+
+pub struct Signer {
+    secret: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Malformed => write!(f, "malformed signature"),
+            VerifyError::BadSignature => write!(f, "signature mismatch"),
+            VerifyError::Expired => write!(f, "signature expired"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl Signer {
+    pub fn new<S: AsRef<[u8]>>(secret: S) -> Self {
+        Self {
+            secret: secret.as_ref().to_vec(),
+        }
+    }
+
+    // The signature covers both the payload and the expiry, so neither can be
+    // altered without invalidating it
+    pub fn sign(&self, payload: &str, expires_at: DateTime<Utc>) -> String {
+        let mac = self.mac(payload, expires_at.timestamp());
+        to_hex(&mac.finalize().into_bytes())
+    }
+
+    pub fn verify(
+        &self,
+        payload: &str,
+        expires_at: i64,
+        signature: &str,
+        now: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>, VerifyError> {
+        let signature = from_hex(signature).ok_or(VerifyError::Malformed)?;
+        let expiry = DateTime::from_timestamp(expires_at, 0).ok_or(VerifyError::Malformed)?;
+
+        // verify_slice compares in constant time
+        self.mac(payload, expires_at)
+            .verify_slice(&signature)
+            .map_err(|_| VerifyError::BadSignature)?;
+
+        if now - Duration::seconds(CLOCK_SKEW_SECONDS) > expiry {
+            return Err(VerifyError::Expired);
+        }
+        Ok(expiry)
+    }
+
+    fn mac(&self, payload: &str, expires_at: i64) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(payload.as_bytes());
+        mac.update(b"\n");
+        mac.update(expires_at.to_string().as_bytes());
+        mac
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would take a sign, e.g. "+f"
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    #[test]
+    fn verifies_its_own_signatures() {
+        let signer = Signer::new("secret");
+        let expires_at = now() + Duration::hours(72);
+        let sig = signer.sign("draft.md", expires_at);
+        assert_eq!(
+            signer.verify("draft.md", expires_at.timestamp(), &sig, now()),
+            Ok(expires_at)
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_payload_or_expiry() {
+        let signer = Signer::new("secret");
+        let expires_at = now() + Duration::hours(1);
+        let sig = signer.sign("draft.md", expires_at);
+        assert_eq!(
+            signer.verify("other.md", expires_at.timestamp(), &sig, now()),
+            Err(VerifyError::BadSignature)
+        );
+        assert_eq!(
+            signer.verify("draft.md", expires_at.timestamp() + 3600, &sig, now()),
+            Err(VerifyError::BadSignature)
+        );
+        let mut flipped = sig.clone().into_bytes();
+        flipped[0] = if flipped[0] == b'0' { b'1' } else { b'0' };
+        let flipped = String::from_utf8(flipped).unwrap();
+        assert_eq!(
+            signer.verify("draft.md", expires_at.timestamp(), &flipped, now()),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_signatures_of_another_secret() {
+        let expires_at = now() + Duration::hours(1);
+        let sig = Signer::new("old secret").sign("draft.md", expires_at);
+        assert_eq!(
+            Signer::new("new secret").verify("draft.md", expires_at.timestamp(), &sig, now()),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let signer = Signer::new("secret");
+        let expiry = now().timestamp();
+        assert!(signer.verify("draft.md", expiry, "", now()).is_err());
+        for sig in ["abc", "zz", "é1", "+f", "-0"] {
+            assert_eq!(
+                signer.verify("draft.md", expiry, sig, now()),
+                Err(VerifyError::Malformed),
+                "{sig:?}"
+            );
+        }
+        let sig = signer.sign("draft.md", now());
+        assert_eq!(
+            signer.verify("draft.md", i64::MAX, &sig, now()),
+            Err(VerifyError::Malformed)
+        );
+    }
+
+    #[test]
+    fn tolerates_some_clock_skew_past_the_expiry() {
+        let signer = Signer::new("secret");
+        let expires_at = now();
+        let sig = signer.sign("draft.md", expires_at);
+        let within = now() + Duration::seconds(CLOCK_SKEW_SECONDS);
+        assert!(signer
+            .verify("draft.md", expires_at.timestamp(), &sig, within)
+            .is_ok());
+        let past = within + Duration::seconds(1);
+        assert_eq!(
+            signer.verify("draft.md", expires_at.timestamp(), &sig, past),
+            Err(VerifyError::Expired)
+        );
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"t"));
+    }
+}
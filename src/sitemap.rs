@@ -0,0 +1,67 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+
+// Only a hint for crawlers, which are free to ignore it
+#[derive(Clone, Copy)]
+pub enum ChangeFrequency {
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl ChangeFrequency {
+    fn name(self) -> &'static str {
+        match self {
+            ChangeFrequency::Weekly => "weekly",
+            ChangeFrequency::Monthly => "monthly",
+            ChangeFrequency::Yearly => "yearly",
+        }
+    }
+}
+
+pub struct SitemapUrl {
+    // Absolute path on the site, e.g. /blog/simple.md
+    pub path: String,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub change_frequency: ChangeFrequency,
+}
+
+pub fn generate(site_url: &str, urls: &[SitemapUrl]) -> String {
+    let site_url = site_url.trim_end_matches('/');
+    let mut sitemap = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for url in urls {
+        sitemap.push_str("  <url>\n");
+        let _ = writeln!(
+            sitemap,
+            "    <loc>{}</loc>",
+            escape_xml(&format!("{site_url}{}", url.path))
+        );
+        if let Some(last_modified) = url.last_modified {
+            let _ = writeln!(
+                sitemap,
+                "    <lastmod>{}</lastmod>",
+                last_modified.format("%Y-%m-%d")
+            );
+        }
+        let _ = writeln!(
+            sitemap,
+            "    <changefreq>{}</changefreq>",
+            url.change_frequency.name()
+        );
+        sitemap.push_str("  </url>\n");
+    }
+    sitemap.push_str("</urlset>\n");
+    sitemap
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,316 @@
+//! End to end checks of a running instance, e.g. a new deployment before
+//! pointing the DNS at it. [`run`] fetches the home page, the feeds, the
+//! sitemap, a static file, a missing entry and some of the entries listed in
+//! the RSS feed, and tells which of them don't look right.
+
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use hyper::{body::HttpBody, client::HttpConnector, Client, StatusCode, Uri};
+
+pub const DEFAULT_SAMPLE_SIZE: usize = 3;
+pub const DEFAULT_BUDGET: Duration = Duration::from_secs(2);
+// The stylesheet of the default theme
+pub const DEFAULT_STATIC_FILE: &str = "/files/style.css";
+// Nothing is ever published there
+const MISSING_ENTRY: &str = "/blog/smoke-test-missing-entry";
+// Pages are expected to be small, a bigger one is read only up to this
+const MAX_BODY: usize = 8 * 1024 * 1024;
+
+pub struct SmokeConfig {
+    // Where the blog is served, e.g. http://10.0.0.2:8080
+    pub base_url: String,
+    // Checked on the home page when set
+    pub blog_name: Option<String>,
+    pub static_file: String,
+    // How many entries of the feed are fetched
+    pub sample_size: usize,
+    // The slowest a single response may be
+    pub budget: Duration,
+}
+
+impl SmokeConfig {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            blog_name: None,
+            static_file: DEFAULT_STATIC_FILE.to_owned(),
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            budget: DEFAULT_BUDGET,
+        }
+    }
+}
+
+pub struct Check {
+    pub name: String,
+    pub path: String,
+    pub elapsed: Option<Duration>,
+    // Why the check failed, empty when it passed
+    pub problems: Vec<String>,
+}
+
+impl Check {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+pub struct SmokeReport {
+    pub checks: Vec<Check>,
+}
+
+impl SmokeReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(Check::passed)
+    }
+
+    pub fn failures(&self) -> usize {
+        self.checks.iter().filter(|c| !c.passed()).count()
+    }
+}
+
+impl Display for SmokeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            let elapsed = check
+                .elapsed
+                .map(|e| format!("{}ms", e.as_millis()))
+                .unwrap_or("-".to_owned());
+            let status = if check.passed() { "PASS" } else { "FAIL" };
+            writeln!(
+                f,
+                "{status} {:<10} {:<40} {elapsed}",
+                check.name, check.path
+            )?;
+            for problem in &check.problems {
+                writeln!(f, "     {problem}")?;
+            }
+        }
+        write!(
+            f,
+            "{} of {} checks passed",
+            self.checks.len() - self.failures(),
+            self.checks.len()
+        )
+    }
+}
+
+struct Fetched {
+    status: StatusCode,
+    content_type: String,
+    body: String,
+}
+
+// What a response must look like to pass
+struct Expect<'a> {
+    status: StatusCode,
+    content_type: &'a str,
+    markers: Vec<&'a str>,
+}
+
+impl<'a> Expect<'a> {
+    fn ok(content_type: &'a str) -> Self {
+        Self {
+            status: StatusCode::OK,
+            content_type,
+            markers: vec![],
+        }
+    }
+
+    fn containing(mut self, marker: &'a str) -> Self {
+        self.markers.push(marker);
+        self
+    }
+}
+
+struct Smoke<'a> {
+    config: &'a SmokeConfig,
+    base_url: &'a str,
+    client: Client<HttpConnector>,
+    checks: Vec<Check>,
+}
+
+impl Smoke<'_> {
+    async fn check(&mut self, name: &str, path: &str, expect: Expect<'_>) -> Option<Fetched> {
+        let started = Instant::now();
+        let fetched = fetch(&self.client, &format!("{}{path}", self.base_url)).await;
+        let elapsed = started.elapsed();
+        let mut problems = vec![];
+        let fetched = match fetched {
+            Ok(fetched) => Some(fetched),
+            Err(e) => {
+                problems.push(format!("request failed: {e:#}"));
+                None
+            }
+        };
+        if let Some(fetched) = &fetched {
+            if fetched.status != expect.status {
+                problems.push(format!(
+                    "answered {} instead of {}",
+                    fetched.status, expect.status
+                ));
+            }
+            if !fetched.content_type.starts_with(expect.content_type) {
+                problems.push(format!(
+                    "content type is {:?} instead of {}",
+                    fetched.content_type, expect.content_type
+                ));
+            }
+            for marker in &expect.markers {
+                if !fetched.body.contains(marker) {
+                    problems.push(format!("{marker:?} is missing"));
+                }
+            }
+            if elapsed > self.config.budget {
+                problems.push(format!(
+                    "took {}ms, over the budget of {}ms",
+                    elapsed.as_millis(),
+                    self.config.budget.as_millis()
+                ));
+            }
+        }
+        self.checks.push(Check {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            elapsed: fetched.is_some().then_some(elapsed),
+            problems,
+        });
+        fetched
+    }
+
+    fn fail(&mut self, name: &str, path: &str, problem: String) {
+        self.checks.push(Check {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            elapsed: None,
+            problems: vec![problem],
+        });
+    }
+}
+
+// Only plain http is supported: a deployment behind a TLS terminator is
+// checked on the address the terminator forwards to
+pub async fn run(config: &SmokeConfig) -> anyhow::Result<SmokeReport> {
+    let base_url = config.base_url.trim_end_matches('/');
+    let uri: Uri = base_url.parse()?;
+    if uri.scheme_str() != Some("http") {
+        anyhow::bail!("Only plain http urls can be checked, not {base_url}");
+    }
+    let mut smoke = Smoke {
+        config,
+        base_url,
+        client: Client::new(),
+        checks: vec![],
+    };
+
+    // As the templates escape it
+    let blog_name = config.blog_name.as_deref().map(handlebars::html_escape);
+    let mut home = Expect::ok("text/html").containing("rel=\"canonical\"");
+    if let Some(name) = &blog_name {
+        home = home.containing(name);
+    }
+    smoke.check("home", "/blog", home).await;
+
+    let rss = smoke
+        .check(
+            "feed",
+            "/feed/rss",
+            Expect::ok("application/rss+xml").containing("<rss"),
+        )
+        .await;
+    smoke
+        .check(
+            "feed",
+            "/feed/atom",
+            Expect::ok("application/atom+xml").containing("<feed"),
+        )
+        .await;
+    smoke
+        .check(
+            "feed",
+            "/feed/json",
+            Expect::ok("application/feed+json").containing("\"items\""),
+        )
+        .await;
+
+    if let Some(rss) = rss.filter(|rss| rss.status.is_success()) {
+        match sample_entries(&rss.body, config.sample_size) {
+            Ok(paths) if paths.is_empty() => {
+                smoke.fail("entry", "/feed/rss", "the feed lists no entry".to_owned())
+            }
+            Ok(paths) => {
+                for path in paths {
+                    let expect = Expect::ok("text/html").containing("rel=\"canonical\"");
+                    smoke.check("entry", &path, expect).await;
+                }
+            }
+            Err(e) => smoke.fail("entry", "/feed/rss", format!("unreadable feed: {e}")),
+        }
+    }
+
+    smoke
+        .check(
+            "sitemap",
+            "/sitemap.xml",
+            Expect::ok("application/xml").containing("<urlset"),
+        )
+        .await;
+    let static_file = config.static_file.clone();
+    let static_type = mime_guess::from_path(&static_file)
+        .first_or(mime_guess::mime::TEXT_PLAIN)
+        .to_string();
+    smoke
+        .check("static", &static_file, Expect::ok(&static_type))
+        .await;
+    let missing = Expect {
+        status: StatusCode::NOT_FOUND,
+        content_type: "text/html",
+        markers: vec![],
+    };
+    smoke.check("not found", MISSING_ENTRY, missing).await;
+
+    Ok(SmokeReport {
+        checks: smoke.checks,
+    })
+}
+
+// The paths of the first entries of the feed. Their links carry the site url
+// of the instance, which isn't the checked address before the DNS points at
+// it, so only the paths are kept
+fn sample_entries(rss: &str, sample_size: usize) -> anyhow::Result<Vec<String>> {
+    let channel = rss::Channel::read_from(rss.as_bytes())?;
+    Ok(channel
+        .items()
+        .iter()
+        .filter_map(|item| item.link())
+        .filter_map(|link| link.parse::<Uri>().ok())
+        .map(|uri| uri.path().to_owned())
+        .take(sample_size)
+        .collect())
+}
+
+async fn fetch(client: &Client<HttpConnector>, url: &str) -> anyhow::Result<Fetched> {
+    let response = client.get(url.parse()?).await?;
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let mut body = response.into_body();
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() > MAX_BODY {
+            break;
+        }
+    }
+    Ok(Fetched {
+        status,
+        content_type,
+        body: String::from_utf8_lossy(&bytes).into_owned(),
+    })
+}
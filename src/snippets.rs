@@ -0,0 +1,259 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use futures_util::future::BoxFuture;
+use log::warn;
+
+// Markdown fragments shared by several entries, in the blog directory. Like
+// every name starting with '_', it's never published by itself
+pub const SNIPPETS_DIR: &str = "_snippets";
+// Snippets may include other snippets, up to this many levels
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// A line holding only @include(name) is replaced by _snippets/name.md
+fn include_name(line: &str) -> Option<&str> {
+    let name = line
+        .trim()
+        .strip_prefix("@include(")?
+        .strip_suffix(')')?
+        .trim()
+        .trim_matches('"');
+    Some(name)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .split('/')
+            .all(|c| !c.is_empty() && !c.starts_with('.'))
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '/' | '.'))
+}
+
+// The name of the snippet a path of the blog directory holds, if it's one
+pub fn snippet_name(entry_name: &str) -> Option<&str> {
+    entry_name
+        .strip_prefix(SNIPPETS_DIR)?
+        .strip_prefix('/')?
+        .strip_suffix(".md")
+}
+
+pub struct Expanded {
+    pub content: String,
+    // Every snippet used, including the ones included by other snippets, so
+    // that the entry is parsed again when any of them changes
+    pub snippets: BTreeSet<String>,
+}
+
+pub struct Snippets {
+    dir: PathBuf,
+    // Mistakes are shown in the page while writing, only logged otherwise
+    show_errors: bool,
+}
+
+impl Snippets {
+    pub fn new<P: AsRef<Path>>(base_path: P) -> Self {
+        Self {
+            dir: base_path.as_ref().join(SNIPPETS_DIR),
+            show_errors: false,
+        }
+    }
+
+    pub fn show_errors(mut self) -> Self {
+        self.show_errors = true;
+        self
+    }
+
+    pub fn shows_errors(&self) -> bool {
+        self.show_errors
+    }
+
+    pub async fn expand(&self, content: &str) -> Expanded {
+        let mut snippets = BTreeSet::new();
+        let content = self.expand_in(content, &mut vec![], &mut snippets).await;
+        Expanded { content, snippets }
+    }
+
+    // `including` is the chain of snippets being expanded, to tell cycles
+    // apart from a snippet simply used twice
+    fn expand_in<'a>(
+        &'a self,
+        content: &'a str,
+        including: &'a mut Vec<String>,
+        snippets: &'a mut BTreeSet<String>,
+    ) -> BoxFuture<'a, String> {
+        Box::pin(async move {
+            if !content.contains("@include(") {
+                return content.to_owned();
+            }
+            let mut expanded = String::with_capacity(content.len());
+            for line in content.split_inclusive('\n') {
+                let Some(name) = include_name(line) else {
+                    expanded.push_str(line);
+                    continue;
+                };
+                let snippet = match self.load(name, including).await {
+                    Ok(snippet) => {
+                        snippets.insert(name.to_owned());
+                        including.push(name.to_owned());
+                        let snippet = self.expand_in(&snippet, including, snippets).await;
+                        including.pop();
+                        snippet
+                    }
+                    Err(error) => {
+                        // Still a dependency: creating the missing snippet
+                        // must fix the entry
+                        if is_valid_name(name) {
+                            snippets.insert(name.to_owned());
+                        }
+                        self.failed(name, &error)
+                    }
+                };
+                expanded.push_str(snippet.trim_end_matches('\n'));
+                if line.ends_with('\n') {
+                    expanded.push('\n');
+                }
+            }
+            expanded
+        })
+    }
+
+    async fn load(&self, name: &str, including: &[String]) -> Result<String, String> {
+        if !is_valid_name(name) {
+            return Err(format!("invalid snippet name \"{name}\""));
+        }
+        if including.iter().any(|n| n == name) {
+            return Err(format!(
+                "snippet \"{name}\" includes itself through {}",
+                including.join(" -> ")
+            ));
+        }
+        if including.len() >= MAX_INCLUDE_DEPTH {
+            return Err(format!(
+                "snippet \"{name}\" is nested deeper than {MAX_INCLUDE_DEPTH} levels"
+            ));
+        }
+        tokio::fs::read_to_string(self.dir.join(format!("{name}.md")))
+            .await
+            .map_err(|e| format!("unknown snippet \"{name}\" ({e})"))
+    }
+
+    fn failed(&self, name: &str, error: &str) -> String {
+        warn!("Failed to include {name}: {error}");
+        if self.show_errors {
+            format!("\n> **Include failed**: {error}\n")
+        } else {
+            String::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn snippet(dir: &TempDir, name: &str, content: &str) {
+        dir.write(format!("{SNIPPETS_DIR}/{name}.md"), content);
+    }
+
+    fn names(expanded: &Expanded) -> Vec<&str> {
+        expanded.snippets.iter().map(String::as_str).collect()
+    }
+
+    #[tokio::test]
+    async fn includes_nested_snippets() {
+        let dir = TempDir::new("snippets-nested");
+        snippet(&dir, "outer", "Outer\n@include(inner)\n");
+        snippet(&dir, "inner", "Inner\n");
+        let expanded = Snippets::new(dir.join(""))
+            .expand("Before\n@include(outer)\nAfter\n@include(inner)")
+            .await;
+        assert_eq!(expanded.content, "Before\nOuter\nInner\nAfter\nInner");
+        assert_eq!(names(&expanded), ["inner", "outer"]);
+    }
+
+    #[tokio::test]
+    async fn cycles_are_cut_where_they_close() {
+        let dir = TempDir::new("snippets-cycle");
+        snippet(&dir, "a", "A\n@include(b)\n");
+        snippet(&dir, "b", "B\n@include(a)\n");
+        let snippets = Snippets::new(dir.join(""));
+        let expanded = snippets.expand("@include(a)\n").await;
+        assert_eq!(expanded.content, "A\nB\n");
+        assert_eq!(names(&expanded), ["a", "b"]);
+
+        let expanded = snippets.show_errors().expand("@include(a)\n").await;
+        assert!(
+            expanded
+                .content
+                .contains("**Include failed**: snippet \"a\" includes itself through a -> b"),
+            "{}",
+            expanded.content
+        );
+    }
+
+    #[tokio::test]
+    async fn nesting_stops_at_the_maximum_depth() {
+        let dir = TempDir::new("snippets-depth");
+        for level in 0..=MAX_INCLUDE_DEPTH {
+            snippet(
+                &dir,
+                &format!("level{level}"),
+                &format!("{level}\n@include(level{})\n", level + 1),
+            );
+        }
+        let expanded = Snippets::new(dir.join(""))
+            .show_errors()
+            .expand("@include(level0)\n")
+            .await;
+        let levels: Vec<_> = (0..MAX_INCLUDE_DEPTH).map(|l| l.to_string()).collect();
+        assert!(
+            expanded.content.starts_with(&levels.join("\n")),
+            "{}",
+            expanded.content
+        );
+        assert!(
+            expanded.content.contains(&format!(
+                "snippet \"level{MAX_INCLUDE_DEPTH}\" is nested deeper than {MAX_INCLUDE_DEPTH} levels"
+            )),
+            "{}",
+            expanded.content
+        );
+        assert!(!expanded
+            .content
+            .contains(&format!("\n{MAX_INCLUDE_DEPTH}\n")));
+    }
+
+    #[tokio::test]
+    async fn missing_snippets_are_shown_only_while_writing() {
+        let dir = TempDir::new("snippets-missing");
+        let snippets = Snippets::new(dir.join(""));
+        let expanded = snippets.expand("Before\n@include(missing)\nAfter\n").await;
+        assert_eq!(expanded.content, "Before\n\nAfter\n");
+        // Creating it must parse the entry again
+        assert_eq!(names(&expanded), ["missing"]);
+
+        let expanded = snippets
+            .show_errors()
+            .expand("Before\n@include(missing)\nAfter\n")
+            .await;
+        assert!(expanded
+            .content
+            .starts_with("Before\n\n> **Include failed**: unknown snippet \"missing\""));
+        assert!(
+            expanded.content.ends_with("\nAfter\n"),
+            "{}",
+            expanded.content
+        );
+        assert_eq!(names(&expanded), ["missing"]);
+        // Invalid names can't be created, so they're no dependency
+        let expanded = Snippets::new(dir.join(""))
+            .expand("@include(../secret)\n")
+            .await;
+        assert!(expanded.snippets.is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use chrono::{Datelike, NaiveDate};
+use serde::Serialize;
+
+use crate::blog_storage::BlogEntry;
+
+const TOP_TAGS: usize = 10;
+
+#[derive(Serialize)]
+pub struct YearCount {
+    pub year: i32,
+    pub posts: usize,
+    // Relative to the busiest year, ready to be used as a bar width
+    pub percent: usize,
+}
+
+#[derive(Serialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub posts: usize,
+}
+
+#[derive(Serialize)]
+pub struct PostLength {
+    pub title: String,
+    pub filename: String,
+    pub slug: String,
+    pub words: usize,
+}
+
+#[derive(Serialize)]
+pub struct MonthCount {
+    // e.g. "March 2024"
+    pub month: String,
+    pub posts: usize,
+}
+
+// The numbers shown on /blog/stats, shaped so that the template only has to
+// loop over them. Ties are kept: every longest post is listed, not just one
+#[derive(Serialize)]
+pub struct PublicStats {
+    pub total_posts: usize,
+    pub total_words: usize,
+    pub posts_per_year: Vec<YearCount>,
+    pub top_tags: Vec<TagCount>,
+    pub longest_posts: Vec<PostLength>,
+    pub shortest_posts: Vec<PostLength>,
+    pub busiest_months: Vec<MonthCount>,
+}
+
+impl PublicStats {
+    pub fn compute(entries: &[Arc<BlogEntry>]) -> Self {
+        Self {
+            total_posts: entries.len(),
+            total_words: entries.iter().map(|e| e.word_count).sum(),
+            posts_per_year: posts_per_year(entries),
+            top_tags: top_tags(entries),
+            longest_posts: posts_with_length(entries, entries.iter().map(|e| e.word_count).max()),
+            shortest_posts: posts_with_length(entries, entries.iter().map(|e| e.word_count).min()),
+            busiest_months: busiest_months(entries),
+        }
+    }
+}
+
+// Years without posts in between are kept, so that the bars line up with time
+fn posts_per_year(entries: &[Arc<BlogEntry>]) -> Vec<YearCount> {
+    let mut years = BTreeMap::new();
+    for entry in entries {
+        *years
+            .entry(entry.description.publish_date.year())
+            .or_insert(0) += 1;
+    }
+    let (Some(first), Some(last)) = (years.keys().next(), years.keys().next_back()) else {
+        return vec![];
+    };
+    let busiest = years.values().copied().max().unwrap_or(1);
+    (*first..=*last)
+        .map(|year| {
+            let posts = years.get(&year).copied().unwrap_or(0);
+            YearCount {
+                year,
+                posts,
+                percent: posts * 100 / busiest,
+            }
+        })
+        .collect()
+}
+
+// Tags are counted case insensitively and shown as first written
+fn top_tags(entries: &[Arc<BlogEntry>]) -> Vec<TagCount> {
+    let mut tags: BTreeMap<String, TagCount> = BTreeMap::new();
+    for tag in entries.iter().flat_map(|e| &e.description.tags) {
+        tags.entry(tag.to_lowercase())
+            .or_insert_with(|| TagCount {
+                tag: tag.clone(),
+                posts: 0,
+            })
+            .posts += 1;
+    }
+    let mut tags: Vec<_> = tags.into_values().collect();
+    tags.sort_by(|a, b| b.posts.cmp(&a.posts).then_with(|| a.tag.cmp(&b.tag)));
+    tags.truncate(TOP_TAGS);
+    tags
+}
+
+fn posts_with_length(entries: &[Arc<BlogEntry>], words: Option<usize>) -> Vec<PostLength> {
+    let Some(words) = words else {
+        return vec![];
+    };
+    let mut posts: Vec<_> = entries
+        .iter()
+        .filter(|e| e.word_count == words)
+        .map(|e| PostLength {
+            title: e.description.title.clone(),
+            filename: e.filename.clone(),
+            slug: e.slug.clone(),
+            words,
+        })
+        .collect();
+    posts.sort_by(|a, b| a.title.cmp(&b.title));
+    posts
+}
+
+fn busiest_months(entries: &[Arc<BlogEntry>]) -> Vec<MonthCount> {
+    let mut months = BTreeMap::new();
+    for entry in entries {
+        let date = entry.description.publish_date;
+        *months.entry((date.year(), date.month())).or_insert(0) += 1;
+    }
+    let Some(busiest) = months.values().copied().max() else {
+        return vec![];
+    };
+    months
+        .into_iter()
+        .filter(|(_, posts)| *posts == busiest)
+        .filter_map(|((year, month), posts)| {
+            let first_day = NaiveDate::from_ymd_opt(year, month, 1)?;
+            Some(MonthCount {
+                month: first_day.format("%B %Y").to_string(),
+                posts,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn entry(title: &str, date: &str, tags: &str, words: usize) -> Arc<BlogEntry> {
+        let front_matter =
+            format!("title: {title}\nauthor: Crax\npublish_date: {date}\ntags: [{tags}]");
+        let mut entry = test_support::entry(&format!("{title}.md"), &front_matter);
+        entry.word_count = words;
+        Arc::new(entry)
+    }
+
+    fn titles(posts: &[PostLength]) -> Vec<&str> {
+        posts.iter().map(|p| p.title.as_str()).collect()
+    }
+
+    #[test]
+    fn empty_blog() {
+        let stats = PublicStats::compute(&[]);
+        assert_eq!((stats.total_posts, stats.total_words), (0, 0));
+        assert!(stats.posts_per_year.is_empty());
+        assert!(stats.top_tags.is_empty());
+        assert!(stats.longest_posts.is_empty());
+        assert!(stats.shortest_posts.is_empty());
+        assert!(stats.busiest_months.is_empty());
+    }
+
+    #[test]
+    fn single_post() {
+        let stats = PublicStats::compute(&[entry("Only", "2024-03-05T10:00:00Z", "rust", 42)]);
+        assert_eq!((stats.total_posts, stats.total_words), (1, 42));
+        assert_eq!(stats.posts_per_year.len(), 1);
+        assert_eq!(stats.posts_per_year[0].year, 2024);
+        assert_eq!(stats.posts_per_year[0].percent, 100);
+        assert_eq!(titles(&stats.longest_posts), ["Only"]);
+        assert_eq!(titles(&stats.shortest_posts), ["Only"]);
+        assert_eq!(stats.longest_posts[0].slug, "Only");
+        assert_eq!(stats.busiest_months.len(), 1);
+        assert_eq!(stats.busiest_months[0].month, "March 2024");
+        assert_eq!(stats.top_tags[0].tag, "rust");
+    }
+
+    #[test]
+    fn ties_are_all_listed() {
+        let stats = PublicStats::compute(&[
+            entry("B", "2022-01-10T10:00:00Z", "Rust, web", 100),
+            entry("A", "2022-01-20T10:00:00Z", "rust", 100),
+            entry("C", "2024-06-01T10:00:00Z", "web", 10),
+            entry("D", "2024-07-01T10:00:00Z", "", 10),
+        ]);
+        assert_eq!(stats.total_words, 220);
+        assert_eq!(titles(&stats.longest_posts), ["A", "B"]);
+        assert_eq!(titles(&stats.shortest_posts), ["C", "D"]);
+        // The busiest month, alone
+        let months: Vec<_> = stats.busiest_months.iter().map(|m| &m.month).collect();
+        assert_eq!(months, ["January 2022"]);
+        // Counted regardless of case, equal counts sorted by name
+        let tags: Vec<_> = stats
+            .top_tags
+            .iter()
+            .map(|t| (t.tag.as_str(), t.posts))
+            .collect();
+        assert_eq!(tags, [("Rust", 2), ("web", 2)]);
+    }
+
+    #[test]
+    fn quiet_years_are_kept() {
+        let stats = PublicStats::compute(&[
+            entry("A", "2021-01-01T10:00:00Z", "", 1),
+            entry("B", "2023-01-01T10:00:00Z", "", 1),
+            entry("C", "2023-05-01T10:00:00Z", "", 1),
+            entry("D", "2023-09-01T10:00:00Z", "", 1),
+        ]);
+        let years: Vec<_> = stats
+            .posts_per_year
+            .iter()
+            .map(|y| (y.year, y.posts, y.percent))
+            .collect();
+        assert_eq!(years, [(2021, 1, 33), (2022, 0, 0), (2023, 3, 100)]);
+        // Four months tied with a post each
+        assert_eq!(stats.busiest_months.len(), 4);
+    }
+
+    #[test]
+    fn keeps_only_the_top_tags() {
+        let entries: Vec<_> = (0..TOP_TAGS + 2)
+            .map(|i| {
+                entry(
+                    &format!("P{i}"),
+                    "2024-01-01T10:00:00Z",
+                    &format!("tag{i:02}, common"),
+                    1,
+                )
+            })
+            .collect();
+        let stats = PublicStats::compute(&entries);
+        assert_eq!(stats.top_tags.len(), TOP_TAGS);
+        assert_eq!(stats.top_tags[0].tag, "common");
+        assert_eq!(stats.top_tags[0].posts, TOP_TAGS + 2);
+        assert_eq!(stats.top_tags[1].tag, "tag00");
+    }
+}
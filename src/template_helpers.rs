@@ -0,0 +1,275 @@
+use std::cmp::Ordering;
+
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperDef, RenderContext, RenderError,
+    RenderErrorReason, ScopedJson,
+};
+use serde_json::{json, Value};
+
+// Helpers for the lists of the render context, which themes can't group or
+// sort otherwise. They return new lists, so they're used as subexpressions:
+//
+//     {{#each (limit (sort_by top_tags "posts" desc=true) 5)}}...{{/each}}
+//     {{#each (where_eq entries "evergreen" true)}}...{{/each}}
+//     {{#each (group_by entries "month")}}
+//         <h3>{{key}}</h3>{{#each items}}...{{/each}}
+//     {{/each}}
+//
+// Fields are dotted paths, e.g. "description.title". A missing one counts as
+// null: sorted last in either direction, and grouped last. Passing anything
+// but a list is a render error
+pub fn register(handlebars: &mut Handlebars) {
+    handlebars.register_helper("sort_by", Box::new(sort_by));
+    handlebars.register_helper("group_by", Box::new(group_by));
+    handlebars.register_helper("limit", Box::new(limit));
+    handlebars.register_helper("where_eq", Box::new(where_eq));
+}
+
+handlebars_helper!(sort_by: |list: array, path: str, { desc: bool = false }| {
+    sorted(list, path, desc)
+});
+handlebars_helper!(group_by: |list: array, path: str| grouped(list, path));
+handlebars_helper!(limit: |list: array, n: u64| {
+    list.iter().take(n as usize).cloned().collect::<Vec<_>>()
+});
+handlebars_helper!(where_eq: |list: array, path: str, value: Json| {
+    list.iter()
+        .filter(|item| field(item, path) == value)
+        .cloned()
+        .collect::<Vec<_>>()
+});
+
+// The links of the templates, e.g. {{link_to "/blog/" slug}}: the parameters
+// joined, under the path the blog is mounted at when they start with a /.
+// Other urls are left as they are
+pub fn register_link_to(handlebars: &mut Handlebars, mount_path: &str) {
+    handlebars.register_helper(
+        "link_to",
+        Box::new(LinkTo {
+            mount_path: mount_path.to_owned(),
+        }),
+    );
+}
+
+struct LinkTo {
+    mount_path: String,
+}
+
+impl HelperDef for LinkTo {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        if h.params().is_empty() {
+            return Err(RenderErrorReason::ParamNotFoundForIndex("link_to", 0).into());
+        }
+        let link: String = h
+            .params()
+            .iter()
+            .map(|param| match param.value() {
+                Value::String(s) => s.clone(),
+                Value::Null => String::new(),
+                value => value.to_string(),
+            })
+            .collect();
+        Ok(ScopedJson::Derived(Value::String(mounted(
+            &self.mount_path,
+            &link,
+        ))))
+    }
+}
+
+fn mounted(mount_path: &str, link: &str) -> String {
+    if link.starts_with('/') && !link.starts_with("//") {
+        format!("{mount_path}{link}")
+    } else {
+        link.to_owned()
+    }
+}
+
+static NULL: Value = Value::Null;
+
+fn field<'a>(item: &'a Value, path: &str) -> &'a Value {
+    path.split('.')
+        .try_fold(item, |value, key| match value {
+            Value::Object(fields) => fields.get(key),
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+        .unwrap_or(&NULL)
+}
+
+// Values of different types are ordered by type
+fn compare(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Bool(_) => 0,
+            Value::Number(_) => 1,
+            Value::String(_) => 2,
+            Value::Array(_) => 3,
+            Value::Object(_) => 4,
+            Value::Null => 5,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+// Stable, so that equal keys keep the order they came in
+fn sorted(list: &[Value], path: &str, desc: bool) -> Vec<Value> {
+    let mut items = list.to_vec();
+    items.sort_by(|a, b| {
+        let (a, b) = (field(a, path), field(b, path));
+        match (a.is_null(), b.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            _ if desc => compare(a, b).reverse(),
+            _ => compare(a, b),
+        }
+    });
+    items
+}
+
+// The groups come in the order their first item does
+fn grouped(list: &[Value], path: &str) -> Vec<Value> {
+    let mut groups: Vec<(&Value, Vec<Value>)> = vec![];
+    for item in list {
+        let key = field(item, path);
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, items)) => items.push(item.clone()),
+            None => groups.push((key, vec![item.clone()])),
+        }
+    }
+    groups.sort_by_key(|(key, _)| key.is_null());
+    groups
+        .into_iter()
+        .map(|(key, items)| json!({ "key": key, "items": items }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, data: &Value) -> Result<String, RenderError> {
+        let mut handlebars = Handlebars::new();
+        register(&mut handlebars);
+        handlebars.render_template(template, data)
+    }
+
+    fn posts() -> Value {
+        json!({
+            "posts": [
+                { "title": "a", "year": 2023, "meta": { "views": 5 } },
+                { "title": "b", "year": 2024, "meta": { "views": 1 } },
+                { "title": "c", "year": 2023 },
+                { "title": "d", "year": 2024, "meta": { "views": 5 } },
+                { "title": "e", "meta": { "views": 3 } },
+            ]
+        })
+    }
+
+    fn titles(template: &str) -> String {
+        render(template, &posts()).unwrap()
+    }
+
+    #[test]
+    fn sorts_by_nested_fields() {
+        assert_eq!(
+            titles("{{#each (sort_by posts \"meta.views\")}}{{title}}{{/each}}"),
+            "beadc"
+        );
+        assert_eq!(
+            titles("{{#each (sort_by posts \"meta.views\" desc=true)}}{{title}}{{/each}}"),
+            "adebc"
+        );
+    }
+
+    #[test]
+    fn sorting_keeps_equal_keys_in_order() {
+        // a and c, b and d share their year; e has none and goes last
+        assert_eq!(
+            titles("{{#each (sort_by posts \"year\")}}{{title}}{{/each}}"),
+            "acbde"
+        );
+        assert_eq!(
+            titles("{{#each (sort_by posts \"year\" desc=true)}}{{title}}{{/each}}"),
+            "bdace"
+        );
+        assert_eq!(
+            titles("{{#each (sort_by posts \"missing\")}}{{title}}{{/each}}"),
+            "abcde"
+        );
+    }
+
+    #[test]
+    fn sorts_mixed_types_by_type() {
+        let data = json!({ "items": [
+            { "k": "x" }, { "k": null }, { "k": 2 }, { "k": true }, { "k": 1.5 }
+        ]});
+        let html = render("{{#each (sort_by items \"k\")}}{{k}},{{/each}}", &data).unwrap();
+        assert_eq!(html, "true,1.5,2,x,,");
+    }
+
+    #[test]
+    fn groups_in_order_of_appearance() {
+        assert_eq!(
+            titles(concat!(
+                "{{#each (group_by posts \"year\")}}",
+                "[{{key}}:{{#each items}}{{title}}{{/each}}]",
+                "{{/each}}"
+            )),
+            "[2023:ac][2024:bd][:e]"
+        );
+    }
+
+    #[test]
+    fn limits_and_filters() {
+        assert_eq!(titles("{{#each (limit posts 2)}}{{title}}{{/each}}"), "ab");
+        assert_eq!(
+            titles("{{#each (limit posts 10)}}{{title}}{{/each}}"),
+            "abcde"
+        );
+        assert_eq!(
+            titles("{{#each (where_eq posts \"meta.views\" 5)}}{{title}}{{/each}}"),
+            "ad"
+        );
+        assert_eq!(
+            titles(concat!(
+                "{{#each (limit (sort_by (where_eq posts \"year\" 2024) ",
+                "\"meta.views\" desc=true) 1)}}{{title}}{{/each}}"
+            )),
+            "d"
+        );
+    }
+
+    #[test]
+    fn anything_but_a_list_is_a_render_error() {
+        let data = json!({ "text": "abc", "object": { "a": 1 }, "posts": [] });
+        for template in [
+            "{{#each (sort_by text \"a\")}}{{/each}}",
+            "{{#each (group_by object \"a\")}}{{/each}}",
+            "{{#each (limit missing 1)}}{{/each}}",
+            "{{#each (where_eq 3 \"a\" 1)}}{{/each}}",
+            "{{#each (sort_by posts)}}{{/each}}",
+            "{{#each (limit posts \"two\")}}{{/each}}",
+        ] {
+            assert!(render(template, &data).is_err(), "{template}");
+        }
+        assert_eq!(
+            render("{{#each (sort_by posts \"a\")}}x{{/each}}", &data).unwrap(),
+            ""
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+use crate::blog_storage::{parse_front_matter, BlogEntry};
+
+// A directory of its own for each test, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "swes-{name}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Failed to create a test directory");
+        Self(path)
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+
+    // Creates the parent directories too
+    pub fn write(&self, path: impl AsRef<Path>, content: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("Failed to create a test directory");
+        }
+        std::fs::write(&path, content).expect("Failed to write a test file");
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// An entry as stored, from the lines of its front matter, e.g.
+// "title: A\nauthor: B\npublish_date: 2024-01-01T00:00:00Z"
+pub fn entry(filename: &str, front_matter: &str) -> BlogEntry {
+    let (description, _) = parse_front_matter(&format!("---\n{front_matter}\n---\n"))
+        .expect("Invalid test front matter");
+    BlogEntry {
+        slug: description
+            .slug
+            .clone()
+            .unwrap_or_else(|| filename.trim_end_matches(".md").to_owned()),
+        description,
+        html: String::new(),
+        markdown: String::new(),
+        creation_date: SystemTime::UNIX_EPOCH,
+        filename: filename.to_owned(),
+        content_hash: front_matter.to_owned(),
+        word_count: 0,
+        reading_time_minutes: 0,
+        accessibility_warnings: vec![],
+        excerpt: String::new(),
+        excerpt_text: String::new(),
+        toc: vec![],
+        snippets: Default::default(),
+    }
+}
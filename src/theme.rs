@@ -0,0 +1,149 @@
+use std::path::Path;
+
+use anyhow::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+// Optional settings shipped with a theme, next to its templates
+pub const THEME_CONFIG_FILE: &str = "theme.toml";
+// Files of the theme itself, served under /theme
+pub const THEME_STATIC_DIR: &str = "static";
+// Used when the theme has no icons of its own
+const GLOBAL_FAVICON: &str = "/files/favicon.ico";
+
+// The icons a theme may provide in its static directory, in the order they
+// are linked: browsers pick the last one they support
+const FAVICONS: [(&str, &str, Option<&str>); 4] = [
+    ("favicon.ico", "icon", None),
+    ("favicon.png", "icon", Some("image/png")),
+    ("favicon.svg", "icon", Some("image/svg+xml")),
+    ("apple-touch-icon.png", "apple-touch-icon", None),
+];
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ThemeConfig {
+    accent_color: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Favicon {
+    pub rel: String,
+    pub href: String,
+    #[serde(rename = "type")]
+    pub mime_type: Option<String>,
+}
+
+// What a theme brings besides its templates
+pub struct Theme {
+    pub accent_color: Option<String>,
+    pub favicons: Vec<Favicon>,
+}
+
+impl Theme {
+    pub fn load(theme_path: &Path) -> anyhow::Result<Self> {
+        let config_path = theme_path.join(THEME_CONFIG_FILE);
+        let config = match std::fs::read_to_string(&config_path) {
+            Ok(content) => toml::from_str::<ThemeConfig>(&content)
+                .with_context(|| format!("Invalid theme config {config_path:?}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ThemeConfig::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let static_dir = theme_path.join(THEME_STATIC_DIR);
+        let mut favicons: Vec<_> = FAVICONS
+            .iter()
+            .filter(|(name, ..)| static_dir.join(name).is_file())
+            .map(|(name, rel, mime_type)| Favicon {
+                rel: rel.to_string(),
+                href: format!("/theme/{name}"),
+                mime_type: mime_type.map(str::to_owned),
+            })
+            .collect();
+        if favicons.is_empty() {
+            favicons.push(Favicon {
+                rel: "icon".to_owned(),
+                href: GLOBAL_FAVICON.to_owned(),
+                mime_type: None,
+            });
+        }
+        Ok(Self {
+            accent_color: accent_color(config.accent_color, &config_path.to_string_lossy()),
+            favicons,
+        })
+    }
+}
+
+// #rgb, #rgba, #rrggbb or #rrggbbaa, the # being optional since YAML reads
+// an unquoted one as a comment. Lowercased, with the #
+fn hex_color(value: &str) -> Option<String> {
+    let digits = value.trim();
+    let digits = digits.strip_prefix('#').unwrap_or(digits);
+    let valid =
+        matches!(digits.len(), 3 | 4 | 6 | 8) && digits.chars().all(|c| c.is_ascii_hexdigit());
+    valid.then(|| format!("#{}", digits.to_ascii_lowercase()))
+}
+
+// Invalid colors are dropped, so that a typo doesn't break the whole file
+pub fn accent_color(value: Option<String>, source: &str) -> Option<String> {
+    let value = value?;
+    let color = hex_color(&value);
+    if color.is_none() {
+        warn!("Ignoring the accent color {value:?} of {source}, it isn't a hex color");
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn reads_hex_colors() {
+        for (value, color) in [
+            ("#abc", "#abc"),
+            ("#ABCD", "#abcd"),
+            ("C0392B", "#c0392b"),
+            (" #c0392b80 ", "#c0392b80"),
+        ] {
+            assert_eq!(hex_color(value).as_deref(), Some(color), "{value}");
+        }
+        for value in ["", "#", "#12345", "#1234567", "#ggg", "red", "##abc"] {
+            assert_eq!(hex_color(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn drops_invalid_accent_colors() {
+        assert_eq!(accent_color(None, "test"), None);
+        assert_eq!(accent_color(Some("#12345".to_owned()), "test"), None);
+        assert_eq!(
+            accent_color(Some("#FFF".to_owned()), "test").as_deref(),
+            Some("#fff")
+        );
+    }
+
+    #[test]
+    fn loads_the_theme_config() {
+        let dir = TempDir::new("theme-config");
+        let theme = Theme::load(&dir.join("")).unwrap();
+        assert_eq!(theme.accent_color, None);
+        // Without icons of its own, the global one is linked
+        assert_eq!(theme.favicons.len(), 1);
+        assert_eq!(theme.favicons[0].href, GLOBAL_FAVICON);
+
+        dir.write(THEME_CONFIG_FILE, "accent_color = \"#2980B9\"");
+        dir.write("static/favicon.svg", "<svg/>");
+        let theme = Theme::load(&dir.join("")).unwrap();
+        assert_eq!(theme.accent_color.as_deref(), Some("#2980b9"));
+        let hrefs: Vec<_> = theme.favicons.iter().map(|f| f.href.as_str()).collect();
+        assert_eq!(hrefs, ["/theme/favicon.svg"]);
+
+        // A typo in the color isn't worth failing the theme for
+        dir.write(THEME_CONFIG_FILE, "accent_color = \"blue\"");
+        assert_eq!(Theme::load(&dir.join("")).unwrap().accent_color, None);
+        // Unknown keys are
+        dir.write(THEME_CONFIG_FILE, "accent = \"#fff\"");
+        assert!(Theme::load(&dir.join("")).is_err());
+    }
+}
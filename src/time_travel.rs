@@ -0,0 +1,229 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+use warp::{
+    http::StatusCode,
+    reply::{Reply, Response},
+    Filter, Rejection,
+};
+
+use crate::admin::is_admin;
+
+// Listings shown as they were at a past instant, when asked to
+pub(crate) type AsOf = Result<Option<DateTime<Utc>>, AsOfError>;
+
+pub(crate) enum AsOfError {
+    Unauthorized,
+    Invalid(String),
+}
+
+impl Reply for AsOfError {
+    fn into_response(self) -> Response {
+        match self {
+            AsOfError::Unauthorized => warp::reply::with_status(
+                "as_of needs the dev mode or the admin token",
+                StatusCode::UNAUTHORIZED,
+            )
+            .into_response(),
+            AsOfError::Invalid(as_of) => warp::reply::with_status(
+                format!("Invalid as_of '{as_of}', expected YYYY-MM-DD or RFC 3339"),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AsOfQuery {
+    as_of: Option<String>,
+}
+
+// ?as_of= hides the entries published after the given date. It's meant for
+// checking how the blog looked back then, so it needs the dev mode or the
+// admin token
+pub(crate) fn as_of(
+    dev: bool,
+    admin_token: Option<Arc<String>>,
+) -> impl Filter<Extract = (AsOf,), Error = Rejection> + Clone {
+    warp::query::<AsOfQuery>()
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |query: AsOfQuery, authorization| {
+            let Some(as_of) = query.as_of else {
+                return Ok(None);
+            };
+            if !dev && !is_admin(authorization, admin_token.clone()) {
+                return Err(AsOfError::Unauthorized);
+            }
+            match parse_as_of(&as_of) {
+                Some(as_of) => Ok(Some(as_of)),
+                None => Err(AsOfError::Invalid(as_of)),
+            }
+        })
+}
+
+// A bare date includes the whole day
+fn parse_as_of(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let end_of_day = date.and_hms_opt(23, 59, 59)?;
+        return Some(end_of_day.and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+// What a past version of the site looks like must not be cached as the
+// current one
+pub(crate) fn no_store(response: Response, as_of: Option<DateTime<Utc>>) -> Response {
+    if as_of.is_none() {
+        return response;
+    }
+    warp::reply::with_header(response, "cache-control", "no-store").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::TempDir, BlogEngine};
+
+    const CUTOFF: &str = "2024-03-01";
+
+    // An entry from before the cutoff and one from after it, both tagged
+    async fn engine(dir: &TempDir, dev: bool) -> BlogEngine {
+        for (name, date) in [("old", "2024-01-01"), ("new", "2024-06-01")] {
+            dir.write(
+                format!("{name}.md"),
+                format!(
+                    "---\ntitle: The {name} entry\nauthor: Crax\n\
+                     publish_date: {date}T08:00:00Z\ntags: [rust]\n---\n\nAbout {name}\n"
+                ),
+            );
+        }
+        BlogEngine::builder()
+            .base_path(dir.join(""))
+            .referrer_tracking(false)
+            .dev(dev)
+            .admin_token("secret")
+            .build()
+            .await
+            .unwrap()
+    }
+
+    // The entries shown at path, then whether the response may be cached
+    async fn listed(engine: &BlogEngine, path: &str, banner: bool) -> (Vec<&'static str>, bool) {
+        let response = warp::test::request()
+            .path(path)
+            .reply(&engine.routes())
+            .await;
+        assert_eq!(response.status(), 200, "{path}");
+        let body = String::from_utf8_lossy(response.body());
+        if banner {
+            assert!(
+                body.contains("Showing the blog as it was on"),
+                "{path}: {body}"
+            );
+        }
+        let entries = ["old", "new"]
+            .into_iter()
+            .filter(|name| body.contains(&format!("The {name} entry")))
+            .collect();
+        (
+            entries,
+            response
+                .headers()
+                .get("cache-control")
+                .is_some_and(|v| v == "no-store"),
+        )
+    }
+
+    async fn check_surface(path: &str, banner: bool) {
+        let dir = TempDir::new("time-travel");
+        let engine = engine(&dir, true).await;
+        let separator = if path.contains('?') { '&' } else { '?' };
+        assert_eq!(
+            listed(&engine, path, false).await,
+            (vec!["old", "new"], false)
+        );
+        assert_eq!(
+            listed(&engine, &format!("{path}{separator}as_of={CUTOFF}"), banner).await,
+            (vec!["old"], true)
+        );
+    }
+
+    #[tokio::test]
+    async fn hides_later_entries_from_the_home_page() {
+        check_surface("/blog", true).await;
+    }
+
+    #[tokio::test]
+    async fn hides_later_entries_from_the_archive() {
+        check_surface("/blog/archive", true).await;
+    }
+
+    #[tokio::test]
+    async fn hides_later_entries_from_the_tag_pages() {
+        check_surface("/blog/tag/rust", true).await;
+    }
+
+    #[tokio::test]
+    async fn hides_later_entries_from_the_feeds() {
+        check_surface("/feed/rss", false).await;
+        check_surface("/feed/json", false).await;
+    }
+
+    #[tokio::test]
+    async fn refuses_dates_it_cannot_read() {
+        let dir = TempDir::new("time-travel-invalid");
+        let engine = engine(&dir, true).await;
+        for path in ["/blog", "/blog/archive", "/blog/tag/rust", "/feed/rss"] {
+            let response = warp::test::request()
+                .path(&format!("{path}?as_of=last-tuesday"))
+                .reply(&engine.routes())
+                .await;
+            assert_eq!(response.status(), 400, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn needs_the_dev_mode_or_the_admin_token() {
+        let dir = TempDir::new("time-travel-auth");
+        let engine = engine(&dir, false).await;
+        let path = format!("/blog/archive?as_of={CUTOFF}");
+        for authorization in [None, Some("Bearer wrong")] {
+            let mut request = warp::test::request().path(&path);
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let response = request.reply(&engine.routes()).await;
+            assert_eq!(response.status(), 401, "{authorization:?}");
+        }
+        let response = warp::test::request()
+            .path(&path)
+            .header("authorization", "Bearer secret")
+            .reply(&engine.routes())
+            .await;
+        assert_eq!(response.status(), 200);
+        // Without as_of there's nothing to authorize
+        assert_eq!(
+            listed(&engine, "/blog/archive", false).await.0,
+            ["old", "new"]
+        );
+    }
+
+    #[test]
+    fn dates_include_the_whole_day() {
+        assert_eq!(
+            parse_as_of("2024-03-01"),
+            Some("2024-03-01T23:59:59Z".parse().unwrap())
+        );
+        assert_eq!(
+            parse_as_of("2024-03-01T10:00:00+02:00"),
+            Some("2024-03-01T08:00:00Z".parse().unwrap())
+        );
+        for value in ["", "2024-13-01", "yesterday", "2024-03-01 10:00"] {
+            assert_eq!(parse_as_of(value), None, "{value}");
+        }
+    }
+}
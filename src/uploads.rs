@@ -0,0 +1,339 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::{
+    blog_storage::{is_draft_name, parse_document, PostMetadata},
+    images::RESIZABLE_EXTENSIONS,
+    markdown::MarkdownOptions,
+    watchers,
+};
+
+pub const DEFAULT_UPLOAD_LIMIT: u64 = 8 * 1024 * 1024;
+
+// Where an upload goes, decided by its name
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UploadKind {
+    Entry,
+    Image,
+}
+
+pub enum UploadError {
+    InvalidName,
+    // Entries that wouldn't parse are refused, rather than left for the
+    // watcher to skip
+    InvalidEntry(anyhow::Error),
+    // An existing file can only be replaced knowing its hash
+    MissingPrecondition { current: String },
+    PreconditionFailed { current: Option<String> },
+    Io(anyhow::Error),
+}
+
+#[derive(Serialize)]
+pub struct Uploaded {
+    // None for drafts, which aren't served
+    pub url: Option<String>,
+    pub etag: String,
+    pub created: bool,
+}
+
+// Writes entries into the blog directory and images into the files one. The
+// entry watcher then picks the entries up like any other edit
+pub struct Uploads {
+    base_path: PathBuf,
+    files_path: PathBuf,
+    site_url: String,
+    limit: u64,
+    markdown: MarkdownOptions,
+    // Held from the If-Match check to the rename, so that two uploads of the
+    // same file can't both pass the check
+    write_lock: Mutex<()>,
+}
+
+impl Uploads {
+    pub fn new<P: AsRef<Path>>(
+        base_path: P,
+        files_path: P,
+        site_url: &str,
+        limit: u64,
+        markdown: MarkdownOptions,
+    ) -> Self {
+        Self {
+            base_path: base_path.as_ref().to_owned(),
+            files_path: files_path.as_ref().to_owned(),
+            site_url: site_url.trim_end_matches('/').to_owned(),
+            limit,
+            markdown,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    // Where the file is served once stored
+    pub fn url(&self, filename: &str) -> Option<String> {
+        match Self::kind(filename)? {
+            UploadKind::Entry if is_draft_name(filename) => None,
+            UploadKind::Entry => Some(format!("{}/blog/{filename}", self.site_url)),
+            UploadKind::Image => Some(format!("{}/files/{filename}", self.site_url)),
+        }
+    }
+
+    pub fn kind(filename: &str) -> Option<UploadKind> {
+        let safe = !filename.contains('\\')
+            && filename
+                .split('/')
+                .all(|c| !c.is_empty() && !c.starts_with('.'));
+        if !safe {
+            return None;
+        }
+        if watchers::is_valid_filename_entry(filename) || is_draft_name(filename) {
+            return Some(UploadKind::Entry);
+        }
+        let extension = Path::new(filename).extension()?.to_str()?.to_lowercase();
+        RESIZABLE_EXTENSIONS
+            .contains(&extension.as_str())
+            .then_some(UploadKind::Image)
+    }
+
+    pub fn validate(&self, filename: &str, content: &[u8]) -> Result<UploadKind, UploadError> {
+        let kind = Self::kind(filename).ok_or(UploadError::InvalidName)?;
+        if kind == UploadKind::Entry {
+            std::str::from_utf8(content)
+                .map_err(anyhow::Error::from)
+                .and_then(|content| parse_document::<PostMetadata>(content, &self.markdown))
+                .map_err(UploadError::InvalidEntry)?;
+        }
+        Ok(kind)
+    }
+
+    // Replacing a file requires if_match to hold its current hash, or '*';
+    // creating one requires no if_match at all
+    pub async fn store(
+        &self,
+        filename: &str,
+        content: &[u8],
+        if_match: Option<&str>,
+    ) -> Result<Uploaded, UploadError> {
+        let kind = self.validate(filename, content)?;
+        let path = match kind {
+            UploadKind::Entry => self.base_path.join(filename),
+            UploadKind::Image => self.files_path.join(filename),
+        };
+
+        let _guard = self.write_lock.lock().await;
+        let current = match tokio::fs::read(&path).await {
+            Ok(current) => Some(etag(&current)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(UploadError::Io(e.into())),
+        };
+        match (&current, if_match) {
+            (None, None) => {}
+            (Some(current), None) => {
+                return Err(UploadError::MissingPrecondition {
+                    current: current.clone(),
+                })
+            }
+            (Some(current), Some(if_match)) if etag_matches(if_match, current) => {}
+            (current, Some(_)) => {
+                return Err(UploadError::PreconditionFailed {
+                    current: current.clone(),
+                })
+            }
+        }
+        write_atomically(&path, content)
+            .await
+            .map_err(UploadError::Io)?;
+
+        Ok(Uploaded {
+            url: self.url(filename),
+            etag: etag(content),
+            created: current.is_none(),
+        })
+    }
+}
+
+// The same hash as the content_hash of the journal
+pub fn etag(content: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(content))
+}
+
+fn etag_matches(if_match: &str, current: &str) -> bool {
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == current)
+}
+
+// Written next to the target and renamed over it, so that readers (and the
+// watcher) never see half a file. The temporary name starts with a dot and
+// doesn't end in .md, so the watcher ignores it
+async fn write_atomically(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().context("Upload without a directory")?;
+    tokio::fs::create_dir_all(dir).await?;
+    let name = path
+        .file_name()
+        .context("Upload without a file name")?
+        .to_string_lossy();
+    let temp = dir.join(format!(".{name}.upload"));
+    tokio::fs::write(&temp, content)
+        .await
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+    if let Err(e) = tokio::fs::rename(&temp, path).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e).with_context(|| format!("Failed to replace {}", path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{markdown::MarkdownConfig, test_support::TempDir};
+
+    const ENTRY: &str =
+        "---\ntitle: Uploaded\nauthor: Crax\npublish_date: 2024-01-01T08:00:00Z\n---\n\nHi\n";
+
+    fn uploads(dir: &TempDir) -> Uploads {
+        Uploads::new(
+            dir.join("blog"),
+            dir.join("files"),
+            "https://blog.example/",
+            DEFAULT_UPLOAD_LIMIT,
+            MarkdownConfig::default().options(),
+        )
+    }
+
+    fn edited() -> String {
+        ENTRY.replace("Hi", "Hello")
+    }
+
+    #[tokio::test]
+    async fn creates_entries_and_images() {
+        let dir = TempDir::new("uploads-create");
+        let uploads = uploads(&dir);
+        let Ok(uploaded) = uploads.store("2024/post.md", ENTRY.as_bytes(), None).await else {
+            panic!("The entry wasn't stored");
+        };
+        assert!(uploaded.created);
+        assert_eq!(uploaded.etag, etag(ENTRY.as_bytes()));
+        assert_eq!(
+            uploaded.url.as_deref(),
+            Some("https://blog.example/blog/2024/post.md")
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("blog/2024/post.md")).unwrap(),
+            ENTRY
+        );
+        // No temporary file left behind
+        assert_eq!(std::fs::read_dir(dir.join("blog/2024")).unwrap().count(), 1);
+
+        let Ok(uploaded) = uploads.store("photos/cat.JPG", b"jpeg", None).await else {
+            panic!("The image wasn't stored");
+        };
+        assert_eq!(
+            uploaded.url.as_deref(),
+            Some("https://blog.example/files/photos/cat.JPG")
+        );
+        assert!(dir.join("files/photos/cat.JPG").exists());
+
+        // Stored, but not served
+        let Ok(uploaded) = uploads.store("_draft.md", ENTRY.as_bytes(), None).await else {
+            panic!("The draft wasn't stored");
+        };
+        assert_eq!(uploaded.url, None);
+    }
+
+    #[tokio::test]
+    async fn replaces_files_knowing_their_hash() {
+        let dir = TempDir::new("uploads-replace");
+        let uploads = uploads(&dir);
+        assert!(uploads
+            .store("post.md", ENTRY.as_bytes(), None)
+            .await
+            .is_ok());
+        let current = etag(ENTRY.as_bytes());
+
+        let missing = uploads.store("post.md", edited().as_bytes(), None).await;
+        assert!(
+            matches!(missing, Err(UploadError::MissingPrecondition { current: c }) if c == current)
+        );
+        let stale = uploads
+            .store("post.md", edited().as_bytes(), Some("\"stale\""))
+            .await;
+        assert!(
+            matches!(stale, Err(UploadError::PreconditionFailed { current: Some(c) }) if c == current)
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("blog/post.md")).unwrap(),
+            ENTRY
+        );
+
+        let if_match = format!("\"other\", {current}");
+        let Ok(replaced) = uploads
+            .store("post.md", edited().as_bytes(), Some(&if_match))
+            .await
+        else {
+            panic!("The entry wasn't replaced");
+        };
+        assert!(!replaced.created);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("blog/post.md")).unwrap(),
+            edited()
+        );
+        assert!(uploads
+            .store("post.md", ENTRY.as_bytes(), Some("*"))
+            .await
+            .is_ok());
+
+        // Nothing to match
+        let absent = uploads.store("new.md", ENTRY.as_bytes(), Some("*")).await;
+        assert!(matches!(
+            absent,
+            Err(UploadError::PreconditionFailed { current: None })
+        ));
+        assert!(!dir.join("blog/new.md").exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_names_and_entries() {
+        let dir = TempDir::new("uploads-invalid");
+        let uploads = uploads(&dir);
+        for name in [
+            "",
+            "post.txt",
+            "script.js",
+            "../post.md",
+            "a/../../post.md",
+            ".hidden.md",
+            "a//post.md",
+            "a\\post.md",
+            "/absolute.md",
+            "_index.md",
+            "_snippets/snippet.md",
+            "image.svg",
+        ] {
+            let stored = uploads.store(name, ENTRY.as_bytes(), None).await;
+            assert!(matches!(stored, Err(UploadError::InvalidName)), "{name:?}");
+        }
+        for content in [
+            "no front matter",
+            "---\ntitle: No author\n---\n",
+            "\u{FFFD}",
+        ] {
+            let stored = uploads.store("post.md", content.as_bytes(), None).await;
+            assert!(
+                matches!(stored, Err(UploadError::InvalidEntry(_))),
+                "{content:?}"
+            );
+        }
+        let stored = uploads.store("post.md", &[0xff, 0xfe], None).await;
+        assert!(matches!(stored, Err(UploadError::InvalidEntry(_))));
+        assert!(!dir.join("blog").exists());
+    }
+}
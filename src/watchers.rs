@@ -0,0 +1,435 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use log::{error, info, warn};
+use notify::{
+    event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode},
+    EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use tokio::{runtime::Handle, sync::Semaphore, task::JoinSet};
+
+use crate::{
+    blog_config::BlogConfig,
+    blog_storage::{BlogStorage, SECTION_INDEX_FILE},
+    chaos::{self, Chaos},
+    event_bus::{EventBus, UpdateEvent},
+    handlebars_support::{HandlebarsSupport, PARTIALS_DIR},
+    page_storage::PageStorage,
+    snippets::snippet_name,
+    theme::{THEME_CONFIG_FILE, THEME_STATIC_DIR},
+};
+
+fn is_change(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(CreateKind::File)
+            | EventKind::Modify(
+                ModifyKind::Name(RenameMode::To)
+                    | ModifyKind::Data(DataChange::Any | DataChange::Content)
+            )
+    )
+}
+
+pub(crate) fn watch_entries(
+    base_path: &Path,
+    storage: Arc<BlogStorage>,
+    event_bus: Arc<EventBus>,
+    handle: Handle,
+    chaos: Option<Arc<Chaos>>,
+) -> anyhow::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(chaos::watcher_events(
+        chaos,
+        move |res: notify::Result<notify::Event>| {
+            match res {
+                Ok(evt) => match evt.kind {
+                    EventKind::Create(CreateKind::File) => {
+                        create_entry(evt.paths[0].clone(), storage.clone(), handle.clone());
+                        event_bus.publish(UpdateEvent::Reload);
+                    }
+                    EventKind::Modify(
+                        ModifyKind::Name(RenameMode::To)
+                        | ModifyKind::Data(DataChange::Any | DataChange::Content),
+                    ) => {
+                        reload_entry(evt.paths[0].clone(), storage.clone(), handle.clone());
+                        event_bus.publish(UpdateEvent::Reload);
+                    }
+                    EventKind::Remove(RemoveKind::File) => remove_entry(
+                        evt.paths[0].clone(),
+                        storage.clone(),
+                        event_bus.clone(),
+                        handle.clone(),
+                    ),
+                    _ => {}
+                },
+                Err(e) => error!("err {e:?}"),
+            };
+        },
+    ))?;
+    watcher.watch(base_path, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+// Nothing to watch when there is no pages directory
+pub(crate) fn watch_pages(
+    pages: Arc<PageStorage>,
+    event_bus: Arc<EventBus>,
+    handle: Handle,
+    chaos: Option<Arc<Chaos>>,
+) -> anyhow::Result<Option<RecommendedWatcher>> {
+    if !pages.base_path().is_dir() {
+        return Ok(None);
+    }
+    let watched = pages.clone();
+    let mut watcher = notify::recommended_watcher(chaos::watcher_events(
+        chaos,
+        move |res: notify::Result<notify::Event>| match res {
+            Ok(evt) => {
+                let path = evt.paths[0].clone();
+                let pages = watched.clone();
+                let event_bus = event_bus.clone();
+                if is_change(&evt.kind) {
+                    handle.spawn(async move {
+                        if let Err(e) = pages.load(&path).await {
+                            error!("Failed to read page {path:?}: {e}");
+                        }
+                        event_bus.publish(UpdateEvent::Reload);
+                    });
+                } else if let EventKind::Remove(RemoveKind::File) = evt.kind {
+                    handle.spawn(async move { pages.remove(&path).await });
+                }
+            }
+            Err(e) => error!("err {e:?}"),
+        },
+    ))?;
+    watcher.watch(pages.base_path(), RecursiveMode::NonRecursive)?;
+    Ok(Some(watcher))
+}
+
+// The directory is watched rather than the file, which editors often replace
+pub(crate) fn watch_config(
+    config_path: PathBuf,
+    storage: Arc<BlogStorage>,
+    event_bus: Arc<EventBus>,
+    handle: Handle,
+    chaos: Option<Arc<Chaos>>,
+) -> anyhow::Result<Option<RecommendedWatcher>> {
+    let config_dir = match config_path.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if !config_dir.is_dir() {
+        return Ok(None);
+    }
+    let mut watcher = notify::recommended_watcher(chaos::watcher_events(
+        chaos,
+        move |res: notify::Result<notify::Event>| match res {
+            Ok(evt) => {
+                let is_config = evt
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == config_path.file_name());
+                if !is_config || !is_change(&evt.kind) {
+                    return;
+                }
+                let storage = storage.clone();
+                let event_bus = event_bus.clone();
+                let path = config_path.clone();
+                handle.spawn(async move {
+                    match BlogConfig::load(&path).await {
+                        Ok(config) => {
+                            info!("Reloading blog config {path:?}");
+                            storage.set_blog_info(config.info());
+                            event_bus.publish(UpdateEvent::Reload);
+                        }
+                        Err(e) => error!("Failed to reload blog config: {e:#}"),
+                    }
+                });
+            }
+            Err(e) => error!("err {e:?}"),
+        },
+    ))?;
+    watcher.watch(&config_dir, RecursiveMode::NonRecursive)?;
+    Ok(Some(watcher))
+}
+
+pub(crate) fn watch_theme(
+    theme_path: &Path,
+    stylesheet: &Path,
+    handlebars_support: Arc<RwLock<HandlebarsSupport>>,
+    event_bus: Arc<EventBus>,
+    chaos: Option<Arc<Chaos>>,
+) -> anyhow::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(chaos::watcher_events(
+        chaos,
+        move |res: notify::Result<notify::Event>| match res {
+            Ok(evt) => {
+                // Partials, icons and the theme config can be added and
+                // removed as well
+                let added_or_removed = evt.paths.iter().any(|p| {
+                    p.ends_with(THEME_CONFIG_FILE)
+                        || p.parent().is_some_and(|dir| {
+                            dir.ends_with(PARTIALS_DIR) || dir.ends_with(THEME_STATIC_DIR)
+                        })
+                });
+                let reload = match evt.kind {
+                    EventKind::Modify(_) => true,
+                    EventKind::Create(_) | EventKind::Remove(_) => added_or_removed,
+                    _ => false,
+                };
+                if reload {
+                    info!("Reloading handlebars theme");
+                    handlebars_support
+                        .write()
+                        .expect("Failed to write hb support")
+                        .reload_theme()
+                        .unwrap_or_else(|e| error!("Handlebars reload failed: {e}"));
+                    event_bus.publish(UpdateEvent::Reload);
+                }
+            }
+            Err(e) => error!("err {e:?}"),
+        },
+    ))?;
+    watcher.watch(theme_path, RecursiveMode::NonRecursive)?;
+    for dir in [PARTIALS_DIR, THEME_STATIC_DIR] {
+        let dir = theme_path.join(dir);
+        if dir.is_dir() {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
+    }
+    if stylesheet.exists() {
+        watcher.watch(stylesheet, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+fn create_entry(p: PathBuf, storage: Arc<BlogStorage>, handle: Handle) {
+    handle.spawn(async move {
+        let Some(entry_name) = storage.entry_name_for_path(&p) else {
+            return;
+        };
+        if let Some(section_path) = section_path_for_index(&entry_name) {
+            load_section(&p, section_path, &storage).await;
+            return;
+        }
+        if let Some(snippet) = snippet_name(&entry_name) {
+            storage.reload_snippet_users(snippet).await;
+            return;
+        }
+        if !is_valid_filename_entry(&entry_name) {
+            info!("Ignoring entry {entry_name} for insertion");
+            return;
+        }
+        let blog_entry = match storage.parse_entry(&entry_name).await {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Failed to read entry {entry_name}: {e}");
+                return;
+            }
+        };
+        info!("Storing new entry {entry_name}");
+        storage
+            .try_store_entry(&entry_name, Arc::new(blog_entry))
+            .await;
+    });
+}
+
+fn reload_entry(path: PathBuf, watcher_storage: Arc<BlogStorage>, handle: Handle) {
+    handle.spawn(async move {
+        let Some(entry_name) = watcher_storage.entry_name_for_path(&path) else {
+            return;
+        };
+        if let Some(section_path) = section_path_for_index(&entry_name) {
+            load_section(&path, section_path, &watcher_storage).await;
+            return;
+        }
+        if let Some(snippet) = snippet_name(&entry_name) {
+            watcher_storage.reload_snippet_users(snippet).await;
+            return;
+        }
+        if !is_valid_filename_entry(&entry_name) {
+            info!("Ignoring entry {entry_name} for reload");
+            return;
+        }
+        // Entries not known yet are stored as well: editors often create an
+        // empty file before writing it, or rename a temporary file into place
+        if watcher_storage.contains_entry(&entry_name).await {
+            info!("Reloading entry {entry_name}");
+        } else {
+            info!("Storing entry {entry_name} seen for the first time");
+        }
+        let blog_entry = match watcher_storage.parse_entry(&entry_name).await {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Failed to read entry {entry_name}: {e}");
+                return;
+            }
+        };
+        watcher_storage
+            .try_store_entry(&entry_name, Arc::new(blog_entry))
+            .await;
+    });
+}
+
+pub(crate) fn is_valid_filename_entry(filename: &str) -> bool {
+    filename.ends_with(".md") && !filename.split('/').any(|c| c.starts_with('_'))
+}
+
+// Section indices are named '_index.md', which is deliberately not a valid entry
+// name, so they need their own rule
+fn section_path_for_index(entry_name: &str) -> Option<String> {
+    let section_path = entry_name
+        .strip_suffix(SECTION_INDEX_FILE)?
+        .strip_suffix('/')?;
+    Some(section_path.to_owned())
+}
+
+async fn load_section(path: &Path, section_path: String, storage: &BlogStorage) {
+    match storage.parse_section(&path, section_path.clone()).await {
+        Ok(section) => storage.store_section(Arc::new(section)).await,
+        Err(e) => error!("Failed to read section {section_path}: {e}"),
+    }
+}
+
+fn remove_entry(
+    path: PathBuf,
+    watcher_storage: Arc<BlogStorage>,
+    event_bus: Arc<EventBus>,
+    handle: Handle,
+) {
+    handle.spawn(async move {
+        let Some(filename) = watcher_storage.entry_name_for_path(&path) else {
+            return;
+        };
+        if let Some(section_path) = section_path_for_index(&filename) {
+            info!("Removing section {section_path}");
+            watcher_storage.remove_section(&section_path).await;
+            return;
+        }
+        // The entries including it now show it as missing
+        if let Some(snippet) = snippet_name(&filename) {
+            watcher_storage.reload_snippet_users(snippet).await;
+            return;
+        }
+        if !filename.ends_with(".md") {
+            info!("Ignoring file removal {path:?}");
+            return;
+        }
+        info!("Removing entry {filename}");
+        watcher_storage.remove_entry(filename.to_owned()).await;
+        // Once it's gone, so that the pages reloading don't list it anymore
+        event_bus.publish(UpdateEvent::Reload);
+    });
+}
+
+// Subdirectories are walked too, so that entries can be organised e.g. by
+// year and month. Hidden ones (a .git checkout of the blog) are skipped
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => collect_files(&entry.path(), files)?,
+            Ok(t) if t.is_file() => files.push(entry.path()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Parsed concurrently, each file in its own task. The semaphore keeps the
+// number of files open at once within what the OS allows
+pub(crate) async fn add_most_recent_entries(
+    storage: Arc<BlogStorage>,
+    base_path: &impl AsRef<Path>,
+    concurrency: usize,
+) -> anyhow::Result<()> {
+    let mut files = vec![];
+    collect_files(base_path.as_ref(), &mut files)?;
+
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for path in files {
+        let Some(entry_name) = storage.entry_name_for_path(&path) else {
+            continue;
+        };
+        let storage = storage.clone();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            load_startup_file(&path, entry_name, &storage).await;
+        });
+    }
+    while let Some(result) = tasks.join_next().await {
+        result.context("Failed to load an entry")?;
+    }
+
+    Ok(())
+}
+
+async fn load_startup_file(path: &Path, entry_name: String, storage: &BlogStorage) {
+    if let Some(section_path) = section_path_for_index(&entry_name) {
+        info!("Added section {section_path}");
+        load_section(path, section_path, storage).await;
+        return;
+    }
+
+    // Drafts are parsed too, so that the admin listing knows whether they do
+    let blog_entry = match storage.cached_or_parse_entry(&entry_name).await {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Failed to read blog entry {}", e);
+            return;
+        }
+    };
+    if !is_valid_filename_entry(&entry_name) {
+        info!("Ignoring entry {entry_name}");
+        return;
+    }
+    info!("Added entry {}", entry_name);
+    storage
+        .try_store_entry(&entry_name, Arc::new(blog_entry))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use super::*;
+    use crate::test_support::{self, TempDir};
+
+    #[tokio::test]
+    async fn removing_an_entry_reloads_the_pages() {
+        let dir = TempDir::new("watch-remove");
+        let storage = Arc::new(BlogStorage::new(
+            dir.join(""),
+            NonZeroUsize::new(10).unwrap(),
+        ));
+        let front_matter = "title: Gone\nauthor: Crax\npublish_date: 2024-01-01T08:00:00Z";
+        let entry = test_support::entry("gone.md", front_matter);
+        storage.try_store_entry("gone.md", Arc::new(entry)).await;
+        let event_bus = Arc::new(EventBus::new());
+        let mut events = event_bus.subscribe();
+
+        remove_entry(
+            dir.join("gone.md"),
+            storage.clone(),
+            event_bus,
+            Handle::current(),
+        );
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("No event after the removal")
+            .unwrap();
+        assert!(matches!(event.event, UpdateEvent::Reload));
+        // Sent once the entry is gone
+        assert!(!storage.contains_entry("gone.md").await);
+        let mut listed = 0;
+        storage.iterate_most_recent_entries(|_| listed += 1).await;
+        assert_eq!(listed, 0);
+    }
+}
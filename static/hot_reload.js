@@ -1,2 +1,29 @@
-var evtSource = new EventSource("/events");
+var evtSource = new EventSource("{{link_to "/events"}}");
+var lastPing = Date.now();
 evtSource.onmessage = (msg) => { location.reload(); }
+evtSource.addEventListener("ping", () => { lastPing = Date.now(); });
+
+// Some proxies buffer the event stream: when the pings stop coming through,
+// fall back to long polling
+var stallCheck = setInterval(() => {
+    if (Date.now() - lastPing > 25000) {
+        clearInterval(stallCheck);
+        evtSource.close();
+        pollForUpdates(null);
+    }
+}, 5000);
+
+function pollForUpdates(cursor) {
+    var url = cursor === null ? "{{link_to "/events/poll"}}" : "{{link_to "/events/poll"}}?cursor=" + cursor;
+    fetch(url)
+        .then((response) => response.json())
+        .then((result) => {
+            // A cursor going backwards means that the server restarted
+            if (cursor !== null && (result.events.length > 0 || result.cursor < cursor)) {
+                location.reload();
+                return;
+            }
+            pollForUpdates(result.cursor);
+        })
+        .catch(() => setTimeout(() => pollForUpdates(cursor), 5000));
+}
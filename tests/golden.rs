@@ -0,0 +1,115 @@
+// Renders the fixture blog of this directory with the default theme and a
+// pinned clock, and compares every response with its snapshot under
+// tests/snapshots. After an intended change, regenerate them with
+//
+//     UPDATE_SNAPSHOTS=1 cargo test --test golden
+//
+// and review the diff of the snapshots like any other change
+use std::{path::PathBuf, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use swes::{clock::FixedClock, BlogEngine};
+
+const UPDATE_VAR: &str = "UPDATE_SNAPSHOTS";
+// Lines shown around the first difference
+const DIFF_CONTEXT: usize = 3;
+
+const ROUTES: &[(&str, &str)] = &[
+    ("home", "/blog"),
+    ("entry", "/blog/formatting"),
+    ("not_found", "/blog/no-such-entry"),
+    ("tag", "/blog/tag/meta"),
+    ("feed_rss", "/feed/rss"),
+    ("feed_atom", "/feed/atom"),
+    ("feed_json", "/feed/json"),
+];
+
+fn pinned_time() -> DateTime<Utc> {
+    "2025-01-01T12:00:00Z".parse().unwrap()
+}
+
+// The bits of the output that change from one machine or build to another
+fn normalize(body: &str) -> String {
+    body.replace(env!("CARGO_PKG_VERSION"), "{version}")
+}
+
+fn snapshot_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("snapshots")
+        .join(format!("{name}.snap"))
+}
+
+// The first differing line, with some context, as - expected / + actual
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+    let first = expected
+        .iter()
+        .zip(&actual)
+        .position(|(e, a)| e != a)
+        .unwrap_or(expected.len().min(actual.len()));
+    let start = first.saturating_sub(DIFF_CONTEXT);
+    let mut out = format!("first difference at line {}\n", first + 1);
+    for line in &expected[start..first] {
+        out.push_str(&format!("  {line}\n"));
+    }
+    let end = |lines: &[&str]| (first + DIFF_CONTEXT + 1).min(lines.len());
+    for line in &expected[first..end(&expected)] {
+        out.push_str(&format!("- {line}\n"));
+    }
+    for line in &actual[first..end(&actual)] {
+        out.push_str(&format!("+ {line}\n"));
+    }
+    out
+}
+
+#[tokio::test]
+async fn rendered_pages_match_their_snapshots() {
+    let engine = BlogEngine::builder()
+        .base_path("tests")
+        .referrer_tracking(false)
+        .site_url("https://blog.example")
+        .clock(Arc::new(FixedClock(pinned_time())))
+        .build()
+        .await
+        .expect("Failed to build the fixture blog");
+    let routes = engine.routes();
+    let update = std::env::var_os(UPDATE_VAR).is_some();
+
+    let mut failures = vec![];
+    for (name, path) in ROUTES {
+        let response = warp::test::request().path(path).reply(&routes).await;
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let actual = normalize(&format!(
+            "{path}\n{} {content_type}\n\n{}",
+            response.status(),
+            String::from_utf8_lossy(response.body())
+        ));
+
+        let snapshot = snapshot_path(name);
+        if update {
+            std::fs::create_dir_all(snapshot.parent().unwrap()).unwrap();
+            std::fs::write(&snapshot, &actual).unwrap();
+            continue;
+        }
+        match std::fs::read_to_string(&snapshot) {
+            Ok(expected) if expected == actual => {}
+            Ok(expected) => failures.push(format!(
+                "{name} ({path}) differs from {snapshot:?}, {}",
+                diff(&expected, &actual)
+            )),
+            Err(e) => failures.push(format!("{name} ({path}): can't read {snapshot:?}: {e}")),
+        }
+    }
+    engine.shutdown().await;
+    assert!(
+        failures.is_empty(),
+        "{}\nRun with {UPDATE_VAR}=1 if the changes are intended",
+        failures.join("\n")
+    );
+}